@@ -1,7 +1,8 @@
 //! Traits for in-memory or persistent maps of blob with bao encoded outboards.
-use std::{io, path::PathBuf};
+use std::{collections::BTreeSet, io, path::PathBuf, time::SystemTime};
 
 use crate::{
+    collection::CollectionParser,
     util::{
         progress::{IdGenerator, ProgressSender},
         RpcError,
@@ -115,9 +116,20 @@ pub trait ReadableStore: Map {
     fn blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static>;
     /// list all roots (collections or other explicitly added things) in the database
     ///
+    /// Roots are the starting points for garbage collection. Everything that is
+    /// not reachable from a root is eligible for deletion, see [gc].
+    ///
     /// This function should not block to perform io. The knowledge about
     /// existing roots must be present in memory.
     fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static>;
+    /// list all tags (named roots) in the database, sorted by name
+    ///
+    /// Tags are roots as well, so everything reachable from a tagged hash will
+    /// be retained by garbage collection.
+    ///
+    /// This function should not block to perform io. The knowledge about
+    /// existing tags must be present in memory.
+    fn tags(&self) -> Box<dyn Iterator<Item = (String, TagInfo)> + Send + Sync + 'static>;
     /// Validate the database
     fn validate(&self, tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>>;
 
@@ -144,7 +156,7 @@ pub trait Store: ReadableStore + PartialMap {
     /// This trait method imports a file from a local path.
     ///
     /// `data` is the path to the file.
-    /// `mode` is a hint how the file should be imported.
+    /// `mode` is a hint how the file should be imported, see [ImportMode].
     /// `progress` is a sender that provides a way for the importer to send progress messages
     /// when importing large files. This also serves as a way to cancel the import. If the
     /// consumer of the progress messages is dropped, subsequent attempts to send progress
@@ -163,6 +175,111 @@ pub trait Store: ReadableStore + PartialMap {
     ///
     /// It is a special case of `import` that does not use the file system.
     fn import_bytes(&self, bytes: Bytes) -> BoxFuture<'_, io::Result<Hash>>;
+
+    /// Pin a hash as a root, so it and everything reachable from it will be
+    /// retained by garbage collection.
+    ///
+    /// Pinning a hash that is already pinned is a no-op.
+    fn pin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Remove a hash from the set of roots.
+    ///
+    /// This does not delete any data. The data will be removed on the next
+    /// garbage collection run unless it is reachable from another root.
+    fn unpin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Set a tag, i.e. a named root, to the given hash.
+    ///
+    /// If a tag with this name already exists, it will be overwritten.
+    fn set_tag(&self, name: String, hash: Hash) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Delete a tag.
+    ///
+    /// Deleting a tag that does not exist is a no-op. Like [Store::unpin], this
+    /// does not delete any data.
+    fn delete_tag(&self, name: String) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Delete a blob from the database.
+    ///
+    /// This will remove both complete and partial data for the hash, and also
+    /// remove the hash from the set of roots and all tags that point to it. Data that is stored externally,
+    /// e.g. because it was imported with [ImportMode::TryReference], is not touched.
+    ///
+    /// Deleting a hash that is not in the database is a no-op.
+    fn delete(&self, hash: &Hash) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Run a mark and sweep garbage collection on the store.
+///
+/// The mark phase starts from the [ReadableStore::roots] and the hashes of the
+/// [ReadableStore::tags] of the store. Every root that is complete and can be parsed by the given collection parser is
+/// considered a collection, and all its children are marked as live as well. Children that
+/// the parser reports as nested collections are followed recursively.
+///
+/// The sweep phase then deletes all complete and partial entries that are not
+/// live.
+///
+/// Note that the store might be modified while this is running. Data that is
+/// added after the mark phase and is not reachable from a root will be deleted.
+/// So it is up to the caller to make sure that no data is added while gc is running.
+///
+/// The returned future is not `Send`, since the collection parser is not
+/// required to be `Send`.
+pub async fn gc<S: Store, C: CollectionParser>(
+    store: &S,
+    collection_parser: &C,
+    progress: impl ProgressSender<Msg = GcProgress>,
+) -> anyhow::Result<()> {
+    // mark phase
+    let mut live = BTreeSet::new();
+    let roots = store
+        .roots()
+        .chain(store.tags().map(|(_, info)| info.hash))
+        .collect::<BTreeSet<_>>();
+    live.extend(roots.iter().copied());
+    // roots are parsed as collections, and so are children that are nested collections
+    let mut collections = roots.into_iter().collect::<Vec<_>>();
+    while let Some(hash) = collections.pop() {
+        // partial collections can not be parsed, but we still keep them
+        if store.get_partial(&hash).is_some() {
+            continue;
+        }
+        let Some(entry) = store.get(&hash) else {
+            continue;
+        };
+        let reader = entry.data_reader().await?;
+        // if the hash can not be parsed as a collection, it is just a blob
+        let Ok((mut links, _stats)) = collection_parser.parse(0, reader).await else {
+            continue;
+        };
+        while let Some(hash) = links.next().await? {
+            if live.insert(hash) && links.is_collection() {
+                collections.push(hash);
+            }
+        }
+    }
+    progress
+        .send(GcProgress::Marked {
+            live: live.len() as u64,
+        })
+        .await?;
+    // sweep phase
+    let candidates = store
+        .blobs()
+        .chain(store.partial_blobs())
+        .filter(|hash| !live.contains(hash))
+        .collect::<BTreeSet<_>>();
+    for hash in &candidates {
+        store.delete(hash).await?;
+        progress.send(GcProgress::Deleted { hash: *hash }).await?;
+    }
+    progress
+        .send(GcProgress::AllDone {
+            live: live.len() as u64,
+            deleted: candidates.len() as u64,
+        })
+        .await?;
+    Ok(())
 }
 
 /// Progress messages for an import operation
@@ -192,9 +309,20 @@ pub enum ImportProgress {
     ///
     /// There will be multiple of these messages for an id
     OutboardProgress { id: u64, offset: u64 },
+    /// Imported a chunk of a file
+    ///
+    /// Only sent for [`ImportMode::Chunked`], instead of `OutboardProgress`. There will be
+    /// one of these messages for every chunk, in order, after `Size`.
+    Chunk {
+        id: u64,
+        offset: u64,
+        size: u64,
+        hash: Hash,
+    },
     /// Done computing the outboard
     ///
-    /// This comes after `Size` and zero or more `OutboardProgress` messages
+    /// This comes after `Size` and zero or more `OutboardProgress` messages.
+    /// For [`ImportMode::Chunked`], the hash is the hash of the [`crate::chunked::Manifest`].
     OutboardDone { id: u64, hash: Hash },
 }
 
@@ -220,6 +348,16 @@ pub enum ImportMode {
     /// Stores are allowed to ignore this mode and always copy the file, e.g.
     /// if the file is very small or if the store does not support referencing files.
     TryReference,
+    /// This mode will split the file into content-defined chunks, and copy every chunk into
+    /// the database as a separate blob.
+    ///
+    /// The result of the import is the hash of a [`crate::chunked::Manifest`] listing the
+    /// chunks, which is a collection with the chunks as children. Different versions of a
+    /// file share most of their chunks, so they can be stored and transferred efficiently.
+    ///
+    /// Unlike the other modes, this is not just a hint. Stores must support it, see
+    /// [`crate::chunked::import_file`].
+    Chunked,
 }
 /// The import mode describes how files will be imported.
 ///
@@ -268,6 +406,39 @@ pub enum ExportProgress {
     Done { id: u64 },
 }
 
+/// Information about a tag, see [ReadableStore::tags]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagInfo {
+    /// The hash the tag points to
+    pub hash: Hash,
+    /// The time the tag was created or last set
+    pub created: SystemTime,
+}
+
+/// Progress updates for the gc operation
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GcProgress {
+    /// We are done with the mark phase.
+    Marked {
+        /// The number of hashes that are reachable from the roots
+        live: u64,
+    },
+    /// An unreachable entry was deleted.
+    Deleted {
+        /// The hash of the deleted entry
+        hash: Hash,
+    },
+    /// We are done with the whole operation.
+    AllDone {
+        /// The number of hashes that are reachable from the roots
+        live: u64,
+        /// The number of entries that were deleted
+        deleted: u64,
+    },
+    /// We got an error and need to abort.
+    Abort(RpcError),
+}
+
 /// Progress updates for the provide operation
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ValidateProgress {
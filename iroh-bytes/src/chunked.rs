@@ -0,0 +1,248 @@
+//! Content-defined chunking of files.
+//!
+//! Files imported with [`ImportMode::Chunked`] are split into chunks with [FastCDC], and
+//! every chunk is stored as a blob of its own. Chunk boundaries depend only on the content
+//! around them, so inserting or removing data in the middle of a file only changes the
+//! chunks around the edit, and a new version of a file shares most of its blobs with the
+//! previous one.
+//!
+//! The chunks of a file are listed, in order, in a [`Manifest`], which is stored as a blob
+//! as well. The hash of the manifest is the result of the import. The chunks are the
+//! children of the manifest when it is requested as a collection.
+//!
+//! [FastCDC]: https://www.usenix.org/conference/atc16/technical-sessions/presentation/xia
+//! [`ImportMode::Chunked`]: crate::baomap::ImportMode::Chunked
+use std::{io, path::Path};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+
+use crate::{baomap::ImportProgress, util::progress::ProgressSender, Hash};
+
+/// Prefix of a serialized [`Manifest`], to tell it apart from other collection formats.
+pub const MAGIC: &[u8; 8] = b"iroh-cdc";
+
+/// Minimum size of a chunk, except for the last chunk of a file
+pub const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+/// Average size of a chunk
+pub const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+/// Maximum size of a chunk
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// The chunks of a file, in order
+///
+/// Note that the format is subject to change.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    chunks: Vec<Chunk>,
+}
+
+/// A chunk of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    /// The hash of the chunk blob
+    pub hash: Hash,
+    /// The size of the chunk blob
+    pub size: u64,
+}
+
+impl Manifest {
+    /// Create a new manifest from a list of chunks, in file order
+    ///
+    /// Fails if the total size of the chunks does not fit into a `u64`.
+    pub fn new(chunks: Vec<Chunk>) -> Result<Self> {
+        total_size(&chunks).context("manifest size overflows")?;
+        Ok(Self { chunks })
+    }
+
+    /// Serialize this manifest to a std `Vec<u8>`
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = MAGIC.to_vec();
+        data.extend(postcard::to_stdvec(self)?);
+        Ok(data)
+    }
+
+    /// Deserialize a manifest from a byte slice
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let data = data
+            .strip_prefix(MAGIC)
+            .context("not a serialized Manifest")?;
+        let manifest: Manifest =
+            postcard::from_bytes(data).context("failed to deserialize Manifest data")?;
+        total_size(&manifest.chunks).context("manifest size overflows")?;
+        Ok(manifest)
+    }
+
+    /// True if the data looks like a serialized manifest
+    pub fn is_manifest(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// The chunks, in file order
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// The size of the file, i.e. the total size of the chunks
+    pub fn size(&self) -> u64 {
+        total_size(&self.chunks).expect("checked when the manifest was created")
+    }
+}
+
+/// The total size of `chunks`, or `None` if it overflows
+fn total_size(chunks: &[Chunk]) -> Option<u64> {
+    chunks
+        .iter()
+        .try_fold(0u64, |size, chunk| size.checked_add(chunk.size))
+}
+
+/// Split the file at `path` into chunks and import them with `import_chunk`.
+///
+/// This is meant to be used by [`crate::baomap::Store::import`] implementations for
+/// [`crate::baomap::ImportMode::Chunked`], on a thread where blocking is allowed. The
+/// [`ImportProgress::Found`] message for `id` must already have been sent.
+///
+/// `import_chunk` is also used to import the [`Manifest`]. Returns the hash of the manifest
+/// and the size of the file.
+pub fn import_file(
+    path: &Path,
+    id: u64,
+    progress: &impl ProgressSender<Msg = ImportProgress>,
+    import_chunk: impl FnMut(Bytes) -> io::Result<Hash>,
+) -> io::Result<(Hash, u64)> {
+    let file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    progress.blocking_send(ImportProgress::Size { id, size })?;
+    import_reader(
+        file,
+        (MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE),
+        id,
+        progress,
+        import_chunk,
+    )
+}
+
+fn import_reader(
+    reader: impl io::Read,
+    (min, avg, max): (u32, u32, u32),
+    id: u64,
+    progress: &impl ProgressSender<Msg = ImportProgress>,
+    mut import_chunk: impl FnMut(Bytes) -> io::Result<Hash>,
+) -> io::Result<(Hash, u64)> {
+    let mut chunks = Vec::new();
+    for chunk in StreamCDC::new(reader, min, avg, max) {
+        let chunk = chunk?;
+        let size = chunk.length as u64;
+        let hash = import_chunk(chunk.data.into())?;
+        progress.blocking_send(ImportProgress::Chunk {
+            id,
+            offset: chunk.offset,
+            size,
+            hash,
+        })?;
+        chunks.push(Chunk { hash, size });
+    }
+    let manifest = Manifest::new(chunks).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let size = manifest.size();
+    let data = manifest
+        .to_bytes()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let hash = import_chunk(data.into())?;
+    progress.blocking_send(ImportProgress::OutboardDone { id, hash })?;
+    Ok((hash, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::util::progress::IgnoreProgressSender;
+
+    const SIZES: (u32, u32, u32) = (1024, 4096, 16384);
+
+    /// Import `data` into `blobs`, and return the manifest
+    fn import(data: &[u8], blobs: &Mutex<BTreeMap<Hash, Bytes>>) -> Manifest {
+        let progress = IgnoreProgressSender::default();
+        let (hash, size) = import_reader(data, SIZES, 0, &progress, |data| {
+            let hash = Hash::from(bao_tree::blake3::hash(&data));
+            blobs.lock().unwrap().insert(hash, data);
+            Ok(hash)
+        })
+        .unwrap();
+        assert_eq!(size, data.len() as u64);
+        let blobs = blobs.lock().unwrap();
+        let manifest = Manifest::from_bytes(&blobs[&hash]).unwrap();
+        let joined = manifest
+            .chunks()
+            .iter()
+            .flat_map(|chunk| blobs[&chunk.hash].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(joined, data);
+        manifest
+    }
+
+    #[test]
+    fn manifest_roundtrip() {
+        let manifest = Manifest::new(vec![Chunk {
+            hash: Hash::from(bao_tree::blake3::hash(b"a")),
+            size: 1,
+        }])
+        .unwrap();
+        let bytes = manifest.to_bytes().unwrap();
+        assert!(Manifest::is_manifest(&bytes));
+        assert_eq!(Manifest::from_bytes(&bytes).unwrap(), manifest);
+        assert_eq!(manifest.size(), 1);
+        assert!(!Manifest::is_manifest(b"something else"));
+        assert!(Manifest::from_bytes(b"something else").is_err());
+    }
+
+    #[test]
+    fn manifest_size_overflow() {
+        let chunk = Chunk {
+            hash: Hash::from(bao_tree::blake3::hash(b"a")),
+            size: u64::MAX,
+        };
+        assert!(Manifest::new(vec![chunk, chunk]).is_err());
+        // a received manifest is rejected as well
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(
+            postcard::to_stdvec(&Manifest {
+                chunks: vec![chunk, chunk],
+            })
+            .unwrap(),
+        );
+        assert!(Manifest::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn chunks_are_shared_after_edit() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut data = vec![0u8; 256 * 1024];
+        rng.fill(&mut data[..]);
+        let blobs = Mutex::new(BTreeMap::new());
+        let before = import(&data, &blobs);
+        assert!(before.chunks().len() > 10);
+        for chunk in before.chunks() {
+            assert!(chunk.size <= SIZES.2 as u64);
+        }
+
+        // insert some bytes in the middle
+        data.splice(100_000..100_000, b"hello world".iter().copied());
+        let after = import(&data, &blobs);
+        let shared = after
+            .chunks()
+            .iter()
+            .filter(|chunk| before.chunks().contains(chunk))
+            .count();
+        // only the chunks around the edit change
+        assert!(shared + 3 >= after.chunks().len());
+
+        let empty = import(&[], &blobs);
+        assert!(empty.chunks().is_empty());
+    }
+}
@@ -38,6 +38,13 @@ pub trait LinkStream: Debug {
     fn next(&mut self) -> LocalBoxFuture<'_, anyhow::Result<Option<Hash>>>;
     /// Skip a number of hashes in the collection.
     fn skip(&mut self, n: u64) -> LocalBoxFuture<'_, anyhow::Result<()>>;
+    /// True if the hash last returned by [`LinkStream::next`] is itself a collection.
+    ///
+    /// This is used to find the contents of nested collections, e.g. during gc.
+    /// The default implementation returns false, for collections that do not nest.
+    fn is_collection(&self) -> bool {
+        false
+    }
 }
 
 /// Information about a collection.
@@ -4,10 +4,12 @@
 #![recursion_limit = "256"]
 
 pub mod baomap;
+pub mod chunked;
 pub mod collection;
 pub mod get;
 pub mod protocol;
 pub mod provider;
+pub mod slice;
 pub mod util;
 
 #[cfg(test)]
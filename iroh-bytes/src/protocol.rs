@@ -254,6 +254,10 @@ pub enum Closed {
     /// Only a single request is allowed on a stream, if more data is received after this a
     /// provider may send this error code in a STOP_STREAM frame.
     RequestReceived = 2,
+    /// The provider rejected the connection or request because a limit was reached.
+    ///
+    /// See [`crate::provider::limits`].
+    RateLimited = 3,
 }
 
 impl Closed {
@@ -263,6 +267,7 @@ impl Closed {
             Closed::StreamDropped => b"stream dropped",
             Closed::ProviderTerminating => b"provider terminating",
             Closed::RequestReceived => b"request received",
+            Closed::RateLimited => b"rate limited",
         }
     }
 }
@@ -286,6 +291,7 @@ impl TryFrom<VarInt> for Closed {
             0 => Ok(Self::StreamDropped),
             1 => Ok(Self::ProviderTerminating),
             2 => Ok(Self::RequestReceived),
+            3 => Ok(Self::RateLimited),
             val => Err(UnknownErrorCode(val)),
         }
     }
@@ -167,6 +167,38 @@ impl RangeSpecSeq {
         Self(res)
     }
 
+    /// Create a new range spec sequence from a sequence of (offset, range set) pairs
+    ///
+    /// The offsets must be strictly increasing. All offsets that are not mentioned
+    /// get an empty range set. Unlike [RangeSpecSeq::new], the cost of this does not
+    /// depend on the size of the gaps, so it is useful for requesting a few children
+    /// of a large collection.
+    pub fn new_sparse(children: impl IntoIterator<Item = (u64, RangeSet2<ChunkNum>)>) -> Self {
+        let mut prev = RangeSet2::empty();
+        let mut count = 0;
+        let mut next = 0;
+        let mut res = SmallVec::new();
+        let mut push = |v: RangeSet2<ChunkNum>, n: u64| {
+            if v == prev {
+                count += n;
+            } else {
+                res.push((count, RangeSpec::new(&v)));
+                prev = v;
+                count = n;
+            }
+        };
+        for (offset, v) in children {
+            debug_assert!(offset >= next, "offsets must be strictly increasing");
+            if offset > next {
+                push(RangeSet2::empty(), offset - next);
+            }
+            push(v, 1);
+            next = offset + 1;
+        }
+        push(RangeSet2::empty(), 1);
+        Self(res)
+    }
+
     /// An infinite iterator of range specs
     pub fn iter(&self) -> RequestRangeSpecIter<'_> {
         let before_first = self.0.get(0).map(|(c, _)| *c).unwrap_or_default();
@@ -340,5 +372,17 @@ mod tests {
             let actual = range_spec_seq_roundtrip_impl(&ranges);
             prop_assert_eq!(expected, actual);
         }
+
+        #[test]
+        fn range_spec_seq_sparse(ranges in proptest::collection::vec(ranges(0..100), 0..10)) {
+            let expected = RangeSpecSeq::new(ranges.iter().cloned());
+            let sparse = ranges
+                .into_iter()
+                .enumerate()
+                .filter(|(_, r)| !r.is_empty())
+                .map(|(i, r)| (i as u64, r));
+            let actual = RangeSpecSeq::new_sparse(sparse);
+            prop_assert_eq!(expected, actual);
+        }
     }
 }
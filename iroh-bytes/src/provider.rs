@@ -1,5 +1,6 @@
 //! The server side API
 use std::fmt::Debug;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -7,6 +8,7 @@ use anyhow::{ensure, Context, Result};
 use bao_tree::io::fsm::{encode_ranges_validated, Outboard};
 use bytes::{Bytes, BytesMut};
 use futures::future::BoxFuture;
+use iroh_net::{magic_endpoint::get_peer_id, tls::PeerId};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWrite;
 use tracing::{debug, debug_span, warn};
@@ -15,11 +17,15 @@ use tracing_futures::Instrument;
 use crate::baomap::*;
 use crate::collection::CollectionParser;
 use crate::protocol::{
-    read_lp, write_lp, CustomGetRequest, GetRequest, RangeSpec, Request, RequestToken,
+    read_lp, write_lp, Closed, CustomGetRequest, GetRequest, RangeSpec, Request, RequestToken,
 };
 use crate::util::RpcError;
 use crate::Hash;
 
+pub mod limits;
+
+use self::limits::{Limiter, Throttled};
+
 /// Events emitted by the provider informing about the current status.
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -32,11 +38,39 @@ pub enum Event {
     ClientConnected {
         /// An unique connection id.
         connection_id: u64,
+        /// The authenticated identity of the client.
+        peer_id: PeerId,
+        /// The address the client connected from.
+        remote_addr: SocketAddr,
+    },
+    /// A connection was rejected because the client has too many connections.
+    ///
+    /// See [`limits::Limits::max_connections_per_peer`].
+    ClientRejected {
+        /// An unique connection id.
+        connection_id: u64,
+        /// The authenticated identity of the client.
+        peer_id: PeerId,
+        /// The address the client connected from.
+        remote_addr: SocketAddr,
+    },
+    /// A request was rejected because the connection has too many requests in progress.
+    ///
+    /// See [`limits::Limits::max_requests_per_connection`].
+    RequestRejected {
+        /// An unique connection id.
+        connection_id: u64,
+        /// The identity of the client.
+        peer_id: PeerId,
+        /// An identifier uniquely identifying this request.
+        request_id: u64,
     },
     /// A request was received from a client.
     GetRequestReceived {
         /// An unique connection id.
         connection_id: u64,
+        /// The identity of the client.
+        peer_id: PeerId,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
         /// Token requester gve for this request, if any
@@ -48,6 +82,8 @@ pub enum Event {
     CustomGetRequestReceived {
         /// An unique connection id.
         connection_id: u64,
+        /// The identity of the client.
+        peer_id: PeerId,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
         /// Token requester gve for this request, if any
@@ -59,6 +95,8 @@ pub enum Event {
     TransferCollectionStarted {
         /// An unique connection id.
         connection_id: u64,
+        /// The identity of the client.
+        peer_id: PeerId,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
         /// The number of blobs in the collection.
@@ -70,6 +108,8 @@ pub enum Event {
     TransferCollectionCompleted {
         /// An unique connection id.
         connection_id: u64,
+        /// The identity of the client.
+        peer_id: PeerId,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
     },
@@ -77,6 +117,8 @@ pub enum Event {
     TransferBlobCompleted {
         /// An unique connection id.
         connection_id: u64,
+        /// The identity of the client.
+        peer_id: PeerId,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
         /// The hash of the blob
@@ -90,6 +132,8 @@ pub enum Event {
     TransferAborted {
         /// The quic connection id.
         connection_id: u64,
+        /// The identity of the client.
+        peer_id: PeerId,
         /// An identifier uniquely identifying this request.
         request_id: u64,
     },
@@ -126,6 +170,11 @@ pub enum ProvideProgress {
         /// The hash of the created collection.
         hash: Hash,
     },
+    /// Rebuilding a watched collection failed.
+    ///
+    /// The previous collection stays provided, and the path is imported again after the
+    /// next change.
+    RebuildFailed(RpcError),
     /// We got an error and need to abort.
     ///
     /// This will be the last message in the stream.
@@ -167,6 +216,25 @@ pub enum ShareProgress {
         /// The unique id of the entry.
         id: u64,
     },
+    /// Item `id` is being downloaded from provider `provider`.
+    ///
+    /// This is only sent when downloading from multiple providers. In that case a
+    /// large blob can be split into several items, and `Found` reports the size of
+    /// the part that is requested from a single provider.
+    FromProvider {
+        /// The unique id of the entry.
+        id: u64,
+        /// The index of the provider in the list of providers.
+        provider: u64,
+    },
+    /// A provider failed, so data that was requested from it will be requested
+    /// from the other providers.
+    ProviderFailed {
+        /// The index of the provider in the list of providers.
+        provider: u64,
+        /// The reason the provider failed.
+        error: RpcError,
+    },
     /// We are done with the network part - all data is local.
     NetworkDone {
         /// The number of bytes written.
@@ -204,12 +272,17 @@ pub enum ShareProgress {
 }
 
 /// hook into the request handling to process authorization by examining
-/// the request and any given token. Any error returned will abort the request,
+/// the requester, the request and any given token. Any error returned will abort the request,
 /// and the error will be sent to the requester.
 pub trait RequestAuthorizationHandler: Send + Sync + Debug + 'static {
     /// Handle the authorization request, given an opaque data blob from the requester.
+    ///
+    /// `peer_id` is the identity of the requester, as authenticated by the TLS handshake, and
+    /// `remote_addr` the address the requester connected from.
     fn authorize(
         &self,
+        peer_id: PeerId,
+        remote_addr: SocketAddr,
         token: Option<RequestToken>,
         request: &Request,
     ) -> BoxFuture<'static, anyhow::Result<()>>;
@@ -278,6 +351,7 @@ pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
             .events
             .send(Event::TransferCollectionStarted {
                 connection_id: writer.connection_id(),
+                peer_id: writer.peer_id,
                 request_id: writer.request_id(),
                 num_blobs: stats.num_blobs,
                 total_blobs_size: stats.total_blob_size,
@@ -315,7 +389,7 @@ pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
                 tokio::task::yield_now().await;
                 let (status, size) = send_blob(db, hash, ranges, &mut writer.inner).await?;
                 if SentStatus::NotFound == status {
-                    writer.inner.finish().await?;
+                    writer.inner.get_mut().finish().await?;
                     return Ok(status);
                 }
 
@@ -323,6 +397,7 @@ pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
                     .events
                     .send(Event::TransferBlobCompleted {
                         connection_id: writer.connection_id(),
+                        peer_id: writer.peer_id,
                         request_id: writer.request_id(),
                         hash,
                         index: offset - 1,
@@ -338,7 +413,7 @@ pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
     }
 
     debug!("done writing");
-    writer.inner.finish().await?;
+    writer.inner.get_mut().finish().await?;
     Ok(SentStatus::Sent)
 }
 
@@ -349,6 +424,10 @@ pub trait EventSender: Clone + Sync + Send + 'static {
 }
 
 /// Handle a single connection.
+///
+/// Connections and requests over the limits of `limiter` are rejected with
+/// [`Closed::RateLimited`], and the data sent is throttled to its upload rates.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_connection<D: Map, E: EventSender, C: CollectionParser>(
     connecting: quinn::Connecting,
     db: D,
@@ -356,6 +435,7 @@ pub async fn handle_connection<D: Map, E: EventSender, C: CollectionParser>(
     collection_parser: C,
     custom_get_handler: Arc<dyn CustomGetHandler>,
     authorization_handler: Arc<dyn RequestAuthorizationHandler>,
+    limiter: Limiter,
     rt: crate::util::runtime::Handle,
 ) {
     let remote_addr = connecting.remote_address();
@@ -367,19 +447,59 @@ pub async fn handle_connection<D: Map, E: EventSender, C: CollectionParser>(
         }
     };
     let connection_id = connection.stable_id() as u64;
-    let span = debug_span!("connection", connection_id, %remote_addr);
+    let peer_id = match get_peer_id(&connection).await {
+        Ok(peer_id) => peer_id,
+        Err(err) => {
+            warn!(%remote_addr, "Failed to get the peer id: {err:#}");
+            return;
+        }
+    };
+    let span = debug_span!("connection", connection_id, %peer_id, %remote_addr);
     async move {
-        while let Ok((writer, reader)) = connection.accept_bi().await {
+        let Some(limits) = limiter.connect(peer_id) else {
+            debug!("too many connections, rejecting");
+            events
+                .send(Event::ClientRejected {
+                    connection_id,
+                    peer_id,
+                    remote_addr,
+                })
+                .await;
+            connection.close(Closed::RateLimited.into(), Closed::RateLimited.reason());
+            return;
+        };
+        while let Ok((mut writer, mut reader)) = connection.accept_bi().await {
             // The stream ID index is used to identify this request.  Requests only arrive in
             // bi-directional RecvStreams initiated by the client, so this uniquely identifies them.
             let request_id = reader.id().index();
             let span = debug_span!("stream", stream_id = %request_id);
+            let Some(request_guard) = limits.try_request() else {
+                debug!(stream_id = %request_id, "too many requests, rejecting");
+                events
+                    .send(Event::RequestRejected {
+                        connection_id,
+                        peer_id,
+                        request_id,
+                    })
+                    .await;
+                reader.stop(Closed::RateLimited.into()).ok();
+                writer.reset(Closed::RateLimited.into()).ok();
+                continue;
+            };
             let writer = ResponseWriter {
                 connection_id,
+                peer_id,
+                remote_addr,
                 events: events.clone(),
-                inner: writer,
+                inner: limits.throttle(writer),
             };
-            events.send(Event::ClientConnected { connection_id }).await;
+            events
+                .send(Event::ClientConnected {
+                    connection_id,
+                    peer_id,
+                    remote_addr,
+                })
+                .await;
             let db = db.clone();
             let custom_get_handler = custom_get_handler.clone();
             let authorization_handler = authorization_handler.clone();
@@ -398,6 +518,7 @@ pub async fn handle_connection<D: Map, E: EventSender, C: CollectionParser>(
                     {
                         warn!("error: {err:#?}",);
                     }
+                    drop(request_guard);
                 }
                 .instrument(span)
             });
@@ -430,7 +551,12 @@ async fn handle_stream<D: Map, E: EventSender, C: CollectionParser>(
     // 2. Authorize the request (may be a no-op)
     debug!("authorizing request");
     if let Err(e) = authorization_handler
-        .authorize(request.token().cloned(), &request)
+        .authorize(
+            writer.peer_id,
+            writer.remote_addr,
+            request.token().cloned(),
+            &request,
+        )
         .await
     {
         writer.notify_transfer_aborted().await;
@@ -456,6 +582,7 @@ async fn handle_custom_get<E: EventSender, D: Map, C: CollectionParser>(
         .send(Event::CustomGetRequestReceived {
             len: request.data.len(),
             connection_id: writer.connection_id(),
+            peer_id: writer.peer_id,
             request_id: writer.request_id(),
             token: request.token.clone(),
         })
@@ -485,6 +612,7 @@ pub async fn handle_get<D: Map, E: EventSender, C: CollectionParser>(
         .send(Event::GetRequestReceived {
             hash,
             connection_id: writer.connection_id(),
+            peer_id: writer.peer_id,
             request_id: writer.request_id(),
             token: request.token().cloned(),
         })
@@ -522,7 +650,7 @@ pub async fn handle_get<D: Map, E: EventSender, C: CollectionParser>(
         None => {
             debug!("not found {}", hash);
             writer.notify_transfer_aborted().await;
-            writer.inner.finish().await?;
+            writer.inner.get_mut().finish().await?;
         }
     };
 
@@ -532,9 +660,11 @@ pub async fn handle_get<D: Map, E: EventSender, C: CollectionParser>(
 /// A helper struct that combines a quinn::SendStream with auxiliary information
 #[derive(Debug)]
 pub struct ResponseWriter<E> {
-    inner: quinn::SendStream,
+    inner: Throttled<quinn::SendStream>,
     events: E,
     connection_id: u64,
+    peer_id: PeerId,
+    remote_addr: SocketAddr,
 }
 
 impl<E: EventSender> ResponseWriter<E> {
@@ -543,13 +673,14 @@ impl<E: EventSender> ResponseWriter<E> {
     }
 
     fn request_id(&self) -> u64 {
-        self.inner.id().index()
+        self.inner.get_ref().id().index()
     }
 
     async fn notify_transfer_completed(&self) {
         self.events
             .send(Event::TransferCollectionCompleted {
                 connection_id: self.connection_id(),
+                peer_id: self.peer_id,
                 request_id: self.request_id(),
             })
             .await;
@@ -559,6 +690,7 @@ impl<E: EventSender> ResponseWriter<E> {
         self.events
             .send(Event::TransferAborted {
                 connection_id: self.connection_id(),
+                peer_id: self.peer_id,
                 request_id: self.request_id(),
             })
             .await;
@@ -0,0 +1,374 @@
+//! Limits for serving requests.
+//!
+//! A [`Limiter`] is shared by all connections of a provider. It caps the number of
+//! concurrent connections per peer and the number of concurrent requests per connection, and
+//! throttles the data sent to requesters with token buckets, globally and per peer.
+//!
+//! Connections and requests over the limits are rejected with [`Closed::RateLimited`].
+//!
+//! [`Closed::RateLimited`]: crate::protocol::Closed::RateLimited
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use iroh_net::tls::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWrite,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep},
+};
+
+/// Limits for serving requests. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Maximum number of concurrent connections of a single peer.
+    pub max_connections_per_peer: Option<usize>,
+    /// Maximum number of concurrent requests on a single connection.
+    pub max_requests_per_connection: Option<usize>,
+    /// Maximum upload rate to all peers together, in bytes per second.
+    pub max_upload_rate: Option<u64>,
+    /// Maximum upload rate to a single peer, in bytes per second.
+    pub max_upload_rate_per_peer: Option<u64>,
+}
+
+/// Enforces [`Limits`] across all connections of a provider.
+///
+/// Cloning is cheap, clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Limiter(Arc<LimiterInner>);
+
+#[derive(Debug, Default)]
+struct LimiterInner {
+    limits: Limits,
+    global: Option<Arc<TokenBucket>>,
+    peers: Mutex<HashMap<PeerId, PeerState>>,
+}
+
+#[derive(Debug)]
+struct PeerState {
+    connections: usize,
+    bucket: Option<Arc<TokenBucket>>,
+}
+
+impl PeerState {
+    /// True if the state can be forgotten without letting the peer bypass its rate limit.
+    ///
+    /// A peer without connections is kept until its bucket has refilled, otherwise it could
+    /// reconnect to get a full bucket.
+    fn is_idle(&self, now: Instant) -> bool {
+        self.connections == 0
+            && self
+                .bucket
+                .as_ref()
+                .map_or(true, |bucket| bucket.full_at() <= now)
+    }
+}
+
+impl Limiter {
+    /// Creates a new limiter.
+    pub fn new(limits: Limits) -> Self {
+        Self(Arc::new(LimiterInner {
+            limits,
+            global: limits
+                .max_upload_rate
+                .map(|rate| Arc::new(TokenBucket::new(rate))),
+            peers: Default::default(),
+        }))
+    }
+
+    /// The limits that are enforced.
+    pub fn limits(&self) -> &Limits {
+        &self.0.limits
+    }
+
+    /// Register a new connection of `peer_id`.
+    ///
+    /// Returns `None` if the peer already has the maximum number of connections. The
+    /// connection counts against the limit until the returned guard is dropped.
+    pub fn connect(&self, peer_id: PeerId) -> Option<ConnectionGuard> {
+        let limits = &self.0.limits;
+        let mut peers = self.0.peers.lock().unwrap();
+        let now = Instant::now();
+        peers.retain(|_, state| !state.is_idle(now));
+        let state = peers.entry(peer_id).or_insert_with(|| PeerState {
+            connections: 0,
+            bucket: limits
+                .max_upload_rate_per_peer
+                .map(|rate| Arc::new(TokenBucket::new(rate))),
+        });
+        if let Some(max) = limits.max_connections_per_peer {
+            if state.connections >= max {
+                return None;
+            }
+        }
+        state.connections += 1;
+        let buckets = self
+            .0
+            .global
+            .iter()
+            .chain(state.bucket.iter())
+            .cloned()
+            .collect();
+        Some(ConnectionGuard {
+            limiter: self.clone(),
+            peer_id,
+            requests: limits
+                .max_requests_per_connection
+                .map(|max| Arc::new(Semaphore::new(max))),
+            buckets,
+        })
+    }
+}
+
+/// A connection registered with a [`Limiter`].
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limiter: Limiter,
+    peer_id: PeerId,
+    requests: Option<Arc<Semaphore>>,
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl ConnectionGuard {
+    /// Start a new request on this connection.
+    ///
+    /// Returns `None` if the connection already has the maximum number of requests. The
+    /// request counts against the limit until the returned guard is dropped.
+    pub fn try_request(&self) -> Option<RequestGuard> {
+        let permit = match &self.requests {
+            Some(requests) => Some(requests.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(RequestGuard { _permit: permit })
+    }
+
+    /// Wrap `writer` so that writes are throttled to the upload rate limits of this
+    /// connection.
+    pub fn throttle<W>(&self, writer: W) -> Throttled<W> {
+        Throttled {
+            inner: writer,
+            buckets: self.buckets.clone(),
+            delay: None,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut peers = self.limiter.0.peers.lock().unwrap();
+        if let Some(state) = peers.get_mut(&self.peer_id) {
+            state.connections -= 1;
+            if state.is_idle(Instant::now()) {
+                peers.remove(&self.peer_id);
+            }
+        }
+    }
+}
+
+/// A request registered with a [`ConnectionGuard`].
+#[derive(Debug)]
+pub struct RequestGuard {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// A token bucket to limit a rate, in units per second.
+///
+/// The bucket holds at most one second worth of tokens. Taking tokens never blocks, instead
+/// the bucket goes into debt and tells the caller how long to wait until the debt is paid.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a new, full bucket for `rate` units per second.
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take `n` tokens, and return how long to wait before taking more.
+    pub fn take(&self, n: u64) -> Duration {
+        let rate = self.rate as f64;
+        let mut state = self.state.lock().unwrap();
+        let (available, last) = &mut *state;
+        let now = Instant::now();
+        let refill = now.duration_since(*last).as_secs_f64() * rate;
+        *available = (*available + refill).min(rate) - n as f64;
+        *last = now;
+        if *available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*available / rate)
+        }
+    }
+
+    /// When the bucket is full again, if no more tokens are taken.
+    pub fn full_at(&self) -> Instant {
+        let rate = self.rate as f64;
+        let (available, last) = *self.state.lock().unwrap();
+        last + Duration::from_secs_f64((rate - available).max(0.0) / rate)
+    }
+}
+
+/// A writer that is throttled by token buckets, see [`ConnectionGuard::throttle`].
+///
+/// Data is charged after it has been written, and the next write waits until all buckets
+/// are out of debt.
+#[derive(Debug)]
+pub struct Throttled<W> {
+    inner: W,
+    buckets: Vec<Arc<TokenBucket>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> Throttled<W> {
+    /// A reference to the wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// A mutable reference to the wrapped writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttled<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(delay) = this.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        let wait = this
+            .buckets
+            .iter()
+            .map(|bucket| bucket.take(n as u64))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::tls::Keypair;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn peer() -> PeerId {
+        PeerId::from(Keypair::generate().public())
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let limiter = Limiter::new(Limits {
+            max_connections_per_peer: Some(2),
+            max_requests_per_connection: Some(1),
+            ..Default::default()
+        });
+        let (a, b) = (peer(), peer());
+        let a1 = limiter.connect(a).unwrap();
+        let a2 = limiter.connect(a).unwrap();
+        assert!(limiter.connect(a).is_none());
+        // other peers are not affected
+        let _b1 = limiter.connect(b).unwrap();
+        drop(a1);
+        let _a3 = limiter.connect(a).unwrap();
+
+        let r1 = a2.try_request().unwrap();
+        assert!(a2.try_request().is_none());
+        drop(r1);
+        assert!(a2.try_request().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled() {
+        let limiter = Limiter::new(Limits {
+            max_upload_rate: Some(1000),
+            max_upload_rate_per_peer: Some(100),
+            ..Default::default()
+        });
+        let conn = limiter.connect(peer()).unwrap();
+        let mut writer = conn.throttle(Vec::new());
+        let start = Instant::now();
+        // the first second worth of data is sent right away
+        writer.write_all(&[0u8; 100]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(10));
+        // after that, the per peer limit applies
+        writer.write_all(&[0u8; 300]).await.unwrap();
+        writer.write_all(&[0u8; 1]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert_eq!(writer.get_ref().len(), 401);
+
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(500), Duration::ZERO);
+        assert_eq!(bucket.take(1000), Duration::from_millis(500));
+        assert_eq!(
+            bucket.full_at(),
+            Instant::now() + Duration::from_millis(1500)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peer_state_expiry() {
+        let limiter = Limiter::new(Limits {
+            max_upload_rate_per_peer: Some(100),
+            ..Default::default()
+        });
+        let a = peer();
+        let conn = limiter.connect(a).unwrap();
+        conn.throttle(Vec::new())
+            .write_all(&[0u8; 300])
+            .await
+            .unwrap();
+        drop(conn);
+
+        // reconnecting does not reset the rate limit
+        let conn = limiter.connect(a).unwrap();
+        let mut writer = conn.throttle(Vec::new());
+        let start = Instant::now();
+        writer.write_all(&[0u8; 1]).await.unwrap();
+        writer.write_all(&[0u8; 1]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        drop(writer);
+        drop(conn);
+        assert_eq!(limiter.0.peers.lock().unwrap().len(), 1);
+
+        // the state is dropped once the bucket has refilled
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let _b = limiter.connect(peer()).unwrap();
+        assert_eq!(limiter.0.peers.lock().unwrap().len(), 1);
+        assert!(!limiter.0.peers.lock().unwrap().contains_key(&a));
+    }
+}
@@ -0,0 +1,182 @@
+//! Verified slices of blobs.
+//!
+//! A slice contains some chunk ranges of a blob, in the same verifiable bao encoding that is
+//! used to send blobs to a requester, see [`crate::provider::send_blob`]. Every chunk of a
+//! slice is verified against the hash of the blob when it is imported, so slices can be
+//! transferred over untrusted channels, e.g. on a usb stick, and merged into partial entries
+//! of a store piece by piece.
+//!
+//! A slice consists of
+//!
+//! - the magic bytes [`MAGIC`]
+//! - the length of the header, as a little endian u32
+//! - the postcard encoding of the [`SliceHeader`]
+//! - the bao encoding of the ranges in the header
+use anyhow::{ensure, Context, Result};
+use bao_tree::{
+    io::fsm::{
+        encode_ranges_validated, BaoContentItem, Outboard, OutboardMut, ResponseDecoderReadingNext,
+        ResponseDecoderStart,
+    },
+    ByteNum, ChunkNum,
+};
+use iroh_io::AsyncSliceWriter;
+use range_collections::RangeSet2;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    baomap::{Map, MapEntry, PartialMap, PartialMapEntry},
+    protocol::RangeSpec,
+    Hash, IROH_BLOCK_SIZE,
+};
+
+/// The magic bytes at the start of every slice.
+pub const MAGIC: &[u8; 8] = b"irohslc1";
+
+/// Maximum size of a serialized [`SliceHeader`].
+const MAX_HEADER_SIZE: u32 = 1024 * 64;
+
+/// Describes which part of which blob a slice contains.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SliceHeader {
+    /// The hash of the blob
+    pub hash: Hash,
+    /// The chunk ranges contained in the slice
+    pub ranges: RangeSpec,
+}
+
+/// The result of importing a slice with [`import_slice`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedSlice {
+    /// The hash of the blob
+    pub hash: Hash,
+    /// The size of the blob
+    pub size: u64,
+    /// The chunk ranges that were imported
+    pub ranges: RangeSet2<ChunkNum>,
+    /// True if the blob is complete in the store, either because it already was, or because
+    /// the slice contained the entire blob.
+    pub complete: bool,
+}
+
+/// Export some chunk ranges of a blob as a slice.
+///
+/// The entry can be partial. `ranges` is restricted to the size of the blob and to
+/// [`MapEntry::available_ranges`], and the data is validated while it is written, so this fails
+/// if the entry does not actually contain the requested ranges.
+///
+/// Returns the chunk ranges that were exported.
+pub async fn export_slice<D: Map, W: AsyncWrite + Unpin>(
+    db: &D,
+    hash: Hash,
+    ranges: &RangeSet2<ChunkNum>,
+    mut writer: W,
+) -> Result<RangeSet2<ChunkNum>> {
+    let entry = db.get(&hash).with_context(|| format!("{hash} not found"))?;
+    let outboard = entry.outboard().await?;
+    let available = entry.available_ranges().await?;
+    let all = RangeSet2::from(..outboard.tree().chunks());
+    let ranges: RangeSet2<ChunkNum> = ranges.intersection(&all);
+    let ranges: RangeSet2<ChunkNum> = ranges.intersection(&available);
+    let header = SliceHeader {
+        hash,
+        ranges: RangeSpec::new(&ranges),
+    };
+    let header = postcard::to_stdvec(&header)?;
+    writer.write_all(MAGIC).await?;
+    writer.write_u32_le(header.len().try_into()?).await?;
+    writer.write_all(&header).await?;
+    let data = entry.data_reader().await?;
+    encode_ranges_validated(data, outboard, &ranges, &mut writer)
+        .await
+        .with_context(|| format!("failed to export {ranges:?} of {hash}"))?;
+    writer.flush().await?;
+    Ok(ranges)
+}
+
+/// Import a slice that was created with [`export_slice`].
+///
+/// The data is verified while it is read, and written to a partial entry for the blob. Data
+/// that is already in the entry is kept. If the slice contains the entire blob, the entry is
+/// completed. If the blob is already complete, the slice is only verified.
+///
+/// Fails at the first chunk that does not match the hash. All chunks before it are written.
+pub async fn import_slice<D: PartialMap, R: AsyncRead + Unpin>(
+    db: &D,
+    mut reader: R,
+) -> Result<ImportedSlice> {
+    let mut magic = [0u8; MAGIC.len()];
+    reader.read_exact(&mut magic).await?;
+    ensure!(&magic == MAGIC, "not a slice");
+    let len = reader.read_u32_le().await?;
+    ensure!(len <= MAX_HEADER_SIZE, "slice header too large");
+    let mut header = vec![0u8; len as usize];
+    reader.read_exact(&mut header).await?;
+    let SliceHeader { hash, ranges } =
+        postcard::from_bytes(&header).context("invalid slice header")?;
+    let ranges = ranges.to_chunk_ranges();
+
+    let decoder = ResponseDecoderStart::new(hash.into(), ranges.clone(), IROH_BLOCK_SIZE, reader);
+    let (mut decoder, size) = decoder.next().await?;
+    let covers_all = RangeSet2::from(..ByteNum(size).chunks()).is_subset(&ranges);
+    let complete = db.get_partial(&hash).is_none() && db.get(&hash).is_some();
+    // reuse the existing partial entry, so the data we already have is not lost
+    let entry = match (complete, db.get_partial(&hash)) {
+        (true, _) => None,
+        (false, Some(entry)) => Some(entry),
+        (false, None) => Some(db.get_or_create_partial(hash, size)?),
+    };
+    let mut writers = match &entry {
+        Some(entry) => {
+            let data = entry.data_writer().await?;
+            let outboard = if needs_outboard(size) {
+                Some(entry.outboard_mut().await?)
+            } else {
+                None
+            };
+            Some((data, outboard))
+        }
+        None => None,
+    };
+    while let ResponseDecoderReadingNext::More((next, item)) = decoder.next().await {
+        decoder = next;
+        let item = item.with_context(|| format!("invalid slice of {hash}"))?;
+        let Some((data, outboard)) = writers.as_mut() else {
+            continue;
+        };
+        match item {
+            BaoContentItem::Parent(parent) => {
+                if let Some(outboard) = outboard.as_mut() {
+                    outboard.save(parent.node, &parent.pair).await?;
+                }
+            }
+            BaoContentItem::Leaf(leaf) => {
+                data.write_bytes_at(leaf.offset.0, leaf.data).await?;
+            }
+        }
+    }
+    if let Some((mut data, outboard)) = writers {
+        data.sync().await?;
+        if let Some(mut outboard) = outboard {
+            outboard.sync().await?;
+        }
+    }
+    let complete = match entry {
+        Some(entry) if covers_all => {
+            db.insert_complete(entry).await?;
+            true
+        }
+        _ => complete,
+    };
+    Ok(ImportedSlice {
+        hash,
+        size,
+        ranges,
+        complete,
+    })
+}
+
+fn needs_outboard(size: u64) -> bool {
+    size > (IROH_BLOCK_SIZE.bytes() as u64)
+}
@@ -515,9 +515,15 @@ impl Actor {
                     Err(err) => warn!("Failed to decode PeerData from {peer}: {err}"),
                     Ok(info) => {
                         debug!("add known addrs for {peer}: {info:?}...");
-                        self.endpoint
+                        // peers that joined before their endpoints were known send empty
+                        // data, which must not stop the actor
+                        if let Err(err) = self
+                            .endpoint
                             .add_known_addrs(peer, info.derp_region, &info.addrs)
-                            .await?;
+                            .await
+                        {
+                            debug!("no usable addrs for {peer}, keep using the connection: {err}");
+                        }
                     }
                 },
             }
@@ -69,6 +69,167 @@ impl Counter {
     }
 }
 
+/// The label set of a [`LabeledCounter`].
+#[cfg(feature = "metrics")]
+type LabelSet = [(&'static str, String); 1];
+
+/// Open Metrics [`Counter`]s that are distinguished by the value of a single label.
+///
+/// Every label value has its own monotonically increasing value.
+#[derive(Debug, Clone)]
+pub struct LabeledCounter {
+    /// The actual prometheus counters.
+    #[cfg(feature = "metrics")]
+    pub family: prometheus_client::metrics::family::Family<
+        LabelSet,
+        prometheus_client::metrics::counter::Counter,
+    >,
+    /// The name of the label.
+    pub label: &'static str,
+    /// What this counter measures.
+    pub description: &'static str,
+}
+
+impl LabeledCounter {
+    /// Constructs a new labeled counter, based on the given `label` name and `description`.
+    pub fn new(label: &'static str, description: &'static str) -> Self {
+        LabeledCounter {
+            #[cfg(feature = "metrics")]
+            family: Default::default(),
+            label,
+            description,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn label_set(&self, value: &str) -> LabelSet {
+        [(self.label, value.to_string())]
+    }
+
+    /// Increase the counter for the label `value` by 1, returning the previous value.
+    pub fn inc(&self, value: &str) -> u64 {
+        self.inc_by(value, 1)
+    }
+
+    /// Increase the counter for the label `value` by `u64`, returning the previous value.
+    pub fn inc_by(&self, value: &str, v: u64) -> u64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.family.get_or_create(&self.label_set(value)).inc_by(v)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = (value, v);
+            0
+        }
+    }
+
+    /// The counter for the label `value`.
+    ///
+    /// Increasing the returned [`Counter`] increases the counter for the label, until the label
+    /// is removed. Keep it to avoid looking up the label for every increment.
+    pub fn counter(&self, value: &str) -> Counter {
+        #[cfg(not(feature = "metrics"))]
+        let _ = value;
+        Counter {
+            #[cfg(feature = "metrics")]
+            counter: self.family.get_or_create(&self.label_set(value)).clone(),
+            description: self.description,
+        }
+    }
+
+    /// Get the current value of the counter for the label `value`.
+    pub fn get(&self, value: &str) -> u64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.family.get_or_create(&self.label_set(value)).get()
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+            0
+        }
+    }
+
+    /// Remove the counter for the label `value`, it is no longer reported.
+    pub fn remove(&self, value: &str) {
+        #[cfg(feature = "metrics")]
+        self.family.remove(&self.label_set(value));
+        #[cfg(not(feature = "metrics"))]
+        let _ = value;
+    }
+}
+
+/// Open Metrics [`Gauge`]s that are distinguished by the value of a single label.
+///
+/// Every label value has its own value, which can go up and down.
+///
+/// [`Gauge`]: prometheus_client::metrics::gauge::Gauge
+#[derive(Debug, Clone)]
+pub struct LabeledGauge {
+    /// The actual prometheus gauges.
+    #[cfg(feature = "metrics")]
+    pub family: prometheus_client::metrics::family::Family<
+        LabelSet,
+        prometheus_client::metrics::gauge::Gauge,
+    >,
+    /// The name of the label.
+    pub label: &'static str,
+    /// What this gauge measures.
+    pub description: &'static str,
+}
+
+impl LabeledGauge {
+    /// Constructs a new labeled gauge, based on the given `label` name and `description`.
+    pub fn new(label: &'static str, description: &'static str) -> Self {
+        LabeledGauge {
+            #[cfg(feature = "metrics")]
+            family: Default::default(),
+            label,
+            description,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn label_set(&self, value: &str) -> LabelSet {
+        [(self.label, value.to_string())]
+    }
+
+    /// Set the gauge for the label `value` to `v`, returning the previous value.
+    pub fn set(&self, value: &str, v: i64) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.family.get_or_create(&self.label_set(value)).set(v)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = (value, v);
+            0
+        }
+    }
+
+    /// Get the current value of the gauge for the label `value`.
+    pub fn get(&self, value: &str) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.family.get_or_create(&self.label_set(value)).get()
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+            0
+        }
+    }
+
+    /// Remove the gauge for the label `value`, it is no longer reported.
+    pub fn remove(&self, value: &str) {
+        #[cfg(feature = "metrics")]
+        self.family.remove(&self.label_set(value));
+        #[cfg(not(feature = "metrics"))]
+        let _ = value;
+    }
+}
+
 /// Description of a group of metrics.
 pub trait Metric:
     Default + struct_iterable::Iterable + Sized + std::fmt::Debug + 'static + Send + Sync
@@ -82,6 +243,10 @@ pub trait Metric:
         for (metric, counter) in this.iter() {
             if let Some(counter) = counter.downcast_ref::<Counter>() {
                 sub_registry.register(metric, counter.description, counter.counter.clone());
+            } else if let Some(counter) = counter.downcast_ref::<LabeledCounter>() {
+                sub_registry.register(metric, counter.description, counter.family.clone());
+            } else if let Some(gauge) = counter.downcast_ref::<LabeledGauge>() {
+                sub_registry.register(metric, gauge.description, gauge.family.clone());
             }
         }
         this
@@ -22,7 +22,8 @@ use iroh_net::{
     derp::{
         self,
         http::{
-            MeshAddrs, ServerBuilder as DerpServerBuilder, TlsAcceptor, TlsConfig as DerpTlsConfig,
+            MeshAddrs, MeshStatus, ServerBuilder as DerpServerBuilder, TlsAcceptor,
+            TlsConfig as DerpTlsConfig,
         },
     },
     key, stun,
@@ -52,6 +53,9 @@ struct Cli {
     #[clap(long, default_value_t = false)]
     dev: bool,
     /// Config file path. Generate a default configuration file by supplying a path.
+    ///
+    /// Send a `SIGHUP` to reload the rate limits, mesh peers and manual certificates from
+    /// this file, without disconnecting the clients.
     #[clap(long, short)]
     config_path: Option<PathBuf>,
 }
@@ -69,7 +73,11 @@ impl CertMode {
         contact: String,
         is_production: bool,
         dir: PathBuf,
-    ) -> Result<(Arc<rustls::ServerConfig>, TlsAcceptor)> {
+    ) -> Result<(
+        Arc<rustls::ServerConfig>,
+        TlsAcceptor,
+        Option<Arc<ManualCertResolver>>,
+    )> {
         let config = rustls::ServerConfig::builder()
             .with_safe_defaults()
             .with_no_client_auth();
@@ -97,31 +105,76 @@ impl CertMode {
                     .instrument(info_span!("acme")),
                 );
 
-                Ok((Arc::new(config), TlsAcceptor::LetsEncrypt(acceptor)))
+                Ok((Arc::new(config), TlsAcceptor::LetsEncrypt(acceptor), None))
             }
             CertMode::Manual => {
                 // load certificates manually
-                let keyname = escape_hostname(&hostname);
-                let cert_path = dir.join(format!("{keyname}.crt"));
-                let key_path = dir.join(format!("{keyname}.key"));
-
-                let (certs, private_key) = tokio::task::spawn_blocking(move || {
-                    let certs = load_certs(cert_path)?;
-                    let key = load_private_key(key_path)?;
-                    anyhow::Ok((certs, key))
-                })
-                .await??;
-
-                let config = config.with_single_cert(certs, private_key)?;
+                let resolver = Arc::new(ManualCertResolver::new(&hostname, &dir).await?);
+                let config = config.with_cert_resolver(resolver.clone());
                 let config = Arc::new(config);
                 let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
 
-                Ok((config, TlsAcceptor::Manual(acceptor)))
+                Ok((config, TlsAcceptor::Manual(acceptor), Some(resolver)))
             }
         }
     }
 }
 
+/// Serves the certificate of [`CertMode::Manual`], which can be reloaded from disk while
+/// the derper is running.
+struct ManualCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: std::sync::RwLock<Arc<rustls::sign::CertifiedKey>>,
+}
+
+impl ManualCertResolver {
+    async fn new(hostname: &str, dir: &Path) -> Result<Self> {
+        let keyname = escape_hostname(hostname);
+        let cert_path = dir.join(format!("{keyname}.crt"));
+        let key_path = dir.join(format!("{keyname}.key"));
+        let certified_key = Self::load(cert_path.clone(), key_path.clone()).await?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            certified_key: std::sync::RwLock::new(certified_key),
+        })
+    }
+
+    /// Reads the certificate and private key again, see [`Self::set`].
+    async fn read(&self) -> Result<Arc<rustls::sign::CertifiedKey>> {
+        Self::load(self.cert_path.clone(), self.key_path.clone()).await
+    }
+
+    /// Uses `certified_key` for new TLS connections.
+    fn set(&self, certified_key: Arc<rustls::sign::CertifiedKey>) {
+        *self.certified_key.write().unwrap() = certified_key;
+    }
+
+    async fn load(
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    ) -> Result<Arc<rustls::sign::CertifiedKey>> {
+        let (certs, private_key) = tokio::task::spawn_blocking(move || {
+            let certs = load_certs(cert_path)?;
+            let key = load_private_key(key_path)?;
+            anyhow::Ok((certs, key))
+        })
+        .await??;
+        let key = rustls::sign::any_supported_type(&private_key).context("invalid private key")?;
+        Ok(Arc::new(rustls::sign::CertifiedKey::new(certs, key)))
+    }
+}
+
+impl rustls::server::ResolvesServerCert for ManualCertResolver {
+    fn resolve(
+        &self,
+        _client_hello: rustls::server::ClientHello,
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
 fn escape_hostname(hostname: &str) -> Cow<'_, str> {
     let unsafe_hostname_characters = regex::Regex::new(r"[^a-zA-Z0-9-\.]").unwrap();
     unsafe_hostname_characters.replace_all(hostname, "")
@@ -191,6 +244,10 @@ struct Config {
     limits: Option<Limits>,
     /// Mesh network configuration
     mesh: Option<MeshConfig>,
+    /// Client admission configuration. When not set, all clients are accepted.
+    verify_clients: Option<VerifyClientsConfig>,
+    /// Admin API configuration. When not set, the admin API is not served.
+    admin: Option<AdminConfig>,
     #[cfg(feature = "metrics")]
     /// Metrics serve address. If not set, metrics are not served.
     metrics_addr: Option<SocketAddr>,
@@ -202,7 +259,98 @@ struct MeshConfig {
     mesh_psk_file: PathBuf,
     /// Comma-separated list of urls to mesh with. Must also include the scheme ('http' or
     /// 'https').
+    ///
+    /// When `mesh_url` is set, these are only the seeds of the mesh: the other derp servers
+    /// of the mesh are discovered through them.
     mesh_with: Vec<Url>,
+    /// The url on which the other derp servers of the mesh reach this derper, in the same form
+    /// as the urls of `mesh_with`, e.g. `https://derp.example.com/derp`.
+    ///
+    /// It is announced to the derp servers we mesh with, so that every derp server of the
+    /// mesh learns about this derper, and meshes with it. Changes need a restart.
+    mesh_url: Option<Url>,
+}
+
+impl MeshConfig {
+    async fn mesh_key(&self) -> Result<derp::MeshKey> {
+        let raw = tokio::fs::read_to_string(&self.mesh_psk_file)
+            .await
+            .context("reading mesh-pks file")?;
+        let mut mesh_key = [0u8; 32];
+        hex::decode_to_slice(raw.trim(), &mut mesh_key).context("invalid mesh-pks content")?;
+        Ok(mesh_key)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct VerifyClientsConfig {
+    /// Hex encoded public keys of the clients that are allowed to connect.
+    allow_list: Option<Vec<String>>,
+    /// Url of a local verifier service, which is asked whether a client may connect.
+    ///
+    /// The hex encoded public key of the client is `POST`ed to the url, any response other
+    /// than a success status rejects the client. Cannot be combined with `allow_list`.
+    verifier_url: Option<Url>,
+}
+
+impl VerifyClientsConfig {
+    fn verifier(self) -> Result<Arc<dyn derp::ClientVerifier>> {
+        match (self.allow_list, self.verifier_url) {
+            (Some(allow_list), None) => {
+                let keys = allow_list
+                    .iter()
+                    .map(|key| {
+                        let mut bytes = [0u8; 32];
+                        hex::decode_to_slice(key.trim(), &mut bytes)
+                            .with_context(|| format!("invalid public key in allow_list: {key}"))?;
+                        Ok(key::node::PublicKey::from(bytes))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                info!(
+                    "verifying clients against an allow list of {} keys",
+                    keys.len()
+                );
+                Ok(Arc::new(derp::AllowList::new(keys)))
+            }
+            (None, Some(url)) => {
+                info!("verifying clients with the verifier service at {url}");
+                Ok(Arc::new(derp::HttpVerifier::new(url)?))
+            }
+            (Some(_), Some(_)) => bail!("`allow_list` and `verifier_url` cannot be combined"),
+            (None, None) => bail!("`verify_clients` needs an `allow_list` or a `verifier_url`"),
+        }
+    }
+}
+
+/// The admin API lists the connected clients and mesh peers, and allows to disconnect
+/// clients:
+///
+/// - `GET /clients`: the connected clients, with their preferred status and traffic
+/// - `GET /mesh`: the derp servers we mesh with, whether they were discovered through the
+///   mesh, and the health of our connections to them
+/// - `DELETE /clients/<hex encoded public key>`: disconnects the client
+///
+/// Every request must carry an `Authorization: Bearer <token>` header.
+#[derive(Serialize, Deserialize)]
+struct AdminConfig {
+    /// Address to serve the admin API on. It is served over plain HTTP, so this should
+    /// not be reachable from the outside.
+    addr: SocketAddr,
+    /// Path to file containing the token to authenticate requests; whitespace is trimmed.
+    token_file: PathBuf,
+}
+
+impl AdminConfig {
+    async fn token(&self) -> Result<String> {
+        let raw = tokio::fs::read_to_string(&self.token_file)
+            .await
+            .context("reading admin token file")?;
+        let token = raw.trim();
+        if token.is_empty() {
+            bail!("admin token file is empty");
+        }
+        Ok(token.to_string())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -238,6 +386,33 @@ struct Limits {
     accept_conn_limit: Option<f64>,
     /// Burst limit for accepting new connection. Unlimited if not set.
     accept_conn_burst: Option<usize>,
+    /// Rate limit in bytes per second for the packets that a client sends. Unlimited if not
+    /// set.
+    bytes_per_second: Option<usize>,
+    /// Burst limit in bytes for the packets that a client sends. Packets larger than this
+    /// are always dropped, so it should be at least 64 KiB. Defaults to
+    /// `bytes_per_second` if not set.
+    bytes_burst: Option<usize>,
+}
+
+impl Limits {
+    /// The rate limit of the clients, as `(bytes_per_second, bytes_burst)`.
+    fn client_rate_limit(limits: Option<&Self>) -> (usize, usize) {
+        match limits {
+            Some(Limits {
+                bytes_per_second: Some(bytes_per_second),
+                bytes_burst,
+                ..
+            }) => {
+                let bytes_burst = bytes_burst.unwrap_or(*bytes_per_second);
+                if bytes_burst < derp::MAX_PACKET_SIZE {
+                    warn!("`bytes_burst` of {bytes_burst} is smaller than the max packet size, large packets will be dropped");
+                }
+                (*bytes_per_second, bytes_burst)
+            }
+            _ => (0, 0),
+        }
+    }
 }
 
 impl Default for Config {
@@ -252,6 +427,8 @@ impl Default for Config {
             tls: None,
             limits: None,
             mesh: None,
+            verify_clients: None,
+            admin: None,
             #[cfg(feature = "metrics")]
             metrics_addr: None,
         }
@@ -350,7 +527,7 @@ async fn main() -> Result<()> {
     #[cfg(feature = "metrics")]
     let metrics_fut = init_metrics_collection(cfg.metrics_addr);
 
-    let r = run(cli.dev, cfg, None).await;
+    let r = run(cli.dev, cfg, cli.config_path, None).await;
 
     #[cfg(feature = "metrics")]
     if let Some(metrics_fut) = metrics_fut {
@@ -363,6 +540,7 @@ async fn main() -> Result<()> {
 async fn run(
     dev_mode: bool,
     cfg: Config,
+    config_path: Option<PathBuf>,
     addr_sender: Option<tokio::sync::oneshot::Sender<SocketAddr>>,
 ) -> Result<()> {
     let (addr, tls_config) = if dev_mode {
@@ -390,27 +568,36 @@ async fn run(
         warn!("The address port is 443, which is typically the expected tls port, but you have not supplied any tls configuration.\nIf you meant to run the derper with tls enabled, adjust the config file to include tls configuration.");
     }
 
+    // set up the rate limit of the clients
+    let client_rate_limit = Limits::client_rate_limit(cfg.limits.as_ref());
+
     // set up derp configuration details
-    let (secret_key, mesh_key, mesh_derpers) = match cfg.enable_derp {
+    let (secret_key, mesh_key, mesh_derpers, mesh_url, client_verifier) = match cfg.enable_derp {
         true => {
-            let (mesh_key, mesh_derpers) = if let Some(mesh_config) = cfg.mesh {
-                let raw = tokio::fs::read_to_string(mesh_config.mesh_psk_file)
-                    .await
-                    .context("reading mesh-pks file")?;
-                let mut mesh_key = [0u8; 32];
-                hex::decode_to_slice(raw.trim(), &mut mesh_key)
-                    .context("invalid mesh-pks content")?;
+            let (mesh_key, mesh_derpers, mesh_url) = if let Some(mesh_config) = cfg.mesh {
+                let mesh_key = mesh_config.mesh_key().await?;
                 info!("DERP mesh key configured");
                 (
                     Some(mesh_key),
                     Some(MeshAddrs::Addrs(mesh_config.mesh_with)),
+                    mesh_config.mesh_url,
                 )
             } else {
-                (None, None)
+                (None, None, None)
             };
-            (Some(cfg.private_key), mesh_key, mesh_derpers)
+            let client_verifier = cfg
+                .verify_clients
+                .map(VerifyClientsConfig::verifier)
+                .transpose()?;
+            (
+                Some(cfg.private_key),
+                mesh_key,
+                mesh_derpers,
+                mesh_url,
+                client_verifier,
+            )
         }
-        false => (None, None, None),
+        false => (None, None, None, None, None),
     };
 
     // run stun
@@ -423,29 +610,31 @@ async fn run(
     };
 
     // set up tls configuration details
-    let (tls_config, headers, captive_portal_port) = if let Some(tls_config) = tls_config {
-        let contact = tls_config.contact;
-        let is_production = tls_config.prod_tls;
-        let (config, acceptor) = tls_config
-            .cert_mode
-            .gen_server_config(
-                cfg.hostname.clone(),
-                contact,
-                is_production,
-                tls_config.cert_dir.unwrap_or_else(|| PathBuf::from(".")),
+    let (tls_config, cert_resolver, headers, captive_portal_port) =
+        if let Some(tls_config) = tls_config {
+            let contact = tls_config.contact;
+            let is_production = tls_config.prod_tls;
+            let (config, acceptor, cert_resolver) = tls_config
+                .cert_mode
+                .gen_server_config(
+                    cfg.hostname.clone(),
+                    contact,
+                    is_production,
+                    tls_config.cert_dir.unwrap_or_else(|| PathBuf::from(".")),
+                )
+                .await?;
+            let headers: Vec<(&str, &str)> = TLS_HEADERS.into();
+            (
+                Some(DerpTlsConfig { config, acceptor }),
+                cert_resolver,
+                headers,
+                tls_config
+                    .captive_portal_port
+                    .unwrap_or(DEFAULT_CAPTIVE_PORTAL_PORT),
             )
-            .await?;
-        let headers: Vec<(&str, &str)> = TLS_HEADERS.into();
-        (
-            Some(DerpTlsConfig { config, acceptor }),
-            headers,
-            tls_config
-                .captive_portal_port
-                .unwrap_or(DEFAULT_CAPTIVE_PORTAL_PORT),
-        )
-    } else {
-        (None, Vec::new(), 0)
-    };
+        } else {
+            (None, None, Vec::new(), 0)
+        };
 
     let mut builder = DerpServerBuilder::new(addr)
         .secret_key(secret_key)
@@ -454,6 +643,9 @@ async fn run(
         .tls_config(tls_config.clone())
         .derp_override(Box::new(derp_disabled_handler))
         .mesh_derpers(mesh_derpers)
+        .mesh_url(mesh_url)
+        .client_verifier(client_verifier)
+        .client_rate_limit(client_rate_limit.0, client_rate_limit.1)
         .request_handler(Method::GET, "/", Box::new(root_handler))
         .request_handler(Method::GET, "/index.html", Box::new(root_handler))
         .request_handler(Method::GET, "/derp/probe", Box::new(probe_handler))
@@ -467,7 +659,25 @@ async fn run(
             Box::new(serve_no_content_handler),
         );
     }
-    let derp_server = builder.spawn().await?;
+    let mut derp_server = builder.spawn().await?;
+
+    // serve the admin api
+    let admin_task = match (cfg.admin, derp_server.admin_handler()) {
+        (Some(admin_config), Some(admin)) => {
+            let token = admin_config.token().await?;
+            let service = AdminService {
+                token: Arc::new(token),
+                admin,
+                mesh: derp_server.mesh_status(),
+            };
+            Some(serve_admin_service(admin_config.addr, service).await?)
+        }
+        (Some(_), None) => {
+            warn!("not serving the admin API, the DERP server is disabled");
+            None
+        }
+        (None, _) => None,
+    };
 
     // captive portal detections must be served over HTTP
     let captive_portal_task = if tls_config.is_some() {
@@ -484,7 +694,28 @@ async fn run(
         }
     }
 
-    tokio::signal::ctrl_c().await?;
+    let reloader = ConfigReloader {
+        config_path,
+        derp_enabled: cfg.enable_derp,
+        mesh_key,
+        cert_resolver,
+    };
+    let mut reload_signal = ReloadSignal::new()?;
+    loop {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => {
+                res?;
+                break;
+            }
+            _ = reload_signal.recv() => {
+                info!("reloading config");
+                if let Err(err) = reloader.reload(&mut derp_server).await {
+                    error!("failed to reload config: {err:?}");
+                }
+            }
+        }
+    }
+
     // Shutdown all tasks
     if let Some(task) = stun_task {
         task.abort();
@@ -492,11 +723,95 @@ async fn run(
     if let Some(task) = captive_portal_task {
         task.abort()
     }
+    if let Some(task) = admin_task {
+        task.abort()
+    }
     derp_server.shutdown().await;
 
     Ok(())
 }
 
+/// Applies changes of the config file to the running derper, without disconnecting the
+/// clients.
+///
+/// Only the client rate limits, the derp servers to mesh with and the certificates in
+/// [`CertMode::Manual`] are reloaded, other changes need a restart.
+struct ConfigReloader {
+    config_path: Option<PathBuf>,
+    derp_enabled: bool,
+    mesh_key: Option<derp::MeshKey>,
+    cert_resolver: Option<Arc<ManualCertResolver>>,
+}
+
+impl ConfigReloader {
+    async fn reload(&self, derp_server: &mut iroh_net::derp::http::Server) -> Result<()> {
+        let Some(config_path) = &self.config_path else {
+            bail!("no config file to reload");
+        };
+        // read everything before applying anything, so a broken config changes nothing
+        let cfg = Config::read_from_file(config_path).await?;
+        let (bytes_per_second, bytes_burst) = Limits::client_rate_limit(cfg.limits.as_ref());
+        let (mesh_key, mesh_with) = match &cfg.mesh {
+            Some(mesh_config) => (
+                Some(mesh_config.mesh_key().await?),
+                mesh_config.mesh_with.clone(),
+            ),
+            None => (None, Vec::new()),
+        };
+        let certified_key = match &self.cert_resolver {
+            Some(cert_resolver) => Some(cert_resolver.read().await?),
+            None => None,
+        };
+
+        if self.derp_enabled {
+            // fails for an invalid limit, before anything else is applied
+            derp_server.set_client_rate_limit(bytes_per_second, bytes_burst)?;
+            info!(bytes_per_second, bytes_burst, "client rate limit reloaded");
+        }
+        if let (Some(cert_resolver), Some(certified_key)) = (&self.cert_resolver, certified_key) {
+            cert_resolver.set(certified_key);
+            info!("certificate reloaded");
+        }
+        if self.derp_enabled {
+            match self.mesh_key {
+                Some(key) if mesh_key.is_none() || mesh_key == Some(key) => {
+                    let meshes = mesh_with.len();
+                    derp_server.re_mesh(MeshAddrs::Addrs(mesh_with)).await?;
+                    info!("meshing with {meshes} derp servers");
+                }
+                _ if mesh_key != self.mesh_key => {
+                    warn!("the mesh key changed, restart the derper to apply the mesh config");
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receives the signal asking the derper to reload its config, a `SIGHUP` on unix.
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl ReloadSignal {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if self.hangup.recv().await.is_some() {
+            return;
+        }
+        futures::future::pending().await
+    }
+}
+
 const NO_CONTENT_CHALLENGE_HEADER: &str = "X-Tailscale-Challenge";
 const NO_CONTENT_RESPONSE_HEADER: &str = "X-Tailscale-Response";
 
@@ -595,6 +910,198 @@ impl hyper::service::Service<Request<Body>> for CaptivePortalService {
     }
 }
 
+async fn serve_admin_service(
+    addr: SocketAddr,
+    service: AdminService,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let admin_listener = TcpListener::bind(&addr)
+        .await
+        .context("failed to bind admin api")?;
+    let admin_addr = admin_listener.local_addr()?;
+    info!("[AdminService]: serving on {}", admin_addr);
+
+    let task = tokio::spawn(
+        async move {
+            loop {
+                match admin_listener.accept().await {
+                    Ok((stream, peer_addr)) => {
+                        debug!("[AdminService] Connection opened from {}", peer_addr);
+                        let handler = service.clone();
+
+                        tokio::task::spawn(async move {
+                            if let Err(err) = Http::new().serve_connection(stream, handler).await {
+                                error!("[AdminService] Failed to serve connection: {:?}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        error!("[AdminService] failed to accept connection: {:#?}", err);
+                    }
+                }
+            }
+        }
+        .instrument(info_span!("admin.service")),
+    );
+    Ok(task)
+}
+
+/// Serves the admin API, see [`AdminConfig`].
+#[derive(Clone)]
+struct AdminService {
+    token: Arc<String>,
+    admin: derp::AdminHandler<derp::HttpClient>,
+    mesh: MeshStatus,
+}
+
+/// A client in the response of the admin API
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminClient {
+    key: String,
+    preferred: bool,
+    mesh_peer: bool,
+    bytes_recv: u64,
+    bytes_sent: u64,
+}
+
+/// The response of the admin API for the mesh
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminMesh {
+    /// Derp servers we connect to
+    outgoing: Vec<AdminMeshPeer>,
+    /// Hex encoded public keys of the derp servers that are connected to us
+    incoming: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminMeshPeer {
+    url: Url,
+    connected: bool,
+    /// Whether the derp server was discovered through the mesh, rather than configured
+    discovered: bool,
+    /// Failed connection attempts since we were last connected
+    failures: u32,
+}
+
+impl hyper::service::Service<Request<Body>> for AdminService {
+    type Response = Response<Body>;
+    type Error = HyperError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { service.handle(req).await })
+    }
+}
+
+impl AdminService {
+    async fn handle(&self, req: Request<Body>) -> HyperResult<Response<Body>> {
+        if !self.is_authorized(&req) {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header("WWW-Authenticate", "Bearer")
+                .body(Body::empty())
+                .unwrap());
+        }
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/clients") => {
+                let clients: Vec<_> = self
+                    .admin
+                    .clients()
+                    .await?
+                    .into_iter()
+                    .map(|client| AdminClient {
+                        key: hex::encode(client.key.as_bytes()),
+                        preferred: client.preferred,
+                        mesh_peer: client.mesh_peer,
+                        bytes_recv: client.bytes_recv,
+                        bytes_sent: client.bytes_sent,
+                    })
+                    .collect();
+                json_response(&clients)
+            }
+            (&Method::GET, "/mesh") => {
+                let outgoing = self
+                    .mesh
+                    .peers()
+                    .await
+                    .into_iter()
+                    .map(|peer| AdminMeshPeer {
+                        url: peer.url,
+                        connected: peer.connected,
+                        discovered: peer.discovered,
+                        failures: peer.failures,
+                    })
+                    .collect();
+                let incoming = self
+                    .admin
+                    .clients()
+                    .await?
+                    .into_iter()
+                    .filter(|client| client.mesh_peer)
+                    .map(|client| hex::encode(client.key.as_bytes()))
+                    .collect();
+                json_response(&AdminMesh { outgoing, incoming })
+            }
+            (&Method::DELETE, path) if path.starts_with("/clients/") => {
+                let mut bytes = [0u8; 32];
+                if hex::decode_to_slice(&path["/clients/".len()..], &mut bytes).is_err() {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body("invalid public key".into())
+                        .unwrap());
+                }
+                let key = key::node::PublicKey::from(bytes);
+                let clients = self.admin.clients().await?;
+                if !clients.iter().any(|client| client.key == key) {
+                    return Ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body("client not connected".into())
+                        .unwrap());
+                }
+                info!("[AdminService] disconnecting client {key:?}");
+                self.admin.close_client(key).await?;
+                Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap())
+            }
+            _ => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(NOTFOUND.into())
+                .unwrap()),
+        }
+    }
+
+    /// Checks the bearer token of the request, in constant time.
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let expected = format!("Bearer {}", self.token);
+        match req.headers().get(hyper::header::AUTHORIZATION) {
+            Some(value) if value.len() == expected.len() => {
+                value
+                    .as_bytes()
+                    .iter()
+                    .zip(expected.as_bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+fn json_response<T: Serialize>(value: &T) -> HyperResult<Response<Body>> {
+    let body = serde_json::to_vec(value)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .unwrap())
+}
+
 fn derp_disabled_handler(
     _r: Request<Body>,
     response: ResponseBuilder,
@@ -940,7 +1447,7 @@ mod tests {
         let derper_task = tokio::spawn(
             async move {
                 // dev mode will bind to IPv6::UNSPECIFIED, so setting it `false`
-                let res = run(false, cfg, Some(addr_send)).await;
+                let res = run(false, cfg, None, Some(addr_send)).await;
                 if let Err(e) = res {
                     eprintln!("error starting derp server {e}");
                 }
@@ -1080,4 +1587,203 @@ mod tests {
         derper_task.abort();
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_admin_service() -> Result<()> {
+        let server = DerpServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let derper_url: Url = format!("http://{}", server.addr()).parse().unwrap();
+        let client_key = SecretKey::generate();
+        let client = ClientBuilder::new()
+            .server_url(derper_url)
+            .build(client_key.clone())?;
+        client.connect().await?;
+        let client_hex = hex::encode(client_key.public_key().as_bytes());
+
+        let service = AdminService {
+            token: Arc::new("secret".to_string()),
+            admin: server.admin_handler().expect("derp enabled"),
+            mesh: server.mesh_status(),
+        };
+        let request = |method: Method, path: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header(hyper::header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let clients = |res: Response<Body>| async move {
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            anyhow::Ok(serde_json::from_slice::<Vec<AdminClient>>(&body)?)
+        };
+
+        // requests without the token are rejected
+        let res = service
+            .handle(request(Method::GET, "/clients", "wrong"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = service
+            .handle(request(Method::GET, "/clients", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let got = clients(res).await?;
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].key, client_hex);
+        assert!(!got[0].mesh_peer);
+
+        let res = service
+            .handle(request(Method::GET, "/mesh", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // disconnect the client
+        let path = format!("/clients/{client_hex}");
+        let res = service
+            .handle(request(Method::DELETE, &path, "secret"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = service
+            .handle(request(Method::GET, "/clients", "secret"))
+            .await
+            .unwrap();
+        assert!(clients(res).await?.is_empty());
+        let res = service
+            .handle(request(Method::DELETE, &path, "secret"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = service
+            .handle(request(Method::DELETE, "/clients/nokey", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        client.close().await;
+        server.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_config_reload() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("derper-reload-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let write_cert = || async {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+            let cert_path = dir.join("localhost.crt");
+            tokio::fs::write(&cert_path, cert.serialize_pem()?).await?;
+            tokio::fs::write(dir.join("localhost.key"), cert.serialize_private_key_pem()).await?;
+            load_certs(cert_path)
+        };
+        let write_mesh_key = |mesh_key: derp::MeshKey| {
+            let path = dir.join("mesh.key");
+            async move {
+                tokio::fs::write(&path, hex::encode(mesh_key)).await?;
+                anyhow::Ok(path)
+            }
+        };
+        let mesh_url =
+            |port: u16| -> Url { format!("http://127.0.0.1:{port}/derp").parse().unwrap() };
+
+        let cert = write_cert().await?;
+        let cert_resolver = Arc::new(ManualCertResolver::new("localhost", &dir).await?);
+        assert_eq!(cert_resolver.certified_key.read().unwrap().cert, cert);
+
+        let mesh_key = [1u8; 32];
+        let mut server = DerpServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .mesh_key(Some(mesh_key))
+            .mesh_derpers(Some(MeshAddrs::Addrs(vec![mesh_url(1)])))
+            .spawn()
+            .await?;
+        let mesh_peers = |server: &iroh_net::derp::http::Server| {
+            let status = server.mesh_status();
+            async move {
+                status
+                    .peers()
+                    .await
+                    .into_iter()
+                    .map(|peer| peer.url)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(mesh_peers(&server).await, vec![mesh_url(1)]);
+
+        let config_path = dir.join("derper.toml");
+        let reloader = ConfigReloader {
+            config_path: Some(config_path.clone()),
+            derp_enabled: true,
+            mesh_key: Some(mesh_key),
+            cert_resolver: Some(cert_resolver.clone()),
+        };
+
+        // new limits, mesh peers and certificate are applied
+        let cfg = Config {
+            limits: Some(Limits {
+                accept_conn_limit: None,
+                accept_conn_burst: None,
+                bytes_per_second: Some(1 << 20),
+                bytes_burst: None,
+            }),
+            mesh: Some(MeshConfig {
+                mesh_psk_file: write_mesh_key(mesh_key).await?,
+                mesh_with: vec![mesh_url(2), mesh_url(3)],
+                mesh_url: None,
+            }),
+            ..Default::default()
+        };
+        cfg.write_to_file(&config_path).await?;
+        let cert = write_cert().await?;
+        reloader.reload(&mut server).await?;
+        assert_eq!(mesh_peers(&server).await, vec![mesh_url(2), mesh_url(3)]);
+        assert_eq!(cert_resolver.certified_key.read().unwrap().cert, cert);
+
+        // a new mesh key needs a restart, the mesh is left as is
+        let cfg = Config {
+            mesh: Some(MeshConfig {
+                mesh_psk_file: write_mesh_key([2u8; 32]).await?,
+                mesh_with: vec![mesh_url(4)],
+                mesh_url: None,
+            }),
+            ..Default::default()
+        };
+        cfg.write_to_file(&config_path).await?;
+        reloader.reload(&mut server).await?;
+        assert_eq!(mesh_peers(&server).await, vec![mesh_url(2), mesh_url(3)]);
+
+        // without a mesh config, the derper stops meshing
+        Config::default().write_to_file(&config_path).await?;
+        reloader.reload(&mut server).await?;
+        assert!(mesh_peers(&server).await.is_empty());
+
+        // invalid configs are not applied
+        tokio::fs::write(&config_path, "not a config").await?;
+        assert!(reloader.reload(&mut server).await.is_err());
+
+        // neither is a valid config together with an unreadable certificate
+        let cfg = Config {
+            mesh: Some(MeshConfig {
+                mesh_psk_file: write_mesh_key(mesh_key).await?,
+                mesh_with: vec![mesh_url(5)],
+                mesh_url: None,
+            }),
+            ..Default::default()
+        };
+        cfg.write_to_file(&config_path).await?;
+        tokio::fs::write(dir.join("localhost.key"), "not a key").await?;
+        assert!(reloader.reload(&mut server).await.is_err());
+        assert!(mesh_peers(&server).await.is_empty());
+        assert_eq!(cert_resolver.certified_key.read().unwrap().cert, cert);
+
+        server.shutdown().await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
 }
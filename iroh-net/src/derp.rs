@@ -17,20 +17,23 @@ mod map;
 mod metrics;
 pub(crate) mod server;
 pub(crate) mod types;
+mod verifier;
 
 pub use self::client::{Client as DerpClient, ReceivedMessage};
 pub use self::http::Client as HttpClient;
 pub use self::map::{DerpMap, DerpNode, DerpRegion, UseIpv4, UseIpv6};
 pub use self::metrics::Metrics;
 pub use self::server::{
-    ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer, PacketForwarderHandler, Server,
+    AdminHandler, ClientConnHandler, ClientStatus, MaybeTlsStream as MaybeTlsStreamServer,
+    PacketForwarderHandler, Server,
 };
 pub use self::types::{MeshKey, PacketForwarder};
+pub use self::verifier::{AllowList, ClientVerifier, HttpVerifier};
 
 use std::time::Duration;
 
 use anyhow::{ensure, Context, Result};
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use postcard::experimental::max_size::MaxSize;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::debug;
@@ -136,6 +139,9 @@ enum FrameType {
     /// connection is detected as a duplicate.
     /// The entire frame body is the text of the error message. An empty message
     /// clears the error state.
+    ///
+    /// Also sent instead of [`FrameType::ServerInfo`] to a client that is rejected by the
+    /// [`ClientVerifier`] of the server, right before the connection is closed.
     Health = 14,
 
     /// Sent from server to client for the server to declare that it's restarting.
@@ -146,6 +152,15 @@ enum FrameType {
     Restarting = 15,
     /// 32B src pub key + 32B dst pub key + packet bytes
     ForwardPacket = 16,
+    /// Like [`FrameType::PeerPresent`], but for the derp servers of the mesh.
+    ///
+    /// Sent by a mesh client after [`FrameType::WatchConns`] to announce the url on which
+    /// the other members of the mesh reach its derp server. The server sends it to its
+    /// watchers for every member of the mesh that announced itself, so that they can mesh
+    /// with it as well.
+    ///
+    /// UTF-8 encoded url
+    MeshPeerPresent = 17,
     Unknown = 255,
 }
 
@@ -174,6 +189,7 @@ impl From<u8> for FrameType {
             14 => FrameType::Health,
             15 => FrameType::Restarting,
             16 => FrameType::ForwardPacket,
+            17 => FrameType::MeshPeerPresent,
             _ => FrameType::Unknown,
         }
     }
@@ -216,6 +232,42 @@ async fn read_frame(
     Ok((frame_type, frame_len))
 }
 
+/// Like [`read_frame`], but cancel safe.
+///
+/// Bytes that are read before the future is dropped are kept in `pending`, and picked up by
+/// the next call, so it can be used in a `tokio::select!` loop. Only complete frames are
+/// taken out of `pending` and put into `buf`.
+async fn read_frame_buffered(
+    mut reader: impl AsyncRead + Unpin,
+    max_size: usize,
+    pending: &mut BytesMut,
+    buf: &mut BytesMut,
+) -> Result<(FrameType, usize)> {
+    // 1 byte frame type + 4 bytes frame length
+    const FRAME_HEADER_LEN: usize = 5;
+    loop {
+        if pending.len() >= FRAME_HEADER_LEN {
+            let frame_type = FrameType::from(pending[0]);
+            let frame_len = u32::from_be_bytes(pending[1..FRAME_HEADER_LEN].try_into()?);
+            let frame_len = usize::try_from(frame_len)?;
+            ensure!(
+                frame_len < max_size,
+                "frame header size {frame_len} exceeds reader limit of {max_size}"
+            );
+            if pending.len() >= FRAME_HEADER_LEN + frame_len {
+                debug!("read frame header: {:?} - {:?}", frame_type, frame_len);
+                pending.advance(FRAME_HEADER_LEN);
+                *buf = pending.split_to(frame_len);
+                return Ok((frame_type, frame_len));
+            }
+            pending.reserve(FRAME_HEADER_LEN + frame_len - pending.len());
+        }
+        if reader.read_buf(pending).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
 async fn read_frame_timeout(
     mut reader: impl AsyncRead + Unpin,
     max_size: usize,
@@ -356,6 +408,47 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_read_frame_buffered_cancel() -> Result<()> {
+        let (mut reader, mut writer) = tokio::io::duplex(1024);
+        let mut pending = BytesMut::new();
+        let mut got_buf = BytesMut::new();
+
+        // only the header and part of the content arrive before the read is cancelled
+        let expect_buf = b"hello world!";
+        let mut frame = Vec::new();
+        write_frame(&mut frame, FrameType::Health, &[expect_buf]).await?;
+        writer.write_all(&frame[..8]).await?;
+        let res = tokio::time::timeout(
+            Duration::from_millis(10),
+            read_frame_buffered(&mut reader, 1024, &mut pending, &mut got_buf),
+        )
+        .await;
+        assert!(res.is_err());
+
+        // the next read picks up where the cancelled one left off
+        writer.write_all(&frame[8..]).await?;
+        write_frame(&mut writer, FrameType::KeepAlive, &[]).await?;
+        let (frame_type, frame_len) =
+            read_frame_buffered(&mut reader, 1024, &mut pending, &mut got_buf).await?;
+        assert_eq!(FrameType::Health, frame_type);
+        assert_eq!(expect_buf.len(), frame_len);
+        assert_eq!(expect_buf.as_slice(), &got_buf);
+        let (frame_type, frame_len) =
+            read_frame_buffered(&mut reader, 1024, &mut pending, &mut got_buf).await?;
+        assert_eq!(FrameType::KeepAlive, frame_type);
+        assert_eq!(0, frame_len);
+
+        // the connection closing is an error
+        drop(writer);
+        let err = read_frame_buffered(&mut reader, 1024, &mut pending, &mut got_buf)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_send_recv_client_key() -> Result<()> {
         let (mut reader, mut writer) = tokio::io::duplex(1024);
@@ -10,6 +10,7 @@ use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 use tracing::{debug, info_span, Instrument};
+use url::Url;
 
 use super::client_conn::Io;
 use super::PER_CLIENT_SEND_QUEUE_DEPTH;
@@ -127,6 +128,17 @@ impl Client {
         Ok(())
     }
 
+    /// Announces the url on which the other members of the mesh reach our derp server.
+    ///
+    /// It's a fatal error if the client wasn't created using [`MeshKey`].
+    pub async fn announce_mesh_peer(&self, url: Url) -> Result<()> {
+        self.inner
+            .writer_channel
+            .send(ClientWriterMessage::MeshPeerPresent(url))
+            .await?;
+        Ok(())
+    }
+
     /// Asks the server to close the target's TCP connection.
     ///
     /// It's a fatal error if the client wasn't created using [`MeshKey`]
@@ -215,6 +227,20 @@ impl Client {
                         &frame_payload[..PUBLIC_KEY_LENGTH],
                     )?));
                 }
+                FrameType::MeshPeerPresent => {
+                    let url = std::str::from_utf8(&frame_payload)
+                        .ok()
+                        .and_then(|url| Url::parse(url).ok());
+                    match url {
+                        Some(url) => return Ok(ReceivedMessage::MeshPeerPresent(url)),
+                        None => {
+                            tracing::warn!(
+                                "unexpected: dropping MESH_PEER_PRESENT frame with an invalid url"
+                            );
+                            continue;
+                        }
+                    }
+                }
                 FrameType::RecvPacket => {
                     if (frame_len) < PUBLIC_KEY_LENGTH {
                         tracing::warn!("unexpected: dropping short packet from DERP server");
@@ -321,6 +347,9 @@ enum ClientWriterMessage {
     /// Asks the server to close the target's connection.
     /// Should only be used for mesh clients.
     ClosePeer(PublicKey),
+    /// Announce the url of our derp server to the server.
+    /// Should only be used for mesh clients.
+    MeshPeerPresent(Url),
     /// Shutdown the writer
     Shutdown,
 }
@@ -371,6 +400,9 @@ impl<W: AsyncWrite + Unpin + Send + 'static> ClientWriter<W> {
                 Some(ClientWriterMessage::ClosePeer(target)) => {
                     close_peer(&mut self.writer, target).await?;
                 }
+                Some(ClientWriterMessage::MeshPeerPresent(url)) => {
+                    send_mesh_peer_present(&mut self.writer, &url).await?;
+                }
                 Some(ClientWriterMessage::Shutdown) => {
                     return Ok(());
                 }
@@ -476,7 +508,16 @@ where
         let mut buf = BytesMut::new();
         let (frame_type, _) =
             crate::derp::read_frame(&mut self.reader, MAX_FRAME_SIZE, &mut buf).await?;
-        assert_eq!(FrameType::ServerInfo, frame_type);
+        match frame_type {
+            FrameType::ServerInfo => {}
+            FrameType::Health => {
+                bail!(
+                    "connection rejected by the server: {}",
+                    String::from_utf8_lossy(&buf)
+                );
+            }
+            _ => bail!("unexpected frame type {frame_type}, expected ServerInfo"),
+        }
         let msg = self.secret_key.open_from(&server_key, &buf)?;
         let info: ServerInfo = postcard::from_bytes(&msg)?;
         if info.version != PROTOCOL_VERSION {
@@ -565,6 +606,9 @@ pub enum ReceivedMessage {
     PeerGone(PublicKey),
     /// Indicates that the client is connected to the server. (Only used by trusted mesh clients)
     PeerPresent(PublicKey),
+    /// Indicates that the derp server at the url is a member of the mesh. (Only used by
+    /// trusted mesh clients)
+    MeshPeerPresent(Url),
     /// Sent by the server upon first connect.
     ServerInfo {
         /// How many bytes per second the server says it will accept, including all framing bytes.
@@ -709,6 +753,20 @@ pub(crate) async fn close_peer<W: AsyncWrite + Unpin>(
     Ok(())
 }
 
+pub(crate) async fn send_mesh_peer_present<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    url: &Url,
+) -> Result<()> {
+    write_frame(
+        &mut writer,
+        FrameType::MeshPeerPresent,
+        &[url.as_str().as_bytes()],
+    )
+    .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
 pub(crate) fn parse_recv_frame(frame: BytesMut) -> Result<(PublicKey, Bytes)> {
     ensure!(
         frame.len() >= PUBLIC_KEY_LENGTH,
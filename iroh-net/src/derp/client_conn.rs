@@ -1,14 +1,15 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{ensure, Context, Result};
 use bytes::{Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use tracing::{trace, Instrument};
+use url::Url;
 
 use crate::{
     disco::looks_like_disco_wrapper,
@@ -19,9 +20,9 @@ use iroh_metrics::{inc, inc_by};
 
 use super::server::MaybeTlsStream;
 use super::{
-    metrics::Metrics,
-    read_frame,
-    types::{Packet, PacketForwarder, PeerConnState, ServerMessage},
+    metrics::{ClientCounters, Metrics},
+    read_frame_buffered,
+    types::{Packet, PacketForwarder, PeerConnState, RateLimiter, ServerInfo, ServerMessage},
     write_frame_timeout, FrameType, KEEP_ALIVE, MAX_FRAME_SIZE, MAX_PACKET_SIZE, PREFERRED,
 };
 
@@ -31,12 +32,14 @@ pub(crate) struct ClientConnManager {
     /// Static after construction, process-wide unique counter, incremented each time we accept  
     pub(crate) conn_num: usize,
 
-    // TODO: in the go impl, we have a ptr to the server & use that ptr to update stats
-    // in rust, we should probably have a stats struct separate from the server that we
-    // can update in different threads, this may become a series of channels on which to
-    // send updates
-    // stats: Stats,
     pub(crate) key: PublicKey,
+    /// Whether the client is a derp server of our mesh
+    pub(crate) can_mesh: bool,
+    /// Whether the client considers this its preferred connection, shared with the
+    /// [`ClientConnIo`]
+    pub(crate) preferred: Arc<AtomicBool>,
+    /// Traffic counters, updated by the [`ClientConnIo`]
+    pub(crate) stats: Arc<ClientConnStats>,
     /// Sent when connection closes
     // TODO: maybe should be a receiver
     done: CancellationToken,
@@ -72,11 +75,37 @@ pub(crate) struct ClientChannels {
     /// allow the client to update their map of who's connected
     /// to this node
     pub(crate) mesh_update: mpsc::Sender<Vec<PeerConnState>>,
+    /// Send a client (if it is a mesh peer) the urls of the other members of the mesh
+    pub(crate) mesh_peers: mpsc::Sender<Url>,
+}
+
+/// Traffic counters of a client connection.
+///
+/// Updated by the [`ClientConnIo`] and read by the server, without going through a channel.
+#[derive(Debug, Default)]
+pub(crate) struct ClientConnStats {
+    /// Bytes of packets received from the client
+    pub(crate) bytes_recv: AtomicU64,
+    /// Bytes of packets sent to the client
+    pub(crate) bytes_sent: AtomicU64,
 }
 
 pub trait Io: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug {}
 impl<T: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug> Io for T {}
 
+/// The rate limiter for the client rate limit in `server_info`, `None` if clients are not
+/// limited.
+fn client_rate_limiter(server_info: &ServerInfo) -> Option<RateLimiter> {
+    RateLimiter::new(
+        server_info.token_bucket_bytes_per_second,
+        server_info.token_bucket_bytes_burst,
+    )
+    .unwrap_or_else(|err| {
+        tracing::warn!("invalid client rate limit: {err:?}");
+        None
+    })
+}
+
 /// A builds a [`ClientConnManager`] from a [`PublicKey`] and an io connection.
 #[derive(Debug)]
 pub struct ClientConnBuilder<P>
@@ -89,6 +118,7 @@ where
     pub(crate) can_mesh: bool,
     pub(crate) write_timeout: Option<Duration>,
     pub(crate) channel_capacity: usize,
+    pub(crate) rate_limit: Option<watch::Receiver<ServerInfo>>,
     pub(crate) server_channel: mpsc::Sender<ServerMessage<P>>,
 }
 
@@ -106,6 +136,7 @@ where
             self.can_mesh,
             self.write_timeout,
             self.channel_capacity,
+            self.rate_limit,
             self.server_channel,
         )
     }
@@ -123,6 +154,7 @@ impl ClientConnManager {
         can_mesh: bool,
         write_timeout: Option<Duration>,
         channel_capacity: usize,
+        mut rate_limit: Option<watch::Receiver<ServerInfo>>,
         server_channel: mpsc::Sender<ServerMessage<P>>,
     ) -> ClientConnManager
     where
@@ -135,8 +167,13 @@ impl ClientConnManager {
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
         let (mesh_update_s, mesh_update_r) = mpsc::channel(channel_capacity);
+        let (mesh_peers_s, mesh_peers_r) = mpsc::channel(channel_capacity);
 
         let preferred = Arc::from(AtomicBool::from(false));
+        let stats = Arc::new(ClientConnStats::default());
+        let rate_limiter = rate_limit
+            .as_mut()
+            .and_then(|rate_limit| client_rate_limiter(&rate_limit.borrow_and_update()));
 
         let conn_io = ClientConnIo {
             can_mesh,
@@ -146,8 +183,13 @@ impl ClientConnManager {
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
             mesh_update_r,
+            mesh_peers: mesh_peers_r,
             mesh_update_s: mesh_update_s.clone(),
 
+            rate_limit,
+            rate_limiter,
+            counters: ClientCounters::new(&key),
+            stats: Arc::clone(&stats),
             key: key.clone(),
             preferred: Arc::clone(&preferred),
             server_channel: server_channel.clone(),
@@ -183,6 +225,9 @@ impl ClientConnManager {
         ClientConnManager {
             conn_num,
             key,
+            can_mesh,
+            preferred,
+            stats,
             io_handle,
             done,
             client_channels: ClientChannels {
@@ -190,6 +235,7 @@ impl ClientConnManager {
                 disco_send_queue: disco_send_queue_s,
                 peer_gone: peer_gone_s,
                 mesh_update: mesh_update_s,
+                mesh_peers: mesh_peers_s,
             },
         }
     }
@@ -237,6 +283,9 @@ impl ClientConnManager {
 ///     to inform that client when peers join and leave the network:
 ///         - PEER_GONE frames inform the client a peer is gone from the network
 ///         - PEER_PRESENT frames inform the client a peer has joined the network
+///         - MESH_PEER_PRESENT frames inform the client of another member of the mesh
+///     - announce the url of the derp server the client belongs to, so the other
+///     members of the mesh can connect to it
 ///     - tell the server to close a given peer
 ///     - tell the server to forward a packet from another peer.
 #[derive(Debug)]
@@ -258,8 +307,18 @@ pub(crate) struct ClientConnIo<P: PacketForwarder> {
     /// connected to this node
     /// Notify the client of a peer state change ([`PeerConnState`])
     mesh_update_r: mpsc::Receiver<Vec<PeerConnState>>,
+    /// Used by mesh peers, the urls of the members of the mesh to send to the client
+    mesh_peers: mpsc::Receiver<Url>,
     /// Used by `reschedule_mesh_update` to reschedule additional mesh_updates
     mesh_update_s: mpsc::Sender<Vec<PeerConnState>>,
+    /// Changes of the rate limit of the client, `None` if the client is never limited
+    rate_limit: Option<watch::Receiver<ServerInfo>>,
+    /// Enforces the rate limit of the client on `SEND_PACKET` frames, if any
+    rate_limiter: Option<RateLimiter>,
+    /// Counters of this client in the per-client metrics
+    counters: ClientCounters,
+    /// Traffic counters, shared with the [`ClientConnManager`]
+    stats: Arc<ClientConnStats>,
 
     /// [`PublicKey`] of this client
     key: PublicKey,
@@ -288,6 +347,8 @@ where
         keep_alive.tick().await;
 
         let mut read_buf = BytesMut::new();
+        // bytes of a frame that is not completely read yet
+        let mut pending = BytesMut::new();
 
         loop {
             trace!("tick");
@@ -300,7 +361,9 @@ where
                     self.io.flush().await?;
                     return Ok(());
                 }
-                read_res = read_frame(&mut self.io, MAX_FRAME_SIZE, &mut read_buf) => {
+                // other branches may complete in the middle of a frame, `read_frame` would lose
+                // the part of the frame it read so far
+                read_res = read_frame_buffered(&mut self.io, MAX_FRAME_SIZE, &mut pending, &mut read_buf) => {
                     trace!("handle read");
                     self.handle_read(read_res, &mut read_buf).await?;
                 }
@@ -314,6 +377,11 @@ where
                     trace!("mesh updates");
                     self.send_mesh_updates(updates).await?;
                 }
+                url = self.mesh_peers.recv() => {
+                    let url = url.context("Server.mesh_peers dropped")?;
+                    trace!("mesh peer present: {}", url);
+                    self.send_mesh_peer_present(url).await?;
+                }
                 packet = self.send_queue.recv() => {
                     let packet = packet.context("Server.send_queue dropped")?;
                     trace!("send packet");
@@ -384,6 +452,23 @@ where
         .await
     }
 
+    /// Sends a mesh peer present frame, does not flush
+    ///
+    /// Errors if the send does not happen within the `timeout` duration
+    async fn send_mesh_peer_present(&mut self, url: Url) -> Result<()> {
+        ensure!(
+            self.can_mesh,
+            "unexpected request to send mesh peers on a connection that is not able to mesh"
+        );
+        write_frame_timeout(
+            &mut self.io,
+            FrameType::MeshPeerPresent,
+            &[url.as_str().as_bytes()],
+            self.timeout,
+        )
+        .await
+    }
+
     // TODO: golang comment:
     // "Drains as many mesh `PEER_STATE_CHANGE`s entries as possible
     // into the write buffer WITHOUT flushing or otherwise blocking (as it holds the mutex while
@@ -433,6 +518,10 @@ where
         let srckey = packet.src;
         let contents = packet.bytes;
         inc_by!(Metrics, bytes_sent, contents.len().try_into().unwrap());
+        self.stats
+            .bytes_sent
+            .fetch_add(contents.len() as u64, Ordering::Relaxed);
+        self.counters.bytes_sent(contents.len() as u64);
         if srckey.is_zero() {
             // TODO: ensure we handle this correctly on the client side
             write_frame_timeout(
@@ -474,6 +563,10 @@ where
                     FrameType::SendPacket => {
                         self.handle_frame_send_packet(&frame).await?;
                         inc_by!(Metrics, bytes_recv, frame_len as u64);
+                        self.stats
+                            .bytes_recv
+                            .fetch_add(frame_len as u64, Ordering::Relaxed);
+                        self.counters.bytes_recv(frame_len as u64);
                     }
                     FrameType::ForwardPacket => {
                         self.handle_frame_forward_packet(&frame).await?;
@@ -487,6 +580,10 @@ where
                         self.handle_frame_close_peer(&frame).await?;
                         inc!(Metrics, other_packets_recv);
                     }
+                    FrameType::MeshPeerPresent => {
+                        self.handle_frame_mesh_peer_present(&frame).await?;
+                        inc!(Metrics, other_packets_recv);
+                    }
                     FrameType::Ping => {
                         self.handle_frame_ping(&frame).await?;
                         inc!(Metrics, got_ping);
@@ -581,6 +678,17 @@ where
         Ok(())
     }
 
+    /// Parse the MESH_PEER_PRESENT frame, the url of the derp server this mesh
+    /// client belongs to, and hand it to the server to share with the rest of the mesh.
+    async fn handle_frame_mesh_peer_present(&self, data: &[u8]) -> Result<()> {
+        ensure!(self.can_mesh, "insufficient permissions");
+        let url = std::str::from_utf8(data).context("FrameType::MeshPeerPresent invalid utf8")?;
+        let url = Url::parse(url).context("FrameType::MeshPeerPresent invalid url")?;
+        self.send_server(ServerMessage::MeshPeerPresent((self.key.clone(), url)))
+            .await?;
+        Ok(())
+    }
+
     async fn handle_frame_close_peer(&self, data: &[u8]) -> Result<()> {
         ensure!(self.can_mesh, "insufficient permissions");
         let key = PublicKey::try_from(data)?;
@@ -615,8 +723,24 @@ where
     ///
     /// Errors if the key cannot be parsed correctly, or if the packet is
     /// larger than MAX_PACKET_SIZE
-    async fn handle_frame_send_packet(&self, data: &[u8]) -> Result<()> {
+    async fn handle_frame_send_packet(&mut self, data: &[u8]) -> Result<()> {
         let (dstkey, data) = parse_send_packet(data)?;
+        if let Some(rate_limit) = &mut self.rate_limit {
+            // the limit of the server changed, apply it to this client as well
+            if rate_limit.has_changed().unwrap_or_default() {
+                self.rate_limiter = client_rate_limiter(&rate_limit.borrow_and_update());
+            }
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if rate_limiter
+                .check_n(PUBLIC_KEY_LENGTH + data.len())
+                .is_err()
+            {
+                trace!("dropping packet to {dstkey:?}: rate limit reached");
+                inc!(Metrics, rate_limited_packets);
+                return Ok(());
+            }
+        }
         let packet = Packet {
             src: self.key.clone(),
             bytes: Bytes::from(data.to_owned()),
@@ -677,6 +801,7 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
+    use crate::derp::read_frame;
 
     use anyhow::bail;
 
@@ -694,6 +819,7 @@ mod tests {
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(10);
         let (mesh_update_s, mesh_update_r) = mpsc::channel(10);
+        let (_mesh_peers_s, mesh_peers_r) = mpsc::channel(10);
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = PublicKey::from([1u8; PUBLIC_KEY_LENGTH]);
@@ -708,7 +834,12 @@ mod tests {
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
             mesh_update_r,
+            mesh_peers: mesh_peers_r,
             mesh_update_s: mesh_update_s.clone(),
+            rate_limit: None,
+            rate_limiter: None,
+            counters: Default::default(),
+            stats: Default::default(),
 
             key: key.clone(),
             server_channel: server_channel_s,
@@ -910,6 +1041,7 @@ mod tests {
         let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
         let (mesh_update_s, mesh_update_r) = mpsc::channel(10);
+        let (_mesh_peers_s, mesh_peers_r) = mpsc::channel(10);
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = PublicKey::from([1u8; PUBLIC_KEY_LENGTH]);
@@ -925,7 +1057,12 @@ mod tests {
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
             mesh_update_r,
+            mesh_peers: mesh_peers_r,
             mesh_update_s: mesh_update_s.clone(),
+            rate_limit: None,
+            rate_limiter: None,
+            counters: Default::default(),
+            stats: Default::default(),
 
             key: key.clone(),
             server_channel: server_channel_s,
@@ -977,4 +1114,82 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_client_conn_rate_limit() -> Result<()> {
+        let (_send_queue_s, send_queue_r) = mpsc::channel(10);
+        let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
+        let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (mesh_update_s, mesh_update_r) = mpsc::channel(10);
+        let (_mesh_peers_s, mesh_peers_r) = mpsc::channel(10);
+
+        let key = PublicKey::from([1u8; PUBLIC_KEY_LENGTH]);
+        let (io, mut io_rw) = tokio::io::duplex(1024);
+        let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
+
+        // the burst fits one packet, and the rate is too low to refill it during the test
+        let (rate_limit_s, rate_limit_r) = watch::channel(ServerInfo {
+            token_bucket_bytes_per_second: 1,
+            token_bucket_bytes_burst: 50,
+            ..ServerInfo::no_rate_limit()
+        });
+        let conn_io = ClientConnIo::<MockPacketForwarder> {
+            can_mesh: false,
+            io: MaybeTlsStream::Test(io),
+            timeout: None,
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            peer_gone: peer_gone_r,
+            mesh_update_r,
+            mesh_peers: mesh_peers_r,
+            mesh_update_s,
+            rate_limit: Some(rate_limit_r),
+            rate_limiter: RateLimiter::new(1, 50)?,
+            counters: Default::default(),
+            stats: Default::default(),
+
+            key: key.clone(),
+            server_channel: server_channel_s,
+            preferred: Default::default(),
+        };
+
+        let done = CancellationToken::new();
+        let io_done = done.clone();
+        let io_handle = tokio::task::spawn(async move { conn_io.run(io_done).await });
+
+        let data = b"hello world!";
+        let target = PublicKey::from([0x10; PUBLIC_KEY_LENGTH]);
+        crate::derp::client::send_packet(&mut io_rw, &None, target.clone(), data).await?;
+        match server_channel_r.recv().await.unwrap() {
+            ServerMessage::SendPacket((got_target, packet)) => {
+                assert_eq!(target, got_target);
+                assert_eq!(&data[..], &packet.bytes);
+            }
+            m => {
+                bail!("expected ServerMessage::SendPacket, got {m:?}");
+            }
+        }
+
+        // exceeds the rate limit, so it is dropped
+        crate::derp::client::send_packet(&mut io_rw, &None, target.clone(), data).await?;
+
+        // frames are handled in order, so once we get the pong the packet was handled
+        let mut buf = BytesMut::new();
+        crate::derp::client::send_ping(&mut io_rw, b"pingpong").await?;
+        let (frame_type, _) = read_frame(&mut io_rw, MAX_PACKET_SIZE, &mut buf).await?;
+        assert_eq!(FrameType::Pong, frame_type);
+        assert!(server_channel_r.try_recv().is_err());
+
+        // lifting the limit of the server applies to connected clients
+        rate_limit_s.send_replace(ServerInfo::no_rate_limit());
+        crate::derp::client::send_packet(&mut io_rw, &None, target, data).await?;
+        assert!(matches!(
+            server_channel_r.recv().await.unwrap(),
+            ServerMessage::SendPacket(_)
+        ));
+
+        done.cancel();
+        io_handle.await??;
+        Ok(())
+    }
 }
@@ -3,16 +3,19 @@
 //! The "Server" side of the client. Uses the `ClientConnManager`.
 use crate::key::node::PublicKey;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
 
 use futures::future::join_all;
 use tokio::sync::mpsc;
 
 use iroh_metrics::inc;
 use tracing::{Instrument, Span};
+use url::Url;
 
 use super::{
     client_conn::ClientConnManager,
     metrics::Metrics,
+    server::ClientStatus,
     types::{Packet, PeerConnState},
 };
 
@@ -116,6 +119,19 @@ impl Client {
         }
         res
     }
+
+    pub fn send_mesh_peer_present(&self, url: Url) -> Result<(), SendError> {
+        let res = try_send(&self.conn.client_channels.mesh_peers, url);
+        match res {
+            Ok(_) => {
+                inc!(Metrics, other_packets_sent);
+            }
+            Err(_) => {
+                inc!(Metrics, other_packets_dropped);
+            }
+        }
+        res
+    }
 }
 
 // TODO: in the goimpl, it also tries 3 times to send a packet. But, in go we can clone receiver
@@ -170,13 +186,6 @@ impl Clients {
         join_all(handles).await;
     }
 
-    pub fn close_conn(&mut self, key: &PublicKey) {
-        tracing::info!("closing conn {:?}", key);
-        if let Some(client) = self.inner.remove(key) {
-            client.shutdown();
-        }
-    }
-
     /// Record that `src` sent or forwarded a packet to `dst`
     pub fn record_send(&mut self, src: &PublicKey, dst: PublicKey) {
         if let Some(client) = self.inner.get_mut(src) {
@@ -184,6 +193,20 @@ impl Clients {
         }
     }
 
+    /// The [`ClientStatus`] of all clients
+    pub fn status(&self) -> Vec<ClientStatus> {
+        self.inner
+            .values()
+            .map(|client| ClientStatus {
+                key: client.conn.key.clone(),
+                preferred: client.conn.preferred.load(Ordering::Relaxed),
+                mesh_peer: client.conn.can_mesh,
+                bytes_recv: client.conn.stats.bytes_recv.load(Ordering::Relaxed),
+                bytes_sent: client.conn.stats.bytes_sent.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
     pub fn all_clients(&mut self) -> impl Iterator<Item = &PublicKey> {
         self.inner.keys()
     }
@@ -209,6 +232,17 @@ impl Clients {
         }
     }
 
+    /// Tells each of the mesh clients at `keys` about the member of the mesh at `url`.
+    pub fn broadcast_mesh_peer_present<'a>(
+        &mut self,
+        keys: impl Iterator<Item = &'a PublicKey>,
+        url: &Url,
+    ) {
+        for k in keys {
+            self.send_mesh_peer_present(k, url.clone());
+        }
+    }
+
     pub fn register(&mut self, client: ClientConnManager) {
         // this builds the client handler & starts the read & write loops to that client connection
         let key = client.key.clone();
@@ -273,6 +307,15 @@ impl Clients {
         tracing::warn!("Could not find client for {key:?}, dropping packet");
     }
 
+    pub fn send_mesh_peer_present(&mut self, key: &PublicKey, url: Url) {
+        if let Some(client) = self.inner.get(key) {
+            let res = client.send_mesh_peer_present(url);
+            let _ = self.process_result(key, res);
+        } else {
+            tracing::warn!("Could not find client for {key:?}, dropping mesh peer");
+        }
+    }
+
     fn process_result(
         &mut self,
         key: &PublicKey,
@@ -326,6 +369,7 @@ mod tests {
                 can_mesh: true,
                 write_timeout: None,
                 channel_capacity: 10,
+                rate_limit: None,
                 server_channel,
             },
             test_io,
@@ -394,6 +438,13 @@ mod tests {
         let got_key = PublicKey::try_from(&buf[..PUBLIC_KEY_LENGTH])?;
         assert_eq!(got_key, b_key);
 
+        // send mesh_peer_present
+        let url: Url = "https://derp.example.com".parse()?;
+        clients.send_mesh_peer_present(&a_key.clone(), url.clone());
+        let (frame_type, _) = read_frame(&mut a_rw, MAX_PACKET_SIZE, &mut buf).await?;
+        assert_eq!(frame_type, FrameType::MeshPeerPresent);
+        assert_eq!(std::str::from_utf8(&buf)?, url.as_str());
+
         clients.unregister(&a_key.clone());
 
         assert!(clients.inner.get(&a_key).is_none());
@@ -5,13 +5,18 @@
 //! DERP Server will have one http DERP Client that is connected to each other http DERP Server in the
 //! region. Those http DERP Clients will act as `PacketForwarder`s for the remote http DERP Servers.
 //!
+//! If the `Upgrade` handshake is stripped by a proxy on the way, the http DERP Client falls back
+//! to sending the DERP frames over a WebSocket connection, see [`WsStream`].
+//!
 mod client;
 mod mesh_clients;
 mod server;
+mod websocket;
 
 pub use self::client::{Client, ClientBuilder, ClientError};
-pub use self::mesh_clients::MeshAddrs;
+pub use self::mesh_clients::{MeshAddrs, MeshPeerStatus, MeshStatus};
 pub use self::server::{Server, ServerBuilder, TlsAcceptor, TlsConfig};
+pub use self::websocket::WsStream;
 
 pub(crate) const HTTP_UPGRADE_PROTOCOL: &str = "iroh derp http";
 
@@ -41,9 +46,13 @@ pub(crate) fn make_tls_config() -> TlsConfig {
 mod tests {
     use super::*;
 
+    use std::net::SocketAddr;
+
     use anyhow::Result;
     use bytes::Bytes;
     use reqwest::Url;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
     use tokio::sync::mpsc;
     use tokio::task::JoinHandle;
     use tracing::{info_span, Instrument};
@@ -274,4 +283,111 @@ mod tests {
         client_b_task.abort();
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_websocket_clients_and_server() -> Result<()> {
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+            .with(EnvFilter::from_default_env())
+            .try_init()
+            .ok();
+
+        let server_key = SecretKey::generate();
+        let a_key = SecretKey::generate();
+        let b_key = SecretKey::generate();
+
+        // start server
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(server_key))
+            .spawn()
+            .await?;
+        let addr = server.addr();
+        let (proxy_addr, proxy_task) = spawn_upgrade_stripping_proxy(addr).await?;
+        println!("DERP listening on: {addr}, proxy on: {proxy_addr}");
+
+        let region = DerpRegion {
+            region_id: 1,
+            avoid: false,
+            nodes: Vec::new(),
+            region_code: "test_region".to_string(),
+        };
+
+        // client a has to fall back to websockets, client b uses the native upgrade
+        let proxy_url: Url = format!("http://{proxy_addr}").parse().unwrap();
+        let (a_key, mut a_recv, client_a_task, client_a) =
+            create_test_client(a_key, region.clone(), Some(proxy_url));
+        println!("created client {a_key:?}");
+        let derp_url: Url = format!("http://{addr}").parse().unwrap();
+        let (b_key, mut b_recv, client_b_task, client_b) =
+            create_test_client(b_key, region, Some(derp_url));
+        println!("created client {b_key:?}");
+
+        client_a.ping().await?;
+        client_b.ping().await?;
+
+        println!("sending message from a to b");
+        let msg = Bytes::from_static(b"hi there, client b!");
+        client_a.send(b_key.clone(), msg.clone()).await?;
+        println!("waiting for message from a on b");
+        let (got_key, got_msg) = b_recv.recv().await.expect("expected message from client_a");
+        assert_eq!(a_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        println!("sending message from b to a");
+        let msg = Bytes::from_static(b"right back at ya, client b!");
+        client_b.send(a_key.clone(), msg.clone()).await?;
+        println!("waiting for message b on a");
+        let (got_key, got_msg) = a_recv.recv().await.expect("expected message from client_b");
+        assert_eq!(b_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        server.shutdown().await;
+        proxy_task.abort();
+        client_a.close().await;
+        client_a_task.abort();
+        client_b.close().await;
+        client_b_task.abort();
+        Ok(())
+    }
+
+    /// Spawns a proxy to `addr` that rejects the native DERP upgrade, like proxies that
+    /// strip the `Upgrade` header do.
+    async fn spawn_upgrade_stripping_proxy(
+        addr: SocketAddr,
+    ) -> Result<(SocketAddr, JoinHandle<()>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((mut downstream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    // read the request head
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match downstream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    if String::from_utf8_lossy(&head).contains(HTTP_UPGRADE_PROTOCOL) {
+                        downstream
+                            .write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n")
+                            .await
+                            .ok();
+                        return;
+                    }
+                    let mut upstream = match TcpStream::connect(addr).await {
+                        Ok(upstream) => upstream,
+                        Err(_) => return,
+                    };
+                    if upstream.write_all(&head).await.is_ok() {
+                        tokio::io::copy_bidirectional(&mut downstream, &mut upstream)
+                            .await
+                            .ok();
+                    }
+                });
+            }
+        });
+        Ok((proxy_addr, task))
+    }
 }
@@ -8,8 +8,9 @@ use std::time::Duration;
 use anyhow::bail;
 use bytes::Bytes;
 use futures::future::BoxFuture;
+use hyper::header::{HeaderValue, SEC_WEBSOCKET_ACCEPT, UPGRADE};
 use hyper::upgrade::Upgraded;
-use hyper::{header::UPGRADE, Body, Request};
+use hyper::{Body, Request};
 use iroh_metrics::inc;
 use rand::Rng;
 use tokio::net::TcpStream;
@@ -17,9 +18,12 @@ use tokio::sync::oneshot;
 use tokio::sync::Mutex;
 use tokio::task::{JoinHandle, JoinSet};
 use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::Role;
 use tracing::{debug, info_span, instrument, trace, warn, Instrument};
 use url::Url;
 
+use super::mesh_clients::MeshMembership;
+use super::websocket::{self, WsStream};
 use crate::derp::{
     client::Client as DerpClient, client::ClientBuilder as DerpClientBuilder, client_conn::Io,
     metrics::Metrics, server::PacketForwarderHandler, DerpNode, DerpRegion, MeshKey,
@@ -32,6 +36,7 @@ const DIAL_NODE_TIMEOUT: Duration = Duration::from_millis(1500);
 const PING_TIMEOUT: Duration = Duration::from_secs(5);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 const MESH_CLIENT_REDIAL_DELAY: Duration = Duration::from_secs(5);
+const MESH_CLIENT_MAX_REDIAL_DELAY: Duration = Duration::from_secs(60);
 
 /// Possible connection errors on the [`Client`]
 #[derive(Debug, thiserror::Error)]
@@ -81,6 +86,9 @@ pub enum ClientError {
     /// The connection failed to upgrade
     #[error("failed to upgrade connection: {0}")]
     Upgrade(String),
+    /// The server answered, but did not agree to the protocol upgrade
+    #[error("upgrade rejected: {0}")]
+    UpgradeRejected(String),
     /// The derp [`super::client::Client`] failed to build
     #[error("failed to build derp client: {0}")]
     Build(String),
@@ -98,6 +106,18 @@ pub enum ClientError {
     Dns(Option<trust_dns_resolver::error::ResolveError>),
 }
 
+impl ClientError {
+    /// Whether the server could be reached, but rejected the upgrade of the connection.
+    ///
+    /// This is usually caused by a proxy that does not pass on the `Upgrade` header.
+    fn is_upgrade_failure(&self) -> bool {
+        matches!(
+            self,
+            ClientError::UnexpectedStatusCode(..) | ClientError::UpgradeRejected(_)
+        )
+    }
+}
+
 /// An HTTP DERP client.
 ///
 /// Cheaply clonable.
@@ -136,6 +156,7 @@ struct InnerClient {
     is_prober: bool,
     server_public_key: Option<key::node::PublicKey>,
     url: Option<Url>,
+    use_websocket: AtomicBool,
 }
 
 /// Build a Client.
@@ -154,6 +175,8 @@ pub struct ClientBuilder {
     is_prober: bool,
     /// Expected PublicKey of the server
     server_public_key: Option<key::node::PublicKey>,
+    /// Default is false
+    use_websocket: bool,
     /// Server url.
     ///
     /// If the `url` field and `get_region` field are both `None`, the `ClientBuilder`
@@ -239,6 +262,14 @@ impl ClientBuilder {
         self
     }
 
+    /// Always connect over a WebSocket connection.
+    ///
+    /// By default the client only falls back to WebSockets if the native upgrade fails.
+    pub fn use_websocket(mut self, use_websocket: bool) -> Self {
+        self.use_websocket = use_websocket;
+        self
+    }
+
     /// Build the [`Client`]
     ///
     /// Will error if there is no region or no url set.
@@ -259,6 +290,7 @@ impl ClientBuilder {
                 is_prober: self.is_prober,
                 server_public_key: self.server_public_key,
                 url: self.url,
+                use_websocket: AtomicBool::new(self.use_websocket),
             }),
         })
     }
@@ -276,6 +308,11 @@ impl Client {
         self.inner.secret_key.public_key()
     }
 
+    /// Whether the client is currently connected to the derp server.
+    pub async fn is_connected(&self) -> bool {
+        self.inner.derp_client.lock().await.is_some()
+    }
+
     /// Let the server know that this client is the preferred client
     pub async fn note_preferred(&self, is_preferred: bool) {
         {
@@ -417,6 +454,27 @@ impl Client {
 
     #[instrument(level = "debug", skip_all)]
     async fn connect_0(&self) -> Result<DerpClient, ClientError> {
+        if !self.inner.use_websocket.load(Ordering::Relaxed) {
+            match self.connect_transport(false).await {
+                Err(err) if err.is_upgrade_failure() => {
+                    warn!(
+                        "upgrade to \"{}\" failed, falling back to websocket: {:?}",
+                        super::HTTP_UPGRADE_PROTOCOL,
+                        err
+                    );
+                }
+                res => return res,
+            }
+        }
+        let derp_client = self.connect_transport(true).await?;
+        // the native upgrade does not work on this path, don't try it again on reconnects
+        self.inner.use_websocket.store(true, Ordering::Relaxed);
+        Ok(derp_client)
+    }
+
+    /// Connects to the derp server, sending the DERP frames directly over the upgraded
+    /// connection, or with `websocket` as messages of a WebSocket connection.
+    async fn connect_transport(&self, websocket: bool) -> Result<DerpClient, ClientError> {
         let url = self.url();
         let is_test_url = url
             .as_ref()
@@ -437,11 +495,16 @@ impl Client {
             .local_addr()
             .map_err(|e| ClientError::NoLocalAddr(e.to_string()))?;
 
-        let req = Request::builder()
-            .uri("/derp")
-            .header(UPGRADE, super::HTTP_UPGRADE_PROTOCOL)
-            .body(Body::empty())
-            .unwrap();
+        let mut req = Request::builder().uri("/derp").body(Body::empty()).unwrap();
+        let websocket_accept = if websocket {
+            Some(websocket::insert_request_headers(req.headers_mut()))
+        } else {
+            req.headers_mut().insert(
+                UPGRADE,
+                HeaderValue::from_static(super::HTTP_UPGRADE_PROTOCOL),
+            );
+            None
+        };
 
         let res = if self.use_https(derp_node.as_ref()) {
             debug!("Starting TLS handshake");
@@ -525,6 +588,20 @@ impl Client {
                 res.status(),
             ));
         }
+        if let Some(accept) = websocket_accept {
+            if res.headers().get(SEC_WEBSOCKET_ACCEPT) != Some(&accept) {
+                return Err(ClientError::Upgrade(
+                    "invalid Sec-WebSocket-Accept header".into(),
+                ));
+            }
+        } else if res.headers().get(UPGRADE).map(|v| v.as_bytes())
+            != Some(super::HTTP_UPGRADE_PROTOCOL.as_bytes())
+        {
+            return Err(ClientError::UpgradeRejected(format!(
+                "missing \"{}\" upgrade header",
+                super::HTTP_UPGRADE_PROTOCOL
+            )));
+        }
 
         debug!("starting upgrade");
         let upgraded = match hyper::upgrade::on(res).await {
@@ -538,6 +615,13 @@ impl Client {
         debug!("connection upgraded");
         let (io, read_buf) =
             downcast_upgrade(upgraded).map_err(|e| ClientError::Upgrade(e.to_string()))?;
+        let (io, read_buf): (Box<dyn Io + Send + Sync + 'static>, _) = if websocket {
+            // data read during the upgrade belongs to the websocket framing
+            let io = WsStream::new(io, read_buf, Role::Client).await;
+            (Box::new(io), Bytes::new())
+        } else {
+            (io, read_buf)
+        };
 
         // TODO: unify client loop
         let (reader, writer) = tokio::io::split(io);
@@ -921,6 +1005,23 @@ impl Client {
         Ok(())
     }
 
+    /// Send our derp server's url to the server, announcing it as a member of the mesh.
+    ///
+    /// If there is no underlying active derp connection, it creates one before attempting to
+    /// send the announcement.
+    ///
+    /// If there is an error sending, it closes the underlying derp connection before
+    /// returning.
+    pub async fn announce_mesh_peer(&self, url: Url) -> Result<(), ClientError> {
+        debug!("announce_mesh_peer");
+        let (client, _) = self.connect().await?;
+        if client.announce_mesh_peer(url).await.is_err() {
+            self.close_for_reconnect().await;
+            return Err(ClientError::Send);
+        }
+        Ok(())
+    }
+
     /// Run this client as a mesh client.
     ///
     /// This method will error if you do not have a `mesh_key`.
@@ -938,33 +1039,78 @@ impl Client {
     /// This `meshed_once` sender is typically used for aligning the mesh network
     /// during tests.
     pub async fn run_mesh_client(
+        self,
+        packet_forwarder_handler: PacketForwarderHandler<Client>,
+        meshed_once: Option<tokio::sync::oneshot::Sender<()>>,
+    ) -> anyhow::Result<()> {
+        self.run_mesh_member(
+            packet_forwarder_handler,
+            meshed_once,
+            MeshMembership::default(),
+        )
+        .await
+    }
+
+    /// Run this client as a mesh client, that also takes part in the discovery of the mesh.
+    ///
+    /// Like [`Client::run_mesh_client`], but after subscribing to the network changes it
+    /// announces the url of our own derp server, if any, and passes on the urls of the
+    /// members of the mesh the remote derp server knows about. The health of the connection
+    /// is recorded, and reconnection attempts back off while the server stays unreachable.
+    pub(crate) async fn run_mesh_member(
         self,
         packet_forwarder_handler: PacketForwarderHandler<Client>,
         mut meshed_once: Option<tokio::sync::oneshot::Sender<()>>,
+        membership: MeshMembership,
     ) -> anyhow::Result<()> {
         // connect to the remote server & request to watching the remote's state changes
         let own_key = self.public_key();
+        let mut forwarders = MeshForwarders::new(packet_forwarder_handler);
+        let mut failures = 0;
         loop {
             let (server_public_key, last_conn_gen) = match self.watch_connection_changes().await {
                 Ok(key) => {
                     if let Some(sender) = meshed_once.take() {
-                        if let Err(e) = sender.send(()) {
-                            bail!("unable to notify sender that we have successfully meshed with the remote server: {e:?}");
+                        // nobody waiting for us to mesh is not a reason to stop meshing
+                        if sender.send(()).is_err() {
+                            debug!("unable to notify sender that we have successfully meshed with the remote server");
                         }
                     }
                     key
                 }
                 Err(e) => {
-                    tracing::warn!("error connecting to derp server {e}");
-                    tokio::time::sleep(MESH_CLIENT_REDIAL_DELAY).await;
+                    failures += 1;
+                    membership.connect_failed();
+                    let delay = mesh_redial_delay(failures);
+                    tracing::warn!("error connecting to derp server {e}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
                     continue;
                 }
             };
 
             if server_public_key == own_key {
+                membership.disconnected();
                 bail!("detected self-connect; closing this client");
             }
 
+            if !membership.check_server_key(server_public_key.clone()).await {
+                self.close().await;
+                return Ok(());
+            }
+
+            if let Some(url) = &membership.own_url {
+                if let Err(e) = self.announce_mesh_peer(url.clone()).await {
+                    failures += 1;
+                    membership.connect_failed();
+                    let delay = mesh_redial_delay(failures);
+                    tracing::warn!("error announcing to derp server {e}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            }
+            failures = 0;
+            membership.connected();
+
             let peers_present = PeersPresent::new(server_public_key.clone());
             tracing::info!("Connected to mesh derp server {server_public_key:?}");
 
@@ -974,6 +1120,7 @@ impl Client {
                     Ok(res) => res,
                     Err(e) => {
                         tracing::warn!("recv error: {e:?}");
+                        membership.disconnected();
                         tokio::time::sleep(MESH_CLIENT_REDIAL_DELAY).await;
                         break;
                     }
@@ -995,7 +1142,7 @@ impl Client {
                             continue;
                         }
                         peers_present.insert(key.clone()).await?;
-                        packet_forwarder_handler.add_packet_forwarder(key, self.clone())?;
+                        forwarders.add(key, self.clone())?;
                     }
                     ReceivedMessage::PeerGone(key) => {
                         // ignore notifications about ourself
@@ -1003,7 +1150,13 @@ impl Client {
                             continue;
                         }
                         peers_present.remove(key.clone()).await?;
-                        packet_forwarder_handler.remove_packet_forwarder(key)?;
+                        forwarders.remove(key)?;
+                    }
+                    ReceivedMessage::MeshPeerPresent(url) => {
+                        if let Some(discovered) = &membership.discovered {
+                            // only fails if the mesh is shutting down
+                            discovered.send(url).await.ok();
+                        }
                     }
                     _ => {}
                 }
@@ -1012,6 +1165,57 @@ impl Client {
     }
 }
 
+/// How long a mesh client waits before reconnecting after `failures` failed attempts.
+///
+/// Doubles with every failure, starting at [`MESH_CLIENT_REDIAL_DELAY`], up to
+/// [`MESH_CLIENT_MAX_REDIAL_DELAY`].
+fn mesh_redial_delay(failures: u32) -> Duration {
+    let factor = 1u32 << failures.saturating_sub(1).min(16);
+    MESH_CLIENT_REDIAL_DELAY
+        .saturating_mul(factor)
+        .min(MESH_CLIENT_MAX_REDIAL_DELAY)
+}
+
+/// The packet forwarders added by a mesh client.
+///
+/// They are removed when the mesh client stops, so the server no longer forwards packets
+/// to a derp server it does not mesh with anymore.
+#[derive(Debug)]
+struct MeshForwarders {
+    handler: PacketForwarderHandler<Client>,
+    keys: HashSet<key::node::PublicKey>,
+}
+
+impl MeshForwarders {
+    fn new(handler: PacketForwarderHandler<Client>) -> Self {
+        Self {
+            handler,
+            keys: HashSet::new(),
+        }
+    }
+
+    fn add(&mut self, key: key::node::PublicKey, client: Client) -> anyhow::Result<()> {
+        self.handler.add_packet_forwarder(key.clone(), client)?;
+        self.keys.insert(key);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: key::node::PublicKey) -> anyhow::Result<()> {
+        self.keys.remove(&key);
+        self.handler.remove_packet_forwarder(key)
+    }
+}
+
+impl Drop for MeshForwarders {
+    fn drop(&mut self) {
+        for key in self.keys.drain() {
+            if let Err(err) = self.handler.remove_packet_forwarder(key) {
+                debug!("unable to remove packet forwarder: {err:?}");
+            }
+        }
+    }
+}
+
 const PEERS_PRESENT_LOGGING_DELAY: Duration = Duration::from_secs(5);
 const PEERS_PRESENT_LOGGING_INTERVAL: Duration = Duration::from_secs(10);
 const PEERS_PRESENT_QUEUE: usize = 100;
@@ -1182,6 +1386,29 @@ mod tests {
 
     use anyhow::Result;
 
+    #[test]
+    fn test_mesh_redial_delay() {
+        assert_eq!(mesh_redial_delay(1), MESH_CLIENT_REDIAL_DELAY);
+        assert_eq!(mesh_redial_delay(2), MESH_CLIENT_REDIAL_DELAY * 2);
+        assert_eq!(mesh_redial_delay(3), MESH_CLIENT_REDIAL_DELAY * 4);
+        assert_eq!(mesh_redial_delay(10), MESH_CLIENT_MAX_REDIAL_DELAY);
+        assert_eq!(mesh_redial_delay(u32::MAX), MESH_CLIENT_MAX_REDIAL_DELAY);
+    }
+
+    #[test]
+    fn test_is_upgrade_failure() {
+        let status = ClientError::UnexpectedStatusCode(
+            hyper::StatusCode::SWITCHING_PROTOCOLS,
+            hyper::StatusCode::BAD_REQUEST,
+        );
+        assert!(status.is_upgrade_failure());
+        assert!(ClientError::UpgradeRejected("no header".into()).is_upgrade_failure());
+        // errors of the connection itself do not mean that the upgrade is not supported
+        assert!(!ClientError::Upgrade("downcast failed".into()).is_upgrade_failure());
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(!ClientError::DialIO(io).is_upgrade_failure());
+    }
+
     #[tokio::test]
     async fn test_recv_detail_connect_error() -> Result<()> {
         let key = SecretKey::generate();
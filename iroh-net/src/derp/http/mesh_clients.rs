@@ -1,50 +1,103 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iroh_metrics::{core::Metric, inc};
 use reqwest::Url;
-use tokio::task::JoinSet;
-use tokio_util::sync::CancellationToken;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
 use tracing::{info_span, Instrument};
 
 use crate::{
-    derp::{http::ClientBuilder, DerpMap, MeshKey, PacketForwarderHandler},
-    key::node::SecretKey,
+    derp::{http::ClientBuilder, metrics::Metrics, DerpMap, MeshKey, PacketForwarderHandler},
+    key::node::{PublicKey, SecretKey},
 };
 
 use super::Client;
 
+/// How often we check for discovered derp servers that have been unreachable for too long
+const MESH_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a discovered derp server may be unreachable before we stop meshing with it
+const MESH_PEER_EXPIRY: Duration = Duration::from_secs(60 * 5);
+/// How many discovered urls can be queued for the [`MeshActor`]
+const MESH_DISCOVERY_QUEUE: usize = 64;
+/// How many derp servers discovered through the mesh we mesh with at most
+const MAX_DISCOVERED_MESH_PEERS: usize = 256;
+
 /// Spawns, connects, and manages special [`crate::derp::http::Client`].
 ///
 /// These clients handled incoming network update notifications from remote
 /// [`super::Server`]s. These servers are used as [`crate::derp::PacketForwarder`]s for
 /// peers to which we are not directly connected.
 /// A [`crate::derp::MeshKey`] is used to ensure the remote server belongs to the same mesh network.
+///
+/// The derp servers of the [`MeshAddrs`] are only the seeds of the mesh: every mesh client
+/// announces the url of our derp server (if we know it) to its remote derp server, which
+/// passes it on to the rest of the mesh. In return we learn about the other members of the
+/// mesh, and mesh with them too. Discovered derp servers that stay unreachable for too long
+/// are dropped from the mesh again.
+///
+/// Different urls can lead to the same derp server, so once connected every mesh client
+/// checks the key of its remote derp server with the [`MeshActor`], and stops if another
+/// mesh client already meshes with that derp server.
 #[derive(Debug)]
 pub(crate) struct MeshClients {
-    tasks: JoinSet<()>,
-    mesh_key: MeshKey,
-    server_key: SecretKey,
     mesh_addrs: MeshAddrs,
-    packet_fwd: PacketForwarderHandler<Client>,
-    cancel: CancellationToken,
+    /// Channel on which to communicate to the [`MeshActor`]
+    actor_channel: mpsc::Sender<MeshMessage>,
+    actor_task: JoinHandle<()>,
 }
 
 impl MeshClients {
+    /// Creates the mesh clients and spawns the [`MeshActor`] that manages them.
+    ///
+    /// `own_url` is the url the other members of the mesh reach our derp server on,
+    /// `announced` the urls announced by the mesh clients connected to our derp server.
     pub(crate) fn new(
         mesh_key: MeshKey,
         server_key: SecretKey,
         mesh_addrs: MeshAddrs,
         packet_fwd: PacketForwarderHandler<Client>,
+        status: MeshStatus,
+        own_url: Option<Url>,
+        announced: watch::Receiver<BTreeSet<Url>>,
     ) -> Self {
-        Self {
-            tasks: JoinSet::new(),
-            cancel: CancellationToken::new(),
+        let (actor_channel, receiver) = mpsc::channel(8);
+        let (discovered_s, discovered_r) = mpsc::channel(MESH_DISCOVERY_QUEUE);
+        let (server_keys_s, server_keys_r) = mpsc::channel(8);
+        let actor = MeshActor {
             mesh_key,
             server_key,
-            mesh_addrs,
             packet_fwd,
+            status,
+            own_url,
+            seeds: Vec::new(),
+            peers: HashMap::new(),
+            servers: HashMap::new(),
+            duplicates: HashMap::new(),
+            announced: Some(announced),
+            discovered_s,
+            discovered_r,
+            server_keys_s,
+            server_keys_r,
+            receiver,
+        };
+        let actor_task = tokio::spawn(actor.run().instrument(info_span!("mesh-clients")));
+        Self {
+            mesh_addrs,
+            actor_channel,
+            actor_task,
         }
     }
 
-    pub(crate) async fn mesh(&mut self) -> anyhow::Result<Vec<tokio::sync::oneshot::Receiver<()>>> {
-        let addrs = match &self.mesh_addrs {
+    /// Connects to every derp server of the [`MeshAddrs`].
+    ///
+    /// Mesh clients that are already running are kept, mesh clients for derp servers that
+    /// are no longer part of the [`MeshAddrs`] are stopped, unless they were discovered
+    /// through the mesh. Returns a receiver for every newly started mesh client, which
+    /// resolves once it meshed with its derp server.
+    pub(crate) async fn mesh(&mut self) -> anyhow::Result<Vec<oneshot::Receiver<()>>> {
+        let seeds = match &self.mesh_addrs {
             MeshAddrs::Addrs(urls) => urls.to_owned(),
             MeshAddrs::DerpMap(derp_map) => {
                 let mut urls = Vec::new();
@@ -59,38 +112,560 @@ impl MeshClients {
                 urls
             }
         };
-        let mut meshed_once_recvs = Vec::new();
-        for addr in addrs {
-            let client = ClientBuilder::new()
-                .mesh_key(Some(self.mesh_key))
-                .server_url(addr)
-                .build(self.server_key.clone())
-                .expect("will only fail if no `server_url` is present");
-
-            let packet_forwarder_handler = self.packet_fwd.clone();
-            let (sender, recv) = tokio::sync::oneshot::channel();
-            self.tasks.spawn(
-                async move {
-                    if let Err(e) = client
-                        .run_mesh_client(packet_forwarder_handler, Some(sender))
-                        .await
-                    {
-                        tracing::warn!("{e:?}");
+
+        let (s, r) = oneshot::channel();
+        self.actor_channel
+            .send(MeshMessage::SetSeeds { seeds, s })
+            .await
+            .map_err(|_| anyhow::anyhow!("mesh actor gone"))?;
+        let meshed_once_recvs = r.await?;
+        Ok(meshed_once_recvs)
+    }
+
+    /// Replaces the [`MeshAddrs`] and updates the mesh clients accordingly, see
+    /// [`MeshClients::mesh`].
+    pub(crate) async fn update(
+        &mut self,
+        mesh_addrs: MeshAddrs,
+    ) -> anyhow::Result<Vec<oneshot::Receiver<()>>> {
+        self.mesh_addrs = mesh_addrs;
+        self.mesh().await
+    }
+
+    pub(crate) async fn shutdown(self) {
+        if self
+            .actor_channel
+            .send(MeshMessage::Shutdown)
+            .await
+            .is_err()
+        {
+            tracing::warn!("mesh actor gone, unable to shutdown the mesh clients gracefully");
+        }
+        if let Err(err) = self.actor_task.await {
+            tracing::warn!("error waiting for the mesh actor to close: {err:?}");
+        }
+    }
+}
+
+#[derive(Debug)]
+enum MeshMessage {
+    /// Mesh with the given seeds, replying with the receivers of the new mesh clients
+    SetSeeds {
+        seeds: Vec<Url>,
+        s: oneshot::Sender<Vec<oneshot::Receiver<()>>>,
+    },
+    /// Stop all mesh clients
+    Shutdown,
+}
+
+/// Sent by a mesh client once it knows the key of its remote derp server.
+///
+/// The [`MeshActor`] replies whether the mesh client may mesh with the derp server.
+#[derive(Debug)]
+pub(crate) struct ServerKey {
+    url: Url,
+    key: PublicKey,
+    s: oneshot::Sender<bool>,
+}
+
+/// Runs the mesh clients, both for the seeds and for the discovered derp servers.
+#[derive(Debug)]
+struct MeshActor {
+    mesh_key: MeshKey,
+    server_key: SecretKey,
+    packet_fwd: PacketForwarderHandler<Client>,
+    status: MeshStatus,
+    /// The url the other members of the mesh reach our derp server on
+    own_url: Option<Url>,
+    /// The derp servers we mesh with regardless of their health
+    seeds: Vec<Url>,
+    /// The running mesh clients, by the url of the derp server they connect to
+    peers: HashMap<Url, MeshPeer>,
+    /// The url of the mesh client meshing with the derp server of each key
+    servers: HashMap<PublicKey, Url>,
+    /// Discovered urls that lead to a derp server we already mesh with, with its key
+    duplicates: HashMap<Url, PublicKey>,
+    /// The urls announced to our derp server, `None` once our derp server is gone
+    announced: Option<watch::Receiver<BTreeSet<Url>>>,
+    /// Handed to the mesh clients, to pass on the urls they learn about
+    discovered_s: mpsc::Sender<Url>,
+    discovered_r: mpsc::Receiver<Url>,
+    /// Handed to the mesh clients, to check the key of their remote derp server
+    server_keys_s: mpsc::Sender<ServerKey>,
+    server_keys_r: mpsc::Receiver<ServerKey>,
+    receiver: mpsc::Receiver<MeshMessage>,
+}
+
+#[derive(Debug)]
+struct MeshPeer {
+    client: Client,
+    task: JoinHandle<()>,
+    health: Arc<MeshPeerHealth>,
+    /// Whether we learned about the derp server from the mesh, rather than from the seeds
+    discovered: bool,
+}
+
+impl MeshPeer {
+    /// Stops the mesh client and closes its connection.
+    async fn stop(self) {
+        self.task.abort();
+        // wait for the task to be dropped, which removes its packet forwarders
+        let _ = self.task.await;
+        self.client.close().await;
+        self.health.forget();
+    }
+}
+
+impl MeshActor {
+    async fn run(mut self) {
+        let mut health_check = tokio::time::interval(MESH_HEALTH_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(MeshMessage::SetSeeds { seeds, s }) => {
+                        let recvs = self.set_seeds(seeds).await;
+                        s.send(recvs).ok();
+                    }
+                    Some(MeshMessage::Shutdown) | None => {
+                        self.shutdown().await;
+                        return;
+                    }
+                },
+                res = announced_changed(&mut self.announced) => {
+                    match res {
+                        Ok(()) => {
+                            let urls = self.announced.as_ref().expect("checked").borrow().clone();
+                            for url in urls {
+                                self.discover(url);
+                            }
+                        }
+                        Err(_) => {
+                            tracing::debug!("derp server gone, no longer watching announced urls");
+                            self.announced = None;
+                        }
                     }
                 }
-                .instrument(info_span!("mesh-client")),
-            );
+                Some(url) = self.discovered_r.recv() => {
+                    self.discover(url);
+                }
+                Some(ServerKey { url, key, s }) = self.server_keys_r.recv() => {
+                    let allowed = self.check_server_key(url, key).await;
+                    s.send(allowed).ok();
+                }
+                _ = health_check.tick() => {
+                    self.expire_unreachable().await;
+                }
+            }
+        }
+    }
+
+    /// Meshes with the `seeds`, and stops meshing with the previous seeds that are not part
+    /// of them. Returns a receiver for every newly started mesh client.
+    async fn set_seeds(&mut self, seeds: Vec<Url>) -> Vec<oneshot::Receiver<()>> {
+        let removed: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(url, peer)| !peer.discovered && !seeds.contains(url))
+            .map(|(url, _)| url.clone())
+            .collect();
+        for url in removed {
+            tracing::info!("stop meshing with {url}");
+            self.stop(&url).await;
+        }
+
+        let mut meshed_once_recvs = Vec::new();
+        for url in seeds.iter() {
+            if Some(url) == self.own_url.as_ref() {
+                continue;
+            }
+            if let Some(peer) = self.peers.get_mut(url) {
+                peer.discovered = false;
+                continue;
+            }
+            let (sender, recv) = oneshot::channel();
+            self.start(url.clone(), false, Some(sender));
             meshed_once_recvs.push(recv);
         }
-        Ok(meshed_once_recvs)
+        self.seeds = seeds;
+        self.update_status();
+        meshed_once_recvs
+    }
+
+    /// Meshes with the derp server at `url`, if we don't already.
+    ///
+    /// Meshes with at most [`MAX_DISCOVERED_MESH_PEERS`] discovered derp servers.
+    fn discover(&mut self, url: Url) {
+        if Some(&url) == self.own_url.as_ref()
+            || self.peers.contains_key(&url)
+            || self.duplicates.contains_key(&url)
+        {
+            return;
+        }
+        let discovered = self.peers.values().filter(|peer| peer.discovered).count();
+        if discovered + self.duplicates.len() >= MAX_DISCOVERED_MESH_PEERS {
+            tracing::debug!("too many discovered mesh peers, ignoring {url}");
+            return;
+        }
+        tracing::info!("discovered mesh peer {url}");
+        inc!(Metrics, mesh_peers_discovered);
+        self.start(url, true, None);
+        self.update_status();
+    }
+
+    fn start(&mut self, url: Url, discovered: bool, meshed_once: Option<oneshot::Sender<()>>) {
+        let client = ClientBuilder::new()
+            .mesh_key(Some(self.mesh_key))
+            .server_url(url.clone())
+            .build(self.server_key.clone())
+            .expect("will only fail if no `server_url` is present");
+        let health = Arc::new(MeshPeerHealth::new(&url));
+        let membership = MeshMembership {
+            own_url: self.own_url.clone(),
+            discovered: Some(self.discovered_s.clone()),
+            health: Some(Arc::clone(&health)),
+            server_keys: Some((url.clone(), self.server_keys_s.clone())),
+        };
+
+        let packet_forwarder_handler = self.packet_fwd.clone();
+        let mesh_client = client.clone();
+        let task = tokio::spawn(
+            async move {
+                if let Err(e) = mesh_client
+                    .run_mesh_member(packet_forwarder_handler, meshed_once, membership)
+                    .await
+                {
+                    tracing::warn!("{e:?}");
+                }
+            }
+            .instrument(info_span!("mesh-client", %url)),
+        );
+        self.peers.insert(
+            url,
+            MeshPeer {
+                client,
+                task,
+                health,
+                discovered,
+            },
+        );
+    }
+
+    /// Decides whether the mesh client for `url` may mesh with the derp server of `key`.
+    ///
+    /// Only one mesh client meshes with every derp server, as the packet forwarders of the
+    /// derp server's clients are removed when the mesh client that added them stops. Seeds
+    /// take over from discovered mesh clients, otherwise the first mesh client wins and the
+    /// other one is stopped.
+    async fn check_server_key(&mut self, url: Url, key: PublicKey) -> bool {
+        let Some(discovered) = self.peers.get(&url).map(|peer| peer.discovered) else {
+            // already stopped
+            return false;
+        };
+        // the derp server at `url` might have changed its key
+        self.servers.retain(|_, other| *other != url);
+        let winner = match self.servers.get(&key) {
+            Some(other) if self.peers.contains_key(other) => {
+                let other_discovered = self.peers[other].discovered;
+                if other_discovered && !discovered {
+                    let other = other.clone();
+                    tracing::info!(
+                        "{url} leads to the same derp server as {other}, stop meshing with {other}"
+                    );
+                    self.stop(&other).await;
+                    self.duplicates.insert(other, key.clone());
+                    self.update_status();
+                    url.clone()
+                } else {
+                    other.clone()
+                }
+            }
+            _ => url.clone(),
+        };
+        if winner != url {
+            tracing::info!(
+                "{url} leads to the same derp server as {winner}, stop meshing with {url}"
+            );
+            if let Some(peer) = self.peers.remove(&url) {
+                // the mesh client stops by itself, without adding any packet forwarders
+                peer.health.forget();
+                if peer.discovered {
+                    self.duplicates.insert(url, key);
+                }
+            }
+            self.update_status();
+            return false;
+        }
+        self.servers.insert(key, url);
+        true
+    }
+
+    /// Stops the mesh client for `url`, if any.
+    ///
+    /// The discovered urls that lead to the same derp server may be meshed with again.
+    async fn stop(&mut self, url: &Url) {
+        if let Some(peer) = self.peers.remove(url) {
+            peer.stop().await;
+        }
+        let keys: Vec<_> = self
+            .servers
+            .iter()
+            .filter(|(_, other)| *other == url)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.servers.remove(&key);
+            self.duplicates.retain(|_, other| *other != key);
+        }
+    }
+
+    /// Stops meshing with the discovered derp servers that have been unreachable for longer
+    /// than [`MESH_PEER_EXPIRY`]. Seeds are never expired.
+    async fn expire_unreachable(&mut self) {
+        let expired: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.discovered
+                    && peer
+                        .health
+                        .unreachable_for()
+                        .map_or(false, |unreachable| unreachable > MESH_PEER_EXPIRY)
+            })
+            .map(|(url, _)| url.clone())
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        for url in expired {
+            tracing::info!("mesh peer {url} unreachable for too long, stop meshing with it");
+            inc!(Metrics, mesh_peers_expired);
+            self.stop(&url).await;
+        }
+        self.update_status();
+    }
+
+    /// Publishes the current mesh clients to the [`MeshStatus`], seeds first.
+    fn update_status(&self) {
+        let mut seeds = Vec::new();
+        let mut discovered = Vec::new();
+        for url in self.seeds.iter() {
+            if let Some(peer) = self.peers.get(url) {
+                seeds.push(MeshStatusEntry::new(url, peer));
+            }
+        }
+        for (url, peer) in self.peers.iter().filter(|(_, peer)| peer.discovered) {
+            discovered.push(MeshStatusEntry::new(url, peer));
+        }
+        discovered.sort_by(|a, b| a.url.cmp(&b.url));
+        seeds.extend(discovered);
+        *self.status.clients.lock().unwrap() = seeds;
+    }
+
+    async fn shutdown(&mut self) {
+        self.status.clients.lock().unwrap().clear();
+        for (_, peer) in self.peers.drain() {
+            peer.stop().await;
+        }
+        self.servers.clear();
+        self.duplicates.clear();
     }
+}
 
-    pub(crate) async fn shutdown(mut self) {
-        self.cancel.cancel();
-        self.tasks.shutdown().await
+/// Waits for the urls announced to our derp server to change, forever once it is gone.
+async fn announced_changed(
+    announced: &mut Option<watch::Receiver<BTreeSet<Url>>>,
+) -> Result<(), watch::error::RecvError> {
+    match announced {
+        Some(announced) => announced.changed().await,
+        None => std::future::pending().await,
     }
 }
 
+/// How a mesh client takes part in the discovery of the mesh, see
+/// [`Client::run_mesh_member`].
+#[derive(Debug, Default)]
+pub(crate) struct MeshMembership {
+    /// The url to announce to the remote derp server
+    pub(crate) own_url: Option<Url>,
+    /// Where to pass on the urls of the members of the mesh that the remote derp server
+    /// tells us about
+    pub(crate) discovered: Option<mpsc::Sender<Url>>,
+    /// Records the health of the connection to the remote derp server
+    pub(crate) health: Option<Arc<MeshPeerHealth>>,
+    /// Where to check the key of the remote derp server, with the url we connect to it on
+    pub(crate) server_keys: Option<(Url, mpsc::Sender<ServerKey>)>,
+}
+
+impl MeshMembership {
+    /// Whether we may mesh with the remote derp server of `key`, see [`ServerKey`].
+    pub(crate) async fn check_server_key(&self, key: PublicKey) -> bool {
+        let Some((url, server_keys)) = &self.server_keys else {
+            return true;
+        };
+        let (s, r) = oneshot::channel();
+        let msg = ServerKey {
+            url: url.clone(),
+            key,
+            s,
+        };
+        if server_keys.send(msg).await.is_err() {
+            // the mesh is shutting down
+            return false;
+        }
+        r.await.unwrap_or(false)
+    }
+
+    pub(crate) fn connected(&self) {
+        if let Some(health) = &self.health {
+            health.connected();
+        }
+    }
+
+    pub(crate) fn connect_failed(&self) {
+        if let Some(health) = &self.health {
+            health.connect_failed();
+        }
+    }
+
+    pub(crate) fn disconnected(&self) {
+        if let Some(health) = &self.health {
+            health.disconnected();
+        }
+    }
+}
+
+/// The health of the connection of a mesh client to its derp server.
+///
+/// Reported in the `mesh_peers` metric, labeled with the url of the derp server.
+#[derive(Debug)]
+pub(crate) struct MeshPeerHealth {
+    label: String,
+    state: Mutex<HealthState>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct HealthState {
+    /// Whether the mesh client is watching the derp server
+    connected: bool,
+    /// Failed connection attempts since the mesh client was last connected
+    failures: u32,
+    /// Since when the derp server is unreachable, `None` when connected or not tried yet
+    unreachable_since: Option<Instant>,
+}
+
+impl MeshPeerHealth {
+    fn new(url: &Url) -> Self {
+        let label = url.to_string();
+        Metrics::with_metric(|m| m.mesh_peers.set(&label, 0));
+        Self {
+            label,
+            state: Default::default(),
+        }
+    }
+
+    fn connected(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = true;
+        state.failures = 0;
+        state.unreachable_since = None;
+        Metrics::with_metric(|m| m.mesh_peers.set(&self.label, 1));
+    }
+
+    fn connect_failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = false;
+        state.failures = state.failures.saturating_add(1);
+        state.unreachable_since.get_or_insert_with(Instant::now);
+        Metrics::with_metric(|m| m.mesh_peers.set(&self.label, 0));
+        inc!(Metrics, mesh_connect_failures);
+    }
+
+    fn disconnected(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = false;
+        state.unreachable_since.get_or_insert_with(Instant::now);
+        Metrics::with_metric(|m| m.mesh_peers.set(&self.label, 0));
+    }
+
+    /// How long the derp server has been unreachable, if it is.
+    fn unreachable_for(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state.unreachable_since.map(|since| since.elapsed())
+    }
+
+    /// Stops reporting the derp server in the metrics.
+    fn forget(&self) {
+        Metrics::with_metric(|m| m.mesh_peers.remove(&self.label));
+    }
+
+    fn state(&self) -> HealthState {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+/// A mesh client, as published in the [`MeshStatus`].
+#[derive(Debug, Clone)]
+struct MeshStatusEntry {
+    url: Url,
+    discovered: bool,
+    health: Arc<MeshPeerHealth>,
+}
+
+impl MeshStatusEntry {
+    fn new(url: &Url, peer: &MeshPeer) -> Self {
+        Self {
+            url: url.clone(),
+            discovered: peer.discovered,
+            health: Arc::clone(&peer.health),
+        }
+    }
+}
+
+/// The state of the connections to the other derp servers in the mesh.
+///
+/// Created by [`super::Server::mesh_status`]. Keeps track of the current mesh clients,
+/// also after [`super::Server::re_mesh`] and as derp servers are discovered.
+///
+/// Can be cheaply cloned.
+#[derive(Debug, Clone, Default)]
+pub struct MeshStatus {
+    clients: Arc<Mutex<Vec<MeshStatusEntry>>>,
+}
+
+impl MeshStatus {
+    /// Returns the state of the connection to every derp server we mesh with.
+    ///
+    /// The seeds come first, followed by the derp servers discovered through the mesh.
+    pub async fn peers(&self) -> Vec<MeshPeerStatus> {
+        let clients = self.clients.lock().unwrap().clone();
+        clients
+            .into_iter()
+            .map(|entry| {
+                let state = entry.health.state();
+                MeshPeerStatus {
+                    url: entry.url,
+                    connected: state.connected,
+                    discovered: entry.discovered,
+                    failures: state.failures,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The state of the connection to another derp server in the mesh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeshPeerStatus {
+    /// The url of the derp server
+    pub url: Url,
+    /// Whether our mesh client is currently connected to the derp server
+    pub connected: bool,
+    /// Whether we learned about the derp server from the mesh, rather than from our seeds
+    pub discovered: bool,
+    /// Failed connection attempts since our mesh client was last connected
+    pub failures: u32,
+}
+
 /// The different ways to express the mesh network you want to join.
 #[derive(Debug, Clone)]
 pub enum MeshAddrs {
@@ -231,4 +806,235 @@ mod tests {
         derp_server_b.shutdown().await;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_re_mesh() -> Result<()> {
+        let mesh_key: MeshKey = [1; 32];
+        let spawn = || {
+            ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+                .secret_key(Some(SecretKey::generate()))
+                .mesh_key(Some(mesh_key))
+                .spawn()
+        };
+        let a_key = SecretKey::generate();
+        let mut derp_server_a = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(a_key.clone()))
+            .mesh_key(Some(mesh_key))
+            .spawn()
+            .await?;
+        let derp_server_b = spawn().await?;
+        let derp_server_c = spawn().await?;
+        let url = |server: &crate::derp::http::Server| -> Url {
+            format!("http://{}/derp", server.addr()).parse().unwrap()
+        };
+        let (b_url, c_url) = (url(&derp_server_b), url(&derp_server_c));
+        let mesh_peers = |server: &crate::derp::http::Server| {
+            let status = server.mesh_status();
+            async move {
+                status
+                    .peers()
+                    .await
+                    .into_iter()
+                    .map(|peer| peer.url)
+                    .collect::<Vec<_>>()
+            }
+        };
+        let is_client_of = |server: &crate::derp::http::Server| {
+            let admin = server.admin_handler().unwrap();
+            let key = a_key.public_key();
+            async move {
+                let clients = admin.clients().await?;
+                anyhow::Ok(clients.iter().any(|client| client.key == key))
+            }
+        };
+
+        let meshed = derp_server_a
+            .re_mesh(MeshAddrs::Addrs(vec![b_url.clone()]))
+            .await?;
+        assert_eq!(meshed.len(), 1);
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            futures::future::try_join_all(meshed),
+        )
+        .await??;
+
+        // only the new derp server is connected to
+        let meshed = derp_server_a
+            .re_mesh(MeshAddrs::Addrs(vec![b_url.clone(), c_url.clone()]))
+            .await?;
+        assert_eq!(meshed.len(), 1);
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            futures::future::try_join_all(meshed),
+        )
+        .await??;
+        assert_eq!(
+            mesh_peers(&derp_server_a).await,
+            vec![b_url.clone(), c_url.clone()]
+        );
+        assert!(is_client_of(&derp_server_b).await?);
+        assert!(is_client_of(&derp_server_c).await?);
+
+        // the connection to the removed derp server is closed
+        let meshed = derp_server_a
+            .re_mesh(MeshAddrs::Addrs(vec![c_url.clone()]))
+            .await?;
+        assert!(meshed.is_empty());
+        assert_eq!(mesh_peers(&derp_server_a).await, vec![c_url]);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while is_client_of(&derp_server_b).await? {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        assert!(is_client_of(&derp_server_c).await?);
+
+        derp_server_a.shutdown().await;
+        derp_server_b.shutdown().await;
+        derp_server_c.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mesh_same_server() -> Result<()> {
+        let mesh_key: MeshKey = [1; 32];
+        let spawn = || {
+            ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+                .secret_key(Some(SecretKey::generate()))
+                .mesh_key(Some(mesh_key))
+                .spawn()
+        };
+        let mut derp_server_a = spawn().await?;
+        let derp_server_b = spawn().await?;
+        let a_url: Url = format!("http://{}/derp", derp_server_a.addr()).parse()?;
+        // two urls that lead to the same derp server
+        let b_url: Url = format!("http://{}/derp", derp_server_b.addr()).parse()?;
+        let b_url_dup: Url = format!("http://{}/derp?dup", derp_server_b.addr()).parse()?;
+
+        let meshed = derp_server_a
+            .re_mesh(MeshAddrs::Addrs(vec![b_url.clone(), b_url_dup.clone()]))
+            .await?;
+        assert_eq!(meshed.len(), 2);
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            futures::future::try_join_all(meshed),
+        )
+        .await??;
+
+        // only one of the mesh clients keeps meshing with the derp server
+        let status = derp_server_a.mesh_status();
+        let kept = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let peers = status.peers().await;
+                if peers.len() == 1 {
+                    return peers[0].url.clone();
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // re-meshing with the kept url only doesn't stop the packet forwarders
+        let meshed = derp_server_a.re_mesh(MeshAddrs::Addrs(vec![kept])).await?;
+        assert!(meshed.is_empty());
+
+        let alice_key = SecretKey::generate();
+        let alice = ClientBuilder::new()
+            .server_url(b_url)
+            .build(alice_key.clone())?;
+        let _ = alice.connect().await?;
+        let bob_key = SecretKey::generate();
+        let bob = ClientBuilder::new().server_url(a_url).build(bob_key)?;
+        let _ = bob.connect().await?;
+
+        // the packet forwarder for alice is added once derp server a learns about her, and
+        // the kept mesh client might have to reconnect after the other one replaced its
+        // connection, so keep sending until she receives the packet
+        let msg = "howdy, alice!";
+        let received = tokio::spawn(async move {
+            loop {
+                let (recv, _) = alice.recv_detail().await?;
+                if let ReceivedMessage::ReceivedPacket { data, .. } = recv {
+                    assert_eq!(msg, data);
+                    return anyhow::Ok(());
+                }
+            }
+        });
+        tokio::time::timeout(std::time::Duration::from_secs(15), async {
+            while !received.is_finished() {
+                bob.send(alice_key.public_key(), msg.into()).await?;
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            received.await?
+        })
+        .await??;
+
+        derp_server_a.shutdown().await;
+        derp_server_b.shutdown().await;
+        Ok(())
+    }
+
+    async fn pick_port() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_mesh_discovery() -> Result<()> {
+        let mesh_key: MeshKey = [1; 32];
+        let spawn = |port: u16, seeds: Vec<Url>| async move {
+            let addr: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
+            let mesh_url: Url = format!("http://{addr}/derp").parse().unwrap();
+            let server = ServerBuilder::new(addr)
+                .secret_key(Some(SecretKey::generate()))
+                .mesh_key(Some(mesh_key))
+                .mesh_derpers(Some(MeshAddrs::Addrs(seeds)))
+                .mesh_url(Some(mesh_url.clone()))
+                .spawn()
+                .await?;
+            anyhow::Ok((server, mesh_url))
+        };
+
+        // b and c only know about a, a doesn't know about anyone
+        let (derp_server_a, a_url) = spawn(pick_port().await, vec![]).await?;
+        let (derp_server_b, b_url) = spawn(pick_port().await, vec![a_url.clone()]).await?;
+        let (derp_server_c, c_url) = spawn(pick_port().await, vec![a_url.clone()]).await?;
+
+        // everyone ends up connected to everyone else
+        let expected = [
+            (&derp_server_a, vec![(&b_url, true), (&c_url, true)]),
+            (&derp_server_b, vec![(&a_url, false), (&c_url, true)]),
+            (&derp_server_c, vec![(&a_url, false), (&b_url, true)]),
+        ];
+        for (server, expected) in expected {
+            let status = server.mesh_status();
+            let mut expected: Vec<_> = expected
+                .into_iter()
+                .map(|(url, discovered)| MeshPeerStatus {
+                    url: url.clone(),
+                    connected: true,
+                    discovered,
+                    failures: 0,
+                })
+                .collect();
+            expected.sort_by(|a, b| a.url.cmp(&b.url));
+            let peers = || async {
+                let mut peers = status.peers().await;
+                peers.sort_by(|a, b| a.url.cmp(&b.url));
+                peers
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(10), async {
+                while peers().await != expected {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            })
+            .await?;
+        }
+
+        derp_server_a.shutdown().await;
+        derp_server_b.shutdown().await;
+        derp_server_c.shutdown().await;
+        Ok(())
+    }
 }
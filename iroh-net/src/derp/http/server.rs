@@ -22,19 +22,22 @@ use tokio::{
     task::JoinHandle,
 };
 use tokio_rustls_acme::AcmeAcceptor;
+use tokio_tungstenite::tungstenite::protocol::Role;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, info_span, warn, Instrument};
+use url::Url;
 
+use super::websocket::{self, WsStream, WEBSOCKET_UPGRADE_PROTOCOL};
 use super::HTTP_UPGRADE_PROTOCOL;
 use crate::{
     derp::{
         http::client::Client as HttpClient,
-        http::mesh_clients::{MeshAddrs, MeshClients},
-        server::ClientConnHandler,
+        http::mesh_clients::{MeshAddrs, MeshClients, MeshStatus},
         server::MaybeTlsStream,
+        server::{AdminHandler, ClientConnHandler},
         types::MeshKey,
         types::PacketForwarder,
-        MaybeTlsStreamServer,
+        ClientVerifier, MaybeTlsStreamServer,
     },
     key::node::SecretKey,
 };
@@ -52,15 +55,25 @@ fn downcast_upgrade(upgraded: Upgraded) -> Result<(MaybeTlsStream, Bytes)> {
 }
 
 /// The server HTTP handler to do HTTP upgrades
+///
+/// With `websocket` the DERP frames are sent as messages over a WebSocket connection,
+/// otherwise they are sent directly over the upgraded connection.
 async fn derp_connection_handler<P>(
     conn_handler: &ClientConnHandler<P>,
     upgraded: Upgraded,
+    websocket: bool,
 ) -> Result<()>
 where
     P: PacketForwarder,
 {
     debug!("derp_connection upgraded");
     let (io, read_buf) = downcast_upgrade(upgraded)?;
+    if websocket {
+        let io = WsStream::new(io, read_buf, Role::Server).await;
+        return conn_handler
+            .accept(MaybeTlsStream::WebSocket(Box::new(io)))
+            .await;
+    }
     ensure!(
         read_buf.is_empty(),
         "can not deal with buffered data yet: {:?}",
@@ -79,6 +92,8 @@ pub struct Server {
     http_server_task: JoinHandle<()>,
     cancel_server_loop: CancellationToken,
     mesh_clients: Option<MeshClients>,
+    mesh_status: MeshStatus,
+    mesh_url: Option<Url>,
 }
 
 impl Server {
@@ -103,12 +118,41 @@ impl Server {
         self.addr
     }
 
+    /// Get an [`AdminHandler`] to inspect and disconnect the clients of the derp server.
+    ///
+    /// Returns `None` if this server does not run a derp server.
+    pub fn admin_handler(&self) -> Option<AdminHandler<HttpClient>> {
+        self.server.as_ref().map(|server| server.admin_handler())
+    }
+
+    /// Get the [`MeshStatus`] of the connections to the other derp servers in the mesh.
+    pub fn mesh_status(&self) -> MeshStatus {
+        self.mesh_status.clone()
+    }
+
+    /// Changes the rate limit of the clients of the derp server, see
+    /// [`crate::derp::Server::set_client_rate_limit`].
+    ///
+    /// Returns an error if this server does not run a derp server.
+    pub fn set_client_rate_limit(&self, bytes_per_second: usize, bytes_burst: usize) -> Result<()> {
+        match &self.server {
+            Some(server) => server.set_client_rate_limit(bytes_per_second, bytes_burst),
+            None => bail!("no derp server, unable to set the client rate limit"),
+        }
+    }
+
     /// Mesh this server to a new list of derp servers.
+    ///
+    /// Connections to derp servers that are part of the current and the new list are
+    /// kept, connections to derp servers that are no longer part of the list are closed,
+    /// unless they were discovered through the mesh, see [`ServerBuilder::mesh_url`].
+    /// Returns a receiver for every derp server we newly mesh with, which resolves once
+    /// we are meshed with it.
     pub async fn re_mesh(
         &mut self,
         mesh_addrs: MeshAddrs,
     ) -> Result<Vec<tokio::sync::oneshot::Receiver<()>>> {
-        let (mesh_key, server_key, packet_fwd) = if let Some(server) = &self.server {
+        let (mesh_key, server_key, packet_fwd, announced) = if let Some(server) = &self.server {
             let mesh_key = if let Some(key) = server.mesh_key() {
                 key
             } else {
@@ -116,15 +160,23 @@ impl Server {
             };
             let server_key = server.private_key();
             let packet_fwd = server.packet_forwarder_handler();
-            (mesh_key, server_key, packet_fwd)
+            (mesh_key, server_key, packet_fwd, server.mesh_peers())
         } else {
             bail!("no derp server, unable to mesh with other derp servers");
         };
-        if let Some(mesh_clients) = self.mesh_clients.take() {
-            mesh_clients.shutdown().await;
+        if let Some(mesh_clients) = &mut self.mesh_clients {
+            return mesh_clients.update(mesh_addrs).await;
         }
 
-        let mut mesh_clients = MeshClients::new(mesh_key, server_key, mesh_addrs, packet_fwd);
+        let mut mesh_clients = MeshClients::new(
+            mesh_key,
+            server_key,
+            mesh_addrs,
+            packet_fwd,
+            self.mesh_status.clone(),
+            self.mesh_url.clone(),
+            announced,
+        );
 
         let recvs = mesh_clients.mesh().await?;
         self.mesh_clients = Some(mesh_clients);
@@ -165,6 +217,19 @@ pub struct ServerBuilder {
     /// Having a `mesh_depers` but no `mesh_key` when attempting to `spawn` a
     /// [`Server`] results in an error.
     mesh_derpers: Option<MeshAddrs>,
+    /// Optional url on which the other derp servers of the mesh reach this server.
+    ///
+    /// When set, it is announced to the derp servers we mesh with, which pass it on to the
+    /// rest of the mesh.
+    mesh_url: Option<Url>,
+    /// Optional [`ClientVerifier`] that decides which clients may connect.
+    ///
+    /// When `None`, all clients are accepted.
+    client_verifier: Option<Arc<dyn ClientVerifier>>,
+    /// Bytes per second and burst in bytes that every client may send.
+    ///
+    /// `(0, 0)` means that clients are not limited.
+    client_rate_limit: (usize, usize),
     /// Optional tls configuration/TlsAcceptor combination.
     ///
     /// When `None`, the server will serve HTTP, otherwise it will serve HTTPS.
@@ -195,6 +260,9 @@ impl ServerBuilder {
             addr,
             mesh_key: None,
             mesh_derpers: None,
+            mesh_url: None,
+            client_verifier: None,
+            client_rate_limit: (0, 0),
             tls_config: None,
             handlers: Default::default(),
             derp_endpoint: "/derp",
@@ -225,6 +293,33 @@ impl ServerBuilder {
         self
     }
 
+    /// The url on which the other derp servers of the mesh reach this server.
+    ///
+    /// Our mesh clients announce it to the derp servers they connect to, which pass it on
+    /// to the rest of the mesh. In return we learn about, and mesh with, the derp servers
+    /// that announced themselves the same way. This way the [`MeshAddrs`] only need to
+    /// contain some seeds of the mesh, rather than every derp server.
+    ///
+    /// Must be in the same form as the urls of the [`MeshAddrs`], including the derp
+    /// endpoint, for example `https://derp.example.com/derp`.
+    pub fn mesh_url(mut self, mesh_url: Option<Url>) -> Self {
+        self.mesh_url = mesh_url;
+        self
+    }
+
+    /// Only accept clients that are admitted by the [`ClientVerifier`].
+    pub fn client_verifier(mut self, verifier: Option<Arc<dyn ClientVerifier>>) -> Self {
+        self.client_verifier = verifier;
+        self
+    }
+
+    /// Limit the rate at which every client may send packets, see
+    /// [`crate::derp::Server::set_client_rate_limit`].
+    pub fn client_rate_limit(mut self, bytes_per_second: usize, bytes_burst: usize) -> Self {
+        self.client_rate_limit = (bytes_per_second, bytes_burst);
+        self
+    }
+
     /// Serve derp content using TLS.
     pub fn tls_config(mut self, config: Option<TlsConfig>) -> Self {
         self.tls_config = config;
@@ -270,8 +365,12 @@ impl ServerBuilder {
     /// Build and spawn an HTTP(S) derp Server
     pub async fn spawn(self) -> Result<Server> {
         ensure!(self.secret_key.is_some() || self.derp_override.is_some(), "Must provide a `SecretKey` for the derp server OR pass in an override function for the 'derp' endpoint");
+        let mesh_status = MeshStatus::default();
         let (derp_handler, derp_server, mesh_clients) = if let Some(secret_key) = self.secret_key {
-            let server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+            let mut server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+            server.set_client_verifier(self.client_verifier);
+            let (bytes_per_second, bytes_burst) = self.client_rate_limit;
+            server.set_client_rate_limit(bytes_per_second, bytes_burst)?;
             let header_map: HeaderMap = HeaderMap::from_iter(
                 self.headers
                     .iter()
@@ -288,7 +387,13 @@ impl ServerBuilder {
 
                 let mesh_key = self.mesh_key.expect("checked");
                 Some(MeshClients::new(
-                    mesh_key, secret_key, mesh_addrs, packet_fwd,
+                    mesh_key,
+                    secret_key,
+                    mesh_addrs,
+                    packet_fwd,
+                    mesh_status.clone(),
+                    self.mesh_url.clone(),
+                    server.mesh_peers(),
                 ))
             } else {
                 None
@@ -335,6 +440,8 @@ impl ServerBuilder {
             server: derp_server,
             service,
             mesh_clients,
+            mesh_status,
+            mesh_url: self.mesh_url,
         };
 
         server_state.serve().await
@@ -348,6 +455,8 @@ struct ServerState {
     server: Option<crate::derp::server::Server<HttpClient>>,
     service: DerpService,
     mesh_clients: Option<MeshClients>,
+    mesh_status: MeshStatus,
+    mesh_url: Option<Url>,
 }
 
 impl ServerState {
@@ -415,6 +524,8 @@ impl ServerState {
             http_server_task: task,
             cancel_server_loop,
             mesh_clients,
+            mesh_status: self.mesh_status,
+            mesh_url: self.mesh_url,
         })
     }
 }
@@ -448,6 +559,12 @@ where
                     *res.status_mut() = StatusCode::BAD_REQUEST;
                     return Ok(res);
                 }
+                let websocket_key = websocket::websocket_key(req.headers()).cloned();
+                let protocol = if websocket_key.is_some() {
+                    WEBSOCKET_UPGRADE_PROTOCOL
+                } else {
+                    HTTP_UPGRADE_PROTOCOL
+                };
 
                 // Setup a future that will eventually receive the upgraded
                 // connection and talk a new protocol, and spawn the future
@@ -460,17 +577,17 @@ where
                     async move {
                         match hyper::upgrade::on(&mut req).await {
                             Ok(upgraded) => {
-                                if let Err(e) =
-                                    derp_connection_handler(&closure_conn_handler, upgraded).await
+                                let websocket = protocol == WEBSOCKET_UPGRADE_PROTOCOL;
+                                if let Err(e) = derp_connection_handler(
+                                    &closure_conn_handler,
+                                    upgraded,
+                                    websocket,
+                                )
+                                .await
                                 {
-                                    tracing::warn!(
-                                        "upgrade to \"{HTTP_UPGRADE_PROTOCOL}\": io error: {:?}",
-                                        e
-                                    );
+                                    tracing::warn!("upgrade to \"{protocol}\": io error: {:?}", e);
                                 } else {
-                                    tracing::info!(
-                                        "upgrade to \"{HTTP_UPGRADE_PROTOCOL}\" success"
-                                    );
+                                    tracing::info!("upgrade to \"{protocol}\" success");
                                 };
                             }
                             Err(e) => tracing::warn!("upgrade error: {:?}", e),
@@ -480,10 +597,15 @@ where
                 );
 
                 // Now return a 101 Response saying we agree to the upgrade to the
-                // HTTP_UPGRADE_PROTOCOL
+                // HTTP_UPGRADE_PROTOCOL, or to a WebSocket connection
                 *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
-                res.headers_mut()
-                    .insert(UPGRADE, HeaderValue::from_static(HTTP_UPGRADE_PROTOCOL));
+                match websocket_key {
+                    Some(key) => websocket::insert_response_headers(res.headers_mut(), &key),
+                    None => {
+                        res.headers_mut()
+                            .insert(UPGRADE, HeaderValue::from_static(HTTP_UPGRADE_PROTOCOL));
+                    }
+                }
                 Ok(res)
             }
         }
@@ -0,0 +1,223 @@
+//! DERP framing over WebSockets.
+//!
+//! Some proxies and load balancers strip the `Upgrade: iroh derp http` handshake used by
+//! default. As a fallback, the same DERP frames can be sent as binary messages over a
+//! WebSocket connection, which is upgraded at the same `/derp` endpoint.
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures::{Sink, Stream};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{self, protocol::Role, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// The value of the `Upgrade` header for WebSocket connections
+pub(crate) const WEBSOCKET_UPGRADE_PROTOCOL: &str = "websocket";
+/// The only WebSocket version defined by RFC 6455
+const WEBSOCKET_VERSION: &str = "13";
+
+/// An io stream that sends and receives bytes as binary WebSocket messages.
+///
+/// Every write is sent as one message, reads return the content of binary messages as
+/// a continuous stream of bytes. Other message types are ignored.
+#[derive(Debug)]
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Bytes,
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wraps an already upgraded connection, acting as the given `role`.
+    ///
+    /// `read_buf` contains data that was already read from the connection during the
+    /// upgrade.
+    pub(crate) async fn new(stream: S, read_buf: Bytes, role: Role) -> Self {
+        Self {
+            inner: WebSocketStream::from_partially_read(stream, read_buf.into(), role, None).await,
+            read_buf: Bytes::new(),
+        }
+    }
+}
+
+fn to_io_error(err: tungstenite::Error) -> std::io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            std::io::ErrorKind::BrokenPipe.into()
+        }
+        err => std::io::Error::new(std::io::ErrorKind::Other, err),
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let len = std::cmp::min(buf.remaining(), self.read_buf.len());
+                buf.put_slice(&self.read_buf[..len]);
+                self.read_buf.advance(len);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read_buf = data.into(),
+                // pings are answered by tungstenite, everything else is not part of DERP
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(err))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
+
+/// Returns the `Sec-WebSocket-Key` if the headers ask for a WebSocket upgrade.
+pub(crate) fn websocket_key(headers: &HeaderMap) -> Option<&HeaderValue> {
+    let is_websocket = headers
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case(WEBSOCKET_UPGRADE_PROTOCOL))
+        .unwrap_or_default();
+    if is_websocket {
+        headers.get(SEC_WEBSOCKET_KEY)
+    } else {
+        None
+    }
+}
+
+/// Adds the headers of a WebSocket upgrade request to `headers`.
+///
+/// Returns the value of the `Sec-WebSocket-Accept` header the server has to respond with.
+pub(crate) fn insert_request_headers(headers: &mut HeaderMap) -> HeaderValue {
+    let key = tungstenite::handshake::client::generate_key();
+    let accept = tungstenite::handshake::derive_accept_key(key.as_bytes());
+    headers.insert(
+        UPGRADE,
+        HeaderValue::from_static(WEBSOCKET_UPGRADE_PROTOCOL),
+    );
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(
+        SEC_WEBSOCKET_VERSION,
+        HeaderValue::from_static(WEBSOCKET_VERSION),
+    );
+    headers.insert(
+        SEC_WEBSOCKET_KEY,
+        HeaderValue::from_str(&key).expect("base64 is a valid header value"),
+    );
+    HeaderValue::from_str(&accept).expect("base64 is a valid header value")
+}
+
+/// Adds the headers of the response accepting the WebSocket upgrade for the `key` of the
+/// request to `headers`.
+pub(crate) fn insert_response_headers(headers: &mut HeaderMap, key: &HeaderValue) {
+    let accept = tungstenite::handshake::derive_accept_key(key.as_bytes());
+    headers.insert(
+        UPGRADE,
+        HeaderValue::from_static(WEBSOCKET_UPGRADE_PROTOCOL),
+    );
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(
+        SEC_WEBSOCKET_ACCEPT,
+        HeaderValue::from_str(&accept).expect("base64 is a valid header value"),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ws_stream() -> Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut client, mut server) = tokio::join!(
+            WsStream::new(client, Bytes::new(), Role::Client),
+            WsStream::new(server, Bytes::new(), Role::Server)
+        );
+
+        client.write_all(b"hello ").await?;
+        client.write_all(b"world").await?;
+        client.flush().await?;
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world");
+
+        // reads smaller than a message get the rest on the next read
+        server.write_all(b"derp").await?;
+        server.flush().await?;
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"der");
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"p");
+
+        // closing the connection is an EOF for the other side
+        client.shutdown().await?;
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await?;
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_headers() {
+        let mut req = HeaderMap::new();
+        let accept = insert_request_headers(&mut req);
+        let key = websocket_key(&req).expect("websocket request").clone();
+        let mut res = HeaderMap::new();
+        insert_response_headers(&mut res, &key);
+        assert_eq!(res.get(SEC_WEBSOCKET_ACCEPT), Some(&accept));
+
+        let mut req = HeaderMap::new();
+        req.insert(
+            UPGRADE,
+            HeaderValue::from_static(super::super::HTTP_UPGRADE_PROTOCOL),
+        );
+        assert!(websocket_key(&req).is_none());
+    }
+}
@@ -1,8 +1,48 @@
 use iroh_metrics::{
-    core::{Counter, Metric},
+    core::{Counter, LabeledCounter, LabeledGauge, Metric},
     struct_iterable::Iterable,
 };
 
+use crate::key::node::PublicKey;
+
+/// The label of a client in the per-client metrics
+pub(crate) fn client_label(key: &PublicKey) -> String {
+    hex::encode(key.as_bytes())
+}
+
+/// The per-client counters of a connection, looked up once instead of for every packet
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientCounters {
+    /// `client_bytes_recv` and `client_bytes_sent` of the client, if metrics are enabled
+    counters: Option<(Counter, Counter)>,
+}
+
+impl ClientCounters {
+    pub(crate) fn new(key: &PublicKey) -> Self {
+        let label = client_label(key);
+        Self {
+            counters: Metrics::try_get().map(|m| {
+                (
+                    m.client_bytes_recv.counter(&label),
+                    m.client_bytes_sent.counter(&label),
+                )
+            }),
+        }
+    }
+
+    pub(crate) fn bytes_recv(&self, n: u64) {
+        if let Some((recv, _)) = &self.counters {
+            recv.inc_by(n);
+        }
+    }
+
+    pub(crate) fn bytes_sent(&self, n: u64) {
+        if let Some((_, sent)) = &self.counters {
+            sent.inc_by(n);
+        }
+    }
+}
+
 /// Metrics tracked for the DERP server
 #[allow(missing_docs)]
 #[derive(Debug, Clone, Iterable)]
@@ -41,6 +81,14 @@ pub struct Metrics {
     /// Number of packets we have been asked to forward
     pub packets_forwarded_in: Counter,
 
+    /// `FrameType::SendPacket` dropped because the client exceeded its rate limit
+    pub rate_limited_packets: Counter,
+
+    /// Bytes received from a `FrameType::SendPacket`, per client
+    pub client_bytes_recv: LabeledCounter,
+    /// Bytes sent in a `FrameType::RecvPacket`, per client
+    pub client_bytes_sent: LabeledCounter,
+
     /// Number of `FrameType::Ping`s received
     pub got_ping: Counter,
     /// Number of `FrameType::Pong`s sent
@@ -58,8 +106,23 @@ pub struct Metrics {
 
     /// Number of connections we have accepted
     pub accepts: Counter,
+    /// Number of connections rejected by the client verifier
+    pub rejects: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
+
+    /*
+     * Metrics about the mesh
+     */
+    /// The derp servers of the mesh we connect to, by url: 1 when connected, 0 otherwise
+    pub mesh_peers: LabeledGauge,
+    /// Number of derp servers we learned about from other members of the mesh
+    pub mesh_peers_discovered: Counter,
+    /// Number of discovered derp servers we stopped meshing with, because they were
+    /// unreachable for too long
+    pub mesh_peers_expired: Counter,
+    /// Number of failed attempts to connect to a derp server of the mesh
+    pub mesh_connect_failures: Counter,
     // TODO: enable when we can have multiple connections for one peer id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -99,6 +162,19 @@ impl Default for Metrics {
                 "Number of times the server has received a forwarded packet.",
             ),
 
+            rate_limited_packets: Counter::new(
+                "Number of packets dropped because the client exceeded its rate limit.",
+            ),
+
+            client_bytes_recv: LabeledCounter::new(
+                "client",
+                "Number of bytes received from each connected client.",
+            ),
+            client_bytes_sent: LabeledCounter::new(
+                "client",
+                "Number of bytes sent to each connected client.",
+            ),
+
             got_ping: Counter::new("Number of times the server has received a Ping from a client."),
             sent_pong: Counter::new("Number of times the server has sent a Pong to a client."),
             unknown_frames: Counter::new("Number of unknown frames sent to this server."),
@@ -114,7 +190,25 @@ impl Default for Metrics {
             ),
 
             accepts: Counter::new("Number of times this server has accepted a connection."),
+            rejects: Counter::new("Number of clients that were rejected by the client verifier."),
             disconnects: Counter::new("Number of clients that have then disconnected."),
+
+            /*
+             * Metrics about the mesh
+             */
+            mesh_peers: LabeledGauge::new(
+                "peer",
+                "Derp servers of the mesh we connect to, 1 when connected, 0 otherwise.",
+            ),
+            mesh_peers_discovered: Counter::new(
+                "Number of derp servers learned about from other members of the mesh.",
+            ),
+            mesh_peers_expired: Counter::new(
+                "Number of discovered derp servers dropped from the mesh after being unreachable.",
+            ),
+            mesh_connect_failures: Counter::new(
+                "Number of failed attempts to connect to a derp server of the mesh.",
+            ),
             // TODO: enable when we can have multiple connections for one peer id
             // pub duplicate_client_keys: Counter::new("Number of dupliate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
@@ -1,5 +1,5 @@
 //! based on tailscale/derp/derp_server.go
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -9,21 +9,24 @@ use std::time::Duration;
 use anyhow::{Context as _, Result};
 use bytes::BytesMut;
 use hyper::HeaderMap;
-use iroh_metrics::inc;
+use iroh_metrics::{core::Metric, inc};
 use postcard::experimental::max_size::MaxSize;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::{info_span, instrument, trace, Instrument};
+use tracing::{debug, info_span, instrument, trace, Instrument};
+use url::Url;
 
 use crate::key::node::{PublicKey, SecretKey};
 
 use super::client_conn::ClientConnBuilder;
+use super::http::WsStream;
 use super::{
     clients::Clients,
-    metrics::Metrics,
-    types::{PacketForwarder, PeerConnState, ServerMessage},
+    metrics::{client_label, Metrics},
+    types::{PacketForwarder, PeerConnState, RateLimiter, ServerMessage},
+    verifier::ClientVerifier,
     MeshKey,
 };
 use super::{
@@ -62,6 +65,8 @@ where
     // be discussed and worked out.
     // from go impl: log.Fatalf("key in %s must contain 64+ hex digits", *meshPSKFile)
     mesh_key: Option<MeshKey>,
+    /// Decides which clients may connect, all clients are accepted when `None`
+    client_verifier: Option<Arc<dyn ClientVerifier>>,
     /// The DER encoded x509 cert to send after `LetsEncrypt` cert+intermediate.
     meta_cert: Vec<u8>,
     /// Channel on which to communicate to the [`ServerActor`]
@@ -69,8 +74,11 @@ where
     /// When true, the server has been shutdown.
     closed: bool,
     /// The information we send to the client about the [`Server`]'s protocol version
-    /// and required rate limiting (if any)
-    server_info: ServerInfo,
+    /// and required rate limiting (if any), watched by the [`ClientConnHandler`]s and the
+    /// connected clients
+    server_info: watch::Sender<ServerInfo>,
+    /// The urls the derp servers of our mesh announced through their mesh clients
+    mesh_peers: watch::Receiver<BTreeSet<Url>>,
     /// Server loop handler
     loop_handler: JoinHandle<Result<()>>,
     /// Done token, forces a hard shutdown. To gracefully shutdown, use [`Server::close`]
@@ -119,21 +127,25 @@ where
     pub fn new(key: SecretKey, mesh_key: Option<MeshKey>) -> Self {
         let (server_channel_s, server_channel_r) = mpsc::channel(SERVER_CHANNEL_SIZE);
         let server_actor = ServerActor::new(key.public_key(), server_channel_r);
+        let mesh_peers = server_actor.mesh_peers();
         let cancel_token = CancellationToken::new();
         let done = cancel_token.clone();
         let server_task = tokio::spawn(
             async move { server_actor.run(done).await }.instrument(info_span!("derp.srv.actor")),
         );
         let meta_cert = init_meta_cert(&key.public_key());
+        // TODO: come up with good default
+        let (server_info, _) = watch::channel(ServerInfo::no_rate_limit());
         Self {
             write_timeout: Some(WRITE_TIMEOUT),
             secret_key: key,
             mesh_key,
+            client_verifier: None,
             meta_cert,
             server_channel: server_channel_s,
             closed: false,
-            // TODO: come up with good default
-            server_info: ServerInfo::no_rate_limit(),
+            server_info,
+            mesh_peers,
             loop_handler: server_task,
             cancel: cancel_token,
         }
@@ -149,6 +161,38 @@ where
         self.mesh_key
     }
 
+    /// Sets the [`ClientVerifier`] that decides which clients may connect.
+    ///
+    /// Only affects [`ClientConnHandler`]s created after this call.
+    pub fn set_client_verifier(&mut self, verifier: Option<Arc<dyn ClientVerifier>>) {
+        self.client_verifier = verifier;
+    }
+
+    /// Limits the rate at which every client may send packets through the server.
+    ///
+    /// The limit is advertised to the clients in the server info, and enforced by the
+    /// server: packets that exceed the limit are dropped. Packets larger than `bytes_burst`
+    /// are always dropped. A limit of `0` bytes per second or `0` bytes burst means that
+    /// clients are not limited. Clients that are part of our mesh are never limited.
+    ///
+    /// Can be changed while the server is running: the new limit is enforced for connected
+    /// clients right away, but only clients that connect afterwards are told about it.
+    pub fn set_client_rate_limit(&self, bytes_per_second: usize, bytes_burst: usize) -> Result<()> {
+        // make sure a rate limiter can be created from the limit
+        RateLimiter::new(bytes_per_second, bytes_burst).context("invalid rate limit")?;
+        self.server_info.send_modify(|server_info| {
+            server_info.token_bucket_bytes_per_second = bytes_per_second;
+            server_info.token_bucket_bytes_burst = bytes_burst;
+        });
+        Ok(())
+    }
+
+    /// Watches the urls of the members of our mesh, as announced by the mesh clients that
+    /// are connected to this server.
+    pub(crate) fn mesh_peers(&self) -> watch::Receiver<BTreeSet<Url>> {
+        self.mesh_peers.clone()
+    }
+
     /// Returns the server's private key.
     pub fn private_key(&self) -> SecretKey {
         self.secret_key.clone()
@@ -189,15 +233,24 @@ where
         PacketForwarderHandler::new(self.server_channel.clone())
     }
 
+    /// Create an [`AdminHandler`], which can inspect and disconnect the clients of the
+    /// [`Server`].
+    pub fn admin_handler(&self) -> AdminHandler<P> {
+        AdminHandler {
+            server_channel: self.server_channel.clone(),
+        }
+    }
+
     /// Create a [`ClientConnHandler`], which can verify connections and add them to the
     /// [`Server`].
     pub fn client_conn_handler(&self, default_headers: HeaderMap) -> ClientConnHandler<P> {
         ClientConnHandler {
             mesh_key: self.mesh_key,
+            client_verifier: self.client_verifier.clone(),
             server_channel: self.server_channel.clone(),
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
-            server_info: self.server_info.clone(),
+            server_info: self.server_info.subscribe(),
             default_headers: Arc::new(default_headers),
         }
     }
@@ -262,6 +315,71 @@ where
     }
 }
 
+/// The state of a client connected to a [`Server`], see [`AdminHandler::clients`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientStatus {
+    /// The [`PublicKey`] of the client
+    pub key: PublicKey,
+    /// Whether the client noted this server as its home, see [`FrameType::NotePreferred`]
+    pub preferred: bool,
+    /// Whether the client is a derp server of our mesh
+    pub mesh_peer: bool,
+    /// Bytes of packets the client sent through the server
+    pub bytes_recv: u64,
+    /// Bytes of packets the server relayed to the client
+    pub bytes_sent: u64,
+}
+
+/// Inspect the clients of the [`Server`], and forcibly disconnect them.
+///
+/// Created by the [`Server`] by calling [`Server::admin_handler`].
+///
+/// Can be cheaply cloned.
+#[derive(Debug)]
+pub struct AdminHandler<P>
+where
+    P: PacketForwarder,
+{
+    server_channel: mpsc::Sender<ServerMessage<P>>,
+}
+
+impl<P> Clone for AdminHandler<P>
+where
+    P: PacketForwarder,
+{
+    fn clone(&self) -> Self {
+        Self {
+            server_channel: self.server_channel.clone(),
+        }
+    }
+}
+
+impl<P> AdminHandler<P>
+where
+    P: PacketForwarder,
+{
+    /// Returns the clients that are currently connected to the [`Server`].
+    pub async fn clients(&self) -> Result<Vec<ClientStatus>> {
+        let (s, r) = oneshot::channel();
+        self.server_channel
+            .send(ServerMessage::GetClients(s))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        r.await.context("server gone")
+    }
+
+    /// Disconnects the client with the given [`PublicKey`], if it is connected.
+    ///
+    /// The client is free to reconnect, unless it is rejected by the [`ClientVerifier`].
+    pub async fn close_client(&self, key: PublicKey) -> Result<()> {
+        self.server_channel
+            .send(ServerMessage::ClosePeer(key))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        Ok(())
+    }
+}
+
 /// Handle incoming connections to the Server.
 ///
 /// Created by the [`Server`] by calling [`Server::client_conn_handler`].
@@ -273,10 +391,11 @@ where
     P: PacketForwarder,
 {
     mesh_key: Option<MeshKey>,
+    client_verifier: Option<Arc<dyn ClientVerifier>>,
     server_channel: mpsc::Sender<ServerMessage<P>>,
     secret_key: SecretKey,
     write_timeout: Option<Duration>,
-    server_info: ServerInfo,
+    server_info: watch::Receiver<ServerInfo>,
     pub(super) default_headers: Arc<HeaderMap>,
 }
 
@@ -287,6 +406,7 @@ where
     fn clone(&self) -> Self {
         Self {
             mesh_key: self.mesh_key,
+            client_verifier: self.client_verifier.clone(),
             server_channel: self.server_channel.clone(),
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
@@ -316,18 +436,36 @@ where
         let (client_key, client_info) = recv_client_key(self.secret_key.clone(), &mut io)
             .await
             .context("unable to receive client information")?;
+        let can_mesh = self.can_mesh(client_info.mesh_key);
+        // clients with the mesh key are derp servers in our mesh, they are always trusted
+        if let (Some(verifier), false) = (&self.client_verifier, can_mesh) {
+            trace!("accept: verify client");
+            if let Err(err) = verifier.verify(&client_key).await {
+                inc!(Metrics, rejects);
+                if let Err(err) = self.send_rejection(&mut io, &err.to_string()).await {
+                    debug!("accept: unable to notify rejected client: {err:?}");
+                }
+                anyhow::bail!("client {client_key:?} rejected: {err}");
+            }
+        }
         trace!("accept: send server info");
-        self.send_server_info(&mut io, &client_key)
+        // the rate limit may change at any time, the client enforces the latest one, starting
+        // with the one it is told about here
+        let mut rate_limit = self.server_info.clone();
+        let server_info = rate_limit.borrow_and_update().clone();
+        self.send_server_info(&mut io, &client_key, &server_info)
             .await
             .context("unable to sent server info to client {client_key}")?;
         trace!("accept: build client conn");
+        let rate_limit = if can_mesh { None } else { Some(rate_limit) };
         let client_conn_builder = ClientConnBuilder {
             key: client_key,
             conn_num: new_conn_num(),
             io,
-            can_mesh: self.can_mesh(client_info.mesh_key),
+            can_mesh,
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
+            rate_limit,
             server_channel: self.server_channel.clone(),
         };
         trace!("accept: create client");
@@ -360,12 +498,17 @@ where
         Ok(())
     }
 
-    async fn send_server_info<T>(&self, mut writer: &mut T, client_key: &PublicKey) -> Result<()>
+    async fn send_server_info<T>(
+        &self,
+        mut writer: &mut T,
+        client_key: &PublicKey,
+        server_info: &ServerInfo,
+    ) -> Result<()>
     where
         T: AsyncWrite + Unpin,
     {
         let mut buf = BytesMut::zeroed(ServerInfo::POSTCARD_MAX_SIZE);
-        let msg = postcard::to_slice(&self.server_info, &mut buf)?;
+        let msg = postcard::to_slice(server_info, &mut buf)?;
         let msg = self.secret_key.seal_to(client_key, msg);
         let msg = &[msg.as_slice()];
         write_frame(&mut writer, FrameType::ServerInfo, msg).await?;
@@ -373,6 +516,19 @@ where
         Ok(())
     }
 
+    /// Tells a rejected client why it is rejected, using a [`FrameType::Health`] frame, and
+    /// closes the connection.
+    async fn send_rejection<T>(&self, mut writer: &mut T, reason: &str) -> Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let content = &[reason.as_bytes()];
+        write_frame_timeout(&mut writer, FrameType::Health, content, self.write_timeout).await?;
+        writer.flush().await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
     /// Determines if the server and client can mesh, and, if so, are apart of the same mesh.
     fn can_mesh(&self, client_mesh_key: Option<MeshKey>) -> bool {
         if let (Some(a), Some(b)) = (self.mesh_key, client_mesh_key) {
@@ -395,6 +551,10 @@ where
     client_mesh: HashMap<PublicKey, Option<P>>,
     /// Mesh clients that need to be appraised on the state of the network
     watchers: HashSet<PublicKey>,
+    /// The urls announced by the connected mesh clients, the derp servers they belong to
+    mesh_peers: HashMap<PublicKey, Url>,
+    /// Publishes the set of urls in `mesh_peers`
+    mesh_peers_s: watch::Sender<BTreeSet<Url>>,
     name: String,
 }
 
@@ -410,10 +570,17 @@ where
             clients: Clients::new(),
             client_mesh: HashMap::default(),
             watchers: HashSet::default(),
+            mesh_peers: HashMap::default(),
+            mesh_peers_s: watch::channel(BTreeSet::new()).0,
             name,
         }
     }
 
+    /// Watches the urls announced by the mesh clients connected to this server.
+    pub(crate) fn mesh_peers(&self) -> watch::Receiver<BTreeSet<Url>> {
+        self.mesh_peers_s.subscribe()
+    }
+
     #[instrument(skip_all, fields(self.name = %self.name))]
     pub(crate) async fn run(mut self, done: CancellationToken) -> Result<()> {
         loop {
@@ -447,14 +614,39 @@ where
                            // send list of connected clients to the client
                            self.clients.send_mesh_updates(&key, updates);
 
+                           // send the members of the mesh we know about to the client
+                           for url in self.mesh_peers_s.borrow().iter() {
+                               self.clients.send_mesh_peer_present(&key, url.clone());
+                           }
+
                            // add to the list of watchers
                            self.watchers.insert(key.clone());
                        },
+                       ServerMessage::MeshPeerPresent((key, url)) => {
+                           tracing::trace!("mesh peer present: {:?} at {}", key, url);
+                           // the url is only valid as long as the mesh client is connected
+                           if !self.clients.contains_key(&key) {
+                               continue;
+                           }
+                           let known = self.mesh_peers_s.borrow().contains(&url);
+                           self.mesh_peers.insert(key, url.clone());
+                           self.update_mesh_peers();
+                           if !known {
+                               // let the rest of the mesh know about the new member
+                               self.clients.broadcast_mesh_peer_present(self.watchers.iter(), &url);
+                           }
+                       },
                        ServerMessage::ClosePeer(key) => {
                            tracing::trace!("close peer: {:?}", key);
-                           // close the actual underlying connection to the client, but don't remove it from
-                           // the list of clients
-                           self.clients.close_conn(&key);
+                           // closes the connection to the client, the `RemoveClient` message of
+                           // the connection is ignored, as the client is not registered anymore
+                           if self.clients.contains_key(&key) {
+                               self.remove_client(key);
+                           }
+                       },
+                       ServerMessage::GetClients(s) => {
+                           tracing::trace!("get clients");
+                           s.send(self.clients.status()).ok();
                        },
                         ServerMessage::SendPacket((key, packet)) => {
                            tracing::trace!("send disco packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
@@ -515,13 +707,7 @@ where
                            tracing::trace!("remove client: {:?}", key);
                            // ensure we still have the client in question
                            if self.clients.has_client(&key, conn_num) {
-                               // remove the client from the map of clients, & notify any peers that it
-                               // has sent messages that it has left the network
-                               self.clients.unregister(&key);
-                               // remove from mesh
-                               self.client_mesh.remove(&key);
-                               // broadcast to watchers that this peer has left the network
-                               self.broadcast_peer_state_change(key, false);
+                               self.remove_client(key);
                             }
                        }
                        ServerMessage::AddPacketForwarder { key, forwarder } => {
@@ -555,6 +741,38 @@ where
         }
     }
 
+    /// Removes the client from the server and closes its connection.
+    fn remove_client(&mut self, key: PublicKey) {
+        // remove the client from the map of clients, & notify any peers that it
+        // has sent messages that it has left the network
+        self.clients.unregister(&key);
+        // stop reporting the per-client metrics
+        let label = client_label(&key);
+        Metrics::with_metric(|m| {
+            m.client_bytes_recv.remove(&label);
+            m.client_bytes_sent.remove(&label);
+        });
+        // remove from mesh
+        self.client_mesh.remove(&key);
+        if self.mesh_peers.remove(&key).is_some() {
+            self.update_mesh_peers();
+        }
+        // broadcast to watchers that this peer has left the network
+        self.broadcast_peer_state_change(key, false);
+    }
+
+    /// Publishes the urls of `mesh_peers`, if they changed.
+    fn update_mesh_peers(&mut self) {
+        let urls: BTreeSet<Url> = self.mesh_peers.values().cloned().collect();
+        self.mesh_peers_s.send_if_modified(|current| {
+            if *current == urls {
+                return false;
+            }
+            *current = urls;
+            true
+        });
+    }
+
     pub(crate) fn broadcast_peer_state_change(&mut self, peer: PublicKey, present: bool) {
         let keys = self.watchers.iter();
         self.clients
@@ -603,6 +821,8 @@ pub enum MaybeTlsStream {
     Plain(tokio::net::TcpStream),
     /// A Tls wrapped [`tokio::net::TcpStream`]
     Tls(tokio_rustls::server::TlsStream<tokio::net::TcpStream>),
+    /// A [`WsStream`] over an upgraded plain or Tls connection
+    WebSocket(Box<WsStream<MaybeTlsStream>>),
     #[cfg(test)]
     Test(tokio::io::DuplexStream),
 }
@@ -616,6 +836,7 @@ impl AsyncRead for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_read(cx, buf),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::WebSocket(ref mut s) => Pin::new(s).poll_read(cx, buf),
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_read(cx, buf),
         }
@@ -630,6 +851,7 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_flush(cx),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::WebSocket(ref mut s) => Pin::new(s).poll_flush(cx),
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_flush(cx),
         }
@@ -642,6 +864,7 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_shutdown(cx),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::WebSocket(ref mut s) => Pin::new(s).poll_shutdown(cx),
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_shutdown(cx),
         }
@@ -655,6 +878,7 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_write(cx, buf),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::WebSocket(ref mut s) => Pin::new(s).poll_write(cx, buf),
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_write(cx, buf),
         }
@@ -668,6 +892,7 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            MaybeTlsStream::WebSocket(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
         }
@@ -724,6 +949,7 @@ mod tests {
                 can_mesh: true,
                 write_timeout: None,
                 channel_capacity: 10,
+                rate_limit: None,
                 server_channel,
             },
             test_io,
@@ -881,15 +1107,107 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_actor_mesh_peers() -> Result<()> {
+        let server_key = PublicKey::from([1u8; PUBLIC_KEY_LENGTH]);
+
+        // make server actor
+        let (server_channel, server_channel_r) = mpsc::channel(20);
+        let server_actor: ServerActor<MockPacketForwarder> =
+            ServerActor::new(server_key, server_channel_r);
+        let mut mesh_peers = server_actor.mesh_peers();
+        let done = CancellationToken::new();
+        let server_done = done.clone();
+
+        // run server actor
+        let server_task = tokio::spawn(
+            async move { server_actor.run(server_done).await }
+                .instrument(info_span!("derp.srv.actor")),
+        );
+
+        // create client a, and add it as a watcher
+        let key_a = PublicKey::from([3u8; PUBLIC_KEY_LENGTH]);
+        let (client_a, mut a_io) = test_client_builder(key_a.clone(), 1, server_channel.clone());
+        server_channel
+            .send(ServerMessage::CreateClient(client_a.build()))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        server_channel
+            .send(ServerMessage::AddWatcher(key_a.clone()))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        let mut buf = BytesMut::new();
+        let (frame_type, _) = crate::derp::read_frame(&mut a_io, MAX_FRAME_SIZE, &mut buf).await?;
+        assert_eq!(frame_type, FrameType::PeerPresent);
+        assert_eq!(key_a.as_bytes()[..], buf[..]);
+
+        // create client b
+        let key_b = PublicKey::from([9u8; PUBLIC_KEY_LENGTH]);
+        let (client_b, mut b_io) = test_client_builder(key_b.clone(), 2, server_channel.clone());
+        server_channel
+            .send(ServerMessage::CreateClient(client_b.build()))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        let (frame_type, _) = crate::derp::read_frame(&mut a_io, MAX_FRAME_SIZE, &mut buf).await?;
+        assert_eq!(frame_type, FrameType::PeerPresent);
+        assert_eq!(key_b.as_bytes()[..], buf[..]);
+
+        // b announces the url of its derp server, the watchers learn about it
+        let url_b: Url = "https://b.derp.example.com".parse()?;
+        server_channel
+            .send(ServerMessage::MeshPeerPresent((
+                key_b.clone(),
+                url_b.clone(),
+            )))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        let (frame_type, _) = crate::derp::read_frame(&mut a_io, MAX_FRAME_SIZE, &mut buf).await?;
+        assert_eq!(frame_type, FrameType::MeshPeerPresent);
+        assert_eq!(url_b.as_str().as_bytes(), &buf[..]);
+        mesh_peers.changed().await?;
+        assert_eq!(*mesh_peers.borrow(), BTreeSet::from([url_b.clone()]));
+
+        // a new watcher learns about the members of the mesh we already know about
+        server_channel
+            .send(ServerMessage::AddWatcher(key_b.clone()))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        for _ in 0..2 {
+            let (frame_type, _) =
+                crate::derp::read_frame(&mut b_io, MAX_FRAME_SIZE, &mut buf).await?;
+            assert_eq!(frame_type, FrameType::PeerPresent);
+        }
+        let (frame_type, _) = crate::derp::read_frame(&mut b_io, MAX_FRAME_SIZE, &mut buf).await?;
+        assert_eq!(frame_type, FrameType::MeshPeerPresent);
+        assert_eq!(url_b.as_str().as_bytes(), &buf[..]);
+
+        // the url is forgotten once b disconnects
+        server_channel
+            .send(ServerMessage::RemoveClient((key_b.clone(), 2)))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        mesh_peers.changed().await?;
+        assert!(mesh_peers.borrow().is_empty());
+
+        // close gracefully
+        server_channel
+            .send(ServerMessage::Shutdown)
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        server_task.await??;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_client_conn_handler() -> Result<()> {
         // create client connection handler
         let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
         let handler = ClientConnHandler::<MockPacketForwarder> {
             mesh_key: Some([1u8; 32]),
+            client_verifier: None,
             secret_key: SecretKey::generate(),
             write_timeout: None,
-            server_info: ServerInfo::no_rate_limit(),
+            server_info: watch::channel(ServerInfo::no_rate_limit()).1,
             server_channel: server_channel_s,
             default_headers: Default::default(),
         };
@@ -1158,4 +1476,131 @@ mod tests {
         assert!(new_client_b.recv().await.is_err());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_server_client_verifier() -> Result<()> {
+        let mesh_key = Some([1u8; 32]);
+        let mut server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), mesh_key);
+        let key_a = SecretKey::generate();
+        let verifier = crate::derp::AllowList::new([key_a.public_key()]);
+        server.set_client_verifier(Some(Arc::new(verifier)));
+
+        // client a is on the allow list
+        let (rw_a, client_a_builder) = make_test_client(key_a);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+        let _client_a = client_a_builder.build(None).await?;
+        handler_task.await??;
+
+        // client b is rejected, and learns why
+        let (rw_b, client_b_builder) = make_test_client(SecretKey::generate());
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+        let err = client_b_builder.build(None).await.unwrap_err();
+        assert!(err.to_string().contains("not on the allow list"), "{err}");
+        assert!(handler_task.await?.is_err());
+
+        // clients with the mesh key are always accepted
+        let (rw_c, client_c_builder) = make_test_client(SecretKey::generate());
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_c)).await });
+        let _client_c = client_c_builder.mesh_key(mesh_key).build(None).await?;
+        handler_task.await??;
+
+        server.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_set_client_rate_limit() -> Result<()> {
+        let server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), None);
+        let handler = server.client_conn_handler(Default::default());
+        let limit = |handler: &ClientConnHandler<MockPacketForwarder>| {
+            let info = handler.server_info.borrow();
+            (
+                info.token_bucket_bytes_per_second,
+                info.token_bucket_bytes_burst,
+            )
+        };
+        assert_eq!(limit(&handler), (0, 0));
+
+        // existing handlers pick up the new limit
+        server.set_client_rate_limit(1000, 2000)?;
+        assert_eq!(limit(&handler), (1000, 2000));
+        server.set_client_rate_limit(0, 0)?;
+        assert_eq!(limit(&handler), (0, 0));
+
+        server.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_admin_handler() -> Result<()> {
+        let server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), None);
+        let admin = server.admin_handler();
+
+        // connect clients a and b
+        let key_a = SecretKey::generate();
+        let (rw_a, client_a_builder) = make_test_client(key_a.clone());
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+        let client_a = client_a_builder.build(None).await?;
+        handler_task.await??;
+
+        let key_b = SecretKey::generate();
+        let (rw_b, client_b_builder) = make_test_client(key_b.clone());
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+        let client_b = client_b_builder.build(None).await?;
+        handler_task.await??;
+
+        // a prefers this server, and sends a packet to b
+        client_a.note_preferred(true).await?;
+        let msg = Bytes::from_static(b"hello b");
+        client_a.send(key_b.public_key(), msg.clone()).await?;
+        match client_b.recv().await? {
+            ReceivedMessage::ReceivedPacket { source, data } => {
+                assert_eq!(key_a.public_key(), source);
+                assert_eq!(msg, data);
+            }
+            msg => anyhow::bail!("expected ReceivedPacket, got {msg:?}"),
+        }
+
+        let status = |key: PublicKey| {
+            let admin = admin.clone();
+            async move {
+                let clients = admin.clients().await?;
+                anyhow::Ok(clients.into_iter().find(|client| client.key == key))
+            }
+        };
+        let status_a = status(key_a.public_key())
+            .await?
+            .expect("client a connected");
+        assert!(status_a.preferred);
+        assert!(!status_a.mesh_peer);
+        assert!(status_a.bytes_recv >= msg.len() as u64);
+        let status_b = status(key_b.public_key())
+            .await?
+            .expect("client b connected");
+        assert!(!status_b.preferred);
+        assert_eq!(status_b.bytes_sent, msg.len() as u64);
+
+        // disconnect a, b learns that a is gone
+        admin.close_client(key_a.public_key()).await?;
+        match client_b.recv().await? {
+            ReceivedMessage::PeerGone(key) => assert_eq!(key_a.public_key(), key),
+            msg => anyhow::bail!("expected PeerGone, got {msg:?}"),
+        }
+        assert!(status(key_a.public_key()).await?.is_none());
+        assert!(status(key_b.public_key()).await?.is_some());
+        assert!(client_a.recv().await.is_err());
+
+        server.close().await;
+        Ok(())
+    }
 }
@@ -4,14 +4,18 @@ use anyhow::{bail, ensure, Result};
 use bytes::Bytes;
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use url::Url;
 
 use super::client_conn::ClientConnManager;
+use super::server::ClientStatus;
 use super::PROTOCOL_VERSION;
 use crate::key::node::PublicKey;
 
 /// A key to identify if a node belongs in a mesh
 pub type MeshKey = [u8; 32];
 
+#[derive(Debug)]
 pub(crate) struct RateLimiter {
     inner: governor::RateLimiter<
         governor::state::direct::NotKeyed,
@@ -39,7 +43,8 @@ impl RateLimiter {
         ensure!(n != 0);
         let n = NonZeroU32::new(u32::try_from(n)?).unwrap();
         match self.inner.check_n(n) {
-            Ok(_) => Ok(()),
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) => bail!("rate limit reached"),
             Err(_) => bail!("batch cannot go through"),
         }
     }
@@ -116,6 +121,7 @@ where
 {
     AddWatcher(PublicKey),
     ClosePeer(PublicKey),
+    MeshPeerPresent((PublicKey, Url)),
     SendPacket((PublicKey, Packet)),
     SendDiscoPacket((PublicKey, Packet)),
     CreateClient(ClientConnManager),
@@ -126,5 +132,6 @@ where
         forwarder: P,
     },
     RemovePacketForwarder(PublicKey),
+    GetClients(oneshot::Sender<Vec<ClientStatus>>),
     Shutdown,
 }
@@ -0,0 +1,187 @@
+//! Admission control for clients of a DERP [`super::Server`].
+//!
+//! By default a DERP server accepts every client that completes the handshake. A
+//! [`ClientVerifier`] can be set on the server to restrict the clients that may connect,
+//! for example to run a private relay.
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use reqwest::Url;
+
+use crate::key::node::PublicKey;
+
+/// How long to wait for a response of the verifier service
+const HTTP_VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum length of a rejection reason that is passed on to the client
+const MAX_REASON_LEN: usize = 256;
+
+/// Decides whether a client is allowed to connect to a DERP server.
+///
+/// The verifier is consulted after the client sent its key, and before the server
+/// sends its server info. Clients that present the mesh key of the server are always
+/// admitted, so meshed DERP servers do not need to be verified.
+pub trait ClientVerifier: Debug + Send + Sync + 'static {
+    /// Verifies the client with the given key.
+    ///
+    /// Returns an error if the client is not allowed to connect. The error message is
+    /// sent to the client as the reason of the rejection.
+    fn verify<'a>(&'a self, client_key: &'a PublicKey) -> BoxFuture<'a, Result<()>>;
+}
+
+/// A [`ClientVerifier`] that only admits a fixed set of keys.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    keys: HashSet<PublicKey>,
+}
+
+impl AllowList {
+    /// Creates an allow list that admits the given keys.
+    pub fn new(keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        Self {
+            keys: keys.into_iter().collect(),
+        }
+    }
+
+    /// Whether the key is on the allow list.
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.keys.contains(key)
+    }
+}
+
+impl ClientVerifier for AllowList {
+    fn verify<'a>(&'a self, client_key: &'a PublicKey) -> BoxFuture<'a, Result<()>> {
+        let res = if self.contains(client_key) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("client key is not on the allow list"))
+        };
+        future::ready(res).boxed()
+    }
+}
+
+/// A [`ClientVerifier`] that asks a verifier service over HTTP.
+///
+/// For every client, a `POST` request is sent to the url of the service, with the hex
+/// encoded key of the client as the body. The client is admitted if the service responds
+/// with a success status. Any other response rejects the client, the body of the
+/// response is used as the reason. Clients are rejected as well if the service cannot be
+/// reached.
+#[derive(Debug, Clone)]
+pub struct HttpVerifier {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl HttpVerifier {
+    /// Creates a verifier that sends its requests to `url`.
+    pub fn new(url: Url) -> Result<Self> {
+        let client = reqwest::ClientBuilder::new()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(HTTP_VERIFY_TIMEOUT)
+            .build()?;
+        Ok(Self { url, client })
+    }
+
+    async fn verify_impl(&self, client_key: &PublicKey) -> Result<()> {
+        let res = self
+            .client
+            .post(self.url.clone())
+            .body(hex::encode(client_key.as_bytes()))
+            .send()
+            .await
+            .context("verifier service unreachable")?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let mut reason = res.text().await.unwrap_or_default().trim().to_string();
+        if reason.is_empty() {
+            reason = format!("rejected by verifier service: {status}");
+        }
+        if reason.len() > MAX_REASON_LEN {
+            let mut end = MAX_REASON_LEN;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+        }
+        bail!(reason)
+    }
+}
+
+impl ClientVerifier for HttpVerifier {
+    fn verify<'a>(&'a self, client_key: &'a PublicKey) -> BoxFuture<'a, Result<()>> {
+        self.verify_impl(client_key).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::key::node::SecretKey;
+
+    #[tokio::test]
+    async fn test_allow_list() -> Result<()> {
+        let allowed = SecretKey::generate().public_key();
+        let other = SecretKey::generate().public_key();
+        let verifier = AllowList::new([allowed.clone()]);
+        verifier.verify(&allowed).await?;
+        assert!(verifier.verify(&other).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_verifier() -> Result<()> {
+        let allowed = SecretKey::generate().public_key();
+        let other = SecretKey::generate().public_key();
+        let allowed_hex = hex::encode(allowed.as_bytes());
+
+        let make_svc = make_service_fn(move |_conn| {
+            let allowed_hex = allowed_hex.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let allowed_hex = allowed_hex.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let res = if body == allowed_hex.as_bytes() {
+                            Response::new(Body::empty())
+                        } else {
+                            Response::builder()
+                                .status(StatusCode::FORBIDDEN)
+                                .body(Body::from("unknown client"))
+                                .unwrap()
+                        };
+                        Ok::<_, hyper::Error>(res)
+                    }
+                }))
+            }
+        });
+        let addr: SocketAddr = "127.0.0.1:0".parse()?;
+        let server = hyper::Server::bind(&addr).serve(make_svc);
+        let url: Url = format!("http://{}/verify", server.local_addr()).parse()?;
+        let server_task = tokio::spawn(server);
+
+        let verifier = HttpVerifier::new(url)?;
+        verifier.verify(&allowed).await?;
+        let err = verifier.verify(&other).await.unwrap_err();
+        assert_eq!(err.to_string(), "unknown client");
+        server_task.abort();
+
+        // clients are rejected if the service cannot be reached
+        let listener = std::net::TcpListener::bind(addr)?;
+        let url = format!("http://{}/verify", listener.local_addr()?).parse()?;
+        drop(listener);
+        let verifier = HttpVerifier::new(url)?;
+        assert!(verifier.verify(&allowed).await.is_err());
+        Ok(())
+    }
+}
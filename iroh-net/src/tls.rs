@@ -12,7 +12,9 @@ use std::{
     sync::Arc,
 };
 
-pub use ed25519_dalek::{Signature, SigningKey as SecretKey, VerifyingKey as PublicKey};
+pub use ed25519_dalek::{
+    Signature, SignatureError, SigningKey as SecretKey, VerifyingKey as PublicKey,
+};
 use serde::{Deserialize, Serialize};
 use ssh_key::LineEnding;
 
@@ -68,7 +70,8 @@ impl Keypair {
         }
     }
 
-    fn sign(&self, msg: &[u8]) -> Signature {
+    /// Sign a message with the secret key of this keypair.
+    pub fn sign(&self, msg: &[u8]) -> Signature {
         use ed25519_dalek::Signer;
 
         self.secret.sign(msg)
@@ -106,6 +109,11 @@ impl PeerId {
     pub fn as_bytes(&self) -> &[u8; 32] {
         self.0.as_bytes()
     }
+
+    /// Verify a signature made by the [`Keypair`] of this peer.
+    pub fn verify(&self, msg: &[u8], signature: &Signature) -> Result<(), SignatureError> {
+        self.0.verify_strict(msg, signature)
+    }
 }
 
 impl From<PublicKey> for PeerId {
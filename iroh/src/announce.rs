@@ -0,0 +1,659 @@
+//! Announce and discover providers of content with gossip.
+//!
+//! Nodes that enable announcements, see [`Builder::announce`], join the swarm for
+//! [`ANNOUNCE_TOPIC`] and periodically broadcast the roots in their store, see
+//! [`ReadableStore::roots`], together with the address they can be reached at. The roots
+//! are announced again whenever a new neighbor joins, so it does not have to wait for the
+//! next interval.
+//!
+//! Every node in the swarm keeps a [`ProviderIndex`] of the announcements it received, which
+//! maps a hash to the providers that announced it. Announcements are signed by the
+//! [`Keypair`] of the provider, and expire after a while, so a provider that goes away is
+//! eventually forgotten.
+//!
+//! [`Builder::announce`]: crate::node::Builder::announce
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use iroh_bytes::{baomap::ReadableStore, util::runtime, Hash};
+use iroh_gossip::{
+    net::{Event, Gossip, MAX_MESSAGE_SIZE},
+    proto::TopicId,
+};
+use iroh_net::{
+    tls::{Keypair, PeerId, Signature},
+    MagicEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::dial::NodeAddr;
+
+/// The gossip topic on which content is announced.
+pub const ANNOUNCE_TOPIC: TopicId = TopicId::from_bytes(*b"n0/iroh-content-announcements/0\0");
+
+/// Configuration for announcing content, see [`crate::node::Builder::announce`].
+#[derive(Debug, Clone)]
+pub struct AnnounceConfig {
+    /// How often the roots of the node are announced.
+    pub interval: Duration,
+    /// How long a received announcement is kept in the [`ProviderIndex`].
+    ///
+    /// This should be a multiple of the `interval` of the other nodes, so a provider is not
+    /// forgotten when a single announcement is lost.
+    pub ttl: Duration,
+    /// Nodes to join the swarm through.
+    ///
+    /// Can be empty for the first node of a swarm, which the other nodes then join through.
+    pub peers: Vec<NodeAddr>,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            ttl: Duration::from_secs(180),
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// An announcement that a node provides some hashes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    /// The node that provides the hashes, and how to reach it.
+    pub provider: NodeAddr,
+    /// The announced hashes.
+    pub hashes: Vec<Hash>,
+    /// When the announcement was made, in seconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+/// An [`Announcement`] signed by the provider, as it is sent over gossip.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SignedAnnouncement {
+    announcement: Announcement,
+    signature: Signature,
+}
+
+impl SignedAnnouncement {
+    fn sign(keypair: &Keypair, announcement: Announcement) -> Self {
+        let signature = keypair.sign(&announcement_bytes(&announcement));
+        Self {
+            announcement,
+            signature,
+        }
+    }
+
+    fn verify(&self) -> Result<&Announcement> {
+        self.announcement
+            .provider
+            .peer
+            .verify(&announcement_bytes(&self.announcement), &self.signature)
+            .context("invalid announcement signature")?;
+        Ok(&self.announcement)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible")
+    }
+}
+
+fn announcement_bytes(announcement: &Announcement) -> Vec<u8> {
+    postcard::to_stdvec(announcement).expect("postcard::to_stdvec is infallible")
+}
+
+/// Sign announcements for `hashes`, split so that every message fits into a single gossip
+/// message.
+fn sign_announcements(
+    keypair: &Keypair,
+    provider: NodeAddr,
+    hashes: impl IntoIterator<Item = Hash>,
+    timestamp: u64,
+) -> Result<Vec<Bytes>> {
+    let mut messages = Vec::new();
+    let mut current = Announcement {
+        provider,
+        hashes: Vec::new(),
+        timestamp,
+    };
+    for hash in hashes {
+        current.hashes.push(hash);
+        if SignedAnnouncement::sign(keypair, current.clone())
+            .to_bytes()
+            .len()
+            > MAX_MESSAGE_SIZE
+        {
+            let hash = current.hashes.pop().expect("just pushed");
+            anyhow::ensure!(!current.hashes.is_empty(), "provider address is too large");
+            let next = Announcement {
+                hashes: vec![hash],
+                ..current.clone()
+            };
+            let full = std::mem::replace(&mut current, next);
+            messages.push(SignedAnnouncement::sign(keypair, full).to_bytes().into());
+        }
+    }
+    if !current.hashes.is_empty() {
+        messages.push(SignedAnnouncement::sign(keypair, current).to_bytes().into());
+    }
+    Ok(messages)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Maximum number of hashes a single provider can have in a [`ProviderIndex`].
+const MAX_HASHES_PER_PROVIDER: usize = 16 * 1024;
+
+/// Maximum number of (hash, provider) entries in a [`ProviderIndex`].
+const MAX_INDEX_ENTRIES: usize = 1024 * 1024;
+
+/// A provider of a hash.
+#[derive(Debug)]
+struct ProviderEntry {
+    addr: NodeAddr,
+    /// When the announcement that added or refreshed the entry was received.
+    received: Instant,
+    /// The timestamp of that announcement.
+    timestamp: u64,
+}
+
+/// What the index knows about a provider, independent of the hashes.
+#[derive(Debug)]
+struct ProviderState {
+    /// The timestamp of the newest announcement of the provider.
+    newest: u64,
+    /// When the newest announcement was received.
+    received: Instant,
+    /// The number of entries of the provider in the index.
+    entries: usize,
+}
+
+#[derive(Debug, Default)]
+struct IndexState {
+    hashes: HashMap<Hash, HashMap<PeerId, ProviderEntry>>,
+    providers: HashMap<PeerId, ProviderState>,
+    entries: usize,
+}
+
+/// An index from hashes to the providers that announced them.
+///
+/// Only the newest announcement of a provider is applied, so replaying an older one can
+/// neither add hashes the provider no longer announces nor extend the expiry of an entry.
+/// The number of entries is bounded, in total and per provider.
+///
+/// Cloning is cheap, clones share the same index.
+#[derive(Debug, Clone)]
+pub struct ProviderIndex {
+    ttl: Duration,
+    max_entries: usize,
+    max_hashes_per_provider: usize,
+    inner: Arc<Mutex<IndexState>>,
+}
+
+impl ProviderIndex {
+    /// Creates a new, empty index, where announcements expire after `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            max_entries: MAX_INDEX_ENTRIES,
+            max_hashes_per_provider: MAX_HASHES_PER_PROVIDER,
+            inner: Default::default(),
+        }
+    }
+
+    /// Add the hashes of an announcement to the index.
+    ///
+    /// If the provider announced a hash before, its address and expiry are updated. An
+    /// announcement that is older than the newest one of the provider is ignored. Hashes over
+    /// the limits are dropped, after expired entries have been evicted.
+    pub fn insert(&self, announcement: &Announcement) {
+        let now = Instant::now();
+        let peer = announcement.provider.peer;
+        let timestamp = announcement.timestamp;
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries + announcement.hashes.len() > self.max_entries {
+            self.evict_expired(&mut inner, now);
+        }
+        let full = inner.entries >= self.max_entries;
+        match inner.providers.get_mut(&peer) {
+            Some(state) if timestamp < state.newest => {
+                debug!("ignoring outdated announcement from {}", peer);
+                return;
+            }
+            Some(state) => {
+                if timestamp > state.newest {
+                    state.newest = timestamp;
+                    state.received = now;
+                }
+            }
+            None if full => {
+                debug!("index is full, ignoring announcement from {}", peer);
+                return;
+            }
+            None => {
+                inner.providers.insert(
+                    peer,
+                    ProviderState {
+                        newest: timestamp,
+                        received: now,
+                        entries: 0,
+                    },
+                );
+            }
+        }
+        let IndexState {
+            hashes,
+            providers,
+            entries,
+        } = &mut *inner;
+        let state = providers.get_mut(&peer).expect("inserted above");
+        let mut dropped = 0;
+        for hash in &announcement.hashes {
+            match hashes
+                .get_mut(hash)
+                .and_then(|by_peer| by_peer.get_mut(&peer))
+            {
+                // parts of the same announcement share the timestamp, and are only applied once
+                Some(entry) if entry.timestamp < timestamp => {
+                    entry.addr = announcement.provider.clone();
+                    entry.received = now;
+                    entry.timestamp = timestamp;
+                }
+                Some(_) => {}
+                None if *entries >= self.max_entries
+                    || state.entries >= self.max_hashes_per_provider =>
+                {
+                    dropped += 1;
+                }
+                None => {
+                    hashes.entry(*hash).or_default().insert(
+                        peer,
+                        ProviderEntry {
+                            addr: announcement.provider.clone(),
+                            received: now,
+                            timestamp,
+                        },
+                    );
+                    state.entries += 1;
+                    *entries += 1;
+                }
+            }
+        }
+        if dropped > 0 {
+            debug!("index is full, dropped {} hashes of {}", dropped, peer);
+        }
+    }
+
+    /// The providers that announced `hash`, most recent announcement first.
+    pub fn providers(&self, hash: &Hash) -> Vec<NodeAddr> {
+        let inner = self.inner.lock().unwrap();
+        let Some(providers) = inner.hashes.get(hash) else {
+            return Vec::new();
+        };
+        let mut providers = providers
+            .values()
+            .filter(|entry| entry.received.elapsed() < self.ttl)
+            .collect::<Vec<_>>();
+        providers.sort_by_key(|entry| std::cmp::Reverse(entry.received));
+        providers
+            .into_iter()
+            .map(|entry| entry.addr.clone())
+            .collect()
+    }
+
+    /// Remove all expired announcements.
+    pub fn prune(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.evict_expired(&mut inner, Instant::now());
+    }
+
+    fn evict_expired(&self, inner: &mut IndexState, now: Instant) {
+        let ttl = self.ttl;
+        let IndexState {
+            hashes,
+            providers,
+            entries,
+        } = inner;
+        hashes.retain(|_, by_peer| {
+            by_peer.retain(|peer, entry| {
+                let keep = now.duration_since(entry.received) < ttl;
+                if !keep {
+                    *entries -= 1;
+                    if let Some(state) = providers.get_mut(peer) {
+                        state.entries -= 1;
+                    }
+                }
+                keep
+            });
+            !by_peer.is_empty()
+        });
+        // older announcements are rejected for their age once the newest one has expired
+        providers.retain(|_, state| state.entries > 0 || now.duration_since(state.received) < ttl);
+    }
+}
+
+/// Join the announcement swarm, announce the roots of `db` and index the announcements of
+/// other nodes, until `cancel` is cancelled.
+pub(crate) fn spawn<D: ReadableStore>(
+    config: AnnounceConfig,
+    gossip: Gossip,
+    endpoint: MagicEndpoint,
+    db: D,
+    rt: &runtime::Handle,
+    cancel: CancellationToken,
+) -> ProviderIndex {
+    let index = ProviderIndex::new(config.ttl);
+    let announcer = Announcer {
+        config,
+        gossip,
+        endpoint,
+        db,
+        index: index.clone(),
+    };
+    rt.main().spawn(async move {
+        tokio::select! {
+            _ = cancel.cancelled() => {}
+            res = announcer.run() => {
+                if let Err(cause) = res {
+                    warn!("announcing content failed: {:?}", cause);
+                }
+            }
+        }
+    });
+    index
+}
+
+#[derive(Debug)]
+struct Announcer<D> {
+    config: AnnounceConfig,
+    gossip: Gossip,
+    endpoint: MagicEndpoint,
+    db: D,
+    index: ProviderIndex,
+}
+
+impl<D: ReadableStore> Announcer<D> {
+    async fn run(self) -> Result<()> {
+        let mut peers = Vec::new();
+        for peer in &self.config.peers {
+            match self
+                .endpoint
+                .add_known_addrs(peer.peer, peer.derp_region, &peer.addrs)
+                .await
+            {
+                Ok(()) => peers.push(peer.peer),
+                Err(cause) => warn!(
+                    "can not join announcements through {}: {:?}",
+                    peer.peer, cause
+                ),
+            }
+        }
+        let mut events = self.gossip.subscribe(ANNOUNCE_TOPIC).await?;
+        // joining completes in the background, announcements are sent once neighbors are up
+        self.gossip.join(ANNOUNCE_TOPIC, peers.clone()).await?;
+        let mut neighbors = HashSet::new();
+        let mut interval = tokio::time::interval(self.config.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.index.prune();
+                    if neighbors.is_empty() && !peers.is_empty() {
+                        // joining failed, or all neighbors went away
+                        debug!("no neighbors, joining announcements again");
+                        self.gossip.join(ANNOUNCE_TOPIC, peers.clone()).await?;
+                    } else {
+                        self.announce().await;
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(Event::Received(msg, _)) => self.handle_message(&msg),
+                    Ok(Event::NeighborUp(peer)) => {
+                        neighbors.insert(peer);
+                        // let new neighbors know what we have right away
+                        self.announce().await;
+                    }
+                    Ok(Event::NeighborDown(peer)) => {
+                        neighbors.remove(&peer);
+                    }
+                    Err(RecvError::Lagged(n)) => debug!("missed {} announcement events", n),
+                    Err(RecvError::Closed) => anyhow::bail!("gossip closed"),
+                }
+            }
+        }
+    }
+
+    /// Broadcast the roots of the store.
+    async fn announce(&self) {
+        let hashes = self.db.roots().collect::<Vec<_>>();
+        if hashes.is_empty() {
+            return;
+        }
+        let addrs = match self.endpoint.local_endpoints().await {
+            Ok(endpoints) => endpoints.into_iter().map(|ep| ep.addr).collect(),
+            Err(cause) => {
+                warn!("failed to get local endpoints: {:?}", cause);
+                Vec::new()
+            }
+        };
+        let provider = NodeAddr::new(
+            self.endpoint.peer_id(),
+            addrs,
+            self.endpoint.my_derp().await,
+        );
+        let messages =
+            match sign_announcements(self.endpoint.keypair(), provider, hashes, unix_now()) {
+                Ok(messages) => messages,
+                Err(cause) => {
+                    warn!("failed to create announcements: {:?}", cause);
+                    return;
+                }
+            };
+        for message in messages {
+            if let Err(cause) = self.gossip.broadcast(ANNOUNCE_TOPIC, message).await {
+                debug!("failed to broadcast announcement: {:?}", cause);
+            }
+        }
+    }
+
+    fn handle_message(&self, msg: &[u8]) {
+        let announcement = match postcard::from_bytes::<SignedAnnouncement>(msg) {
+            Ok(signed) => match signed.verify() {
+                Ok(announcement) => announcement.clone(),
+                Err(cause) => {
+                    debug!("ignoring announcement: {:?}", cause);
+                    return;
+                }
+            },
+            Err(cause) => {
+                debug!("ignoring invalid announcement: {:?}", cause);
+                return;
+            }
+        };
+        if announcement.provider.peer == self.endpoint.peer_id() {
+            return;
+        }
+        // signed announcements can be replayed by anyone, so old ones are ignored
+        let age = unix_now().saturating_sub(announcement.timestamp);
+        if age > self.config.ttl.as_secs() {
+            debug!(
+                "ignoring expired announcement from {}",
+                announcement.provider.peer
+            );
+            return;
+        }
+        self.index.insert(&announcement);
+    }
+}
+
+#[cfg(all(test, feature = "mem-db"))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use iroh_bytes::baomap::Store;
+
+    use super::*;
+    use crate::{baomap::mem, node::Node};
+
+    #[test]
+    fn test_sign_announcements() -> Result<()> {
+        let keypair = Keypair::generate();
+        let provider = NodeAddr::new(
+            keypair.public().into(),
+            vec!["127.0.0.1:4433".parse().unwrap()],
+            Some(1),
+        );
+        let hashes = (0u32..100)
+            .map(|i| Hash::new(i.to_le_bytes()))
+            .collect::<Vec<_>>();
+        let messages = sign_announcements(&keypair, provider.clone(), hashes.clone(), 1)?;
+        assert!(messages.len() > 1);
+        let mut announced = Vec::new();
+        for message in messages {
+            assert!(message.len() <= MAX_MESSAGE_SIZE);
+            let signed: SignedAnnouncement = postcard::from_bytes(&message)?;
+            let announcement = signed.verify()?;
+            assert_eq!(announcement.provider, provider);
+            announced.extend(announcement.hashes.iter().copied());
+        }
+        assert_eq!(announced, hashes);
+
+        // announcements can not be forged for other providers
+        let forged = SignedAnnouncement {
+            announcement: Announcement {
+                provider: NodeAddr::new(Keypair::generate().public().into(), vec![], Some(1)),
+                hashes: vec![hashes[0]],
+                timestamp: 1,
+            },
+            signature: keypair.sign(b"something else"),
+        };
+        assert!(forged.verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_provider_index() {
+        let mut index = ProviderIndex::new(Duration::from_millis(200));
+        index.max_entries = 4;
+        index.max_hashes_per_provider = 3;
+        let provider = |keypair: &Keypair, port: u16| {
+            NodeAddr::new(
+                keypair.public().into(),
+                vec![(Ipv4Addr::LOCALHOST, port).into()],
+                None,
+            )
+        };
+        let hash = |i: u32| Hash::new(i.to_le_bytes());
+        let (a, b) = (Keypair::generate(), Keypair::generate());
+
+        let old = Announcement {
+            provider: provider(&a, 1),
+            hashes: vec![hash(0), hash(1)],
+            timestamp: 10,
+        };
+        index.insert(&old);
+        let new = Announcement {
+            provider: provider(&a, 2),
+            hashes: vec![hash(0)],
+            timestamp: 11,
+        };
+        index.insert(&new);
+        assert_eq!(index.providers(&hash(0)), vec![provider(&a, 2)]);
+        // replaying an older announcement changes nothing
+        index.insert(&old);
+        assert_eq!(index.providers(&hash(0)), vec![provider(&a, 2)]);
+
+        // a provider can not fill the index on its own
+        index.insert(&Announcement {
+            provider: provider(&a, 2),
+            hashes: vec![hash(2), hash(3)],
+            timestamp: 11,
+        });
+        assert_eq!(index.providers(&hash(2)).len(), 1);
+        assert!(index.providers(&hash(3)).is_empty());
+        // and the index as a whole is bounded
+        let other = |hashes: Vec<Hash>, timestamp| Announcement {
+            provider: provider(&b, 3),
+            hashes,
+            timestamp,
+        };
+        index.insert(&other(vec![hash(0), hash(4)], 20));
+        assert_eq!(index.providers(&hash(0)).len(), 2);
+        assert!(index.providers(&hash(4)).is_empty());
+
+        // expired entries are evicted to make room
+        std::thread::sleep(Duration::from_millis(250));
+        index.insert(&other(vec![hash(4), hash(5)], 21));
+        assert!(index.providers(&hash(0)).is_empty());
+        assert_eq!(index.providers(&hash(5)), vec![provider(&b, 3)]);
+        let inner = index.inner.lock().unwrap();
+        assert_eq!(inner.entries, 2);
+        assert_eq!(inner.hashes.len(), 2);
+        assert_eq!(inner.providers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+
+        let spawn_node = |db: mem::Store, peers: Vec<NodeAddr>| {
+            let rt = rt.clone();
+            async move {
+                Node::builder(db)
+                    .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+                    .announce(AnnounceConfig {
+                        interval: Duration::from_secs(1),
+                        peers,
+                        ..Default::default()
+                    })
+                    .runtime(&rt)
+                    .spawn()
+                    .await
+            }
+        };
+        let db2 = mem::Store::new(rt.clone());
+        let node1 = spawn_node(mem::Store::new(rt.clone()), vec![]).await?;
+        let bootstrap = NodeAddr::new(
+            node1.peer_id(),
+            node1.local_endpoint_addresses().await?,
+            None,
+        );
+        let node2 = spawn_node(db2.clone(), vec![bootstrap.clone()]).await?;
+        let node3 = spawn_node(mem::Store::new(rt.clone()), vec![bootstrap]).await?;
+        let _guards = [&node1, &node2, &node3].map(|node| node.cancel_token().drop_guard());
+
+        let hash = db2.import_bytes(Bytes::from_static(b"hello")).await?;
+        db2.pin(hash).await?;
+
+        let index = node3.provider_index().unwrap();
+        let providers = tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                let providers = index.providers(&hash);
+                if !providers.is_empty() {
+                    break providers;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .context("no announcement received")?;
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].peer, node2.peer_id());
+        // nodes do not index their own content
+        assert!(node2.provider_index().unwrap().providers(&hash).is_empty());
+        Ok(())
+    }
+}
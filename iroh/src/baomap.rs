@@ -1,12 +1,14 @@
 //! Various database implementations for storing blob data
 #[cfg(feature = "flat-db")]
 pub mod flat;
+#[cfg(feature = "kv-db")]
+pub mod kv;
 #[cfg(feature = "mem-db")]
 pub mod mem;
 
 pub mod readonly_mem;
 
-#[cfg(any(feature = "mem-db", feature = "flat-db"))]
+#[cfg(any(feature = "mem-db", feature = "flat-db", feature = "kv-db"))]
 fn flatten_to_io<T>(
     e: std::result::Result<std::io::Result<T>, tokio::task::JoinError>,
 ) -> std::io::Result<T> {
@@ -15,3 +17,64 @@ fn flatten_to_io<T>(
         Err(cause) => Err(std::io::Error::new(std::io::ErrorKind::Other, cause)),
     }
 }
+
+/// Synchronously compute hash and outboard of `size` bytes of data from a reader.
+///
+/// The outboard is `None` if the data is small enough to not need one.
+#[cfg(any(feature = "flat-db", feature = "kv-db"))]
+fn outboard_from_reader(
+    reader: impl std::io::Read,
+    size: u64,
+    progress: impl Fn(u64) -> std::io::Result<()> + Send + Sync + 'static,
+) -> std::io::Result<(iroh_bytes::Hash, Option<Vec<u8>>)> {
+    use bao_tree::io::outboard::PostOrderMemOutboard;
+    use iroh_bytes::IROH_BLOCK_SIZE;
+
+    // compute outboard size so we can pre-allocate the buffer.
+    let outboard_size = usize::try_from(bao_tree::io::outboard_size(size, IROH_BLOCK_SIZE))
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "size too large"))?;
+    let mut outboard = Vec::with_capacity(outboard_size);
+
+    // wrap the reader in a progress reader, so we can report progress.
+    let reader = ProgressReader2::new(reader, progress);
+    // wrap the reader in a buffered reader, so we read in large chunks
+    // this reduces the number of io ops and also the number of progress reports
+    let mut reader = std::io::BufReader::with_capacity(1024 * 1024, reader);
+
+    let hash =
+        bao_tree::io::sync::outboard_post_order(&mut reader, size, IROH_BLOCK_SIZE, &mut outboard)?;
+    let ob = PostOrderMemOutboard::load(hash, &outboard, IROH_BLOCK_SIZE)?.flip();
+    tracing::trace!(%hash, "done");
+    let ob = ob.into_inner();
+    let ob = if ob.len() > 8 { Some(ob) } else { None };
+    Ok((hash.into(), ob))
+}
+
+#[cfg(any(feature = "flat-db", feature = "kv-db"))]
+pub(crate) struct ProgressReader2<R, F: Fn(u64) -> std::io::Result<()>> {
+    inner: R,
+    offset: u64,
+    cb: F,
+}
+
+#[cfg(any(feature = "flat-db", feature = "kv-db"))]
+impl<R: std::io::Read, F: Fn(u64) -> std::io::Result<()>> ProgressReader2<R, F> {
+    #[allow(dead_code)]
+    pub fn new(inner: R, cb: F) -> Self {
+        Self {
+            inner,
+            offset: 0,
+            cb,
+        }
+    }
+}
+
+#[cfg(any(feature = "flat-db", feature = "kv-db"))]
+impl<R: std::io::Read, F: Fn(u64) -> std::io::Result<()>> std::io::Read for ProgressReader2<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.offset += read as u64;
+        (self.cb)(self.offset)?;
+        Ok(read)
+    }
+}
@@ -26,6 +26,37 @@
 //!
 //! These files can become quite large and make up the vast majority of the disk usage.
 //!
+//! ### Compressed data files
+//!
+//! If the store is loaded with [`Compression::Zstd`], complete data files are stored
+//! compressed, with the extension `.zdata` instead of `.data`. The hash is still the
+//! blake3 hash of the uncompressed data, so the outboard and the wire protocol are the
+//! same as for uncompressed data.
+//!
+//! The data is compressed in the zstd seekable format, with one zstd frame per chunk
+//! group of 16 KiB, so reads of a range of the data only decompress the chunk groups in
+//! that range.
+//!
+//! Compression only applies to new data, existing uncompressed data files are kept as
+//! they are. A store can always read both compressed and uncompressed files.
+//!
+//! ### Pack files
+//!
+//! If the store is loaded with a [`Settings::pack_threshold`], complete blobs smaller than
+//! the threshold are not stored in files of their own. Instead their data and outboard is
+//! appended to a pack file, which has as name a hex encoded 8 byte big endian id, and the
+//! extension `.pack`. Every pack file has an index file with the same id and the extension
+//! `.pidx`, to which a record is appended whenever a blob is added to or removed from the
+//! pack. See the `pack` module for the record format.
+//!
+//! New blobs are appended to the pack with the highest id, until it exceeds 256 MiB. Pack
+//! files are never rewritten, so removing packed blobs does not free disk space. Packed
+//! data is never compressed.
+//!
+//! Packing only applies to new data. Existing data files are kept as they are, and a store
+//! can always read both packed and unpacked blobs. If a blob is both in a data file and in
+//! a pack, the data file is used.
+//!
 //! ### Path files
 //!
 //! Path files have as name the hex encoded blake3 hash of the data, and the extension
@@ -88,6 +119,27 @@
 //! It is unusual but not impossible to have multiple partial data files for the same
 //! hash. In that case the best partial data file should be chosen on startup.
 //!
+//! ### Roots file
+//!
+//! The set of roots, i.e. hashes that are pinned and will be retained by garbage
+//! collection together with everything reachable from them, is stored in a meta file
+//! in the complete directory. The file has the name `726f6f7473.meta`, which is the hex
+//! encoded string `roots`, and contains a postcard serialized sorted set of hashes.
+//!
+//! The file is written to a temp file and then atomically renamed whenever the set of
+//! roots changes.
+//!
+//! Stores created before roots existed have no roots file. When it is missing on load,
+//! every complete entry is considered a root, and the roots file is written, so that
+//! garbage collection does not delete data that was added before.
+//!
+//! ### Tags file
+//!
+//! Tags, i.e. named roots, are stored in a meta file in the complete directory with the
+//! name `74616773.meta`, which is the hex encoded string `tags`. It contains a postcard
+//! serialized sorted map from tag name to the tagged hash and the creation time of the
+//! tag. It is written in the same way as the roots file.
+//!
 //! ### Temp files
 //!
 //! When copying data into the database, we first copy the data into a temporary file to
@@ -126,9 +178,10 @@ use std::fmt;
 use std::io::{self, BufReader};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
 
-use bao_tree::io::outboard::{PostOrderMemOutboard, PreOrderOutboard};
+use bao_tree::io::outboard::PreOrderOutboard;
 use bao_tree::io::sync::ReadAt;
 use bao_tree::{blake3, ChunkNum};
 use bao_tree::{BaoTree, ByteNum};
@@ -139,16 +192,22 @@ use futures::{Future, FutureExt};
 use iroh_bytes::baomap::range_collections::RangeSet2;
 use iroh_bytes::baomap::{
     self, ExportMode, ImportMode, ImportProgress, Map, MapEntry, PartialMap, PartialMapEntry,
-    ReadableStore, ValidateProgress,
+    ReadableStore, TagInfo, ValidateProgress,
 };
 use iroh_bytes::util::progress::{IdGenerator, ProgressSender};
 use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
 use iroh_io::{AsyncSliceReader, AsyncSliceWriter, File};
 use rand::Rng;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::sync::mpsc;
 use tracing::trace_span;
 
-use super::flatten_to_io;
+use super::{flatten_to_io, outboard_from_reader};
+
+mod compressed;
+mod pack;
+pub use compressed::CompressedFile;
+use pack::{PackLocation, PackWriter};
 
 #[derive(Debug, Default)]
 struct State {
@@ -160,6 +219,10 @@ struct State {
     outboard: BTreeMap<Hash, Bytes>,
     // data, cached for all complete entries that are small enough
     data: BTreeMap<Hash, Bytes>,
+    // pinned roots
+    roots: BTreeSet<Hash>,
+    // named roots
+    tags: BTreeMap<String, TagInfo>,
 }
 
 #[derive(Debug, Default)]
@@ -168,6 +231,10 @@ struct CompleteEntry {
     size: u64,
     // true means we own the data, false means it is stored externally
     owned_data: bool,
+    // true means the owned data is stored compressed
+    compressed: bool,
+    // where the owned data is stored if it is packed
+    packed: Option<PackLocation>,
     // external storage locations
     external: BTreeSet<PathBuf>,
 }
@@ -184,9 +251,22 @@ impl CompleteEntry {
     // create a new complete entry with the given size
     //
     // the generated entry will have no data or outboard data yet
-    fn new_default(size: u64) -> Self {
+    fn new_default(size: u64, compressed: bool) -> Self {
         Self {
             owned_data: true,
+            compressed,
+            packed: None,
+            external: Default::default(),
+            size,
+        }
+    }
+
+    // create a new complete entry with the given size, for data in a pack
+    fn new_packed(size: u64, location: PackLocation) -> Self {
+        Self {
+            owned_data: true,
+            compressed: false,
+            packed: Some(location),
             external: Default::default(),
             size,
         }
@@ -198,24 +278,35 @@ impl CompleteEntry {
     fn new_external(size: u64, path: PathBuf) -> Self {
         Self {
             owned_data: false,
+            compressed: false,
+            packed: None,
             external: [path].into_iter().collect(),
             size,
         }
     }
 
-    #[allow(dead_code)]
     fn is_valid(&self) -> bool {
         !self.external.is_empty() || self.owned_data
     }
 
-    fn union_with(&mut self, new: CompleteEntry) -> io::Result<()> {
+    /// Merge `new` into this entry.
+    ///
+    /// Returns the pack location the owned data moved out of, if any. Its pack record must
+    /// be removed, otherwise the pack brings the blob back after it is deleted.
+    fn union_with(&mut self, new: CompleteEntry) -> io::Result<Option<PackLocation>> {
         if self.size != 0 && self.size != new.size {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "size mismatch"));
         }
         self.size = new.size;
+        let mut unpacked = None;
+        if new.owned_data {
+            self.compressed = new.compressed;
+            unpacked = std::mem::replace(&mut self.packed, new.packed)
+                .filter(|location| Some(*location) != new.packed);
+        }
         self.owned_data |= new.owned_data;
         self.external.extend(new.external.into_iter());
-        Ok(())
+        Ok(unpacked)
     }
 }
 
@@ -339,24 +430,42 @@ impl PartialMap for Store {
 
     fn insert_complete(&self, entry: Self::PartialEntry) -> BoxFuture<'_, io::Result<()>> {
         let hash = entry.hash.into();
-        let data_path = self.0.options.owned_data_path(&hash);
         async move {
             let size = entry.size;
             let temp_data_path = entry.data_path;
             let temp_outboard_path = entry.outboard_path;
             // for a short time we will have neither partial nor complete
             self.0.state.write().unwrap().partial.remove(&hash);
-            tokio::fs::rename(temp_data_path, &data_path).await?;
             let outboard = if tokio::fs::try_exists(&temp_outboard_path).await? {
-                let outboard_path = self.0.options.owned_outboard_path(&hash);
-                tokio::fs::rename(temp_outboard_path, &outboard_path).await?;
-                Some(tokio::fs::read(&outboard_path).await?.into())
+                Some(Bytes::from(tokio::fs::read(&temp_outboard_path).await?))
             } else {
                 None
             };
+            let this = self.clone();
+            let outboard2 = outboard.clone();
+            let new = self
+                .0
+                .options
+                .rt
+                .spawn_blocking(move || {
+                    this.write_owned_data(&hash, size, &temp_data_path, outboard2.as_deref())
+                })
+                .map(flatten_to_io)
+                .await?;
+            if outboard.is_some() {
+                if new.packed.is_some() {
+                    // the outboard is in the pack as well
+                    tokio::fs::remove_file(temp_outboard_path).await?;
+                } else {
+                    let outboard_path = self.0.options.owned_outboard_path(&hash);
+                    tokio::fs::rename(temp_outboard_path, &outboard_path).await?;
+                }
+            }
             let mut state = self.0.state.write().unwrap();
             let entry = state.complete.entry(hash).or_default();
-            entry.union_with(CompleteEntry::new_default(size))?;
+            if let Some(location) = entry.union_with(new)? {
+                self.0.packs.lock().unwrap().remove(&hash, location)?;
+            }
             if let Some(outboard) = outboard {
                 state.outboard.insert(hash, outboard);
             }
@@ -372,9 +481,34 @@ struct Options {
     partial_path: PathBuf,
     move_threshold: u64,
     inline_threshold: u64,
+    compression: Compression,
+    pack_threshold: u64,
     rt: tokio::runtime::Handle,
 }
 
+/// Settings for how a [`Store`] stores new data.
+///
+/// See the [module docs](self) for details.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Settings {
+    /// Compression of new data files.
+    pub compression: Compression,
+    /// New blobs smaller than this are stored in pack files. 0 disables packing.
+    pub pack_threshold: u64,
+}
+
+/// Compression of the data files of a [`Store`].
+///
+/// See the [module docs](self) for details.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Store data uncompressed.
+    #[default]
+    None,
+    /// Compress data with zstd at the given level.
+    Zstd(i32),
+}
+
 impl Options {
     fn partial_data_path(&self, hash: Hash, uuid: &[u8; 16]) -> PathBuf {
         self.partial_path
@@ -390,6 +524,11 @@ impl Options {
         self.complete_path.join(FileName::Data(*hash).to_string())
     }
 
+    fn compressed_data_path(&self, hash: &Hash) -> PathBuf {
+        self.complete_path
+            .join(FileName::CompressedData(*hash).to_string())
+    }
+
     fn owned_outboard_path(&self, hash: &Hash) -> PathBuf {
         self.complete_path
             .join(FileName::Outboard(*hash).to_string())
@@ -398,12 +537,33 @@ impl Options {
     fn paths_path(&self, hash: Hash) -> PathBuf {
         self.complete_path.join(FileName::Paths(hash).to_string())
     }
+
+    fn pack_path(&self, pack: u64) -> PathBuf {
+        self.complete_path.join(FileName::Pack(pack).to_string())
+    }
+
+    fn meta_path(&self, name: &[u8]) -> PathBuf {
+        self.complete_path
+            .join(FileName::Meta(name.to_vec()).to_string())
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let uuid = rand::thread_rng().gen::<[u8; 16]>();
+        self.complete_path
+            .join(format!("{}.temp", hex::encode(uuid)))
+    }
 }
 
+/// Name of the meta file that contains the roots, see the module docs.
+const ROOTS_META: &[u8] = b"roots";
+/// Name of the meta file that contains the tags, see the module docs.
+const TAGS_META: &[u8] = b"tags";
+
 #[derive(Debug)]
 struct Inner {
     options: Options,
     state: RwLock<State>,
+    packs: Mutex<PackWriter>,
 }
 
 /// Flat file database implementation.
@@ -463,10 +623,23 @@ impl MapEntry<Store> for Entry {
 struct EntryData {
     /// The data itself.
     data: Either<Bytes, (PathBuf, u64)>,
+    /// How the data is stored in the data file.
+    format: DataFormat,
     /// The bao outboard data.
     outboard: Either<Bytes, PathBuf>,
 }
 
+/// How the data of an entry is stored in its data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataFormat {
+    /// The file contains just the data
+    Plain,
+    /// The file is compressed, see [`Compression`]
+    Compressed,
+    /// The data is at the given offset in a pack file
+    Packed(u64),
+}
+
 /// A reader for either a file or a byte slice.
 ///
 /// This is used to read small data from memory, and large data from disk.
@@ -476,30 +649,40 @@ pub enum MemOrFile {
     Mem(Bytes),
     /// An iroh_io::File
     File(File),
+    /// A compressed file
+    Compressed(CompressedFile),
 }
 
 impl AsyncSliceReader for MemOrFile {
     type ReadAtFuture<'a> = futures::future::Either<
-        <Bytes as AsyncSliceReader>::ReadAtFuture<'a>,
-        <File as AsyncSliceReader>::ReadAtFuture<'a>,
+        futures::future::Either<
+            <Bytes as AsyncSliceReader>::ReadAtFuture<'a>,
+            <File as AsyncSliceReader>::ReadAtFuture<'a>,
+        >,
+        <CompressedFile as AsyncSliceReader>::ReadAtFuture<'a>,
     >;
 
     fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
         match self {
-            MemOrFile::Mem(mem) => Either::Left(mem.read_at(offset, len)),
-            MemOrFile::File(file) => Either::Right(file.read_at(offset, len)),
+            MemOrFile::Mem(mem) => Either::Left(Either::Left(mem.read_at(offset, len))),
+            MemOrFile::File(file) => Either::Left(Either::Right(file.read_at(offset, len))),
+            MemOrFile::Compressed(file) => Either::Right(file.read_at(offset, len)),
         }
     }
 
     type LenFuture<'a> = futures::future::Either<
-        <Bytes as AsyncSliceReader>::LenFuture<'a>,
-        <File as AsyncSliceReader>::LenFuture<'a>,
+        futures::future::Either<
+            <Bytes as AsyncSliceReader>::LenFuture<'a>,
+            <File as AsyncSliceReader>::LenFuture<'a>,
+        >,
+        <CompressedFile as AsyncSliceReader>::LenFuture<'a>,
     >;
 
     fn len(&mut self) -> Self::LenFuture<'_> {
         match self {
-            MemOrFile::Mem(mem) => Either::Left(mem.len()),
-            MemOrFile::File(file) => Either::Right(file.len()),
+            MemOrFile::Mem(mem) => Either::Left(Either::Left(mem.len())),
+            MemOrFile::File(file) => Either::Left(Either::Right(file.len())),
+            MemOrFile::Compressed(file) => Either::Right(file.len()),
         }
     }
 }
@@ -519,10 +702,28 @@ impl EntryData {
     /// A reader for the data.
     pub fn data_reader(&self) -> impl Future<Output = io::Result<MemOrFile>> + 'static {
         let data = self.data.clone();
+        let format = self.format;
         async move {
-            Ok(match data {
-                Either::Left(mem) => MemOrFile::Mem(mem),
-                Either::Right((path, _)) => MemOrFile::File(File::open(path).await?),
+            Ok(match (data, format) {
+                (Either::Left(mem), _) => MemOrFile::Mem(mem),
+                (Either::Right((path, _)), DataFormat::Plain) => {
+                    MemOrFile::File(File::open(path).await?)
+                }
+                (Either::Right((path, _)), DataFormat::Compressed) => {
+                    MemOrFile::Compressed(CompressedFile::open(File::open(path).await?).await?)
+                }
+                (Either::Right((path, size)), DataFormat::Packed(offset)) => {
+                    // packed data is small, so just read all of it
+                    let mut file = File::open(path).await?;
+                    let data = file.read_at(offset, size as usize).await?;
+                    if (data.len() as u64) < size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "pack file too short",
+                        ));
+                    }
+                    MemOrFile::Mem(data)
+                }
             })
         }
     }
@@ -559,24 +760,18 @@ impl Map for Store {
             tracing::trace!("got complete: {} {}", hash, entry.size);
             let outboard = state.load_outboard(entry.size, hash)?;
             // check if we have the data cached
-            let data = state.data.get(hash).cloned();
+            let (data, format) = if let Some(data) = state.data.get(hash) {
+                (Either::Left(data.clone()), DataFormat::Plain)
+            } else {
+                // get the data path. if we don't have any we don't have a valid entry
+                let (path, format) = self.data_path(hash, entry)?;
+                (Either::Right((path, entry.size)), format)
+            };
             Some(Entry {
                 hash: blake3::Hash::from(*hash),
                 entry: EntryData {
-                    data: if let Some(data) = data {
-                        Either::Left(data)
-                    } else {
-                        // get the data path
-                        let path = if entry.owned_data {
-                            // use the path for the data in the default location
-                            self.owned_data_path(hash)
-                        } else {
-                            // use the first external path. if we don't have any
-                            // we don't have a valid entry
-                            entry.external_path()?.clone()
-                        };
-                        Either::Right((path, entry.size))
-                    },
+                    data,
+                    format,
                     outboard: Either::Left(outboard),
                 },
             })
@@ -593,6 +788,7 @@ impl Map for Store {
                 hash: blake3::Hash::from(*hash),
                 entry: EntryData {
                     data: Either::Right((data_path, entry.size)),
+                    format: DataFormat::Plain,
                     outboard: Either::Right(outboard_path),
                 },
             })
@@ -611,11 +807,29 @@ impl ReadableStore for Store {
     }
 
     fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
-        unimplemented!()
+        let inner = self.0.state.read().unwrap();
+        let items = inner.roots.iter().copied().collect::<Vec<_>>();
+        Box::new(items.into_iter())
+    }
+
+    fn tags(&self) -> Box<dyn Iterator<Item = (String, TagInfo)> + Send + Sync + 'static> {
+        let inner = self.0.state.read().unwrap();
+        let items = inner
+            .tags
+            .iter()
+            .map(|(name, info)| (name.clone(), *info))
+            .collect::<Vec<_>>();
+        Box::new(items.into_iter())
     }
 
-    fn validate(&self, _tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
-        unimplemented!()
+    fn validate(&self, tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
+        let this = self.clone();
+        let task = self
+            .0
+            .options
+            .rt
+            .spawn_blocking(move || this.validate_sync(tx));
+        async move { task.await? }.boxed()
     }
 
     fn partial_blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
@@ -666,6 +880,57 @@ impl baomap::Store for Store {
             .map(flatten_to_io)
             .boxed()
     }
+
+    fn pin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.set_pinned_sync(hash, true))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn unpin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.set_pinned_sync(hash, false))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn set_tag(&self, name: String, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.set_tag_sync(name, Some(hash)))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn delete_tag(&self, name: String) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.set_tag_sync(name, None))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn delete(&self, hash: &Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        let hash = *hash;
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.delete_sync(hash))
+            .map(flatten_to_io)
+            .boxed()
+    }
 }
 
 impl State {
@@ -707,6 +972,11 @@ impl Store {
             path: path.clone(),
         })?;
         let (hash, new, outboard) = match mode {
+            ImportMode::Chunked => {
+                return iroh_bytes::chunked::import_file(&path, id, &progress, |data| {
+                    self.import_bytes_sync(data)
+                });
+            }
             ImportMode::TryReference => {
                 // compute outboard and hash from the data in place, since we assume that it is stable
                 let size = path.metadata()?.len();
@@ -740,20 +1010,25 @@ impl Store {
                     Ok(progress2.try_send(ImportProgress::OutboardProgress { id, offset })?)
                 })?;
                 progress.blocking_send(ImportProgress::OutboardDone { id, hash })?;
-                let data_path = self.owned_data_path(&hash);
-                std::fs::rename(temp_data_path, data_path)?;
-                (hash, CompleteEntry::new_default(size), outboard)
+                let new =
+                    self.write_owned_data(&hash, size, &temp_data_path, outboard.as_deref())?;
+                (hash, new, outboard)
             }
         };
         if let Some(outboard) = outboard.as_ref() {
-            let outboard_path = self.owned_outboard_path(&hash);
-            std::fs::write(outboard_path, outboard)?;
+            // packed outboards are stored in the pack
+            if new.packed.is_none() {
+                let outboard_path = self.owned_outboard_path(&hash);
+                std::fs::write(outboard_path, outboard)?;
+            }
         }
         let size = new.size;
         let mut state = self.0.state.write().unwrap();
         let entry = state.complete.entry(hash).or_default();
         let n = entry.external.len();
-        entry.union_with(new)?;
+        if let Some(location) = entry.union_with(new)? {
+            self.0.packs.lock().unwrap().remove(&hash, location)?;
+        }
         if entry.external.len() != n {
             let path = self.0.options.paths_path(hash);
             std::fs::write(path, entry.external_to_bytes())?;
@@ -767,16 +1042,33 @@ impl Store {
     fn import_bytes_sync(&self, data: Bytes) -> io::Result<Hash> {
         let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
         let hash = hash.into();
-        let data_path = self.owned_data_path(&hash);
-        std::fs::write(data_path, &data)?;
-        if outboard.len() > 8 {
-            let outboard_path = self.owned_outboard_path(&hash);
-            std::fs::write(outboard_path, &outboard)?;
-        }
         let size = data.len() as u64;
+        let new = if size < self.0.options.pack_threshold {
+            self.write_packed_data(&hash, &data, Some(&outboard))?
+        } else {
+            let compressed = match self.0.options.compression {
+                Compression::None => {
+                    std::fs::write(self.owned_data_path(&hash), &data)?;
+                    remove_file_if_exists(&self.0.options.compressed_data_path(&hash))?;
+                    false
+                }
+                Compression::Zstd(level) => {
+                    self.compress_owned_data(&hash, &data[..], level)?;
+                    remove_file_if_exists(&self.owned_data_path(&hash))?;
+                    true
+                }
+            };
+            if outboard.len() > 8 {
+                let outboard_path = self.owned_outboard_path(&hash);
+                std::fs::write(outboard_path, &outboard)?;
+            }
+            CompleteEntry::new_default(size, compressed)
+        };
         let mut state = self.0.state.write().unwrap();
         let entry = state.complete.entry(hash).or_default();
-        entry.union_with(CompleteEntry::new_default(size))?;
+        if let Some(location) = entry.union_with(new)? {
+            self.0.packs.lock().unwrap().remove(&hash, location)?;
+        }
         state.outboard.insert(hash, outboard.into());
         if size < self.0.options.inline_threshold {
             state.data.insert(hash, data.to_vec().into());
@@ -784,6 +1076,182 @@ impl Store {
         Ok(hash)
     }
 
+    /// Move the complete data in `temp_path` into place as the owned data for `hash`.
+    ///
+    /// Small data is appended to a pack file, together with the outboard. Other data is
+    /// compressed if compression is enabled. Returns the new entry.
+    fn write_owned_data(
+        &self,
+        hash: &Hash,
+        size: u64,
+        temp_path: &Path,
+        outboard: Option<&[u8]>,
+    ) -> io::Result<CompleteEntry> {
+        if size < self.0.options.pack_threshold {
+            let data = std::fs::read(temp_path)?;
+            let new = self.write_packed_data(hash, &data, outboard)?;
+            std::fs::remove_file(temp_path)?;
+            return Ok(new);
+        }
+        let compressed = match self.0.options.compression {
+            Compression::None => {
+                std::fs::rename(temp_path, self.owned_data_path(hash))?;
+                remove_file_if_exists(&self.0.options.compressed_data_path(hash))?;
+                false
+            }
+            Compression::Zstd(level) => {
+                let file = BufReader::new(std::fs::File::open(temp_path)?);
+                self.compress_owned_data(hash, file, level)?;
+                std::fs::remove_file(temp_path)?;
+                remove_file_if_exists(&self.owned_data_path(hash))?;
+                true
+            }
+        };
+        Ok(CompleteEntry::new_default(size, compressed))
+    }
+
+    /// Append data and outboard for `hash` to a pack, unless they are already packed.
+    fn write_packed_data(
+        &self,
+        hash: &Hash,
+        data: &[u8],
+        outboard: Option<&[u8]>,
+    ) -> io::Result<CompleteEntry> {
+        let size = data.len() as u64;
+        let existing = {
+            let state = self.0.state.read().unwrap();
+            let entry = state.complete.get(hash);
+            entry
+                .filter(|entry| entry.owned_data)
+                .and_then(|entry| entry.packed)
+        };
+        let location = match existing {
+            Some(location) => location,
+            None => {
+                // small outboards just contain the size, and are not stored
+                let outboard = outboard
+                    .filter(|_| needs_outboard(size))
+                    .unwrap_or_default();
+                self.0.packs.lock().unwrap().append(hash, data, outboard)?
+            }
+        };
+        remove_file_if_exists(&self.owned_data_path(hash))?;
+        remove_file_if_exists(&self.0.options.compressed_data_path(hash))?;
+        Ok(CompleteEntry::new_packed(size, location))
+    }
+
+    /// Compress data into the compressed data file for `hash`.
+    ///
+    /// The data is compressed into a temp file first, so the data file never contains
+    /// partial data.
+    fn compress_owned_data(&self, hash: &Hash, data: impl io::Read, level: i32) -> io::Result<()> {
+        let temp_path = self.0.options.temp_path();
+        let file = io::BufWriter::new(std::fs::File::create(&temp_path)?);
+        if let Err(e) = compressed::compress(data, file, level) {
+            std::fs::remove_file(&temp_path).ok();
+            return Err(e);
+        }
+        std::fs::rename(temp_path, self.0.options.compressed_data_path(hash))
+    }
+
+    /// The file to read the data of a complete entry from, and how the data is stored in it.
+    fn data_path(&self, hash: &Hash, entry: &CompleteEntry) -> Option<(PathBuf, DataFormat)> {
+        if !entry.owned_data {
+            // use the first external path
+            Some((entry.external_path()?.clone(), DataFormat::Plain))
+        } else if let Some(location) = entry.packed {
+            let path = self.0.options.pack_path(location.pack);
+            Some((path, DataFormat::Packed(location.offset)))
+        } else if entry.compressed {
+            let path = self.0.options.compressed_data_path(hash);
+            Some((path, DataFormat::Compressed))
+        } else {
+            // use the path for the data in the default location
+            Some((self.owned_data_path(hash), DataFormat::Plain))
+        }
+    }
+
+    fn validate_sync(&self, tx: mpsc::Sender<ValidateProgress>) -> anyhow::Result<()> {
+        let entries = {
+            let state = self.0.state.read().unwrap();
+            state
+                .complete
+                .iter()
+                .map(|(hash, entry)| (*hash, entry.size, self.data_path(hash, entry)))
+                .collect::<Vec<_>>()
+        };
+        tx.blocking_send(ValidateProgress::Starting {
+            total: entries.len() as u64,
+        })?;
+        for (id, (hash, size, source)) in entries.into_iter().enumerate() {
+            let id = id as u64;
+            tx.blocking_send(ValidateProgress::Entry {
+                id,
+                hash,
+                path: source.as_ref().map(|(path, _)| path.display().to_string()),
+                size,
+            })?;
+            let result = match source {
+                Some((path, format)) => {
+                    let tx = tx.clone();
+                    self.validate_entry(hash, size, &path, format, move |offset| {
+                        // progress is best effort
+                        tx.try_send(ValidateProgress::Progress { id, offset }).ok();
+                        Ok(())
+                    })
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no data file for entry",
+                )),
+            };
+            let error = result.err().map(|e| e.to_string());
+            tx.blocking_send(ValidateProgress::Done { id, error })?;
+        }
+        tx.blocking_send(ValidateProgress::AllDone)?;
+        Ok(())
+    }
+
+    /// Check that the data file of a complete entry matches its hash and outboard.
+    fn validate_entry(
+        &self,
+        hash: Hash,
+        size: u64,
+        path: &Path,
+        format: DataFormat,
+        progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
+    ) -> io::Result<()> {
+        let (actual_size, reader): (u64, Box<dyn io::Read>) = match format {
+            DataFormat::Plain => {
+                let file = std::fs::File::open(path)?;
+                (file.metadata()?.len(), Box::new(file))
+            }
+            DataFormat::Compressed => {
+                let file = std::fs::File::open(path)?;
+                let table = compressed::SeekTable::read_sync(&file)?;
+                (table.size(), Box::new(compressed::decompress(file)?))
+            }
+            DataFormat::Packed(offset) => (size, Box::new(pack::reader(path, offset, size)?)),
+        };
+        if actual_size != size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "size mismatch"));
+        }
+        let (actual_hash, outboard) = outboard_from_reader(reader, size, progress)?;
+        if actual_hash != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "hash mismatch"));
+        }
+        let expected = self.0.state.read().unwrap().load_outboard(size, &hash);
+        if let (Some(outboard), Some(expected)) = (outboard, expected) {
+            if outboard != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "outboard mismatch",
+                ));
+            }
+        }
+        Ok(())
+    }
+
     fn export_sync(
         &self,
         hash: Hash,
@@ -807,27 +1275,22 @@ impl Store {
         })?;
         // create the directory in which the target file is
         std::fs::create_dir_all(parent)?;
-        let (source, size, owned) = {
+        let (source, format, size, owned) = {
             let state = self.0.state.read().unwrap();
             let entry = state.complete.get(&hash).ok_or_else(|| {
                 io::Error::new(io::ErrorKind::NotFound, "hash not found in database")
             })?;
-            let source = if entry.owned_data {
-                self.owned_data_path(&hash)
-            } else {
-                entry
-                    .external
-                    .iter()
-                    .next()
-                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no valid path found"))?
-                    .clone()
-            };
+            let (source, format) = self
+                .data_path(&hash, entry)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no valid path found"))?;
             let size = entry.size;
-            (source, size, entry.owned_data)
+            (source, format, size, entry.owned_data)
         };
         // copy all the things
         let stable = mode == ExportMode::TryReference;
-        let path_bytes = if size >= self.0.options.move_threshold && stable && owned {
+        // compressed and packed data can not be moved, since the target needs just the data
+        let movable = format == DataFormat::Plain;
+        let path_bytes = if size >= self.0.options.move_threshold && stable && owned && movable {
             tracing::info!("moving {} to {}", source.display(), target.display());
             if let Err(e) = std::fs::rename(source, &target) {
                 tracing::error!("rename failed: {}", e);
@@ -847,7 +1310,18 @@ impl Store {
             tracing::info!("copying {} to {}", source.display(), target.display());
             progress(0)?;
             // todo: progress
-            std::fs::copy(&source, &target)?;
+            match format {
+                DataFormat::Plain => {
+                    std::fs::copy(&source, &target)?;
+                }
+                DataFormat::Compressed => {
+                    let reader = compressed::decompress(std::fs::File::open(&source)?)?;
+                    write_file(reader, &target)?;
+                }
+                DataFormat::Packed(offset) => {
+                    write_file(pack::reader(&source, offset, size)?, &target)?;
+                }
+            }
             progress(size)?;
             let mut state = self.0.state.write().unwrap();
             let Some(entry) = state.complete.get_mut(&hash) else {
@@ -870,10 +1344,87 @@ impl Store {
         Ok(())
     }
 
+    fn set_pinned_sync(&self, hash: Hash, pinned: bool) -> io::Result<()> {
+        // keep the lock while writing, so concurrent changes are persisted in order
+        let mut state = self.0.state.write().unwrap();
+        let changed = if pinned {
+            state.roots.insert(hash)
+        } else {
+            state.roots.remove(&hash)
+        };
+        if changed {
+            self.persist_meta(ROOTS_META, &state.roots)?;
+        }
+        Ok(())
+    }
+
+    fn set_tag_sync(&self, name: String, hash: Option<Hash>) -> io::Result<()> {
+        let mut state = self.0.state.write().unwrap();
+        let changed = if let Some(hash) = hash {
+            let created = SystemTime::now();
+            state.tags.insert(name, TagInfo { hash, created });
+            true
+        } else {
+            state.tags.remove(&name).is_some()
+        };
+        if changed {
+            self.persist_meta(TAGS_META, &state.tags)?;
+        }
+        Ok(())
+    }
+
+    /// Write a meta file such as the roots or tags file, see the module docs.
+    ///
+    /// Must be called with the state write lock held.
+    fn persist_meta(&self, name: &[u8], value: &impl Serialize) -> io::Result<()> {
+        let data = postcard::to_stdvec(value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let temp_path = self.0.options.temp_path();
+        std::fs::write(&temp_path, data)?;
+        std::fs::rename(temp_path, self.0.options.meta_path(name))
+    }
+
+    fn delete_sync(&self, hash: Hash) -> io::Result<()> {
+        let mut state = self.0.state.write().unwrap();
+        if let Some(entry) = state.complete.remove(&hash) {
+            tracing::info!("deleting complete {}", hash);
+            if entry.owned_data {
+                remove_file_if_exists(&self.owned_data_path(&hash))?;
+                remove_file_if_exists(&self.0.options.compressed_data_path(&hash))?;
+            }
+            if let Some(location) = entry.packed {
+                self.0.packs.lock().unwrap().remove(&hash, location)?;
+            }
+            if !entry.external.is_empty() {
+                // only remove the paths file, the external files belong to the user
+                remove_file_if_exists(&self.paths_path(hash))?;
+            }
+            remove_file_if_exists(&self.owned_outboard_path(&hash))?;
+        }
+        if let Some(entry) = state.partial.remove(&hash) {
+            tracing::info!("deleting partial {}", hash);
+            let options = &self.0.options;
+            remove_file_if_exists(&options.partial_data_path(hash, &entry.uuid))?;
+            remove_file_if_exists(&options.partial_outboard_path(hash, &entry.uuid))?;
+        }
+        state.outboard.remove(&hash);
+        state.data.remove(&hash);
+        if state.roots.remove(&hash) {
+            self.persist_meta(ROOTS_META, &state.roots)?;
+        }
+        let n = state.tags.len();
+        state.tags.retain(|_, info| info.hash != hash);
+        if state.tags.len() != n {
+            self.persist_meta(TAGS_META, &state.tags)?;
+        }
+        Ok(())
+    }
+
     /// scan a directory for data
     pub(crate) fn load_sync(
         complete_path: PathBuf,
         partial_path: PathBuf,
+        settings: Settings,
         rt: iroh_bytes::util::runtime::Handle,
     ) -> anyhow::Result<Self> {
         tracing::info!(
@@ -884,7 +1435,8 @@ impl Store {
         let mut partial_index =
             BTreeMap::<Hash, BTreeMap<[u8; 16], (Option<PathBuf>, Option<PathBuf>)>>::new();
         let mut full_index =
-            BTreeMap::<Hash, (Option<PathBuf>, Option<PathBuf>, Option<PathBuf>)>::new();
+            BTreeMap::<Hash, (Option<(PathBuf, bool)>, Option<PathBuf>, Option<PathBuf>)>::new();
+        let mut pack_index = BTreeMap::<u64, (Option<PathBuf>, Option<PathBuf>)>::new();
         let mut outboard = BTreeMap::new();
         for entry in std::fs::read_dir(&partial_path)? {
             let entry = entry?;
@@ -934,7 +1486,11 @@ impl Store {
                     match purpose {
                         FileName::Data(hash) => {
                             let (data, _, _) = full_index.entry(hash).or_default();
-                            *data = Some(path);
+                            *data = Some((path, false));
+                        }
+                        FileName::CompressedData(hash) => {
+                            let (data, _, _) = full_index.entry(hash).or_default();
+                            *data = Some((path, true));
                         }
                         FileName::Outboard(hash) => {
                             let (_, outboard, _) = full_index.entry(hash).or_default();
@@ -944,6 +1500,14 @@ impl Store {
                             let (_, _, paths) = full_index.entry(hash).or_default();
                             *paths = Some(path);
                         }
+                        FileName::Pack(id) => {
+                            let (pack, _) = pack_index.entry(id).or_default();
+                            *pack = Some(path);
+                        }
+                        FileName::PackIndex(id) => {
+                            let (_, index) = pack_index.entry(id).or_default();
+                            *index = Some(path);
+                        }
                         _ => {
                             // silently ignore other files, there could be a valid reason for them
                         }
@@ -961,12 +1525,24 @@ impl Store {
                 Default::default()
             };
             let owned_data = data_path.is_some();
-            let size = if let Some(data_path) = &data_path {
-                let Ok(meta) = std::fs::metadata(data_path) else {
-                    tracing::warn!("unable to open owned data file {}. removing {}", data_path.display(), hex::encode(hash));
-                    continue
+            let compressed = matches!(data_path, Some((_, true)));
+            let size = if let Some((data_path, compressed)) = &data_path {
+                let size = if *compressed {
+                    std::fs::File::open(data_path)
+                        .and_then(|file| compressed::SeekTable::read_sync(&file))
+                        .map(|table| table.size())
+                } else {
+                    std::fs::metadata(data_path).map(|meta| meta.len())
                 };
-                meta.len()
+                let Ok(size) = size else {
+                    tracing::warn!(
+                        "unable to open owned data file {}. removing {}",
+                        data_path.display(),
+                        hex::encode(hash)
+                    );
+                    continue;
+                };
+                size
             } else if let Some(external) = external.iter().next() {
                 let Ok(meta) = std::fs::metadata(external) else {
                     tracing::warn!("unable to open external data file {}. removing {}", external.display(), hex::encode(hash));
@@ -994,11 +1570,69 @@ impl Store {
                 hash,
                 CompleteEntry {
                     owned_data,
+                    compressed,
+                    packed: None,
                     external,
                     size,
                 },
             );
         }
+        // add the packed entries, unless we have a data file for them
+        let mut current_pack = None;
+        // records of blobs that also have a data file, after a crash while unpacking them
+        let mut stale_records = Vec::new();
+        for (id, (pack_path, index_path)) in pack_index {
+            let (Some(pack_path), Some(index_path)) = (pack_path, index_path) else {
+                tracing::warn!("missing pack or pack index file for pack {:016x}", id);
+                continue;
+            };
+            current_pack = Some(id);
+            let records = std::fs::metadata(&pack_path)
+                .and_then(|meta| pack::read_index(&index_path, id, meta.len()));
+            let records = match records {
+                Ok(records) => records,
+                Err(e) => {
+                    tracing::warn!("skipping unreadable pack {:016x}: {}", id, e);
+                    continue;
+                }
+            };
+            for (hash, record) in records {
+                if complete.get(&hash).map_or(false, |entry| entry.owned_data) {
+                    // tombstone the record, so the blob does not come back after a delete
+                    stale_records.push((hash, record.location));
+                    continue;
+                }
+                if needs_outboard(record.size) && !outboard.contains_key(&hash) {
+                    if record.outboard_size == 0 {
+                        tracing::error!("missing packed outboard for {}", hex::encode(hash));
+                        continue;
+                    }
+                    let offset = record.location.offset + record.size;
+                    let data = match pack::read_sync(&pack_path, offset, record.outboard_size) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            tracing::warn!(
+                                "skipping unreadable packed outboard for {}: {}",
+                                hex::encode(hash),
+                                e
+                            );
+                            continue;
+                        }
+                    };
+                    outboard.insert(hash, data.into());
+                }
+                let entry = complete.entry(hash).or_insert_with(|| CompleteEntry {
+                    size: record.size,
+                    ..Default::default()
+                });
+                if let Err(e) =
+                    entry.union_with(CompleteEntry::new_packed(record.size, record.location))
+                {
+                    tracing::error!("invalid packed entry {}: {}", hex::encode(hash), e);
+                }
+            }
+        }
+        complete.retain(|_, entry| entry.is_valid());
         // retain only entries for which we have both outboard and data
         partial_index.retain(|hash, entries| {
             entries.retain(|uuid, (data, outboard)| match (data, outboard) {
@@ -1087,21 +1721,51 @@ impl Store {
         for hash in partial.keys() {
             tracing::info!("partial {}", hash);
         }
-        Ok(Self(Arc::new(Inner {
+        // a store without a roots file predates roots, keep everything that is in it
+        let roots_path = complete_path.join(FileName::Meta(ROOTS_META.to_vec()).to_string());
+        let migrate_roots = !roots_path.exists();
+        let roots: BTreeSet<Hash> = if migrate_roots {
+            complete.keys().copied().collect()
+        } else {
+            load_meta(&complete_path, ROOTS_META)?
+        };
+        let tags: BTreeMap<String, TagInfo> = load_meta(&complete_path, TAGS_META)?;
+        let mut packs = PackWriter::new(complete_path.clone(), current_pack);
+        for (hash, location) in stale_records {
+            if let Err(e) = packs.remove(&hash, location) {
+                tracing::warn!("unable to remove stale pack record for {}: {}", hash, e);
+            }
+        }
+        let db = Self(Arc::new(Inner {
             state: RwLock::new(State {
                 complete,
                 partial,
                 outboard,
                 data: Default::default(),
+                roots,
+                tags,
             }),
             options: Options {
                 complete_path,
                 partial_path,
                 move_threshold: 1024 * 128,
                 inline_threshold: 1024 * 16,
+                compression: settings.compression,
+                pack_threshold: settings.pack_threshold,
                 rt: rt.main().clone(),
             },
-        })))
+            packs: Mutex::new(packs),
+        }));
+        if migrate_roots {
+            // the store is not shared yet, so there can not be a concurrent writer
+            let state = db.0.state.read().unwrap();
+            tracing::info!(
+                "no roots file, pinning all {} complete entries",
+                state.roots.len()
+            );
+            db.persist_meta(ROOTS_META, &state.roots)?;
+        }
+        Ok(db)
     }
 
     /// Blocking load a database from disk.
@@ -1113,7 +1777,7 @@ impl Store {
         let complete_path = complete_path.as_ref().to_path_buf();
         let partial_path = partial_path.as_ref().to_path_buf();
         let rt = rt.clone();
-        let db = Self::load_sync(complete_path, partial_path, rt)?;
+        let db = Self::load_sync(complete_path, partial_path, Settings::default(), rt)?;
         Ok(db)
     }
 
@@ -1122,13 +1786,41 @@ impl Store {
         complete_path: impl AsRef<Path>,
         partial_path: impl AsRef<Path>,
         rt: &iroh_bytes::util::runtime::Handle,
+    ) -> anyhow::Result<Self> {
+        Self::load_with_compression(complete_path, partial_path, Compression::None, rt).await
+    }
+
+    /// Load a database from disk, storing new data with the given compression.
+    ///
+    /// See the [module docs](self#compressed-data-files) for how existing data is handled.
+    pub async fn load_with_compression(
+        complete_path: impl AsRef<Path>,
+        partial_path: impl AsRef<Path>,
+        compression: Compression,
+        rt: &iroh_bytes::util::runtime::Handle,
+    ) -> anyhow::Result<Self> {
+        let settings = Settings {
+            compression,
+            ..Default::default()
+        };
+        Self::load_with_settings(complete_path, partial_path, settings, rt).await
+    }
+
+    /// Load a database from disk, storing new data with the given settings.
+    ///
+    /// See the [module docs](self) for how existing data is handled.
+    pub async fn load_with_settings(
+        complete_path: impl AsRef<Path>,
+        partial_path: impl AsRef<Path>,
+        settings: Settings,
+        rt: &iroh_bytes::util::runtime::Handle,
     ) -> anyhow::Result<Self> {
         let complete_path = complete_path.as_ref().to_path_buf();
         let partial_path = partial_path.as_ref().to_path_buf();
         let rtc = rt.clone();
         let db = rt
             .main()
-            .spawn_blocking(move || Self::load_sync(complete_path, partial_path, rtc))
+            .spawn_blocking(move || Self::load_sync(complete_path, partial_path, settings, rtc))
             .await??;
         Ok(db)
     }
@@ -1146,6 +1838,35 @@ impl Store {
     }
 }
 
+/// Read a meta file from the complete directory, see the module docs.
+///
+/// Returns the default value if the file does not exist.
+fn load_meta<T: DeserializeOwned + Default>(
+    complete_path: &Path,
+    name: &[u8],
+) -> anyhow::Result<T> {
+    let path = complete_path.join(FileName::Meta(name.to_vec()).to_string());
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let data = std::fs::read(path)?;
+    Ok(postcard::from_bytes(&data)?)
+}
+
+/// Write all data from `reader` to a new file at `path`.
+fn write_file(mut reader: impl io::Read, path: &Path) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(std::fs::File::create(path)?);
+    io::copy(&mut reader, &mut writer)?;
+    io::Write::flush(&mut writer)
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
 /// Synchronously compute the outboard of a file, and return hash and outboard.
 ///
 /// It is assumed that the file is not modified while this is running.
@@ -1163,50 +1884,7 @@ fn compute_outboard(
     let span = trace_span!("outboard.compute", path = %path.display());
     let _guard = span.enter();
     let file = std::fs::File::open(path)?;
-    // compute outboard size so we can pre-allocate the buffer.
-    let outboard_size = usize::try_from(bao_tree::io::outboard_size(size, IROH_BLOCK_SIZE))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "size too large"))?;
-    let mut outboard = Vec::with_capacity(outboard_size);
-
-    // wrap the reader in a progress reader, so we can report progress.
-    let reader = ProgressReader2::new(file, progress);
-    // wrap the reader in a buffered reader, so we read in large chunks
-    // this reduces the number of io ops and also the number of progress reports
-    let mut reader = BufReader::with_capacity(1024 * 1024, reader);
-
-    let hash =
-        bao_tree::io::sync::outboard_post_order(&mut reader, size, IROH_BLOCK_SIZE, &mut outboard)?;
-    let ob = PostOrderMemOutboard::load(hash, &outboard, IROH_BLOCK_SIZE)?.flip();
-    tracing::trace!(%hash, "done");
-    let ob = ob.into_inner();
-    let ob = if ob.len() > 8 { Some(ob) } else { None };
-    Ok((hash.into(), ob))
-}
-
-pub(crate) struct ProgressReader2<R, F: Fn(u64) -> io::Result<()>> {
-    inner: R,
-    offset: u64,
-    cb: F,
-}
-
-impl<R: io::Read, F: Fn(u64) -> io::Result<()>> ProgressReader2<R, F> {
-    #[allow(dead_code)]
-    pub fn new(inner: R, cb: F) -> Self {
-        Self {
-            inner,
-            offset: 0,
-            cb,
-        }
-    }
-}
-
-impl<R: io::Read, F: Fn(u64) -> io::Result<()>> io::Read for ProgressReader2<R, F> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read = self.inner.read(buf)?;
-        self.offset += read as u64;
-        (self.cb)(self.offset)?;
-        Ok(read)
-    }
+    outboard_from_reader(file, size, progress)
 }
 
 /// A file name that indicates the purpose of the file.
@@ -1216,6 +1894,8 @@ pub enum FileName {
     PartialData(Hash, [u8; 16]),
     /// File is storing data for the hash
     Data(Hash),
+    /// File is storing compressed data for the hash
+    CompressedData(Hash),
     /// File is storing a partial outboard
     PartialOutboard(Hash, [u8; 16]),
     /// File is storing an outboard
@@ -1225,6 +1905,10 @@ pub enum FileName {
     Outboard(Hash),
     /// External paths for the hash
     Paths(Hash),
+    /// Pack file with the given id, containing data and outboards of small blobs
+    Pack(u64),
+    /// Index of the pack file with the given id
+    PackIndex(u64),
     /// File is going to be used to store metadata
     Meta(Vec<u8>),
 }
@@ -1264,7 +1948,10 @@ impl fmt::Display for FileName {
                 write!(f, "{}.paths", hex::encode(hash))
             }
             Self::Data(hash) => write!(f, "{}.data", hex::encode(hash)),
+            Self::CompressedData(hash) => write!(f, "{}.zdata", hex::encode(hash)),
             Self::Outboard(hash) => write!(f, "{}.{}", hex::encode(hash), OUTBOARD_EXT),
+            Self::Pack(id) => write!(f, "{:016x}.pack", id),
+            Self::PackIndex(id) => write!(f, "{:016x}.pidx", id),
             Self::Meta(name) => write!(f, "{}.meta", hex::encode(name)),
         }
     }
@@ -1296,10 +1983,21 @@ impl FromStr for FileName {
         } else if ext == "meta" {
             let data = hex::decode(base).map_err(|_| ())?;
             Ok(Self::Meta(data))
+        } else if ext == "pack" || ext == "pidx" {
+            let mut id = [0u8; 8];
+            hex::decode_to_slice(base, &mut id).map_err(|_| ())?;
+            let id = u64::from_be_bytes(id);
+            if ext == "pack" {
+                Ok(Self::Pack(id))
+            } else {
+                Ok(Self::PackIndex(id))
+            }
         } else {
             hex::decode_to_slice(base, &mut hash).map_err(|_| ())?;
             if ext == "data" {
                 Ok(Self::Data(hash.into()))
+            } else if ext == "zdata" {
+                Ok(Self::CompressedData(hash.into()))
             } else if ext == OUTBOARD_EXT {
                 Ok(Self::Outboard(hash.into()))
             } else if ext == "paths" {
@@ -1328,12 +2026,15 @@ impl fmt::Debug for FileName {
                 .field(&DD(hex::encode(guid)))
                 .finish(),
             Self::Data(hash) => f.debug_tuple("Data").field(&DD(hash)).finish(),
+            Self::CompressedData(hash) => f.debug_tuple("CompressedData").field(&DD(hash)).finish(),
             Self::PartialOutboard(hash, guid) => f
                 .debug_tuple("PartialOutboard")
                 .field(&DD(hash))
                 .field(&DD(hex::encode(guid)))
                 .finish(),
             Self::Outboard(hash) => f.debug_tuple("Outboard").field(&DD(hash)).finish(),
+            Self::Pack(id) => f.debug_tuple("Pack").field(id).finish(),
+            Self::PackIndex(id) => f.debug_tuple("PackIndex").field(id).finish(),
             Self::Meta(arg0) => f.debug_tuple("Meta").field(&DD(hex::encode(arg0))).finish(),
             Self::Paths(arg0) => f
                 .debug_tuple("Paths")
@@ -1349,8 +2050,11 @@ impl FileName {
         match self {
             FileName::PartialData(_, _) => true,
             FileName::Data(_) => false,
+            FileName::CompressedData(_) => false,
             FileName::PartialOutboard(_, _) => true,
             FileName::Outboard(_) => false,
+            FileName::Pack(_) => false,
+            FileName::PackIndex(_) => false,
             FileName::Meta(_) => false,
             FileName::Paths(_) => false,
         }
@@ -1361,10 +2065,13 @@ impl FileName {
         match self {
             FileName::PartialData(hash, _) => hash.as_bytes(),
             FileName::Data(hash) => hash.as_bytes(),
+            FileName::CompressedData(hash) => hash.as_bytes(),
             FileName::PartialOutboard(hash, _) => hash.as_bytes(),
             FileName::Meta(data) => data.as_slice(),
             FileName::Outboard(_) => &[],
             FileName::Paths(_) => &[],
+            FileName::Pack(_) => &[],
+            FileName::PackIndex(_) => &[],
         }
     }
 }
@@ -1372,6 +2079,7 @@ impl FileName {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use iroh_bytes::baomap::Store as _;
     use proptest::prelude::*;
 
     fn arb_hash() -> impl Strategy<Value = Hash> {
@@ -1381,8 +2089,11 @@ mod tests {
     fn arb_filename() -> impl Strategy<Value = FileName> {
         prop_oneof![
             arb_hash().prop_map(FileName::Data),
+            arb_hash().prop_map(FileName::CompressedData),
             arb_hash().prop_map(FileName::Outboard),
             arb_hash().prop_map(FileName::Paths),
+            any::<u64>().prop_map(FileName::Pack),
+            any::<u64>().prop_map(FileName::PackIndex),
             (arb_hash(), any::<[u8; 16]>())
                 .prop_map(|(hash, uuid)| FileName::PartialData(hash, uuid)),
             (arb_hash(), any::<[u8; 16]>())
@@ -1400,6 +2111,300 @@ mod tests {
         assert!(FileName::from_str("1234ABDC-1234.outboard").is_err());
     }
 
+    #[tokio::test]
+    async fn pin_and_delete() -> anyhow::Result<()> {
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let complete = dir.path().join("complete");
+        let partial = dir.path().join("partial");
+        std::fs::create_dir_all(&complete)?;
+        std::fs::create_dir_all(&partial)?;
+        let db = Store::load(&complete, &partial, &rt).await?;
+        // large enough to not be inlined
+        let data = Bytes::from(vec![1u8; 1024 * 1024]);
+        let hash = db.import_bytes(data).await?;
+        db.pin(hash).await?;
+        assert!(db.owned_data_path(&hash).exists());
+
+        db.set_tag("test".to_string(), hash).await?;
+
+        // roots and tags survive a reload
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert_eq!(db.roots().collect::<Vec<_>>(), vec![hash]);
+        let tags = db.tags().collect::<Vec<_>>();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].0, "test");
+        assert_eq!(tags[0].1.hash, hash);
+
+        db.delete(&hash).await?;
+        assert!(db.get(&hash).is_none());
+        assert!(!db.owned_data_path(&hash).exists());
+        assert!(!db.owned_outboard_path(&hash).exists());
+
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert!(db.get(&hash).is_none());
+        assert_eq!(db.roots().count(), 0);
+        assert_eq!(db.tags().count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gc_store_without_roots() -> anyhow::Result<()> {
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let complete = dir.path().join("complete");
+        let partial = dir.path().join("partial");
+        std::fs::create_dir_all(&complete)?;
+        std::fs::create_dir_all(&partial)?;
+        let db = Store::load(&complete, &partial, &rt).await?;
+        let hash = db.import_bytes(Bytes::from(vec![1u8; 1024 * 1024])).await?;
+        // a store from before roots existed has no roots file
+        let roots_path = db.0.options.meta_path(ROOTS_META);
+        std::fs::remove_file(&roots_path)?;
+
+        // all existing data is pinned, and survives gc
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert_eq!(db.roots().collect::<Vec<_>>(), vec![hash]);
+        assert!(roots_path.exists());
+        let progress = iroh_bytes::util::progress::IgnoreProgressSender::default();
+        iroh_bytes::baomap::gc(&db, &iroh_bytes::collection::NoCollectionParser, progress).await?;
+        assert!(db.get(&hash).is_some());
+
+        // once the roots file exists, unpinned data is collected
+        db.unpin(hash).await?;
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert_eq!(db.roots().count(), 0);
+        let progress = iroh_bytes::util::progress::IgnoreProgressSender::default();
+        iroh_bytes::baomap::gc(&db, &iroh_bytes::collection::NoCollectionParser, progress).await?;
+        assert!(db.get(&hash).is_none());
+        Ok(())
+    }
+
+    /// Run validate and return the errors for all entries.
+    async fn validate(db: &Store) -> anyhow::Result<Vec<Option<String>>> {
+        let (tx, mut rx) = mpsc::channel(16);
+        let task = tokio::spawn({
+            let db = db.clone();
+            async move { db.validate(tx).await }
+        });
+        let mut errors = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            if let ValidateProgress::Done { error, .. } = msg {
+                errors.push(error);
+            }
+        }
+        task.await??;
+        Ok(errors)
+    }
+
+    #[tokio::test]
+    async fn compressed_store() -> anyhow::Result<()> {
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let complete = dir.path().join("complete");
+        let partial = dir.path().join("partial");
+        std::fs::create_dir_all(&complete)?;
+        std::fs::create_dir_all(&partial)?;
+        let compression = Compression::Zstd(3);
+        let db = Store::load_with_compression(&complete, &partial, compression, &rt).await?;
+        // large enough to not be inlined, and compressible
+        let data = (0..1024 * 1024 + 1234)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let hash = db.import_bytes(data.clone().into()).await?;
+        let path = db.0.options.compressed_data_path(&hash);
+        assert!(path.exists());
+        assert!(!db.owned_data_path(&hash).exists());
+        assert!(std::fs::metadata(&path)?.len() < data.len() as u64 / 10);
+
+        // import a file, in copy mode
+        let source = dir.path().join("source");
+        let data2 = data.iter().rev().copied().collect::<Vec<_>>();
+        std::fs::write(&source, &data2)?;
+        let progress = iroh_bytes::util::progress::IgnoreProgressSender::default();
+        let (hash2, size2) = db.import(source, ImportMode::Copy, progress).await?;
+        assert_eq!(size2, data2.len() as u64);
+        assert!(db.0.options.compressed_data_path(&hash2).exists());
+
+        // the data and outboard are for the uncompressed data
+        let entry = db.get(&hash).unwrap();
+        assert_eq!(entry.size(), data.len() as u64);
+        let mut reader = entry.data_reader().await?;
+        assert_eq!(reader.read_at(0, usize::MAX).await?, data);
+        assert_eq!(reader.read_at(20000, 50000).await?, data[20000..70000]);
+        let mut outboard = entry.outboard().await?;
+        let (expected, _) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        assert_eq!(outboard.data.len().await?, expected.len() as u64);
+        assert_eq!(validate(&db).await?, vec![None, None]);
+
+        // compressed data survives a reload, also without compression
+        let db = Store::load(&complete, &partial, &rt).await?;
+        let entry = db.get(&hash).unwrap();
+        assert_eq!(entry.size(), data.len() as u64);
+        assert_eq!(
+            entry.data_reader().await?.read_at(0, usize::MAX).await?,
+            data
+        );
+
+        // export decompresses
+        let target = dir.path().join("target");
+        db.export(hash, target.clone(), ExportMode::TryReference, |_| Ok(()))
+            .await?;
+        assert_eq!(std::fs::read(&target)?, data);
+
+        // new data is stored uncompressed
+        let hash3 = db.import_bytes(vec![7u8; 100_000].into()).await?;
+        assert!(db.owned_data_path(&hash3).exists());
+
+        // corrupt the compressed data, by replacing it with other compressed data
+        std::fs::copy(db.0.options.compressed_data_path(&hash2), &path)?;
+        let mut errors = validate(&db).await?;
+        errors.sort();
+        assert_eq!(errors[..2], [None, None]);
+        assert!(errors[2].is_some());
+
+        db.delete(&hash).await?;
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn packed_store() -> anyhow::Result<()> {
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let complete = dir.path().join("complete");
+        let partial = dir.path().join("partial");
+        std::fs::create_dir_all(&complete)?;
+        std::fs::create_dir_all(&partial)?;
+        let settings = Settings {
+            pack_threshold: 1024 * 64,
+            ..Default::default()
+        };
+        let db = Store::load_with_settings(&complete, &partial, settings, &rt).await?;
+        // a tiny blob, a packed blob that needs an outboard, and a blob too large to pack
+        let blobs = [100, 1024 * 40, 1024 * 100]
+            .into_iter()
+            .map(|size| (0..size).map(|i| (i % 241) as u8).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut hashes = Vec::new();
+        for data in &blobs {
+            hashes.push(db.import_bytes(data.clone().into()).await?);
+        }
+        assert!(!db.owned_data_path(&hashes[0]).exists());
+        assert!(!db.owned_data_path(&hashes[1]).exists());
+        assert!(!db.owned_outboard_path(&hashes[1]).exists());
+        assert!(db.owned_data_path(&hashes[2]).exists());
+        assert!(db.0.options.pack_path(0).exists());
+
+        // import a small file, in copy mode
+        let source = dir.path().join("source");
+        let data3 = vec![3u8; 1000];
+        std::fs::write(&source, &data3)?;
+        let progress = iroh_bytes::util::progress::IgnoreProgressSender::default();
+        let (hash3, _) = db.import(source, ImportMode::Copy, progress).await?;
+        assert!(!db.owned_data_path(&hash3).exists());
+
+        // complete a small partial entry
+        let data4 = vec![4u8; 1024 * 20];
+        let (outboard4, hash4) = bao_tree::io::outboard(&data4, IROH_BLOCK_SIZE);
+        let hash4 = Hash::from(hash4);
+        let entry = db.get_or_create_partial(hash4, data4.len() as u64)?;
+        entry.data_writer().await?.write_at(0, &data4).await?;
+        entry
+            .outboard_mut()
+            .await?
+            .data
+            .write_at(0, &outboard4)
+            .await?;
+        db.insert_complete(entry.clone()).await?;
+        assert!(!entry.data_path.exists());
+        assert!(!entry.outboard_path.exists());
+        assert!(!db.owned_data_path(&hash4).exists());
+
+        // packed data survives a reload, also without packing
+        let db = Store::load(&complete, &partial, &rt).await?;
+        let mut all = blobs.clone();
+        all.push(data3);
+        all.push(data4);
+        hashes.push(hash3);
+        hashes.push(hash4);
+        for (hash, data) in hashes.iter().zip(&all) {
+            let entry = db.get(hash).unwrap();
+            let mut reader = entry.data_reader().await?;
+            assert_eq!(reader.read_at(0, usize::MAX).await?, data[..]);
+            let mut outboard = entry.outboard().await?;
+            let (expected, _) = bao_tree::io::outboard(data, IROH_BLOCK_SIZE);
+            assert_eq!(outboard.data.read_at(0, usize::MAX).await?, expected);
+        }
+        assert_eq!(validate(&db).await?, vec![None; 5]);
+
+        // export copies out of the pack
+        let target = dir.path().join("target");
+        db.export(hashes[1], target.clone(), ExportMode::TryReference, |_| {
+            Ok(())
+        })
+        .await?;
+        assert_eq!(std::fs::read(&target)?, all[1]);
+
+        // deleted packed blobs stay deleted after a reload
+        db.delete(&hashes[0]).await?;
+        assert!(db.get(&hashes[0]).is_none());
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert!(db.get(&hashes[0]).is_none());
+        assert_eq!(db.blobs().count(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unpacked_store() -> anyhow::Result<()> {
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let complete = dir.path().join("complete");
+        let partial = dir.path().join("partial");
+        std::fs::create_dir_all(&complete)?;
+        std::fs::create_dir_all(&partial)?;
+        let settings = Settings {
+            pack_threshold: 1024 * 64,
+            ..Default::default()
+        };
+        let data = Bytes::from(vec![1u8; 1000]);
+        let db = Store::load_with_settings(&complete, &partial, settings, &rt).await?;
+        let hash = db.import_bytes(data.clone()).await?;
+        assert!(!db.owned_data_path(&hash).exists());
+
+        // importing again without packing moves the blob out of the pack
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert_eq!(db.import_bytes(data).await?, hash);
+        assert!(db.owned_data_path(&hash).exists());
+
+        // so the pack does not bring it back after it is deleted
+        db.delete(&hash).await?;
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert!(db.get(&hash).is_none());
+
+        // a crash while moving a blob out of a pack leaves both a data file and a live
+        // record, which must not bring the blob back after it is deleted
+        let settings = Settings {
+            pack_threshold: 1024 * 64,
+            ..Default::default()
+        };
+        let data = Bytes::from(vec![2u8; 1000]);
+        let db = Store::load_with_settings(&complete, &partial, settings, &rt).await?;
+        let hash = db.import_bytes(data.clone()).await?;
+        std::fs::write(db.owned_data_path(&hash), &data)?;
+        let db = Store::load(&complete, &partial, &rt).await?;
+        db.delete(&hash).await?;
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert!(db.get(&hash).is_none());
+
+        // an unreadable pack is skipped
+        std::fs::write(complete.join(FileName::Pack(1).to_string()), b"")?;
+        std::fs::create_dir(complete.join(FileName::PackIndex(1).to_string()))?;
+        let db = Store::load(&complete, &partial, &rt).await?;
+        assert_eq!(db.blobs().count(), 0);
+        Ok(())
+    }
+
     proptest! {
         #[test]
         fn filename_roundtrip(name in arb_filename()) {
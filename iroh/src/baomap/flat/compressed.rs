@@ -0,0 +1,317 @@
+//! Compressed data files for the flat store.
+//!
+//! Data is compressed with zstd in the [seekable format]: every 16 KiB chunk group of
+//! the data is compressed as an independent zstd frame, and a seek table in a skippable
+//! frame at the end of the file lists the compressed and decompressed size of every
+//! frame. The file is a valid zstd file, so it can be decompressed with the zstd cli.
+//!
+//! Since the frames are aligned to the chunk groups of the bao tree, a read of a range
+//! of chunk groups only needs to decompress the frames for these chunk groups.
+//!
+//! [seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+use std::io::{self, Read, Write};
+
+use bao_tree::io::sync::ReadAt;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use iroh_bytes::IROH_BLOCK_SIZE;
+use iroh_io::{AsyncSliceReader, File};
+
+/// Magic number of a skippable frame that contains a seek table
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+/// Magic number at the very end of a seekable file
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+/// Size of the seek table footer: number of frames, descriptor and magic number
+const FOOTER_SIZE: usize = 9;
+/// Size of a seek table entry without checksum
+const ENTRY_SIZE: usize = 8;
+
+/// The decompressed size of every frame except the last one.
+fn frame_size() -> u64 {
+    IROH_BLOCK_SIZE.bytes() as u64
+}
+
+/// Compress all data from `reader` to `writer` in the seekable format.
+///
+/// Returns the size of the uncompressed data.
+pub(super) fn compress(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    level: i32,
+) -> io::Result<u64> {
+    let mut buf = vec![0u8; frame_size() as usize];
+    let mut entries = Vec::new();
+    let mut size = 0u64;
+    loop {
+        let n = read_full(&mut reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let frame = zstd::bulk::compress(&buf[..n], level)?;
+        writer.write_all(&frame)?;
+        entries.push((frame.len() as u32, n as u32));
+        size += n as u64;
+        if n < buf.len() {
+            break;
+        }
+    }
+    let table_size = entries.len() * ENTRY_SIZE + FOOTER_SIZE;
+    let mut table = Vec::with_capacity(8 + table_size);
+    table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    table.extend_from_slice(&(table_size as u32).to_le_bytes());
+    for (compressed, decompressed) in &entries {
+        table.extend_from_slice(&compressed.to_le_bytes());
+        table.extend_from_slice(&decompressed.to_le_bytes());
+    }
+    table.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    // no checksums
+    table.push(0);
+    table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    writer.write_all(&table)?;
+    writer.flush()?;
+    Ok(size)
+}
+
+/// A reader for the decompressed data of a compressed file.
+pub(super) fn decompress(file: std::fs::File) -> io::Result<impl Read> {
+    zstd::stream::read::Decoder::new(file)
+}
+
+/// Read until `buf` is full or the reader is exhausted.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The seek table of a compressed file.
+#[derive(Debug, Clone)]
+pub(super) struct SeekTable {
+    /// Offset of every frame in the compressed file, followed by the end of the last frame
+    offsets: Vec<u64>,
+    /// Size of the decompressed data
+    size: u64,
+}
+
+impl SeekTable {
+    /// Size of the seek table, given the footer at the end of the file
+    fn table_len(footer: &[u8]) -> io::Result<u64> {
+        if u32_at(footer, 5) != SEEKABLE_MAGIC {
+            return Err(invalid("not a seekable zstd file"));
+        }
+        if footer[4] != 0 {
+            return Err(invalid("unsupported seek table descriptor"));
+        }
+        let frames = u32_at(footer, 0) as u64;
+        Ok(8 + frames * ENTRY_SIZE as u64 + FOOTER_SIZE as u64)
+    }
+
+    /// Parse the seek table, including the skippable frame header and the footer
+    fn parse(table: &[u8], file_len: u64) -> io::Result<Self> {
+        if u32_at(table, 0) != SKIPPABLE_MAGIC || u32_at(table, 4) as usize != table.len() - 8 {
+            return Err(invalid("invalid seek table frame"));
+        }
+        let entries = &table[8..table.len() - FOOTER_SIZE];
+        let count = entries.len() / ENTRY_SIZE;
+        let mut offsets = Vec::with_capacity(count + 1);
+        let mut offset = 0u64;
+        let mut size = 0u64;
+        offsets.push(offset);
+        for i in 0..count {
+            let compressed = u32_at(entries, i * ENTRY_SIZE) as u64;
+            let decompressed = u32_at(entries, i * ENTRY_SIZE + 4) as u64;
+            // all frames must be full, except for the last one
+            let last = i + 1 == count;
+            if decompressed > frame_size() || (!last && decompressed != frame_size()) {
+                return Err(invalid("seek table frames are not aligned to chunk groups"));
+            }
+            offset += compressed;
+            size += decompressed;
+            offsets.push(offset);
+        }
+        if offset + table.len() as u64 != file_len {
+            return Err(invalid("seek table does not match the file size"));
+        }
+        Ok(Self { offsets, size })
+    }
+
+    /// Read the seek table from the end of a file.
+    pub fn read_sync(file: &std::fs::File) -> io::Result<Self> {
+        let file_len = file.metadata()?.len();
+        if file_len < FOOTER_SIZE as u64 {
+            return Err(invalid("compressed file too short"));
+        }
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.read_exact_at(file_len - FOOTER_SIZE as u64, &mut footer)?;
+        let table_len = Self::table_len(&footer)?;
+        if table_len > file_len {
+            return Err(invalid("compressed file too short"));
+        }
+        let mut table = vec![0u8; table_len as usize];
+        file.read_exact_at(file_len - table_len, &mut table)?;
+        Self::parse(&table, file_len)
+    }
+
+    /// Read the seek table from the end of a file.
+    pub async fn read(file: &mut File) -> io::Result<Self> {
+        let file_len = file.len().await?;
+        if file_len < FOOTER_SIZE as u64 {
+            return Err(invalid("compressed file too short"));
+        }
+        let footer = file
+            .read_at(file_len - FOOTER_SIZE as u64, FOOTER_SIZE)
+            .await?;
+        let table_len = Self::table_len(&footer)?;
+        if table_len > file_len {
+            return Err(invalid("compressed file too short"));
+        }
+        let table = file
+            .read_at(file_len - table_len, table_len as usize)
+            .await?;
+        if table.len() as u64 != table_len {
+            return Err(invalid("compressed file too short"));
+        }
+        Self::parse(&table, file_len)
+    }
+
+    /// Size of the decompressed data
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// An [AsyncSliceReader] for the decompressed data of a compressed file.
+///
+/// Reads only decompress the frames that overlap with the requested range.
+#[derive(Debug)]
+pub struct CompressedFile {
+    file: File,
+    table: SeekTable,
+}
+
+impl CompressedFile {
+    /// Open a compressed file and read its seek table.
+    pub(super) async fn open(mut file: File) -> io::Result<Self> {
+        let table = SeekTable::read(&mut file).await?;
+        Ok(Self { file, table })
+    }
+
+    async fn read_at_impl(&mut self, offset: u64, len: usize) -> io::Result<Bytes> {
+        let size = self.table.size;
+        let end = offset.saturating_add(len as u64).min(size);
+        if offset >= end {
+            return Ok(Bytes::new());
+        }
+        let first = (offset / frame_size()) as usize;
+        let last = ((end - 1) / frame_size()) as usize;
+        let start = self.table.offsets[first];
+        let compressed = self
+            .file
+            .read_at(start, (self.table.offsets[last + 1] - start) as usize)
+            .await?;
+        let mut res = BytesMut::with_capacity((last + 1 - first) * frame_size() as usize);
+        for i in first..=last {
+            let range = (self.table.offsets[i] - start) as usize
+                ..(self.table.offsets[i + 1] - start) as usize;
+            let frame = compressed
+                .get(range)
+                .ok_or_else(|| invalid("compressed file too short"))?;
+            let data = zstd::bulk::decompress(frame, frame_size() as usize)?;
+            res.extend_from_slice(&data);
+        }
+        let skip = (offset - first as u64 * frame_size()) as usize;
+        let len = (end - offset) as usize;
+        if res.len() < skip + len {
+            return Err(invalid("frame is smaller than listed in the seek table"));
+        }
+        Ok(res.freeze().slice(skip..skip + len))
+    }
+}
+
+impl AsyncSliceReader for CompressedFile {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        self.read_at_impl(offset, len).boxed()
+    }
+
+    type LenFuture<'a> = future::Ready<io::Result<u64>>;
+
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        future::ok(self.table.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn test_data(size: usize) -> Vec<u8> {
+        // compressible, but not trivially so
+        let mut rng = rand::rngs::StdRng::seed_from_u64(size as u64);
+        (0..size).map(|_| b"abcd"[rng.gen_range(0..4)]).collect()
+    }
+
+    #[tokio::test]
+    async fn compressed_roundtrip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fs = frame_size() as usize;
+        for size in [0, 1, fs - 1, fs, fs + 1, 10 * fs + 1234] {
+            let data = test_data(size);
+            let path = dir.path().join(format!("{size}.zdata"));
+            let n = compress(&data[..], std::fs::File::create(&path)?, 3)?;
+            assert_eq!(n, size as u64);
+            assert!(std::fs::metadata(&path)?.len() < size as u64 + 100);
+
+            // sequential decompression
+            let mut decompressed = Vec::new();
+            decompress(std::fs::File::open(&path)?)?.read_to_end(&mut decompressed)?;
+            assert_eq!(decompressed, data);
+            let table = SeekTable::read_sync(&std::fs::File::open(&path)?)?;
+            assert_eq!(table.size(), size as u64);
+
+            // random access
+            let mut reader = CompressedFile::open(File::open(path).await?).await?;
+            assert_eq!(reader.len().await?, size as u64);
+            let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+            for _ in 0..20 {
+                let offset = rng.gen_range(0..=size + 10);
+                let len = rng.gen_range(0..3 * fs);
+                let start = offset.min(size);
+                let end = (offset + len).min(size);
+                let read = reader.read_at(offset as u64, len).await?;
+                assert_eq!(&read[..], &data[start..end]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_table() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("data");
+        std::fs::write(&path, test_data(100_000))?;
+        assert!(SeekTable::read_sync(&std::fs::File::open(&path)?).is_err());
+        std::fs::write(&path, b"")?;
+        assert!(SeekTable::read_sync(&std::fs::File::open(&path)?).is_err());
+        Ok(())
+    }
+}
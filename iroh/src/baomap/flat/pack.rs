@@ -0,0 +1,280 @@
+//! Pack files for small blobs in the flat store.
+//!
+//! Small blobs are appended to a pack file, together with their outboard if they need
+//! one. Every pack file has an index file, to which a record is appended for every blob
+//! that is added to or removed from the pack.
+//!
+//! An index record is [RECORD_SIZE] bytes: the hash of the blob, followed by the little
+//! endian encoded offset of the data in the pack file, the size of the data and the size
+//! of the outboard. The outboard directly follows the data. A record with an offset of
+//! `u64::MAX` is a tombstone, it marks the blob as removed.
+//!
+//! The data is always written before the index record, so after a crash the index never
+//! refers to data that is not there. Records that point beyond the end of the pack file
+//! are ignored anyway, as well as an incomplete record at the end of the index file. Such
+//! a record is truncated before the next record is appended.
+//!
+//! Pack files are never rewritten, so the space of removed blobs is not reclaimed.
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use bao_tree::io::sync::ReadAt;
+use iroh_bytes::Hash;
+
+use super::FileName;
+
+/// Size of an index record
+const RECORD_SIZE: usize = 32 + 8 + 8 + 8;
+/// Offset of a tombstone record
+const TOMBSTONE: u64 = u64::MAX;
+/// New blobs go to a new pack file once the current one is at least this large
+const MAX_PACK_SIZE: u64 = 1024 * 1024 * 256;
+
+/// Where the data of a packed blob is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct PackLocation {
+    /// Id of the pack file
+    pub pack: u64,
+    /// Offset of the data in the pack file
+    pub offset: u64,
+}
+
+/// A live blob in a pack file, as read from the index.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PackRecord {
+    pub location: PackLocation,
+    /// Size of the data
+    pub size: u64,
+    /// Size of the outboard that follows the data
+    pub outboard_size: u64,
+}
+
+fn encode_record(hash: &Hash, offset: u64, size: u64, outboard_size: u64) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[..32].copy_from_slice(hash.as_bytes());
+    record[32..40].copy_from_slice(&offset.to_le_bytes());
+    record[40..48].copy_from_slice(&size.to_le_bytes());
+    record[48..].copy_from_slice(&outboard_size.to_le_bytes());
+    record
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn append_options() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    options.append(true).create(true);
+    options
+}
+
+/// Open an index file for appending, dropping an incomplete record at its end.
+///
+/// Otherwise every record appended after a crash mid-record would be misaligned.
+fn open_index(path: &Path) -> io::Result<std::fs::File> {
+    let file = append_options().open(path)?;
+    let len = file.metadata()?.len();
+    let aligned = len / RECORD_SIZE as u64 * RECORD_SIZE as u64;
+    if aligned != len {
+        tracing::warn!(
+            "truncating incomplete record at the end of {}",
+            path.display()
+        );
+        file.set_len(aligned)?;
+    }
+    Ok(file)
+}
+
+/// Read the index of a pack, and return the live blobs in it.
+pub(super) fn read_index(
+    index_path: &Path,
+    pack: u64,
+    pack_len: u64,
+) -> io::Result<BTreeMap<Hash, PackRecord>> {
+    let data = std::fs::read(index_path)?;
+    let mut records = BTreeMap::new();
+    for record in data.chunks_exact(RECORD_SIZE) {
+        let hash: [u8; 32] = record[..32].try_into().unwrap();
+        let hash = Hash::from(hash);
+        let offset = u64_at(record, 32);
+        if offset == TOMBSTONE {
+            records.remove(&hash);
+            continue;
+        }
+        let size = u64_at(record, 40);
+        let outboard_size = u64_at(record, 48);
+        let end = offset
+            .checked_add(size)
+            .and_then(|end| end.checked_add(outboard_size));
+        if !end.map_or(false, |end| end <= pack_len) {
+            tracing::warn!("pack index record for {} is out of bounds", hash);
+            continue;
+        }
+        let location = PackLocation { pack, offset };
+        records.insert(
+            hash,
+            PackRecord {
+                location,
+                size,
+                outboard_size,
+            },
+        );
+    }
+    Ok(records)
+}
+
+/// Read `len` bytes at `offset` from a pack file.
+pub(super) fn read_sync(path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let file = std::fs::File::open(path)?;
+    let mut data = vec![0u8; len as usize];
+    file.read_exact_at(offset, &mut data)?;
+    Ok(data)
+}
+
+/// A reader for the data of a packed blob.
+pub(super) fn reader(path: &Path, offset: u64, size: u64) -> io::Result<impl Read> {
+    let mut file = std::fs::File::open(path)?;
+    if file.metadata()?.len() < offset + size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "pack file too short",
+        ));
+    }
+    file.seek(io::SeekFrom::Start(offset))?;
+    Ok(file.take(size))
+}
+
+/// Appends blobs to the pack files in a directory.
+#[derive(Debug)]
+pub(super) struct PackWriter {
+    dir: PathBuf,
+    /// Id of the pack that new blobs are appended to
+    current: Option<u64>,
+    /// Open pack and index files of the current pack
+    files: Option<(std::fs::File, std::fs::File)>,
+}
+
+impl PackWriter {
+    /// Create a writer that appends to the pack with id `current`, if any.
+    pub fn new(dir: PathBuf, current: Option<u64>) -> Self {
+        Self {
+            dir,
+            current,
+            files: None,
+        }
+    }
+
+    fn index_path(&self, pack: u64) -> PathBuf {
+        self.dir.join(FileName::PackIndex(pack).to_string())
+    }
+
+    /// Append a blob and its outboard, and return where the data is stored.
+    pub fn append(
+        &mut self,
+        hash: &Hash,
+        data: &[u8],
+        outboard: &[u8],
+    ) -> io::Result<PackLocation> {
+        let (pack, offset) = loop {
+            let pack = *self.current.get_or_insert(0);
+            if self.files.is_none() {
+                let pack_path = self.dir.join(FileName::Pack(pack).to_string());
+                let pack_file = append_options().open(pack_path)?;
+                let index_file = open_index(&self.index_path(pack))?;
+                self.files = Some((pack_file, index_file));
+            }
+            let (pack_file, _) = self.files.as_ref().unwrap();
+            // the writer is the only one appending, so this is where the data will go
+            let offset = pack_file.metadata()?.len();
+            if offset < MAX_PACK_SIZE {
+                break (pack, offset);
+            }
+            self.current = Some(pack + 1);
+            self.files = None;
+        };
+        let (pack_file, index_file) = self.files.as_mut().unwrap();
+        pack_file.write_all(data)?;
+        pack_file.write_all(outboard)?;
+        let record = encode_record(hash, offset, data.len() as u64, outboard.len() as u64);
+        index_file.write_all(&record)?;
+        Ok(PackLocation { pack, offset })
+    }
+
+    /// Mark a blob as removed from the pack at `location`.
+    pub fn remove(&mut self, hash: &Hash, location: PackLocation) -> io::Result<()> {
+        let record = encode_record(hash, TOMBSTONE, 0, 0);
+        match self.files.as_mut() {
+            Some((_, index_file)) if self.current == Some(location.pack) => {
+                index_file.write_all(&record)
+            }
+            _ => open_index(&self.index_path(location.pack))?.write_all(&record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_roundtrip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut writer = PackWriter::new(dir.path().to_path_buf(), None);
+        let pack_path = dir.path().join(FileName::Pack(0).to_string());
+        let blobs = (0u8..10)
+            .map(|i| {
+                let data = vec![i; 1000 + i as usize];
+                let hash = Hash::from(bao_tree::blake3::hash(&data));
+                (hash, data)
+            })
+            .collect::<Vec<_>>();
+        let mut locations = Vec::new();
+        for (hash, data) in &blobs {
+            locations.push(writer.append(hash, data, &[1, 2, 3])?);
+        }
+        writer.remove(&blobs[3].0, locations[3])?;
+        // an incomplete record at the end is ignored
+        append_options()
+            .open(writer.index_path(0))?
+            .write_all(&[0u8; 10])?;
+
+        let pack_len = std::fs::metadata(&pack_path)?.len();
+        let records = read_index(&writer.index_path(0), 0, pack_len)?;
+        assert_eq!(records.len(), 9);
+        assert!(!records.contains_key(&blobs[3].0));
+        for (i, (hash, data)) in blobs.iter().enumerate().filter(|(i, _)| *i != 3) {
+            let record = records[hash];
+            assert_eq!(record.location, locations[i]);
+            assert_eq!(record.size, data.len() as u64);
+            assert_eq!(record.outboard_size, 3);
+            let offset = record.location.offset;
+            assert_eq!(&read_sync(&pack_path, offset, record.size)?, data);
+            assert_eq!(read_sync(&pack_path, offset + record.size, 3)?, [1, 2, 3]);
+            let mut read = Vec::new();
+            reader(&pack_path, offset, record.size)?.read_to_end(&mut read)?;
+            assert_eq!(&read, data);
+        }
+
+        // records that point beyond the end of the pack are ignored
+        let records = read_index(&writer.index_path(0), 0, pack_len - 1)?;
+        assert_eq!(records.len(), 8);
+
+        // appending after a restart drops the incomplete record first
+        let mut writer = PackWriter::new(dir.path().to_path_buf(), Some(0));
+        let data = vec![42u8; 100];
+        let hash = Hash::from(bao_tree::blake3::hash(&data));
+        let location = writer.append(&hash, &data, &[])?;
+        let pack_len = std::fs::metadata(&pack_path)?.len();
+        let records = read_index(&writer.index_path(0), 0, pack_len)?;
+        assert_eq!(records.len(), 10);
+        let record = records[&hash];
+        assert_eq!(record.location, location);
+        assert_eq!(record.size, 100);
+        assert_eq!(read_sync(&pack_path, location.offset, record.size)?, data);
+        writer.remove(&hash, location)?;
+        let records = read_index(&writer.index_path(0), 0, pack_len)?;
+        assert!(!records.contains_key(&hash));
+        Ok(())
+    }
+}
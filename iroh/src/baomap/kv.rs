@@ -0,0 +1,1212 @@
+//! A database implementation that keeps its metadata in an embedded key-value store.
+//!
+//! The [flat](super::flat) store keeps all knowledge about entries in memory, and
+//! rebuilds it by scanning its directory on startup. With millions of small blobs this
+//! makes startup slow and memory usage high. This store instead keeps the metadata in a
+//! [redb] database file, so startup time does not depend on the number of entries, and
+//! entries are only loaded when they are used.
+//!
+//! Note that this means that [Map::get] and friends read from the database. Reads are
+//! usually served from the page cache of the database, but they can block on io.
+//!
+//! # File format
+//!
+//! All files are stored in a single directory:
+//!
+//! - `meta.redb` is the database, see below.
+//! - `<hash>.data` contains the data of a complete blob that is too large to be inlined.
+//! - `<hash>.obao4` contains the outboard of a complete blob, if it is too large to be
+//!   inlined. See the [flat](super::flat) store for the outboard format.
+//! - `<hash>-<uuid>.data` and `<hash>-<uuid>.obao4` contain the data and outboard of a
+//!   partial blob.
+//! - `<uuid>.temp` are temporary files, which are deleted on startup.
+//!
+//! Hashes and uuids are hex encoded.
+//!
+//! ## Tables
+//!
+//! - `blobs` maps the hash of every complete blob to a postcard serialized `BlobInfo`
+//!   with its size and where its data and outboard are stored.
+//! - `inline-data` contains the data of blobs of at most 16 KiB.
+//! - `inline-outboard` contains outboards of at most 16 KiB, for blobs of up to a few MiB.
+//!   Blobs of at most 16 KiB don't need an outboard at all.
+//! - `partial` maps the hash of every partial blob to a postcard serialized
+//!   `PartialInfo`.
+//! - `roots` contains the pinned hashes.
+//! - `tags` maps tag names to postcard serialized [`TagInfo`]s.
+use std::collections::BTreeSet;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bao_tree::io::outboard::PreOrderOutboard;
+use bao_tree::{blake3, BaoTree, ByteNum, ChunkNum};
+use bytes::Bytes;
+use futures::future::{BoxFuture, Either};
+use futures::FutureExt;
+use iroh_bytes::baomap::range_collections::RangeSet2;
+use iroh_bytes::baomap::{
+    self, ExportMode, ImportMode, ImportProgress, Map, MapEntry, PartialMap, PartialMapEntry,
+    ReadableStore, TagInfo, ValidateProgress,
+};
+use iroh_bytes::util::progress::{IdGenerator, ProgressSender};
+use iroh_bytes::util::runtime;
+use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
+use iroh_io::{AsyncSliceWriter, File};
+use rand::Rng;
+use redb::{
+    Database, ReadTransaction, ReadableTable, TableDefinition, TableHandle, WriteTransaction,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{flatten_to_io, outboard_from_reader};
+
+/// Name of the database file in the store directory.
+const DB_FILE_NAME: &str = "meta.redb";
+
+const BLOBS: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("blobs");
+const INLINE_DATA: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("inline-data");
+const INLINE_OUTBOARD: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("inline-outboard");
+const PARTIAL: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("partial");
+const ROOTS: TableDefinition<&[u8; 32], ()> = TableDefinition::new("roots");
+const TAGS: TableDefinition<&str, &[u8]> = TableDefinition::new("tags");
+
+/// Data up to this size is stored in the database.
+const INLINE_DATA_THRESHOLD: u64 = 1024 * 16;
+/// Outboards up to this size are stored in the database.
+const INLINE_OUTBOARD_THRESHOLD: usize = 1024 * 16;
+/// Data files from this size on are moved instead of copied when exporting.
+const MOVE_THRESHOLD: u64 = 1024 * 128;
+
+/// Where the store keeps data or an outboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Location {
+    /// In the database
+    Inline,
+    /// In a file in the store directory
+    File,
+}
+
+/// Information about a complete blob, stored in the `blobs` table.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BlobInfo {
+    /// Size of the data
+    size: u64,
+    /// Where the store keeps its own copy of the data, if it has one
+    owned: Option<Location>,
+    /// External files that contain the data
+    external: BTreeSet<PathBuf>,
+    /// Where the outboard is stored, if the blob needs one
+    outboard: Option<Location>,
+}
+
+/// Information about a partial blob, stored in the `partial` table.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct PartialInfo {
+    /// Size of the complete data
+    size: u64,
+    /// Unique id of the partial data and outboard files
+    uuid: [u8; 16],
+}
+
+/// New data for a complete blob.
+enum NewData {
+    /// Data to store in the database
+    Inline(Bytes),
+    /// The data has been moved to the data file of the blob
+    Owned,
+    /// The data is in an external file
+    External(PathBuf),
+}
+
+/// A reader for data or outboards that are stored in the database or in a file.
+pub type Reader = Either<Bytes, File>;
+
+/// The [MapEntry] implementation for [Store].
+#[derive(Debug, Clone)]
+pub struct Entry {
+    hash: blake3::Hash,
+    size: u64,
+    data: Either<Bytes, PathBuf>,
+    outboard: Either<Bytes, PathBuf>,
+}
+
+async fn open(source: Either<Bytes, PathBuf>) -> io::Result<Reader> {
+    Ok(match source {
+        Either::Left(mem) => Either::Left(mem),
+        Either::Right(path) => Either::Right(File::open(path).await?),
+    })
+}
+
+impl MapEntry<Store> for Entry {
+    fn hash(&self) -> blake3::Hash {
+        self.hash
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn available_ranges(&self) -> BoxFuture<'_, io::Result<RangeSet2<ChunkNum>>> {
+        futures::future::ok(RangeSet2::all()).boxed()
+    }
+
+    fn outboard(&self) -> BoxFuture<'_, io::Result<PreOrderOutboard<Reader>>> {
+        async move {
+            Ok(PreOrderOutboard {
+                root: self.hash,
+                tree: BaoTree::new(ByteNum(self.size), IROH_BLOCK_SIZE),
+                data: open(self.outboard.clone()).await?,
+            })
+        }
+        .boxed()
+    }
+
+    fn data_reader(&self) -> BoxFuture<'_, io::Result<Reader>> {
+        open(self.data.clone()).boxed()
+    }
+}
+
+/// The [PartialMapEntry] implementation for [Store].
+#[derive(Debug, Clone)]
+pub struct PartialEntry {
+    hash: blake3::Hash,
+    size: u64,
+    data_path: PathBuf,
+    outboard_path: PathBuf,
+}
+
+impl MapEntry<Store> for PartialEntry {
+    fn hash(&self) -> blake3::Hash {
+        self.hash
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn available_ranges(&self) -> BoxFuture<'_, io::Result<RangeSet2<ChunkNum>>> {
+        futures::future::ok(RangeSet2::all()).boxed()
+    }
+
+    fn outboard(&self) -> BoxFuture<'_, io::Result<PreOrderOutboard<Reader>>> {
+        async move {
+            Ok(PreOrderOutboard {
+                root: self.hash,
+                tree: BaoTree::new(ByteNum(self.size), IROH_BLOCK_SIZE),
+                data: open(Either::Right(self.outboard_path.clone())).await?,
+            })
+        }
+        .boxed()
+    }
+
+    fn data_reader(&self) -> BoxFuture<'_, io::Result<Reader>> {
+        open(Either::Right(self.data_path.clone())).boxed()
+    }
+}
+
+impl PartialMapEntry<Store> for PartialEntry {
+    fn outboard_mut(&self) -> BoxFuture<'_, io::Result<PreOrderOutboard<File>>> {
+        let hash = self.hash;
+        let size = self.size;
+        let tree = BaoTree::new(ByteNum(size), IROH_BLOCK_SIZE);
+        let path = self.outboard_path.clone();
+        async move {
+            let mut writer = File::create(move || {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)
+            })
+            .await?;
+            writer.write_at(0, &size.to_le_bytes()).await?;
+            Ok(PreOrderOutboard {
+                root: hash,
+                tree,
+                data: writer,
+            })
+        }
+        .boxed()
+    }
+
+    fn data_writer(&self) -> BoxFuture<'_, io::Result<File>> {
+        let path = self.data_path.clone();
+        File::create(move || {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+        })
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    db: Database,
+    rt: tokio::runtime::Handle,
+}
+
+/// A database that keeps its metadata in a [redb] database, see the module docs.
+#[derive(Debug, Clone)]
+pub struct Store(Arc<Inner>);
+
+fn to_io_error(e: impl Into<redb::Error>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.into())
+}
+
+fn serialize(value: &impl Serialize) -> io::Result<Vec<u8>> {
+    postcard::to_stdvec(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn deserialize<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    postcard::from_bytes(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn needs_outboard(size: u64) -> bool {
+    size > (IROH_BLOCK_SIZE.bytes() as u64)
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Read a value from a table with hash keys.
+fn read_value(
+    tx: &ReadTransaction<'_>,
+    table: TableDefinition<&[u8; 32], &[u8]>,
+    hash: &Hash,
+) -> io::Result<Option<Vec<u8>>> {
+    let table = tx.open_table(table).map_err(to_io_error)?;
+    let value = table.get(hash.as_bytes()).map_err(to_io_error)?;
+    Ok(value.map(|value| value.value().to_vec()))
+}
+
+/// Read the info of a complete blob in a write transaction.
+fn read_info_mut(tx: &WriteTransaction<'_>, hash: &Hash) -> io::Result<Option<BlobInfo>> {
+    let table = tx.open_table(BLOBS).map_err(to_io_error)?;
+    let value = table.get(hash.as_bytes()).map_err(to_io_error)?;
+    value.map(|value| deserialize(value.value())).transpose()
+}
+
+fn write_info(tx: &WriteTransaction<'_>, hash: &Hash, info: &BlobInfo) -> io::Result<()> {
+    let mut table = tx.open_table(BLOBS).map_err(to_io_error)?;
+    table
+        .insert(hash.as_bytes(), serialize(info)?.as_slice())
+        .map_err(to_io_error)?;
+    Ok(())
+}
+
+impl Map for Store {
+    type Entry = Entry;
+    type Outboard = PreOrderOutboard<Reader>;
+    type DataReader = Reader;
+
+    fn get(&self, hash: &Hash) -> Option<Self::Entry> {
+        match self.get_sync(hash) {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::warn!("failed to read entry {}: {}", hash, e);
+                None
+            }
+        }
+    }
+}
+
+impl PartialMap for Store {
+    type OutboardMut = PreOrderOutboard<File>;
+
+    type DataWriter = File;
+
+    type PartialEntry = PartialEntry;
+
+    fn get_partial(&self, hash: &Hash) -> Option<Self::PartialEntry> {
+        let info = self
+            .read(|tx| read_value(tx, PARTIAL, hash))
+            .and_then(|value| value.map(|value| deserialize(&value)).transpose());
+        match info {
+            Ok(info) => Some(self.partial_entry(*hash, info?)),
+            Err(e) => {
+                tracing::warn!("failed to read partial entry {}: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    fn get_or_create_partial(&self, hash: Hash, size: u64) -> io::Result<Self::PartialEntry> {
+        let info = self.write(|tx| {
+            let mut table = tx.open_table(PARTIAL).map_err(to_io_error)?;
+            let existing = table.get(hash.as_bytes()).map_err(to_io_error)?;
+            if let Some(existing) = existing {
+                return deserialize(existing.value());
+            }
+            drop(existing);
+            let info = PartialInfo {
+                size,
+                uuid: rand::thread_rng().gen(),
+            };
+            table
+                .insert(hash.as_bytes(), serialize(&info)?.as_slice())
+                .map_err(to_io_error)?;
+            Ok(info)
+        })?;
+        Ok(self.partial_entry(hash, info))
+    }
+
+    fn insert_complete(&self, entry: Self::PartialEntry) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.insert_complete_sync(entry))
+            .map(flatten_to_io)
+            .boxed()
+    }
+}
+
+impl ReadableStore for Store {
+    fn blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
+        Box::new(self.keys(BLOBS).into_iter())
+    }
+
+    fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
+        let roots = self.read(|tx| {
+            let table = tx.open_table(ROOTS).map_err(to_io_error)?;
+            let mut roots = Vec::new();
+            for item in table.iter().map_err(to_io_error)? {
+                let (key, _) = item.map_err(to_io_error)?;
+                roots.push(Hash::from(*key.value()));
+            }
+            Ok(roots)
+        });
+        Box::new(self.log_error(roots, "roots").into_iter())
+    }
+
+    fn tags(&self) -> Box<dyn Iterator<Item = (String, TagInfo)> + Send + Sync + 'static> {
+        let tags = self.read(|tx| {
+            let table = tx.open_table(TAGS).map_err(to_io_error)?;
+            let mut tags = Vec::new();
+            for item in table.iter().map_err(to_io_error)? {
+                let (name, info) = item.map_err(to_io_error)?;
+                tags.push((name.value().to_string(), deserialize(info.value())?));
+            }
+            Ok(tags)
+        });
+        Box::new(self.log_error(tags, "tags").into_iter())
+    }
+
+    fn validate(&self, tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
+        let this = self.clone();
+        let task = self.0.rt.spawn_blocking(move || this.validate_sync(tx));
+        async move { task.await? }.boxed()
+    }
+
+    fn partial_blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
+        Box::new(self.keys(PARTIAL).into_iter())
+    }
+
+    fn export(
+        &self,
+        hash: Hash,
+        target: PathBuf,
+        mode: ExportMode,
+        progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
+    ) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.export_sync(hash, target, mode, progress))
+            .map(flatten_to_io)
+            .boxed()
+    }
+}
+
+impl baomap::Store for Store {
+    fn import(
+        &self,
+        path: PathBuf,
+        mode: ImportMode,
+        progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
+    ) -> BoxFuture<'_, io::Result<(Hash, u64)>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.import_sync(path, mode, progress))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn import_bytes(&self, data: Bytes) -> BoxFuture<'_, io::Result<Hash>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.import_bytes_sync(data))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn pin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.set_pinned_sync(hash, true))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn unpin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.set_pinned_sync(hash, false))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn set_tag(&self, name: String, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.set_tag_sync(name, Some(hash)))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn delete_tag(&self, name: String) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .rt
+            .spawn_blocking(move || this.set_tag_sync(name, None))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn delete(&self, hash: &Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        let hash = *hash;
+        self.0
+            .rt
+            .spawn_blocking(move || this.delete_sync(hash))
+            .map(flatten_to_io)
+            .boxed()
+    }
+}
+
+impl Store {
+    /// Load a database from disk.
+    ///
+    /// The directory and the database file are created if they don't exist.
+    pub async fn load(path: impl AsRef<Path>, rt: &runtime::Handle) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let rtc = rt.clone();
+        let db = rt
+            .main()
+            .spawn_blocking(move || Self::load_sync(path, rtc))
+            .await??;
+        Ok(db)
+    }
+
+    /// Blocking load a database from disk.
+    pub fn load_blocking(path: impl AsRef<Path>, rt: &runtime::Handle) -> anyhow::Result<Self> {
+        Self::load_sync(path.as_ref().to_path_buf(), rt.clone())
+    }
+
+    fn load_sync(path: PathBuf, rt: runtime::Handle) -> anyhow::Result<Self> {
+        tracing::info!("loading database from {}", path.display());
+        std::fs::create_dir_all(&path)?;
+        // temp files are never needed after a restart
+        for entry in std::fs::read_dir(&path)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "temp") {
+                tracing::info!("removing temp file {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+        }
+        let db = Database::create(path.join(DB_FILE_NAME))?;
+        // create the tables, so they can be opened in read transactions
+        let tx = db.begin_write()?;
+        tx.open_table(BLOBS)?;
+        tx.open_table(INLINE_DATA)?;
+        tx.open_table(INLINE_OUTBOARD)?;
+        tx.open_table(PARTIAL)?;
+        tx.open_table(ROOTS)?;
+        tx.open_table(TAGS)?;
+        tx.commit()?;
+        Ok(Self(Arc::new(Inner {
+            path,
+            db,
+            rt: rt.main().clone(),
+        })))
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&ReadTransaction<'_>) -> io::Result<T>) -> io::Result<T> {
+        let tx = self.0.db.begin_read().map_err(to_io_error)?;
+        f(&tx)
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&WriteTransaction<'_>) -> io::Result<T>) -> io::Result<T> {
+        let tx = self.0.db.begin_write().map_err(to_io_error)?;
+        let res = f(&tx)?;
+        tx.commit().map_err(to_io_error)?;
+        Ok(res)
+    }
+
+    /// The keys of a table with hash keys.
+    fn keys(&self, table: TableDefinition<&[u8; 32], &[u8]>) -> Vec<Hash> {
+        let keys = self.read(|tx| {
+            let table = tx.open_table(table).map_err(to_io_error)?;
+            let mut keys = Vec::new();
+            for item in table.iter().map_err(to_io_error)? {
+                let (key, _) = item.map_err(to_io_error)?;
+                keys.push(Hash::from(*key.value()));
+            }
+            Ok(keys)
+        });
+        self.log_error(keys, table.name())
+    }
+
+    fn log_error<T: Default>(&self, res: io::Result<T>, what: &str) -> T {
+        res.unwrap_or_else(|e| {
+            tracing::warn!("failed to read {}: {}", what, e);
+            T::default()
+        })
+    }
+
+    fn get_sync(&self, hash: &Hash) -> io::Result<Option<Entry>> {
+        self.read(|tx| {
+            let Some(info) = read_value(tx, BLOBS, hash)? else {
+                let Some(partial) = read_value(tx, PARTIAL, hash)? else {
+                    return Ok(None);
+                };
+                let partial = self.partial_entry(*hash, deserialize(&partial)?);
+                return Ok(Some(Entry {
+                    hash: partial.hash,
+                    size: partial.size,
+                    data: Either::Right(partial.data_path),
+                    outboard: Either::Right(partial.outboard_path),
+                }));
+            };
+            let info: BlobInfo = deserialize(&info)?;
+            let data = match info.owned {
+                Some(Location::Inline) => Either::Left(
+                    read_value(tx, INLINE_DATA, hash)?
+                        .ok_or_else(|| missing("inline data"))?
+                        .into(),
+                ),
+                Some(Location::File) => Either::Right(self.data_path(hash)),
+                None => match info.external.iter().next() {
+                    Some(path) => Either::Right(path.clone()),
+                    // no valid data location, so we don't have the data
+                    None => return Ok(None),
+                },
+            };
+            let outboard = if !needs_outboard(info.size) {
+                Either::Left(Bytes::from(info.size.to_le_bytes().to_vec()))
+            } else {
+                match info.outboard {
+                    Some(Location::Inline) => Either::Left(
+                        read_value(tx, INLINE_OUTBOARD, hash)?
+                            .ok_or_else(|| missing("inline outboard"))?
+                            .into(),
+                    ),
+                    Some(Location::File) => Either::Right(self.outboard_path(hash)),
+                    None => return Err(missing("outboard")),
+                }
+            };
+            Ok(Some(Entry {
+                hash: (*hash).into(),
+                size: info.size,
+                data,
+                outboard,
+            }))
+        })
+    }
+
+    fn partial_entry(&self, hash: Hash, info: PartialInfo) -> PartialEntry {
+        PartialEntry {
+            hash: hash.into(),
+            size: info.size,
+            data_path: self.partial_data_path(&hash, &info.uuid),
+            outboard_path: self.partial_outboard_path(&hash, &info.uuid),
+        }
+    }
+
+    fn import_sync(
+        self,
+        path: PathBuf,
+        mode: ImportMode,
+        progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
+    ) -> io::Result<(Hash, u64)> {
+        if !path.is_absolute() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path must be absolute",
+            ));
+        }
+        if !path.is_file() && !path.is_symlink() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path is not a file or symlink",
+            ));
+        }
+        let id = progress.new_id();
+        progress.blocking_send(ImportProgress::Found {
+            id,
+            path: path.clone(),
+        })?;
+        let progress2 = progress.clone();
+        let outboard_progress =
+            move |offset| Ok(progress2.try_send(ImportProgress::OutboardProgress { id, offset })?);
+        let (hash, size, data, outboard) = match mode {
+            ImportMode::Chunked => {
+                return iroh_bytes::chunked::import_file(&path, id, &progress, |data| {
+                    self.import_bytes_sync(data)
+                });
+            }
+            ImportMode::TryReference => {
+                // compute outboard and hash from the data in place, since we assume that it is stable
+                let size = path.metadata()?.len();
+                progress.blocking_send(ImportProgress::Size { id, size })?;
+                let file = std::fs::File::open(&path)?;
+                let (hash, outboard) = outboard_from_reader(file, size, outboard_progress)?;
+                (hash, size, NewData::External(path), outboard)
+            }
+            ImportMode::Copy => {
+                // copy the data, since it is not stable
+                let temp_path = self.temp_path();
+                progress.try_send(ImportProgress::CopyProgress { id, offset: 0 })?;
+                let size = std::fs::copy(&path, &temp_path)?;
+                progress.blocking_send(ImportProgress::Size { id, size })?;
+                // compute outboard and hash from the temp file that we own
+                let file = std::fs::File::open(&temp_path)?;
+                let (hash, outboard) = outboard_from_reader(file, size, outboard_progress)?;
+                let data = self.take_owned_data(&hash, size, &temp_path)?;
+                (hash, size, data, outboard)
+            }
+        };
+        progress.blocking_send(ImportProgress::OutboardDone { id, hash })?;
+        self.insert_entry(hash, size, data, outboard)?;
+        Ok((hash, size))
+    }
+
+    fn import_bytes_sync(&self, data: Bytes) -> io::Result<Hash> {
+        let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        let hash = hash.into();
+        let size = data.len() as u64;
+        let data = if size <= INLINE_DATA_THRESHOLD {
+            NewData::Inline(data)
+        } else {
+            let temp_path = self.temp_path();
+            std::fs::write(&temp_path, &data)?;
+            std::fs::rename(temp_path, self.data_path(&hash))?;
+            NewData::Owned
+        };
+        let outboard = if outboard.len() > 8 {
+            Some(outboard)
+        } else {
+            None
+        };
+        self.insert_entry(hash, size, data, outboard)?;
+        Ok(hash)
+    }
+
+    /// Take ownership of the complete data in the file at `path`.
+    ///
+    /// Small data is moved into the database, larger data to the data file for `hash`.
+    fn take_owned_data(&self, hash: &Hash, size: u64, path: &Path) -> io::Result<NewData> {
+        if size <= INLINE_DATA_THRESHOLD {
+            let mut data = Vec::with_capacity(size as usize);
+            std::fs::File::open(path)?
+                .take(size)
+                .read_to_end(&mut data)?;
+            std::fs::remove_file(path)?;
+            Ok(NewData::Inline(data.into()))
+        } else {
+            std::fs::rename(path, self.data_path(hash))?;
+            Ok(NewData::Owned)
+        }
+    }
+
+    fn insert_complete_sync(&self, entry: PartialEntry) -> io::Result<()> {
+        let hash = entry.hash.into();
+        let data = self.take_owned_data(&hash, entry.size, &entry.data_path)?;
+        let outboard = match std::fs::read(&entry.outboard_path) {
+            Ok(outboard) => {
+                std::fs::remove_file(&entry.outboard_path)?;
+                Some(outboard)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        self.insert_entry(hash, entry.size, data, outboard)
+    }
+
+    /// Add new data for a complete blob, replacing a partial entry for it.
+    fn insert_entry(
+        &self,
+        hash: Hash,
+        size: u64,
+        data: NewData,
+        outboard: Option<Vec<u8>>,
+    ) -> io::Result<()> {
+        let outboard = outboard.filter(|_| needs_outboard(size));
+        if let Some(outboard) = outboard.as_ref() {
+            if outboard.len() > INLINE_OUTBOARD_THRESHOLD {
+                let temp_path = self.temp_path();
+                std::fs::write(&temp_path, outboard)?;
+                std::fs::rename(temp_path, self.outboard_path(&hash))?;
+            }
+        }
+        let partial = self.write(|tx| {
+            let mut info = read_info_mut(tx, &hash)?.unwrap_or_default();
+            if info.size != 0 && info.size != size {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "size mismatch"));
+            }
+            info.size = size;
+            match data {
+                NewData::Inline(data) => {
+                    let mut table = tx.open_table(INLINE_DATA).map_err(to_io_error)?;
+                    table
+                        .insert(hash.as_bytes(), &data[..])
+                        .map_err(to_io_error)?;
+                    info.owned = Some(Location::Inline);
+                }
+                NewData::Owned => info.owned = Some(Location::File),
+                NewData::External(path) => {
+                    info.external.insert(path);
+                }
+            }
+            if let Some(outboard) = outboard {
+                if outboard.len() > INLINE_OUTBOARD_THRESHOLD {
+                    info.outboard = Some(Location::File);
+                } else {
+                    let mut table = tx.open_table(INLINE_OUTBOARD).map_err(to_io_error)?;
+                    table
+                        .insert(hash.as_bytes(), outboard.as_slice())
+                        .map_err(to_io_error)?;
+                    info.outboard = Some(Location::Inline);
+                }
+            }
+            write_info(tx, &hash, &info)?;
+            let mut table = tx.open_table(PARTIAL).map_err(to_io_error)?;
+            let partial = table.remove(hash.as_bytes()).map_err(to_io_error)?;
+            partial
+                .map(|partial| deserialize::<PartialInfo>(partial.value()))
+                .transpose()
+        })?;
+        // the complete entry replaces the partial entry
+        if let Some(partial) = partial {
+            remove_file_if_exists(&self.partial_data_path(&hash, &partial.uuid))?;
+            remove_file_if_exists(&self.partial_outboard_path(&hash, &partial.uuid))?;
+        }
+        Ok(())
+    }
+
+    fn export_sync(
+        &self,
+        hash: Hash,
+        target: PathBuf,
+        mode: ExportMode,
+        progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
+    ) -> io::Result<()> {
+        tracing::trace!("exporting {} to {} ({:?})", hash, target.display(), mode);
+        if !target.is_absolute() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "target path must be absolute",
+            ));
+        }
+        let parent = target.parent().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "target path has no parent directory",
+            )
+        })?;
+        // create the directory in which the target file is
+        std::fs::create_dir_all(parent)?;
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "hash not found in database");
+        let info = self
+            .read(|tx| read_value(tx, BLOBS, &hash))?
+            .ok_or_else(not_found)?;
+        let info: BlobInfo = deserialize(&info)?;
+        let stable = mode == ExportMode::TryReference;
+        let mut moved = false;
+        if info.owned == Some(Location::File) && stable && info.size >= MOVE_THRESHOLD {
+            tracing::info!("moving {} to {}", hash, target.display());
+            // the target might be on another file system, copy it then
+            match std::fs::rename(self.data_path(&hash), &target) {
+                Ok(()) => moved = true,
+                Err(e) => tracing::warn!("rename failed, copying instead: {}", e),
+            }
+        }
+        if !moved {
+            tracing::info!("copying {} to {}", hash, target.display());
+            progress(0)?;
+            match info.owned {
+                Some(Location::Inline) => {
+                    let data = self
+                        .read(|tx| read_value(tx, INLINE_DATA, &hash))?
+                        .ok_or_else(|| missing("inline data"))?;
+                    std::fs::write(&target, data)?;
+                }
+                Some(Location::File) => {
+                    std::fs::copy(self.data_path(&hash), &target)?;
+                }
+                None => {
+                    let source = info.external.iter().next().ok_or_else(not_found)?;
+                    std::fs::copy(source, &target)?;
+                }
+            }
+            progress(info.size)?;
+        }
+        if stable {
+            self.write(|tx| {
+                let mut info = read_info_mut(tx, &hash)?.ok_or_else(not_found)?;
+                if moved {
+                    info.owned = None;
+                }
+                info.external.insert(target);
+                write_info(tx, &hash, &info)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn set_pinned_sync(&self, hash: Hash, pinned: bool) -> io::Result<()> {
+        self.write(|tx| {
+            let mut table = tx.open_table(ROOTS).map_err(to_io_error)?;
+            if pinned {
+                table.insert(hash.as_bytes(), ()).map_err(to_io_error)?;
+            } else {
+                table.remove(hash.as_bytes()).map_err(to_io_error)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_tag_sync(&self, name: String, hash: Option<Hash>) -> io::Result<()> {
+        self.write(|tx| {
+            let mut table = tx.open_table(TAGS).map_err(to_io_error)?;
+            if let Some(hash) = hash {
+                let created = SystemTime::now();
+                let info = serialize(&TagInfo { hash, created })?;
+                table
+                    .insert(name.as_str(), info.as_slice())
+                    .map_err(to_io_error)?;
+            } else {
+                table.remove(name.as_str()).map_err(to_io_error)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_sync(&self, hash: Hash) -> io::Result<()> {
+        let key = hash.as_bytes();
+        let (info, partial) = self.write(|tx| {
+            let info = read_info_mut(tx, &hash)?;
+            for table in [BLOBS, INLINE_DATA, INLINE_OUTBOARD] {
+                let mut table = tx.open_table(table).map_err(to_io_error)?;
+                table.remove(key).map_err(to_io_error)?;
+            }
+            let mut roots = tx.open_table(ROOTS).map_err(to_io_error)?;
+            roots.remove(key).map_err(to_io_error)?;
+            drop(roots);
+            let partial = {
+                let mut table = tx.open_table(PARTIAL).map_err(to_io_error)?;
+                let partial = table.remove(key).map_err(to_io_error)?;
+                partial
+                    .map(|partial| deserialize::<PartialInfo>(partial.value()))
+                    .transpose()?
+            };
+            // remove all tags that point to the hash
+            let mut table = tx.open_table(TAGS).map_err(to_io_error)?;
+            let mut names = Vec::new();
+            for item in table.iter().map_err(to_io_error)? {
+                let (name, info) = item.map_err(to_io_error)?;
+                let info: TagInfo = deserialize(info.value())?;
+                if info.hash == hash {
+                    names.push(name.value().to_string());
+                }
+            }
+            for name in names {
+                table.remove(name.as_str()).map_err(to_io_error)?;
+            }
+            Ok((info, partial))
+        })?;
+        if let Some(info) = info {
+            tracing::info!("deleting complete {}", hash);
+            // external files belong to the user
+            if info.owned == Some(Location::File) {
+                remove_file_if_exists(&self.data_path(&hash))?;
+            }
+            if info.outboard == Some(Location::File) {
+                remove_file_if_exists(&self.outboard_path(&hash))?;
+            }
+        }
+        if let Some(partial) = partial {
+            tracing::info!("deleting partial {}", hash);
+            remove_file_if_exists(&self.partial_data_path(&hash, &partial.uuid))?;
+            remove_file_if_exists(&self.partial_outboard_path(&hash, &partial.uuid))?;
+        }
+        Ok(())
+    }
+
+    fn validate_sync(&self, tx: mpsc::Sender<ValidateProgress>) -> anyhow::Result<()> {
+        let hashes = self.keys(BLOBS);
+        tx.blocking_send(ValidateProgress::Starting {
+            total: hashes.len() as u64,
+        })?;
+        for (id, hash) in hashes.into_iter().enumerate() {
+            let id = id as u64;
+            let entry = self.get_sync(&hash);
+            let (size, path) = match &entry {
+                Ok(Some(Entry {
+                    size,
+                    data: Either::Right(path),
+                    ..
+                })) => (*size, Some(path.clone())),
+                Ok(Some(entry)) => (entry.size, None),
+                _ => (0, None),
+            };
+            tx.blocking_send(ValidateProgress::Entry {
+                id,
+                hash,
+                path: path.map(|path| path.display().to_string()),
+                size,
+            })?;
+            let result = entry.and_then(|entry| {
+                let entry = entry.ok_or_else(|| missing("data"))?;
+                let tx = tx.clone();
+                validate_entry(&entry, move |offset| {
+                    // progress is best effort
+                    tx.try_send(ValidateProgress::Progress { id, offset }).ok();
+                    Ok(())
+                })
+            });
+            let error = result.err().map(|e| e.to_string());
+            tx.blocking_send(ValidateProgress::Done { id, error })?;
+        }
+        tx.blocking_send(ValidateProgress::AllDone)?;
+        Ok(())
+    }
+
+    fn data_path(&self, hash: &Hash) -> PathBuf {
+        self.0.path.join(format!("{}.data", hex::encode(hash)))
+    }
+
+    fn outboard_path(&self, hash: &Hash) -> PathBuf {
+        self.0.path.join(format!("{}.obao4", hex::encode(hash)))
+    }
+
+    fn partial_data_path(&self, hash: &Hash, uuid: &[u8; 16]) -> PathBuf {
+        let name = format!("{}-{}.data", hex::encode(hash), hex::encode(uuid));
+        self.0.path.join(name)
+    }
+
+    fn partial_outboard_path(&self, hash: &Hash, uuid: &[u8; 16]) -> PathBuf {
+        let name = format!("{}-{}.obao4", hex::encode(hash), hex::encode(uuid));
+        self.0.path.join(name)
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let uuid = rand::thread_rng().gen::<[u8; 16]>();
+        self.0.path.join(format!("{}.temp", hex::encode(uuid)))
+    }
+}
+
+fn missing(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("missing {what}"))
+}
+
+/// Check that the data of a complete entry matches its hash and outboard.
+fn validate_entry(
+    entry: &Entry,
+    progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
+) -> io::Result<()> {
+    let (actual_size, reader): (u64, Box<dyn Read>) = match &entry.data {
+        Either::Left(data) => (data.len() as u64, Box::new(io::Cursor::new(data.clone()))),
+        Either::Right(path) => {
+            let file = std::fs::File::open(path)?;
+            (file.metadata()?.len(), Box::new(file))
+        }
+    };
+    if actual_size != entry.size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "size mismatch"));
+    }
+    let (hash, outboard) = outboard_from_reader(reader, entry.size, progress)?;
+    if blake3::Hash::from(hash) != entry.hash {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "hash mismatch"));
+    }
+    if let Some(outboard) = outboard {
+        let expected = match &entry.outboard {
+            Either::Left(data) => data.to_vec(),
+            Either::Right(path) => std::fs::read(path)?,
+        };
+        if outboard != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "outboard mismatch",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_bytes::baomap::Store as _;
+    use iroh_bytes::util::progress::IgnoreProgressSender;
+    use iroh_io::AsyncSliceReader;
+
+    use super::*;
+
+    /// Run validate and return the errors for all entries.
+    async fn validate(db: &Store) -> anyhow::Result<Vec<Option<String>>> {
+        let (tx, mut rx) = mpsc::channel(16);
+        let task = tokio::spawn({
+            let db = db.clone();
+            async move { db.validate(tx).await }
+        });
+        let mut errors = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            if let ValidateProgress::Done { error, .. } = msg {
+                errors.push(error);
+            }
+        }
+        task.await??;
+        Ok(errors)
+    }
+
+    async fn read_data(db: &Store, hash: &Hash) -> anyhow::Result<Bytes> {
+        let entry = db.get(hash).expect("entry exists");
+        Ok(entry.data_reader().await?.read_at(0, usize::MAX).await?)
+    }
+
+    #[tokio::test]
+    async fn store_roundtrip() -> anyhow::Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("store");
+        let db = Store::load(&path, &rt).await?;
+        // one inlined blob, one with inlined outboard, one with everything in files
+        let sizes = [100, 1024 * 1024, 8 * 1024 * 1024];
+        let mut hashes = Vec::new();
+        for size in sizes {
+            let data = Bytes::from(vec![(size % 251) as u8; size]);
+            let hash = db.import_bytes(data.clone()).await?;
+            assert_eq!(read_data(&db, &hash).await?, data);
+            let entry = db.get(&hash).unwrap();
+            let mut outboard = entry.outboard().await?;
+            let (expected, _) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+            assert_eq!(outboard.data.len().await?, expected.len() as u64);
+            hashes.push(hash);
+        }
+        assert!(!db.data_path(&hashes[0]).exists());
+        assert!(db.data_path(&hashes[1]).exists());
+        assert!(!db.outboard_path(&hashes[1]).exists());
+        assert!(db.outboard_path(&hashes[2]).exists());
+        db.pin(hashes[0]).await?;
+        db.set_tag("test".to_string(), hashes[1]).await?;
+
+        // everything survives a reload
+        drop(db);
+        let db = Store::load(&path, &rt).await?;
+        let mut blobs = db.blobs().collect::<Vec<_>>();
+        blobs.sort();
+        let mut expected = hashes.clone();
+        expected.sort();
+        assert_eq!(blobs, expected);
+        assert_eq!(db.roots().collect::<Vec<_>>(), vec![hashes[0]]);
+        let tags = db.tags().collect::<Vec<_>>();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].0, "test");
+        assert_eq!(tags[0].1.hash, hashes[1]);
+        assert_eq!(read_data(&db, &hashes[0]).await?, vec![100u8; 100]);
+        assert_eq!(validate(&db).await?, vec![None, None, None]);
+
+        // corrupt a data file
+        std::fs::write(db.data_path(&hashes[1]), vec![0u8; 1024 * 1024])?;
+        let errors = validate(&db).await?;
+        assert_eq!(errors.iter().filter(|e| e.is_some()).count(), 1);
+
+        for hash in &hashes {
+            db.delete(hash).await?;
+            assert!(db.get(hash).is_none());
+            assert!(!db.data_path(hash).exists());
+            assert!(!db.outboard_path(hash).exists());
+        }
+        assert_eq!(db.blobs().count(), 0);
+        assert_eq!(db.roots().count(), 0);
+        assert_eq!(db.tags().count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_and_export() -> anyhow::Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let db = Store::load(dir.path().join("store"), &rt).await?;
+        let source = dir.path().join("source");
+        let data = (0..1024 * 1024).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+        std::fs::write(&source, &data)?;
+
+        let progress = IgnoreProgressSender::default();
+        let (hash, size) = db
+            .import(source.clone(), ImportMode::Copy, progress.clone())
+            .await?;
+        assert_eq!(size, data.len() as u64);
+        assert!(db.data_path(&hash).exists());
+        let (hash2, _) = db
+            .import(source.clone(), ImportMode::TryReference, progress)
+            .await?;
+        assert_eq!(hash, hash2);
+
+        // exporting with TryReference moves the data out of the store
+        let target = dir.path().join("target");
+        db.export(hash, target.clone(), ExportMode::TryReference, |_| Ok(()))
+            .await?;
+        assert_eq!(std::fs::read(&target)?, data);
+        assert!(!db.data_path(&hash).exists());
+        assert_eq!(read_data(&db, &hash).await?, data);
+        assert_eq!(validate(&db).await?, vec![None]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_to_complete() -> anyhow::Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let dir = tempfile::tempdir()?;
+        let db = Store::load(dir.path(), &rt).await?;
+        let data = (0..100_000).map(|i| (i % 7) as u8).collect::<Vec<_>>();
+        let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        let hash = Hash::from(hash);
+
+        let entry = db.get_or_create_partial(hash, data.len() as u64)?;
+        entry.data_writer().await?.write_at(0, &data).await?;
+        entry
+            .outboard_mut()
+            .await?
+            .data
+            .write_at(0, &outboard)
+            .await?;
+        assert_eq!(db.partial_blobs().collect::<Vec<_>>(), vec![hash]);
+        assert!(db.get_partial(&hash).is_some());
+        assert_eq!(read_data(&db, &hash).await?, data);
+
+        db.insert_complete(entry.clone()).await?;
+        assert!(db.get_partial(&hash).is_none());
+        assert!(!entry.data_path.exists());
+        assert!(!entry.outboard_path.exists());
+        assert_eq!(db.blobs().collect::<Vec<_>>(), vec![hash]);
+        assert_eq!(read_data(&db, &hash).await?, data);
+        assert_eq!(validate(&db).await?, vec![None]);
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 //! A full in memory database for iroh-bytes
 //!
 //! Main entry point is [Store].
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
 use std::io::Write;
 use std::num::TryFromIntError;
@@ -9,6 +9,7 @@ use std::ops::DerefMut;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::SystemTime;
 
 use bao_tree::blake3;
 use bao_tree::io::fsm::Outboard;
@@ -29,6 +30,7 @@ use iroh_bytes::baomap::ImportMode;
 use iroh_bytes::baomap::ImportProgress;
 use iroh_bytes::baomap::PartialMap;
 use iroh_bytes::baomap::PartialMapEntry;
+use iroh_bytes::baomap::TagInfo;
 use iroh_bytes::baomap::ValidateProgress;
 use iroh_bytes::baomap::{Map, MapEntry, ReadableStore};
 use iroh_bytes::util::progress::IdGenerator;
@@ -196,6 +198,8 @@ struct Inner {
 struct State {
     complete: BTreeMap<Hash, (Bytes, PreOrderOutboard<Bytes>)>,
     partial: BTreeMap<Hash, (MutableMemFile, PreOrderOutboard<MutableMemFile>)>,
+    roots: BTreeSet<Hash>,
+    tags: BTreeMap<String, TagInfo>,
 }
 
 /// The [MapEntry] implementation for [Store].
@@ -313,7 +317,19 @@ impl ReadableStore for Store {
     }
 
     fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
-        Box::new(std::iter::empty())
+        let state = self.0.state.read().unwrap();
+        let roots = state.roots.iter().cloned().collect::<Vec<_>>();
+        Box::new(roots.into_iter())
+    }
+
+    fn tags(&self) -> Box<dyn Iterator<Item = (String, TagInfo)> + Send + Sync + 'static> {
+        let state = self.0.state.read().unwrap();
+        let tags = state
+            .tags
+            .iter()
+            .map(|(name, info)| (name.clone(), *info))
+            .collect::<Vec<_>>();
+        Box::new(tags.into_iter())
     }
 
     fn validate(&self, _tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
@@ -414,7 +430,7 @@ impl baomap::Store for Store {
     fn import(
         &self,
         path: std::path::PathBuf,
-        _mode: ImportMode,
+        mode: ImportMode,
         progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
     ) -> BoxFuture<'_, io::Result<(Hash, u64)>> {
         let this = self.clone();
@@ -427,6 +443,11 @@ impl baomap::Store for Store {
                     id,
                     path: path.clone(),
                 })?;
+                if mode == ImportMode::Chunked {
+                    return iroh_bytes::chunked::import_file(&path, id, &progress, |data| {
+                        this.import_bytes_sync(data, IgnoreProgressSender::default())
+                    });
+                }
                 progress.try_send(ImportProgress::CopyProgress { id, offset: 0 })?;
                 // todo: provide progress for reading into mem
                 let bytes: Bytes = std::fs::read(path)?.into();
@@ -451,6 +472,38 @@ impl baomap::Store for Store {
             .map(flatten_to_io)
             .boxed()
     }
+
+    fn pin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        self.0.state.write().unwrap().roots.insert(hash);
+        futures::future::ok(()).boxed()
+    }
+
+    fn unpin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        self.0.state.write().unwrap().roots.remove(&hash);
+        futures::future::ok(()).boxed()
+    }
+
+    fn set_tag(&self, name: String, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let created = SystemTime::now();
+        let mut state = self.0.state.write().unwrap();
+        state.tags.insert(name, TagInfo { hash, created });
+        futures::future::ok(()).boxed()
+    }
+
+    fn delete_tag(&self, name: String) -> BoxFuture<'_, io::Result<()>> {
+        self.0.state.write().unwrap().tags.remove(&name);
+        futures::future::ok(()).boxed()
+    }
+
+    fn delete(&self, hash: &Hash) -> BoxFuture<'_, io::Result<()>> {
+        tracing::info!("delete {}", hash);
+        let mut state = self.0.state.write().unwrap();
+        state.complete.remove(hash);
+        state.partial.remove(hash);
+        state.roots.remove(hash);
+        state.tags.retain(|_, info| info.hash != *hash);
+        futures::future::ok(()).boxed()
+    }
 }
 
 impl Store {
@@ -24,7 +24,7 @@ use futures::{
 use iroh_bytes::{
     baomap::{
         self, range_collections::RangeSet2, ExportMode, ImportMode, ImportProgress, Map, MapEntry,
-        PartialMap, PartialMapEntry, ReadableStore, ValidateProgress,
+        PartialMap, PartialMapEntry, ReadableStore, TagInfo, ValidateProgress,
     },
     util::progress::{IdGenerator, ProgressSender},
     Hash, IROH_BLOCK_SIZE,
@@ -223,6 +223,10 @@ impl ReadableStore for Store {
         Box::new(std::iter::empty())
     }
 
+    fn tags(&self) -> Box<dyn Iterator<Item = (String, TagInfo)> + Send + Sync + 'static> {
+        Box::new(std::iter::empty())
+    }
+
     fn validate(
         &self,
         _tx: mpsc::Sender<ValidateProgress>,
@@ -300,4 +304,29 @@ impl baomap::Store for Store {
         let _ = bytes;
         async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
     }
+
+    fn pin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let _ = hash;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn unpin(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let _ = hash;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn set_tag(&self, name: String, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let _ = (name, hash);
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn delete_tag(&self, name: String) -> BoxFuture<'_, io::Result<()>> {
+        let _ = name;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn delete(&self, hash: &Hash) -> BoxFuture<'_, io::Result<()>> {
+        let _ = hash;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
 }
@@ -9,6 +9,8 @@ use iroh_bytes::Hash;
 use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
 use serde::{Deserialize, Serialize};
 
+pub mod tree;
+
 /// A collection of blobs
 ///
 /// Note that the format is subject to change.
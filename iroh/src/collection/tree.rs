@@ -0,0 +1,452 @@
+//! A hierarchical collection format.
+//!
+//! A [`Directory`] is a blob describing the entries of a single directory. File entries link
+//! to the blob with their data, sub-directory entries link to the [`Directory`] blob that
+//! describes them, and symlinks just store their target. Since every sub-directory is a
+//! collection of its own, it can also be fetched on its own using its hash.
+//!
+//! The children of a directory, as seen by the iroh-bytes protocol, are the linked blobs of
+//! its entries, in order. Symlinks do not have a child. Files that were imported with
+//! [`iroh_bytes::baomap::ImportMode::Chunked`] link to the [`Manifest`] of their chunks,
+//! which is a collection of its own, just like a sub-directory.
+//!
+//! [`TreeCollectionParser`] parses this format and chunk manifests, and falls back to the flat
+//! [`Collection`] format for anything else.
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use futures::{
+    future::{self, LocalBoxFuture},
+    FutureExt,
+};
+use iroh_bytes::chunked::Manifest;
+use iroh_bytes::collection::{CollectionParser, CollectionStats, LinkStream};
+use iroh_bytes::Hash;
+use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
+use serde::{Deserialize, Serialize};
+
+use super::{ArrayLinkStream, Collection};
+
+/// Prefix of a serialized [`Directory`], to tell it apart from a flat [`Collection`].
+const MAGIC: &[u8; 8] = b"iroh-dir";
+
+/// The entries of a single directory
+///
+/// Note that the format is subject to change.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Directory {
+    /// The entries, sorted by name
+    entries: Vec<Entry>,
+}
+
+impl Directory {
+    /// Create a new directory from a list of entries
+    ///
+    /// Entry names must be unique, non empty, and can not contain path separators.
+    pub fn new(mut entries: Vec<Entry>) -> Result<Self> {
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let dir = Self { entries };
+        dir.validate()?;
+        Ok(dir)
+    }
+
+    /// Check that all entry names are a single normal path component, and that the
+    /// entries are sorted by name without duplicates.
+    fn validate(&self) -> Result<()> {
+        for entry in &self.entries {
+            let mut components = Path::new(&entry.name).components();
+            let single = matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(name)), None) if name == entry.name.as_str()
+            );
+            anyhow::ensure!(
+                single && !entry.name.contains(['/', '\\']),
+                "invalid entry name {:?}",
+                entry.name
+            );
+        }
+        for pair in self.entries.windows(2) {
+            anyhow::ensure!(
+                pair[0].name < pair[1].name,
+                "duplicate entry name {:?}",
+                pair[1].name
+            );
+        }
+        Ok(())
+    }
+
+    /// Serialize this directory to a std `Vec<u8>`
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = MAGIC.to_vec();
+        data.extend(postcard::to_stdvec(self)?);
+        Ok(data)
+    }
+
+    /// Deserialize a directory from a byte slice
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let data = data
+            .strip_prefix(MAGIC)
+            .context("not a serialized Directory")?;
+        let dir: Directory =
+            postcard::from_bytes(data).context("failed to deserialize Directory data")?;
+        // the data comes from a remote peer, and the names are used as paths on export
+        dir.validate()?;
+        Ok(dir)
+    }
+
+    /// True if the data looks like a serialized directory
+    pub fn is_directory(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Entries in this directory, sorted by name
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The linked blobs of the entries, in order, and whether each one is a collection,
+    /// i.e. a sub-directory or a chunk manifest
+    ///
+    /// These are the children of the directory when it is requested as a collection.
+    pub fn links(&self) -> impl Iterator<Item = (Hash, bool)> + '_ {
+        self.entries.iter().filter_map(|entry| match entry.kind {
+            EntryKind::File { hash, .. } => Some((hash, false)),
+            EntryKind::Directory { hash, .. } | EntryKind::Chunked { hash, .. } => {
+                Some((hash, true))
+            }
+            EntryKind::Symlink { .. } => None,
+        })
+    }
+
+    /// Total size of the linked blobs of the entries
+    ///
+    /// This does not include the contents of sub-directories. For chunked files, this is
+    /// the size of the file rather than the size of the manifest.
+    pub fn total_blobs_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| match entry.kind {
+                EntryKind::File { size, .. }
+                | EntryKind::Directory { size, .. }
+                | EntryKind::Chunked { size, .. } => size,
+                EntryKind::Symlink { .. } => 0,
+            })
+            .sum()
+    }
+}
+
+/// An entry of a [`Directory`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The name of the entry, a single path component
+    pub name: String,
+    /// The unix file mode, including the permission bits
+    pub mode: u32,
+    /// The modification time, in seconds since the unix epoch
+    pub mtime: u64,
+    /// What kind of entry this is
+    pub kind: EntryKind,
+}
+
+impl Entry {
+    /// The modification time of the entry
+    pub fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.mtime)
+    }
+
+    /// The hash of the linked blob, if any
+    pub fn hash(&self) -> Option<Hash> {
+        match self.kind {
+            EntryKind::File { hash, .. }
+            | EntryKind::Directory { hash, .. }
+            | EntryKind::Chunked { hash, .. } => Some(hash),
+            EntryKind::Symlink { .. } => None,
+        }
+    }
+}
+
+/// The kind of a directory [`Entry`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    /// A regular file
+    File {
+        /// The hash of the file data
+        hash: Hash,
+        /// The size of the file data
+        size: u64,
+    },
+    /// A sub-directory
+    Directory {
+        /// The hash of the serialized [`Directory`]
+        hash: Hash,
+        /// The size of the serialized [`Directory`]
+        size: u64,
+    },
+    /// A symbolic link
+    Symlink {
+        /// The target of the link
+        target: String,
+    },
+    /// A regular file, split into content-defined chunks
+    Chunked {
+        /// The hash of the [`Manifest`] listing the chunks
+        hash: Hash,
+        /// The size of the file data
+        size: u64,
+    },
+}
+
+/// Parser for hierarchical collections
+///
+/// Parses [`Directory`] and [`Manifest`] blobs, and falls back to parsing flat
+/// [`Collection`]s otherwise.
+/// Like [`super::IrohCollectionParser`] it loads the entire collection into memory.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TreeCollectionParser;
+
+/// Stream of links of a [`Directory`]
+///
+/// Knows which of the links are collections, see [`LinkStream::is_collection`].
+#[derive(Debug, Clone)]
+pub struct TreeLinkStream {
+    links: Box<[(Hash, bool)]>,
+    offset: usize,
+}
+
+impl LinkStream for TreeLinkStream {
+    fn next(&mut self) -> LocalBoxFuture<'_, anyhow::Result<Option<Hash>>> {
+        let res = if self.offset < self.links.len() {
+            let (hash, _) = self.links[self.offset];
+            self.offset += 1;
+            Some(hash)
+        } else {
+            None
+        };
+        future::ok(res).boxed_local()
+    }
+
+    fn skip(&mut self, n: u64) -> LocalBoxFuture<'_, anyhow::Result<()>> {
+        let res = if let Some(offset) = self
+            .offset
+            .checked_add(usize::try_from(n).unwrap_or(usize::MAX))
+        {
+            self.offset = offset;
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("overflow"))
+        };
+        future::ready(res).boxed_local()
+    }
+
+    fn is_collection(&self) -> bool {
+        self.offset
+            .checked_sub(1)
+            .and_then(|i| self.links.get(i))
+            .map(|(_, is_dir)| *is_dir)
+            .unwrap_or_default()
+    }
+}
+
+impl CollectionParser for TreeCollectionParser {
+    fn parse<'a, R: AsyncSliceReader + 'a>(
+        &'a self,
+        _format: u64,
+        mut reader: R,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(Box<dyn LinkStream>, CollectionStats)>> {
+        async move {
+            // read to end
+            let data = reader.read_to_end().await?;
+            let res: (Box<dyn LinkStream>, CollectionStats) = if Directory::is_directory(&data) {
+                let dir = Directory::from_bytes(&data)?;
+                let links = dir.links().collect::<Vec<_>>().into_boxed_slice();
+                let stats = CollectionStats {
+                    num_blobs: Some(links.len() as u64),
+                    total_blob_size: Some(dir.total_blobs_size()),
+                };
+                (Box::new(TreeLinkStream { links, offset: 0 }), stats)
+            } else if Manifest::is_manifest(&data) {
+                let manifest = Manifest::from_bytes(&data)?;
+                let stats = CollectionStats {
+                    num_blobs: Some(manifest.chunks().len() as u64),
+                    total_blob_size: Some(manifest.size()),
+                };
+                let hashes = manifest
+                    .chunks()
+                    .iter()
+                    .map(|chunk| chunk.hash)
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice();
+                (Box::new(ArrayLinkStream::new(hashes)), stats)
+            } else {
+                let collection = Collection::from_bytes(&data)?;
+                let stats = CollectionStats {
+                    num_blobs: Some(collection.total_entries()),
+                    total_blob_size: Some(collection.total_blobs_size()),
+                };
+                let hashes = collection
+                    .into_inner()
+                    .into_iter()
+                    .map(|x| x.hash)
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice();
+                (Box::new(ArrayLinkStream::new(hashes)), stats)
+            };
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bao_tree::blake3;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::collection::Blob;
+
+    fn file(name: &str, data: &[u8]) -> Entry {
+        Entry {
+            name: name.to_string(),
+            mode: 0o100644,
+            mtime: 1_700_000_000,
+            kind: EntryKind::File {
+                hash: blake3::hash(data).into(),
+                size: data.len() as u64,
+            },
+        }
+    }
+
+    #[test]
+    fn directory_roundtrip() {
+        let sub = Directory::new(vec![file("c", b"c")]).unwrap();
+        let sub_bytes = sub.to_bytes().unwrap();
+        let dir = Directory::new(vec![
+            file("b", b"bb"),
+            Entry {
+                name: "a".to_string(),
+                mode: 0o40755,
+                mtime: 0,
+                kind: EntryKind::Directory {
+                    hash: blake3::hash(&sub_bytes).into(),
+                    size: sub_bytes.len() as u64,
+                },
+            },
+            Entry {
+                name: "link".to_string(),
+                mode: 0o120777,
+                mtime: 0,
+                kind: EntryKind::Symlink {
+                    target: "b".to_string(),
+                },
+            },
+        ])
+        .unwrap();
+        // entries are sorted by name
+        let names = dir
+            .entries()
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "b", "link"]);
+        assert_eq!(dir.total_blobs_size(), sub_bytes.len() as u64 + 2);
+
+        let bytes = dir.to_bytes().unwrap();
+        assert!(Directory::is_directory(&bytes));
+        assert_eq!(Directory::from_bytes(&bytes).unwrap(), dir);
+        let flat = Collection::new(vec![], 0).unwrap().to_bytes().unwrap();
+        assert!(!Directory::is_directory(&flat));
+        assert!(Directory::from_bytes(&flat).is_err());
+    }
+
+    #[test]
+    fn directory_invalid_names() {
+        assert!(Directory::new(vec![file("a", b"a"), file("a", b"b")]).is_err());
+        assert!(Directory::new(vec![file("a/b", b"a")]).is_err());
+        assert!(Directory::new(vec![file("..", b"a")]).is_err());
+        assert!(Directory::new(vec![file("", b"a")]).is_err());
+        assert!(Directory::new(vec![file(".", b"a")]).is_err());
+        assert!(Directory::new(vec![file("/a", b"a")]).is_err());
+        assert!(Directory::new(vec![file("a\\b", b"a")]).is_err());
+
+        // the same checks apply to received data, which bypasses the constructor
+        let serialize = |entries: Vec<Entry>| {
+            let mut data = MAGIC.to_vec();
+            data.extend(postcard::to_stdvec(&Directory { entries }).unwrap());
+            data
+        };
+        assert!(Directory::from_bytes(&serialize(vec![file("a", b"a")])).is_ok());
+        for name in ["", ".", "..", "/etc", "a/b", "a\\b"] {
+            assert!(Directory::from_bytes(&serialize(vec![file(name, b"a")])).is_err());
+        }
+        let duplicate = serialize(vec![file("a", b"a"), file("a", b"b")]);
+        assert!(Directory::from_bytes(&duplicate).is_err());
+    }
+
+    #[tokio::test]
+    async fn tree_parser() {
+        let sub_hash: Hash = blake3::hash(b"sub").into();
+        let dir = Directory::new(vec![
+            file("a", b"a"),
+            Entry {
+                name: "b".to_string(),
+                mode: 0o40755,
+                mtime: 0,
+                kind: EntryKind::Directory {
+                    hash: sub_hash,
+                    size: 3,
+                },
+            },
+        ])
+        .unwrap();
+        let data = Bytes::from(dir.to_bytes().unwrap());
+        let (mut links, stats) = TreeCollectionParser.parse(0, data).await.unwrap();
+        assert_eq!(stats.num_blobs, Some(2));
+        assert_eq!(stats.total_blob_size, Some(4));
+        assert_eq!(links.next().await.unwrap(), Some(blake3::hash(b"a").into()));
+        assert!(!links.is_collection());
+        assert_eq!(links.next().await.unwrap(), Some(sub_hash));
+        assert!(links.is_collection());
+        assert_eq!(links.next().await.unwrap(), None);
+
+        // flat collections are still supported
+        let blob = Blob {
+            name: "a".to_string(),
+            hash: blake3::hash(b"a").into(),
+        };
+        let data = Bytes::from(Collection::new(vec![blob], 1).unwrap().to_bytes().unwrap());
+        let (mut links, stats) = TreeCollectionParser.parse(0, data).await.unwrap();
+        assert_eq!(stats.num_blobs, Some(1));
+        assert_eq!(links.next().await.unwrap(), Some(blake3::hash(b"a").into()));
+        assert!(!links.is_collection());
+
+        // chunked files link to their manifest, whose children are the chunks
+        let manifest = Manifest::new(vec![iroh_bytes::chunked::Chunk {
+            hash: blake3::hash(b"a").into(),
+            size: 1,
+        }])
+        .unwrap();
+        let manifest_hash: Hash = blake3::hash(&manifest.to_bytes().unwrap()).into();
+        let dir = Directory::new(vec![Entry {
+            name: "big".to_string(),
+            mode: 0o100644,
+            mtime: 0,
+            kind: EntryKind::Chunked {
+                hash: manifest_hash,
+                size: 1,
+            },
+        }])
+        .unwrap();
+        let data = Bytes::from(dir.to_bytes().unwrap());
+        let (mut links, _) = TreeCollectionParser.parse(0, data).await.unwrap();
+        assert_eq!(links.next().await.unwrap(), Some(manifest_hash));
+        assert!(links.is_collection());
+        let data = Bytes::from(manifest.to_bytes().unwrap());
+        let (mut links, stats) = TreeCollectionParser.parse(0, data).await.unwrap();
+        assert_eq!(stats.num_blobs, Some(1));
+        assert_eq!(stats.total_blob_size, Some(1));
+        assert_eq!(links.next().await.unwrap(), Some(blake3::hash(b"a").into()));
+        assert!(!links.is_collection());
+    }
+}
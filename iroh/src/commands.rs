@@ -24,10 +24,15 @@ const MAX_RPC_CONNECTIONS: u32 = 16;
 const MAX_RPC_STREAMS: u64 = 1024;
 
 pub mod add;
+pub mod blobs;
 pub mod doctor;
+pub mod downloads;
 pub mod get;
 pub mod list;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub mod mount;
 pub mod provide;
+pub mod tags;
 pub mod validate;
 
 /// Send data.
@@ -82,6 +87,7 @@ impl Cli {
                 let client = make_rpc_client(rpc_port).await?;
                 let (peer, addr, token, derp_region, hash, recursive) =
                     if let Some(ticket) = ticket.as_ref() {
+                        anyhow::ensure!(!ticket.is_expired(), "ticket has expired");
                         (
                             ticket.peer(),
                             ticket.addrs().to_vec(),
@@ -127,12 +133,15 @@ impl Cli {
                 token,
                 out,
                 single,
+                rpc_port,
             } => {
                 let get = if let Some(ticket) = ticket {
+                    anyhow::ensure!(!ticket.is_expired(), "ticket has expired");
                     self::get::GetInteractive {
                         rt: rt.clone(),
                         hash: ticket.hash(),
-                        opts: ticket.as_get_options(Keypair::generate(), config.derp_map()),
+                        providers: ticket
+                            .as_all_get_options(Keypair::generate(), config.derp_map()),
                         token: ticket.token().cloned(),
                         single: !ticket.recursive(),
                     }
@@ -140,19 +149,48 @@ impl Cli {
                     self::get::GetInteractive {
                         rt: rt.clone(),
                         hash,
-                        opts: iroh::dial::Options {
+                        providers: vec![iroh::dial::Options {
                             addrs,
                             peer_id: peer,
                             keylog: self.keylog,
                             derp_region: region,
                             derp_map: config.derp_map(),
                             keypair: Keypair::generate(),
-                        },
+                        }],
+                        token,
+                        single,
+                    }
+                } else if let Some(hash) = hash {
+                    // no provider given, ask the running node who announced the hash
+                    let client = make_rpc_client(rpc_port).await?;
+                    let response = client
+                        .rpc(FindProvidersRequest { hash })
+                        .await?
+                        .context("failed to look up providers")?;
+                    anyhow::ensure!(
+                        !response.providers.is_empty(),
+                        "no providers found for {hash}, specify one with --peer or use a ticket"
+                    );
+                    self::get::GetInteractive {
+                        rt: rt.clone(),
+                        hash,
+                        providers: response
+                            .providers
+                            .into_iter()
+                            .map(|provider| iroh::dial::Options {
+                                addrs: provider.addrs,
+                                peer_id: provider.peer,
+                                keylog: self.keylog,
+                                derp_region: provider.derp_region,
+                                derp_map: config.derp_map(),
+                                keypair: Keypair::generate(),
+                            })
+                            .collect(),
                         token,
                         single,
                     }
                 } else {
-                    anyhow::bail!("Either ticket or hash and peer must be specified")
+                    anyhow::bail!("Either ticket or hash must be specified")
                 };
                 tokio::select! {
                     biased;
@@ -169,27 +207,46 @@ impl Cli {
                 rpc_port,
                 request_token,
                 in_place,
+                watch,
+                tree,
+                chunked,
+                gateway,
             } => {
+                let signed_tokens = matches!(request_token, Some(RequestTokenOptions::Signed));
                 let request_token = match request_token {
                     Some(RequestTokenOptions::Random) => Some(RequestToken::generate()),
                     Some(RequestTokenOptions::Token(token)) => Some(token),
-                    None => None,
+                    Some(RequestTokenOptions::Signed) | None => None,
                 };
                 self::provide::run(
                     rt,
                     path,
                     in_place,
+                    watch,
+                    tree,
+                    chunked,
                     ProvideOptions {
                         addr,
                         rpc_port,
                         keylog: self.keylog,
                         request_token,
+                        signed_tokens,
                         derp_map: config.derp_map(),
+                        gateway,
+                        allowed_peers: config.allowed_peers.clone(),
+                        denied_peers: config.denied_peers.clone(),
+                        limits: config.limits,
+                        announce: config.announce.config(),
+                        store: config.store.settings(),
                     },
                 )
                 .await
             }
             Commands::List(cmd) => cmd.run().await,
+            Commands::Blobs(cmd) => cmd.run().await,
+            Commands::Tags(cmd) => cmd.run().await,
+            Commands::Downloads(cmd) => cmd.run().await,
+            Commands::Gc { rpc_port } => self::blobs::gc(rpc_port).await,
             Commands::Validate { rpc_port, repair } => self::validate::run(rpc_port, repair).await,
             Commands::Shutdown { force, rpc_port } => {
                 let client = make_rpc_client(rpc_port).await?;
@@ -208,7 +265,10 @@ impl Cli {
                 path,
                 rpc_port,
                 in_place,
-            } => self::add::run(path, in_place, rpc_port).await,
+                tag,
+                tree,
+                chunked,
+            } => self::add::run(path, in_place, tag, tree, chunked, rpc_port).await,
             Commands::Addresses { rpc_port } => {
                 let client = make_rpc_client(rpc_port).await?;
                 let response = client.rpc(AddrsRequest).await?;
@@ -216,6 +276,10 @@ impl Cli {
                 Ok(())
             }
             Commands::Doctor { command } => self::doctor::run(command, config).await,
+            #[cfg(all(feature = "mount", target_os = "linux"))]
+            Commands::Mount { source, mountpoint } => {
+                self::mount::run(rt, source, mountpoint, config.derp_map()).await
+            }
         }
     }
 }
@@ -242,6 +306,24 @@ pub enum Commands {
         /// will not change.
         #[clap(long, default_value_t = false)]
         in_place: bool,
+        /// Keep watching PATH for changes, and provide a new collection after each change
+        ///
+        /// A new collection hash and ticket is printed every time the data changes.
+        #[clap(long, default_value_t = false, requires = "path")]
+        watch: bool,
+        /// Provide PATH as a tree of directories
+        ///
+        /// Keeps empty directories, symlinks, file modes and modification times, and every
+        /// sub-directory can be fetched on its own.
+        #[clap(long, default_value_t = false, requires = "path")]
+        tree: bool,
+        /// Split files into content-defined chunks
+        ///
+        /// Every chunk is stored as a blob of its own, so new versions of large files share
+        /// most of their data with previous versions, and only the changed chunks have to be
+        /// transferred. Requires --tree.
+        #[clap(long, default_value_t = false, requires = "tree")]
+        chunked: bool,
         #[clap(long, short)]
         /// Listening address to bind to
         #[clap(long, short, default_value_t = SocketAddr::from(iroh::node::DEFAULT_BIND_ADDR))]
@@ -251,13 +333,39 @@ pub enum Commands {
         rpc_port: ProviderRpcPort,
         /// Use a token to authenticate requests for data
         ///
-        /// Pass "random" to generate a random token, or base32-encoded bytes to use as a token
+        /// Pass "random" to generate a random token, or base32-encoded bytes to use as a token.
+        /// Pass "signed" to require tokens signed by the provider's keypair, a token that
+        /// expires after a day is then included in the ticket.
         #[clap(long)]
         request_token: Option<RequestTokenOptions>,
+        /// Serve blobs and collections over HTTP on this address
+        ///
+        /// Requests are checked like requests over iroh, pass the request token in an
+        /// `Authorization: Bearer <token>` header or a `token` query parameter. Requests over
+        /// HTTP are always rejected if peers are restricted with an allow list.
+        #[clap(long)]
+        gateway: Option<SocketAddr>,
     },
     /// List availble content on the provider.
     #[clap(subcommand)]
     List(self::list::Commands),
+    /// Manage blobs on the running provider.
+    #[clap(subcommand)]
+    Blobs(self::blobs::Commands),
+    /// Manage tags on the running provider.
+    #[clap(subcommand)]
+    Tags(self::tags::Commands),
+    /// Manage the download queue of the running provider.
+    #[clap(subcommand)]
+    Downloads(self::downloads::Commands),
+    /// Delete all data that is not reachable from a root on the running provider.
+    ///
+    /// Roots are collections that have been added to or shared with the provider.
+    Gc {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
     /// Validate hashes on the running provider.
     Validate {
         /// RPC port of the provider
@@ -295,6 +403,22 @@ pub enum Commands {
         /// will not change.
         #[clap(long, default_value_t = false)]
         in_place: bool,
+        /// Tag the resulting collection with this name
+        #[clap(long)]
+        tag: Option<String>,
+        /// Add PATH as a tree of directories
+        ///
+        /// Keeps empty directories, symlinks, file modes and modification times, and every
+        /// sub-directory can be fetched on its own.
+        #[clap(long, default_value_t = false)]
+        tree: bool,
+        /// Split files into content-defined chunks
+        ///
+        /// Every chunk is stored as a blob of its own, so new versions of large files share
+        /// most of their data with previous versions, and only the changed chunks have to be
+        /// transferred. Requires --tree.
+        #[clap(long, default_value_t = false, requires = "tree")]
+        chunked: bool,
         /// RPC port
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
@@ -305,12 +429,10 @@ pub enum Commands {
         #[clap(conflicts_with = "ticket", required_unless_present = "ticket")]
         hash: Option<Hash>,
         /// PeerId of the provider
-        #[clap(
-            long,
-            short,
-            conflicts_with = "ticket",
-            required_unless_present = "ticket"
-        )]
+        ///
+        /// If not specified, the providers that announced the hash to the running node are
+        /// used, see the `announce` section of the config.
+        #[clap(long, short, conflicts_with = "ticket")]
         peer: Option<PeerId>,
         /// Addresses of the provider
         #[clap(long, short)]
@@ -331,12 +453,28 @@ pub enum Commands {
         #[clap(long, short)]
         out: Option<PathBuf>,
         #[clap(conflicts_with_all = &["hash", "peer", "addrs", "token"])]
-        /// Ticket containing everything to retrieve the data from a provider.
+        /// Ticket containing everything to retrieve the data from one or more providers.
+        ///
+        /// If the ticket contains multiple providers, they are tried in order.
         #[clap(long)]
         ticket: Option<Ticket>,
         /// True to download a single blob, false (default) to download a collection and its children.
         #[clap(long, default_value_t = false)]
         single: bool,
+        /// RPC port of the running node, to look up providers when no peer is specified
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Mount a collection as a read-only filesystem
+    ///
+    /// Data is read from the local store. When mounting a ticket, data that is missing
+    /// locally is fetched from the providers as it is read, and kept in the local store.
+    #[cfg(all(feature = "mount", target_os = "linux"))]
+    Mount {
+        /// Ticket or hash of the collection to mount
+        source: self::mount::MountSource,
+        /// Directory to mount the collection at
+        mountpoint: PathBuf,
     },
     /// Download data to the running provider's database and provide it.
     ///
@@ -456,6 +594,7 @@ pub fn init_metrics_collection(
 #[derive(Debug, Clone)]
 pub enum RequestTokenOptions {
     Random,
+    Signed,
     Token(RequestToken),
 }
 
@@ -463,8 +602,10 @@ impl FromStr for RequestTokenOptions {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.to_lowercase().trim() == "random" {
-            return Ok(Self::Random);
+        match s.to_lowercase().trim() {
+            "random" => return Ok(Self::Random),
+            "signed" => return Ok(Self::Signed),
+            _ => {}
         }
         let token = RequestToken::from_str(s)?;
         Ok(Self::Token(token))
@@ -12,7 +12,14 @@ use iroh_bytes::{provider::ProvideProgress, Hash};
 
 use crate::commands::make_rpc_client;
 
-pub async fn run(path: PathBuf, in_place: bool, rpc_port: u16) -> Result<()> {
+pub async fn run(
+    path: PathBuf,
+    in_place: bool,
+    tag: Option<String>,
+    tree: bool,
+    chunked: bool,
+    rpc_port: u16,
+) -> Result<()> {
     let client = make_rpc_client(rpc_port).await?;
     let absolute = path.canonicalize()?;
     println!("Adding {} as {}...", path.display(), absolute.display());
@@ -20,6 +27,10 @@ pub async fn run(path: PathBuf, in_place: bool, rpc_port: u16) -> Result<()> {
         .server_streaming(ProvideRequest {
             path: absolute,
             in_place,
+            tag,
+            watch: false,
+            tree,
+            chunked,
         })
         .await?;
     let (hash, entries) = aggregate_add_response(stream).await?;
@@ -82,6 +93,15 @@ where
                 collection_hash = Some(hash);
                 break;
             }
+            ProvideProgress::RebuildFailed(e) => {
+                // only sent while watching, wait for the next collection
+                if let Some(mp) = mp.take() {
+                    mp.error();
+                }
+                eprintln!("Failed to update data: {e}");
+                collections.clear();
+                mp = Some(ProvideProgressState::new());
+            }
             ProvideProgress::Abort(e) => {
                 if let Some(mp) = mp.take() {
                     mp.error();
@@ -0,0 +1,187 @@
+use std::{ops::Range, path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Result};
+use bao_tree::{ByteNum, ChunkNum};
+use clap::Subcommand;
+use futures::StreamExt;
+use iroh::rpc_protocol::{
+    DeleteBlobRequest, ExportSliceRequest, GcProgress, GcRequest, ImportSliceRequest,
+};
+use iroh_bytes::{baomap::range_collections::RangeSet2, protocol::RangeSpec, Hash};
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Delete a blob from the running provider's database.
+    ///
+    /// Data that was added in place is not deleted from its original location.
+    Delete {
+        /// The hash of the blob to delete
+        hash: Hash,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Export a verifiable slice of a blob, which can be imported by another node.
+    ///
+    /// The blob can be incomplete, but must contain the requested ranges.
+    ExportSlice {
+        /// The hash of the blob
+        hash: Hash,
+        /// Byte range to export, like `0..1024`, `1024..` or `..1024`
+        ///
+        /// Can be given multiple times. Ranges are extended to whole chunks. If not given,
+        /// the entire blob is exported.
+        #[clap(long)]
+        range: Vec<ByteRange>,
+        /// File to write the slice to. If not given, the slice is written to stdout.
+        #[clap(long, short)]
+        out: Option<PathBuf>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Import a slice that was created with `export-slice`.
+    ///
+    /// The data is verified and merged with the data that is already there.
+    ImportSlice {
+        /// File to read the slice from. If not given, the slice is read from stdin.
+        path: Option<PathBuf>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::Delete { hash, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client.rpc(DeleteBlobRequest { hash }).await??;
+                println!("Deleted {}", hash);
+            }
+            Commands::ExportSlice {
+                hash,
+                range,
+                out,
+                rpc_port,
+            } => {
+                let ranges = if range.is_empty() {
+                    RangeSet2::all()
+                } else {
+                    range
+                        .iter()
+                        .fold(RangeSet2::<ChunkNum>::empty(), |acc, range| {
+                            acc.union(&RangeSet2::from(range.to_chunks()))
+                        })
+                };
+                // the provider writes to a file, so use a temporary one for stdout
+                let (path, tmp) = match out {
+                    Some(out) => (std::env::current_dir()?.join(out), None),
+                    None => {
+                        let tmp = tempfile::NamedTempFile::new()?.into_temp_path();
+                        (tmp.to_path_buf(), Some(tmp))
+                    }
+                };
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client
+                    .rpc(ExportSliceRequest {
+                        hash,
+                        ranges: RangeSpec::new(ranges),
+                        path: path.clone(),
+                    })
+                    .await??;
+                match tmp {
+                    Some(tmp) => {
+                        let mut file = tokio::fs::File::open(&tmp).await?;
+                        tokio::io::copy(&mut file, &mut tokio::io::stdout()).await?;
+                    }
+                    None => {
+                        let ranges = response.ranges.to_chunk_ranges();
+                        println!("Exported {ranges:?} of {hash} to {}", path.display());
+                    }
+                }
+            }
+            Commands::ImportSlice { path, rpc_port } => {
+                // the provider reads from a file, so copy stdin to a temporary one
+                let (path, _tmp) = match path {
+                    Some(path) => (path.canonicalize()?, None),
+                    None => {
+                        let (file, tmp) = tempfile::NamedTempFile::new()?.into_parts();
+                        let mut file = tokio::fs::File::from_std(file);
+                        tokio::io::copy(&mut tokio::io::stdin(), &mut file).await?;
+                        (tmp.to_path_buf(), Some(tmp))
+                    }
+                };
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client.rpc(ImportSliceRequest { path }).await??;
+                let ranges = response.ranges.to_chunk_ranges();
+                println!(
+                    "Imported {ranges:?} of {} ({} bytes)",
+                    response.hash, response.size
+                );
+                if response.complete {
+                    println!("The blob is complete");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A range of bytes, given on the command line as `start..end`, `start..` or `..end`.
+#[derive(Debug, Clone)]
+pub struct ByteRange(Range<u64>);
+
+impl ByteRange {
+    /// The chunks covering this range.
+    fn to_chunks(&self) -> Range<ChunkNum> {
+        ByteNum(self.0.start).full_chunks()..ByteNum(self.0.end).chunks()
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once("..")
+            .context("expected a range like 0..1024")?;
+        let start = match start {
+            "" => 0,
+            start => start.parse().context("invalid range start")?,
+        };
+        let end = match end {
+            "" => u64::MAX,
+            end => end.parse().context("invalid range end")?,
+        };
+        anyhow::ensure!(start <= end, "range start is after its end");
+        Ok(Self(start..end))
+    }
+}
+
+/// Run a garbage collection on the running provider.
+pub async fn gc(rpc_port: u16) -> Result<()> {
+    let client = make_rpc_client(rpc_port).await?;
+    let mut response = client.server_streaming(GcRequest).await?;
+    while let Some(item) = response.next().await {
+        match item? {
+            GcProgress::Marked { live } => {
+                println!("Found {} live blobs", live);
+            }
+            GcProgress::Deleted { hash } => {
+                println!("Deleted {}", hash);
+            }
+            GcProgress::AllDone { live, deleted } => {
+                println!("Done, kept {} blobs, deleted {} blobs", live, deleted);
+                break;
+            }
+            GcProgress::Abort(error) => {
+                anyhow::bail!("gc failed: {}", error);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use clap::Subcommand;
+use futures::StreamExt;
+use iroh::rpc_protocol::{CancelDownloadRequest, DownloadStatus, ListDownloadsRequest};
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// List the downloads that are queued or running on the provider.
+    List {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Cancel a download. The data that was already downloaded is kept.
+    Cancel {
+        /// The id of the download, as shown by `list`
+        id: u64,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::List { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut response = client.server_streaming(ListDownloadsRequest).await?;
+                while let Some(item) = response.next().await {
+                    let item = item?;
+                    let status = match item.status {
+                        DownloadStatus::Queued => "queued".to_string(),
+                        DownloadStatus::Running => "running".to_string(),
+                        DownloadStatus::Retrying { attempts, error } => {
+                            format!("retrying after {attempts} failed attempts: {error}")
+                        }
+                    };
+                    println!("{}: {} ({})", item.id, item.hash, status);
+                    for peer in item.providers {
+                        println!("    from {peer}");
+                    }
+                    if let Some(out) = item.out {
+                        println!("    to {out}");
+                    }
+                }
+            }
+            Commands::Cancel { id, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client.rpc(CancelDownloadRequest { id }).await??;
+            }
+        }
+        Ok(())
+    }
+}
@@ -8,7 +8,7 @@ use indicatif::{
     HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
 };
 use iroh::{
-    collection::{Collection, IrohCollectionParser},
+    collection::{tree::TreeCollectionParser, Collection},
     rpc_protocol::ShareRequest,
     util::{io::pathbuf_from_name, progress::ProgressSliceWriter},
 };
@@ -29,7 +29,8 @@ use tokio::sync::mpsc;
 pub struct GetInteractive {
     pub rt: iroh_bytes::util::runtime::Handle,
     pub hash: Hash,
-    pub opts: iroh::dial::Options,
+    /// The providers to try, in order.
+    pub providers: Vec<iroh::dial::Options>,
     pub token: Option<RequestToken>,
     pub single: bool,
 }
@@ -70,9 +71,13 @@ impl GetInteractive {
         let db: iroh::baomap::flat::Store =
             iroh::baomap::flat::Store::load(temp_dir.clone(), temp_dir.clone(), &self.rt).await?;
         // spin up temp node and ask it to download the data for us
-        let mut provider = iroh::node::Node::builder(db).collection_parser(IrohCollectionParser);
-        if let Some(ref dm) = self.opts.derp_map {
-            provider = provider.derp_map(dm.clone());
+        let mut provider = iroh::node::Node::builder(db).collection_parser(TreeCollectionParser);
+        if let Some(dm) = self
+            .providers
+            .first()
+            .and_then(|opts| opts.derp_map.clone())
+        {
+            provider = provider.derp_map(dm);
         }
         let provider = provider
             .runtime(&iroh_bytes::util::runtime::Handle::from_currrent(1)?)
@@ -85,71 +90,29 @@ impl GetInteractive {
         let hash = self.hash;
         write(format!("Fetching: {}", hash));
         write(format!("{} Connecting ...", style("[1/3]").bold().dim()));
-        let mut stream = provider
-            .controller()
-            .server_streaming(ShareRequest {
+        let pb = make_download_pb();
+        let count = self.providers.len();
+        for (i, opts) in self.providers.into_iter().enumerate() {
+            let peer = opts.peer_id;
+            let request = ShareRequest {
                 hash: self.hash,
                 recursive: !self.single,
-                peer: self.opts.peer_id,
-                addrs: self.opts.addrs,
-                derp_region: self.opts.derp_region,
-                token: self.token,
+                peer,
+                addrs: opts.addrs,
+                derp_region: opts.derp_region,
+                token: self.token.clone(),
                 in_place: true,
-                out: Some(out),
-            })
-            .await?;
-        let pb = make_download_pb();
-        let mut sizes = BTreeMap::new();
-        while let Some(x) = stream.next().await {
-            match x? {
-                ShareProgress::Connected => {
-                    write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
-                }
-                ShareProgress::FoundCollection {
-                    total_blobs_size,
-                    num_blobs,
-                    ..
-                } => {
-                    init_download_progress(
-                        &pb,
-                        num_blobs.unwrap_or_default(),
-                        total_blobs_size.unwrap_or_default(),
-                    )?;
-                }
-                ShareProgress::Found { id, size, .. } => {
-                    sizes.insert(id, (size, 0));
-                }
-                ShareProgress::Progress { id, offset } => {
-                    if let Some((_, current)) = sizes.get_mut(&id) {
-                        *current = offset;
-                        let total = sizes.values().map(|(_, current)| current).sum::<u64>();
-                        pb.set_position(total);
-                    }
-                }
-                ShareProgress::Done { id } => {
-                    if let Some((size, current)) = sizes.get_mut(&id) {
-                        *current = *size;
-                        let total = sizes.values().map(|(_, current)| current).sum::<u64>();
-                        pb.set_position(total);
-                    }
-                }
-                ShareProgress::NetworkDone {
-                    bytes_read,
-                    elapsed,
-                    ..
-                } => {
-                    pb.finish_and_clear();
+                out: Some(out.clone()),
+            };
+            match share_with_progress(&provider, request, &pb).await {
+                Ok(()) => break,
+                // the partial download is kept in the temp dir, so the next provider resumes it
+                Err(cause) if i + 1 < count => {
                     write(format!(
-                        "Transferred {} in {}, {}/s",
-                        HumanBytes(bytes_read),
-                        HumanDuration(elapsed),
-                        HumanBytes((bytes_read as f64 / elapsed.as_secs_f64()) as u64)
+                        "Provider {peer} failed: {cause:#}, trying next provider"
                     ));
                 }
-                ShareProgress::AllDone => {
-                    break;
-                }
-                _ => {}
+                Err(cause) => return Err(cause),
             }
         }
         tokio::fs::remove_dir_all(temp_dir).await?;
@@ -178,7 +141,7 @@ impl GetInteractive {
 
         let pb = make_download_pb();
         let request = self.new_request(query).with_token(self.token.clone());
-        let connection = iroh::dial::dial(self.opts).await?;
+        let connection = dial_in_order(self.providers).await?;
         let response = fsm::start(connection, request);
         let connected = response.next().await?;
         write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
@@ -202,6 +165,88 @@ impl GetInteractive {
     }
 }
 
+/// Download using the share RPC of the temp node, reporting progress.
+async fn share_with_progress<D: iroh_bytes::baomap::Store>(
+    provider: &iroh::node::Node<D>,
+    request: ShareRequest,
+    pb: &ProgressBar,
+) -> Result<()> {
+    let mut stream = provider.controller().server_streaming(request).await?;
+    let mut sizes = BTreeMap::new();
+    while let Some(x) = stream.next().await {
+        match x? {
+            ShareProgress::Connected => {
+                write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
+            }
+            ShareProgress::FoundCollection {
+                total_blobs_size,
+                num_blobs,
+                ..
+            } => {
+                init_download_progress(
+                    pb,
+                    num_blobs.unwrap_or_default(),
+                    total_blobs_size.unwrap_or_default(),
+                )?;
+            }
+            ShareProgress::Found { id, size, .. } => {
+                sizes.insert(id, (size, 0));
+            }
+            ShareProgress::Progress { id, offset } => {
+                if let Some((_, current)) = sizes.get_mut(&id) {
+                    *current = offset;
+                    let total = sizes.values().map(|(_, current)| current).sum::<u64>();
+                    pb.set_position(total);
+                }
+            }
+            ShareProgress::Done { id } => {
+                if let Some((size, current)) = sizes.get_mut(&id) {
+                    *current = *size;
+                    let total = sizes.values().map(|(_, current)| current).sum::<u64>();
+                    pb.set_position(total);
+                }
+            }
+            ShareProgress::NetworkDone {
+                bytes_read,
+                elapsed,
+                ..
+            } => {
+                pb.finish_and_clear();
+                write(format!(
+                    "Transferred {} in {}, {}/s",
+                    HumanBytes(bytes_read),
+                    HumanDuration(elapsed),
+                    HumanBytes((bytes_read as f64 / elapsed.as_secs_f64()) as u64)
+                ));
+            }
+            ShareProgress::Abort(cause) => {
+                return Err(cause.into());
+            }
+            ShareProgress::AllDone => {
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    anyhow::bail!("share stream ended unexpectedly")
+}
+
+/// Dial the given providers in order, returning the first connection that succeeds.
+pub async fn dial_in_order(providers: Vec<iroh::dial::Options>) -> Result<quinn::Connection> {
+    let mut last_error = anyhow::anyhow!("no providers given");
+    for opts in providers {
+        let peer = opts.peer_id;
+        match iroh::dial::dial(opts).await {
+            Ok(connection) => return Ok(connection),
+            Err(cause) => {
+                write(format!("Failed to connect to provider {peer}: {cause:#}"));
+                last_error = cause;
+            }
+        }
+    }
+    Err(last_error)
+}
+
 async fn get_to_stdout_single(curr: get::fsm::AtStartRoot) -> Result<get::Stats> {
     let curr = curr.next();
     let mut writer = ConcatenateSliceWriter::new(tokio::io::stdout());
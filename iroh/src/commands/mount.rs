@@ -0,0 +1,76 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Result};
+use iroh::{
+    baomap::flat,
+    dial::Ticket,
+    mount::{CollectionFs, Remote},
+};
+use iroh_bytes::{util::runtime, Hash};
+use iroh_net::{derp::DerpMap, tls::Keypair};
+
+use crate::config::IrohPaths;
+
+use super::get::dial_in_order;
+
+/// What to mount: a ticket, or the hash of a collection in the local store
+#[derive(Debug, Clone)]
+pub enum MountSource {
+    Ticket(Ticket),
+    Hash(Hash),
+}
+
+impl FromStr for MountSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(hash) = Hash::from_str(s) {
+            return Ok(MountSource::Hash(hash));
+        }
+        let ticket = Ticket::from_str(s).context("expected a ticket or a hash")?;
+        Ok(MountSource::Ticket(ticket))
+    }
+}
+
+pub async fn run(
+    rt: &runtime::Handle,
+    source: MountSource,
+    mountpoint: PathBuf,
+    derp_map: Option<DerpMap>,
+) -> Result<()> {
+    anyhow::ensure!(
+        mountpoint.is_dir(),
+        "mountpoint {} is not a directory",
+        mountpoint.display()
+    );
+    let blob_dir = IrohPaths::BaoFlatStoreComplete.with_env()?;
+    let partial_blob_dir = IrohPaths::BaoFlatStorePartial.with_env()?;
+    tokio::fs::create_dir_all(&blob_dir).await?;
+    tokio::fs::create_dir_all(&partial_blob_dir).await?;
+    let db = flat::Store::load(&blob_dir, &partial_blob_dir, rt)
+        .await
+        .with_context(|| format!("Failed to load iroh database from {}", blob_dir.display()))?;
+
+    let (hash, remote) = match source {
+        MountSource::Ticket(ticket) => {
+            anyhow::ensure!(!ticket.is_expired(), "ticket has expired");
+            let providers = ticket.as_all_get_options(Keypair::generate(), derp_map);
+            let conn = dial_in_order(providers).await?;
+            let remote = Remote {
+                conn,
+                token: ticket.token().cloned(),
+            };
+            (ticket.hash(), Some(remote))
+        }
+        MountSource::Hash(hash) => (hash, None),
+    };
+    let fs = CollectionFs::new(db, hash, remote).await?;
+    let _session = iroh::mount::mount(fs, &mountpoint)?;
+    println!(
+        "Mounted {} at {}, press Ctrl+C to unmount",
+        hash,
+        mountpoint.display()
+    );
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
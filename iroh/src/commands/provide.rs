@@ -4,17 +4,29 @@ use std::{
     path::PathBuf,
     str::FromStr,
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{anyhow, ensure, Context, Result};
 use iroh::{
+    announce::AnnounceConfig,
     baomap::flat,
-    collection::IrohCollectionParser,
-    node::{Node, StaticTokenAuthHandler},
+    collection::tree::TreeCollectionParser,
+    gateway::GatewayConfig,
+    node::{Node, PeerListAuthHandler, StaticTokenAuthHandler},
     rpc_protocol::{ProvideRequest, ProviderRequest, ProviderResponse, ProviderService},
+    token::SignedTokenAuthHandler,
+};
+use iroh_bytes::{
+    baomap::Store,
+    protocol::RequestToken,
+    provider::{limits::Limits, RequestAuthorizationHandler},
+    util::runtime,
+};
+use iroh_net::{
+    derp::DerpMap,
+    tls::{Keypair, PeerId},
 };
-use iroh_bytes::{baomap::Store, protocol::RequestToken, util::runtime};
-use iroh_net::{derp::DerpMap, tls::Keypair};
 use quic_rpc::{transport::quinn::QuinnServerEndpoint, ServiceEndpoint};
 use tokio::io::AsyncWriteExt;
 use tracing::{info_span, Instrument};
@@ -26,19 +38,38 @@ use super::{
     MAX_RPC_CONNECTIONS, MAX_RPC_STREAMS, RPC_ALPN,
 };
 
+/// How long the signed request tokens minted for tickets are valid.
+const SIGNED_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24);
+
 #[derive(Debug)]
 pub struct ProvideOptions {
     pub addr: SocketAddr,
     pub rpc_port: ProviderRpcPort,
     pub keylog: bool,
     pub request_token: Option<RequestToken>,
+    /// Require tokens signed by the provider, and mint one per ticket.
+    pub signed_tokens: bool,
     pub derp_map: Option<DerpMap>,
+    /// Address to serve blobs over HTTP on.
+    pub gateway: Option<SocketAddr>,
+    /// If not empty, only these peers may fetch data.
+    pub allowed_peers: Vec<PeerId>,
+    /// Peers that may not fetch data.
+    pub denied_peers: Vec<PeerId>,
+    pub limits: Limits,
+    /// Announce the content of the node, and index the content of other nodes.
+    pub announce: Option<AnnounceConfig>,
+    /// How new data is stored in the store.
+    pub store: flat::Settings,
 }
 
 pub async fn run(
     rt: &runtime::Handle,
     path: Option<PathBuf>,
     in_place: bool,
+    watch: bool,
+    tree: bool,
+    chunked: bool,
     opts: ProvideOptions,
 ) -> Result<()> {
     if let Some(ref path) = path {
@@ -53,12 +84,14 @@ pub async fn run(
     let partial_blob_dir = IrohPaths::BaoFlatStorePartial.with_env()?;
     tokio::fs::create_dir_all(&blob_dir).await?;
     tokio::fs::create_dir_all(&partial_blob_dir).await?;
-    let db = flat::Store::load(&blob_dir, &partial_blob_dir, rt)
+    let db = flat::Store::load_with_settings(&blob_dir, &partial_blob_dir, opts.store, rt)
         .await
         .with_context(|| format!("Failed to load iroh database from {}", blob_dir.display()))?;
     let key = Some(IrohPaths::Keypair.with_env()?);
+    let downloads = IrohPaths::Downloads.with_env()?;
     let token = opts.request_token.clone();
-    let provider = provide(db.clone(), rt, key, opts).await?;
+    let signed_tokens = opts.signed_tokens;
+    let provider = provide(db.clone(), rt, key, downloads, opts).await?;
     let controller = provider.controller();
     if let Some(t) = token.as_ref() {
         println!("Request token: {}", t);
@@ -85,21 +118,41 @@ pub async fn run(
                     (path_buf, Some(path))
                 };
                 // tell the provider to add the data
-                let stream = controller
-                    .server_streaming(ProvideRequest { path, in_place })
+                let mut stream = controller
+                    .server_streaming(ProvideRequest {
+                        path,
+                        in_place,
+                        tag: None,
+                        watch,
+                        tree,
+                        chunked,
+                    })
                     .await?;
-                match aggregate_add_response(stream).await {
-                    Ok((hash, entries)) => {
-                        print_add_response(hash, entries);
-                        let ticket = provider.ticket(hash).await?.with_token(token);
-                        println!("All-in-one ticket: {ticket}");
-                        anyhow::Ok(tmp_path)
+                let mut last = None;
+                loop {
+                    match aggregate_add_response(&mut stream).await {
+                        Ok((hash, entries)) if last != Some(hash) => {
+                            print_add_response(hash, entries);
+                            let ticket = if signed_tokens {
+                                let expires = SystemTime::now() + SIGNED_TOKEN_LIFETIME;
+                                provider.signed_ticket(hash, expires, None).await?
+                            } else {
+                                provider.ticket(hash).await?.with_token(token.clone())
+                            };
+                            println!("All-in-one ticket: {ticket}");
+                            last = Some(hash);
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("Failed to add data: {}", e);
+                            std::process::exit(-1);
+                        }
                     }
-                    Err(e) => {
-                        eprintln!("Failed to add data: {}", e);
-                        std::process::exit(-1);
+                    if !watch {
+                        break;
                     }
                 }
+                anyhow::Ok(tmp_path)
             }
             .instrument(info_span!("provider-add")),
         )
@@ -129,17 +182,41 @@ async fn provide<D: Store>(
     db: D,
     rt: &runtime::Handle,
     key: Option<PathBuf>,
+    downloads: PathBuf,
     opts: ProvideOptions,
 ) -> Result<Node<D>> {
     let keypair = get_keypair(key).await?;
 
+    let auth_handler: Arc<dyn RequestAuthorizationHandler> = if opts.signed_tokens {
+        Arc::new(SignedTokenAuthHandler::new(PeerId::from(keypair.public())))
+    } else {
+        Arc::new(StaticTokenAuthHandler::new(opts.request_token))
+    };
+    let auth_handler: Arc<dyn RequestAuthorizationHandler> =
+        if opts.allowed_peers.is_empty() && opts.denied_peers.is_empty() {
+            auth_handler
+        } else {
+            let mut handler = PeerListAuthHandler::new(auth_handler).deny(opts.denied_peers);
+            if !opts.allowed_peers.is_empty() {
+                handler = handler.allow(opts.allowed_peers);
+            }
+            Arc::new(handler)
+        };
     let mut builder = Node::builder(db)
-        .collection_parser(IrohCollectionParser)
-        .custom_auth_handler(Arc::new(StaticTokenAuthHandler::new(opts.request_token)))
+        .collection_parser(TreeCollectionParser)
+        .custom_auth_handler(auth_handler)
+        .limits(opts.limits)
+        .downloads_path(downloads)
         .keylog(opts.keylog);
     if let Some(dm) = opts.derp_map {
         builder = builder.derp_map(dm);
     }
+    if let Some(addr) = opts.gateway {
+        builder = builder.gateway(GatewayConfig::new(addr));
+    }
+    if let Some(config) = opts.announce {
+        builder = builder.announce(config);
+    }
     let builder = builder.bind_addr(opts.addr).runtime(rt);
 
     let provider = if let Some(rpc_port) = opts.rpc_port.into() {
@@ -163,6 +240,9 @@ async fn provide<D: Store>(
         region.map_or("None".to_string(), |r| r.to_string())
     );
     println!("PeerID: {}", provider.peer_id());
+    if let Some(addr) = provider.gateway_addr() {
+        println!("HTTP gateway: http://{addr}");
+    }
     println!();
     Ok(provider)
 }
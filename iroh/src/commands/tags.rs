@@ -0,0 +1,74 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+use clap::Subcommand;
+use futures::StreamExt;
+use indicatif::HumanDuration;
+use iroh::rpc_protocol::{DeleteTagRequest, ListTagsRequest, SetTagRequest};
+use iroh_bytes::Hash;
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// List all tags on the running provider.
+    List {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Set a tag to point to a hash, overwriting an existing tag with the same name.
+    Set {
+        /// The name of the tag
+        name: String,
+        /// The hash the tag should point to
+        hash: Hash,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Delete a tag. This does not delete the tagged data.
+    Delete {
+        /// The name of the tag
+        name: String,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::List { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut response = client.server_streaming(ListTagsRequest).await?;
+                while let Some(item) = response.next().await {
+                    let item = item?;
+                    let age = SystemTime::now()
+                        .duration_since(item.created)
+                        .unwrap_or_default();
+                    println!(
+                        "{}: {} (created {} ago)",
+                        item.name,
+                        item.hash,
+                        HumanDuration(age)
+                    );
+                }
+            }
+            Commands::Set {
+                name,
+                hash,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client.rpc(SetTagRequest { name, hash }).await??;
+            }
+            Commands::Delete { name, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client.rpc(DeleteTagRequest { name }).await??;
+            }
+        }
+        Ok(())
+    }
+}
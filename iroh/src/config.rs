@@ -3,15 +3,24 @@
 use std::{
     collections::HashMap,
     env, fmt,
+    net::SocketAddr,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
 use anyhow::{anyhow, bail, Result};
 use config::{Environment, File, Value};
+use iroh::{
+    announce::AnnounceConfig,
+    baomap::flat::{self, Compression},
+    dial::NodeAddr,
+};
+use iroh_bytes::provider::limits::Limits;
 use iroh_net::{
     defaults::{default_eu_derp_region, default_na_derp_region},
     derp::{DerpMap, DerpRegion},
+    tls::PeerId,
 };
 use serde::{Deserialize, Serialize};
 use tracing::debug;
@@ -32,6 +41,8 @@ pub enum IrohPaths {
     BaoFlatStoreComplete,
     /// Path to the node's [flat-file store](iroh::baomap::flat) for partial blobs.
     BaoFlatStorePartial,
+    /// Path to the node's [download queue](iroh::downloads).
+    Downloads,
 }
 impl From<&IrohPaths> for &'static str {
     fn from(value: &IrohPaths) -> Self {
@@ -39,6 +50,7 @@ impl From<&IrohPaths> for &'static str {
             IrohPaths::Keypair => "keypair",
             IrohPaths::BaoFlatStoreComplete => "blobs.v0",
             IrohPaths::BaoFlatStorePartial => "blobs-partial.v0",
+            IrohPaths::Downloads => "downloads.v0",
         }
     }
 }
@@ -49,6 +61,7 @@ impl FromStr for IrohPaths {
             "keypair" => Self::Keypair,
             "blobs.v0" => Self::BaoFlatStoreComplete,
             "blobs-partial.v0" => Self::BaoFlatStorePartial,
+            "downloads.v0" => Self::Downloads,
             _ => bail!("unknown file or directory"),
         })
     }
@@ -88,6 +101,108 @@ impl IrohPaths {
 pub struct Config {
     /// The regions for DERP to use.
     pub derp_regions: Vec<DerpRegion>,
+    /// Peers that are allowed to fetch data from the provider.
+    ///
+    /// If empty, all peers that are not denied are allowed.
+    #[serde(with = "peer_ids")]
+    pub allowed_peers: Vec<PeerId>,
+    /// Peers that are never allowed to fetch data from the provider.
+    #[serde(with = "peer_ids")]
+    pub denied_peers: Vec<PeerId>,
+    /// Limits for serving requests.
+    pub limits: Limits,
+    /// Announcing content to other nodes, and discovering content they announce.
+    pub announce: AnnounceSettings,
+    /// Settings for the blob store.
+    pub store: StoreSettings,
+}
+
+/// Settings for the [flat-file store](iroh::baomap::flat).
+#[derive(PartialEq, Eq, Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StoreSettings {
+    /// If set, new data is stored compressed with zstd at this level, see
+    /// [compressed data files](iroh::baomap::flat#compressed-data-files).
+    pub compression_level: Option<i32>,
+    /// New blobs smaller than this many bytes are stored in pack files instead of files
+    /// of their own, see [pack files](iroh::baomap::flat#pack-files). 0 disables packing.
+    pub pack_threshold: u64,
+}
+
+impl StoreSettings {
+    /// The compression for new data in the store.
+    pub fn compression(&self) -> Compression {
+        match self.compression_level {
+            Some(level) => Compression::Zstd(level),
+            None => Compression::None,
+        }
+    }
+
+    /// The settings for new data in the store.
+    pub fn settings(&self) -> flat::Settings {
+        flat::Settings {
+            compression: self.compression(),
+            pack_threshold: self.pack_threshold,
+        }
+    }
+}
+
+/// Settings for announcing and discovering content, see [`iroh::announce`].
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AnnounceSettings {
+    /// Whether `iroh provide` announces its content and indexes the content of other nodes.
+    pub enabled: bool,
+    /// How often the content is announced, in seconds.
+    pub interval_secs: u64,
+    /// How long announcements of other nodes are kept, in seconds.
+    pub ttl_secs: u64,
+    /// Nodes to join the announcements through.
+    pub peers: Vec<AnnouncePeer>,
+}
+
+impl Default for AnnounceSettings {
+    fn default() -> Self {
+        let config = AnnounceConfig::default();
+        Self {
+            enabled: false,
+            interval_secs: config.interval.as_secs(),
+            ttl_secs: config.ttl.as_secs(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// A node to join the announcements through.
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct AnnouncePeer {
+    /// The peer id of the node.
+    #[serde(with = "peer_id")]
+    pub peer: PeerId,
+    /// Direct addresses of the node.
+    #[serde(default)]
+    pub addrs: Vec<SocketAddr>,
+    /// The DERP region of the node.
+    #[serde(default)]
+    pub derp_region: Option<u16>,
+}
+
+impl AnnounceSettings {
+    /// The config for [`iroh::node::Builder::announce`], if announcements are enabled.
+    pub fn config(&self) -> Option<AnnounceConfig> {
+        if !self.enabled {
+            return None;
+        }
+        Some(AnnounceConfig {
+            interval: Duration::from_secs(self.interval_secs),
+            ttl: Duration::from_secs(self.ttl_secs),
+            peers: self
+                .peers
+                .iter()
+                .map(|p| NodeAddr::new(p.peer, p.addrs.clone(), p.derp_region))
+                .collect(),
+        })
+    }
 }
 
 impl Default for Config {
@@ -95,10 +210,50 @@ impl Default for Config {
         Self {
             // TODO(ramfox): this should probably just be a derp map
             derp_regions: [default_na_derp_region(), default_eu_derp_region()].into(),
+            allowed_peers: Vec::new(),
+            denied_peers: Vec::new(),
+            limits: Limits::default(),
+            announce: AnnounceSettings::default(),
+            store: StoreSettings::default(),
         }
     }
 }
 
+/// (De)serialize peer ids in their string form, so they can be written in config files.
+mod peer_ids {
+    use iroh_net::tls::PeerId;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(peers: &[PeerId], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(peers.iter().map(|peer| peer.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PeerId>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|peer| peer.parse().map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+/// (De)serialize a single peer id in its string form.
+mod peer_id {
+    use iroh_net::tls::PeerId;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(peer: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(peer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
 impl Config {
     /// Make a config using a default, files, environment variables, and commandline flags.
     ///
@@ -254,6 +409,91 @@ mod tests {
         let config = Config::load::<String, String>(&[][..], "__FOO", Default::default()).unwrap();
 
         assert_eq!(config.derp_regions.len(), 2);
+        assert!(config.allowed_peers.is_empty());
+        assert!(config.denied_peers.is_empty());
+        assert_eq!(config.limits, Limits::default());
+        assert_eq!(config.announce, AnnounceSettings::default());
+        assert!(config.announce.config().is_none());
+        assert_eq!(config.store.compression(), Compression::None);
+        assert_eq!(config.store.settings(), flat::Settings::default());
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = testdir::testdir!();
+        let path = dir.join(CONFIG_FILE_NAME);
+        std::fs::write(
+            &path,
+            "[store]\ncompression_level = 3\npack_threshold = 16384\n",
+        )
+        .unwrap();
+        let config =
+            Config::load::<String, String>(&[Some(&path)], "__FOO", Default::default()).unwrap();
+        assert_eq!(config.store.compression(), Compression::Zstd(3));
+        assert_eq!(config.store.settings().pack_threshold, 16384);
+    }
+
+    #[test]
+    fn test_announce() {
+        let dir = testdir::testdir!();
+        let path = dir.join(CONFIG_FILE_NAME);
+        let peer = PeerId::from(iroh_net::tls::Keypair::generate().public());
+        std::fs::write(
+            &path,
+            format!(
+                "[announce]\nenabled = true\ninterval_secs = 10\n\n[[announce.peers]]\npeer = \"{peer}\"\nderp_region = 1\n"
+            ),
+        )
+        .unwrap();
+        let config =
+            Config::load::<String, String>(&[Some(&path)], "__FOO", Default::default()).unwrap();
+        let announce = config.announce.config().unwrap();
+        assert_eq!(announce.interval, Duration::from_secs(10));
+        assert_eq!(announce.ttl, AnnounceConfig::default().ttl);
+        assert_eq!(announce.peers, vec![NodeAddr::new(peer, vec![], Some(1))]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let dir = testdir::testdir!();
+        let path = dir.join(CONFIG_FILE_NAME);
+        std::fs::write(
+            &path,
+            "[limits]\nmax_connections_per_peer = 2\nmax_upload_rate = 1000000\n",
+        )
+        .unwrap();
+        let config =
+            Config::load::<String, String>(&[Some(&path)], "__FOO", Default::default()).unwrap();
+        assert_eq!(
+            config.limits,
+            Limits {
+                max_connections_per_peer: Some(2),
+                max_upload_rate: Some(1_000_000),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_peer_lists() {
+        let dir = testdir::testdir!();
+        let path = dir.join(CONFIG_FILE_NAME);
+        let allowed = PeerId::from(iroh_net::tls::Keypair::generate().public());
+        let denied = PeerId::from(iroh_net::tls::Keypair::generate().public());
+        std::fs::write(
+            &path,
+            format!("allowed_peers = [\"{allowed}\"]\ndenied_peers = [\"{denied}\"]\n"),
+        )
+        .unwrap();
+        let config =
+            Config::load::<String, String>(&[Some(&path)], "__FOO", Default::default()).unwrap();
+        assert_eq!(config.allowed_peers, vec![allowed]);
+        assert_eq!(config.denied_peers, vec![denied]);
+
+        std::fs::write(&path, "denied_peers = [\"not a peer id\"]\n").unwrap();
+        assert!(
+            Config::load::<String, String>(&[Some(&path)], "__FOO", Default::default()).is_err()
+        );
     }
 
     #[test]
@@ -262,6 +502,7 @@ mod tests {
             IrohPaths::BaoFlatStoreComplete,
             IrohPaths::BaoFlatStorePartial,
             IrohPaths::Keypair,
+            IrohPaths::Downloads,
         ];
         for iroh_path in &kinds {
             let root = PathBuf::from("/tmp");
@@ -1,11 +1,12 @@
 //! The ticket type for the provider.
 //!
 //! This is in it's own module to enforce the invariant that you can not construct a ticket
-//! with an empty address list.
+//! with an empty provider or address list.
 
 use std::fmt::{self, Display};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{ensure, Context, Result};
 use iroh_bytes::protocol::RequestToken;
@@ -54,30 +55,83 @@ pub async fn dial(opts: Options) -> anyhow::Result<quinn::Connection> {
         .context("failed to connect to provider")
 }
 
-/// A token containing everything to get a file from the provider.
+/// The address of a single provider node in a [`Ticket`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NodeAddr {
+    /// The peer ID identifying the provider.
+    pub peer: PeerId,
+    /// The socket addresses the provider is listening on.
+    pub addrs: Vec<SocketAddr>,
+    /// DERP region of the provider
+    pub derp_region: Option<u16>,
+}
+
+impl NodeAddr {
+    /// Creates a new node address.
+    pub fn new(peer: PeerId, addrs: Vec<SocketAddr>, derp_region: Option<u16>) -> Self {
+        Self {
+            peer,
+            addrs,
+            derp_region,
+        }
+    }
+
+    /// Convert this node address into a [`Options`], adding the given keypair.
+    pub fn as_get_options(&self, keypair: Keypair, derp_map: Option<DerpMap>) -> Options {
+        Options {
+            peer_id: self.peer,
+            addrs: self.addrs.clone(),
+            keypair,
+            keylog: true,
+            derp_region: self.derp_region,
+            derp_map,
+        }
+    }
+}
+
+/// A token containing everything to get a file from one or more providers.
 ///
 /// It is a single item which can be easily serialized and deserialized.  The [`Display`]
 /// and [`FromStr`] implementations serialize to base32.
+///
+/// The serialized form starts with a format version byte, followed by the postcard
+/// encoding of the ticket. Tickets in the original, unversioned format (a single
+/// provider and no expiry) can still be parsed.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Ticket {
     /// The hash to retrieve.
     hash: Hash,
-    /// The peer ID identifying the provider.
-    peer: PeerId,
+    /// The providers of the data, in order of preference.
+    ///
+    /// This will never be empty, and none of the providers will have an empty address list.
+    nodes: Vec<NodeAddr>,
     /// Optional Request token.
     token: Option<RequestToken>,
-    /// The socket addresses the provider is listening on.
-    ///
-    /// This will never be empty.
-    addrs: Vec<SocketAddr>,
     /// True to treat the hash as a collection and retrieve all blobs in it.
     recursive: bool,
-    /// DERP region of the provider
+    /// Optional expiry time, in seconds since the unix epoch.
+    expires: Option<u64>,
+}
+
+/// The current ticket format version.
+const TICKET_VERSION: u8 = 2;
+
+/// The original, unversioned ticket format.
+///
+/// Its serialized form always starts with the postcard length prefix of the hash, 32,
+/// so it can not be confused with a versioned ticket.
+#[derive(Debug, Serialize, Deserialize)]
+struct TicketV1 {
+    hash: Hash,
+    peer: PeerId,
+    token: Option<RequestToken>,
+    addrs: Vec<SocketAddr>,
+    recursive: bool,
     derp_region: Option<u16>,
 }
 
 impl Ticket {
-    /// Creates a new ticket.
+    /// Creates a new ticket for a single provider.
     pub fn new(
         hash: Hash,
         peer: PeerId,
@@ -86,27 +140,72 @@ impl Ticket {
         recursive: bool,
         derp_region: Option<u16>,
     ) -> Result<Self> {
-        ensure!(!addrs.is_empty(), "addrs list can not be empty");
-        Ok(Self {
+        Self::with_nodes(
             hash,
-            peer,
-            addrs,
+            vec![NodeAddr::new(peer, addrs, derp_region)],
             token,
             recursive,
-            derp_region,
-        })
+        )
+    }
+
+    /// Creates a new ticket for multiple providers.
+    ///
+    /// The providers will be tried in the given order.
+    pub fn with_nodes(
+        hash: Hash,
+        nodes: Vec<NodeAddr>,
+        token: Option<RequestToken>,
+        recursive: bool,
+    ) -> Result<Self> {
+        let slf = Self {
+            hash,
+            nodes,
+            token,
+            recursive,
+            expires: None,
+        };
+        slf.verify()?;
+        Ok(slf)
+    }
+
+    fn verify(&self) -> Result<()> {
+        ensure!(!self.nodes.is_empty(), "node list can not be empty");
+        ensure!(
+            self.nodes.iter().all(|node| !node.addrs.is_empty()),
+            "addrs list can not be empty"
+        );
+        Ok(())
     }
 
     /// Deserializes from bytes.
+    ///
+    /// Accepts both the current versioned format and the original unversioned format.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        let slf: Ticket = postcard::from_bytes(bytes)?;
-        ensure!(!slf.addrs.is_empty(), "Invalid address list in ticket");
+        let slf = match bytes.split_first() {
+            Some((&TICKET_VERSION, rest)) => postcard::from_bytes(rest)?,
+            Some((&version, _)) if version < 32 => {
+                anyhow::bail!("unsupported ticket version {version}")
+            }
+            _ => {
+                let v1: TicketV1 = postcard::from_bytes(bytes)?;
+                Self {
+                    hash: v1.hash,
+                    nodes: vec![NodeAddr::new(v1.peer, v1.addrs, v1.derp_region)],
+                    token: v1.token,
+                    recursive: v1.recursive,
+                    expires: None,
+                }
+            }
+        };
+        slf.verify().context("Invalid address list in ticket")?;
         Ok(slf)
     }
 
     /// Serializes to bytes.
     pub fn to_bytes(&self) -> Vec<u8> {
-        postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible")
+        let mut bytes = vec![TICKET_VERSION];
+        bytes.extend(postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible"));
+        bytes
     }
 
     /// The hash of the item this ticket can retrieve.
@@ -114,9 +213,23 @@ impl Ticket {
         self.hash
     }
 
-    /// The [`PeerId`] of the provider for this ticket.
+    /// The [`PeerId`] of the first provider for this ticket.
     pub fn peer(&self) -> PeerId {
-        self.peer
+        self.nodes[0].peer
+    }
+
+    /// The providers for this ticket, in order of preference.
+    ///
+    /// This is guaranteed to be non-empty.
+    pub fn nodes(&self) -> &[NodeAddr] {
+        &self.nodes
+    }
+
+    /// Add another provider to this ticket, to be tried after the existing ones.
+    pub fn with_node(mut self, node: NodeAddr) -> Result<Self> {
+        ensure!(!node.addrs.is_empty(), "addrs list can not be empty");
+        self.nodes.push(node);
+        Ok(self)
     }
 
     /// The [`RequestToken`] for this ticket.
@@ -139,50 +252,66 @@ impl Ticket {
         Self { recursive, ..self }
     }
 
-    /// The addresses on which the provider can be reached.
+    /// The time after which this ticket should no longer be used, if any.
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Set the expiry time for this ticket.
+    ///
+    /// The time is stored with a resolution of one second.
+    pub fn with_expires(self, expires: Option<SystemTime>) -> Self {
+        let expires = expires.map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+        Self { expires, ..self }
+    }
+
+    /// True if the ticket has an expiry time and it is in the past.
+    pub fn is_expired(&self) -> bool {
+        self.expires()
+            .map(|expires| expires <= SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    /// The addresses on which the first provider can be reached.
     ///
     /// This is guaranteed to be non-empty.
     pub fn addrs(&self) -> &[SocketAddr] {
-        &self.addrs
+        &self.nodes[0].addrs
     }
 
-    /// DERP region of the provider
+    /// DERP region of the first provider
     pub fn derp_region(&self) -> Option<u16> {
-        self.derp_region
+        self.nodes[0].derp_region
     }
 
     /// Get the contents of the ticket, consuming it.
-    pub fn into_parts(
-        self,
-    ) -> (
-        Hash,
-        PeerId,
-        Vec<SocketAddr>,
-        Option<RequestToken>,
-        bool,
-        Option<u16>,
-    ) {
+    pub fn into_parts(self) -> (Hash, Vec<NodeAddr>, Option<RequestToken>, bool, Option<u64>) {
         let Ticket {
             hash,
-            peer,
+            nodes,
             token,
-            addrs,
             recursive,
-            derp_region,
+            expires,
         } = self;
-        (hash, peer, addrs, token, recursive, derp_region)
+        (hash, nodes, token, recursive, expires)
     }
 
-    /// Convert this ticket into a [`Options`], adding the given keypair.
+    /// Convert this ticket into a [`Options`] for the first provider, adding the given keypair.
     pub fn as_get_options(&self, keypair: Keypair, derp_map: Option<DerpMap>) -> Options {
-        Options {
-            peer_id: self.peer,
-            addrs: self.addrs.clone(),
-            keypair,
-            keylog: true,
-            derp_region: self.derp_region,
-            derp_map,
-        }
+        self.nodes[0].as_get_options(keypair, derp_map)
+    }
+
+    /// Convert this ticket into [`Options`] for all providers, in order of preference.
+    pub fn as_all_get_options(&self, keypair: Keypair, derp_map: Option<DerpMap>) -> Vec<Options> {
+        self.nodes
+            .iter()
+            .map(|node| node.as_get_options(keypair.clone(), derp_map.clone()))
+            .collect()
     }
 }
 
@@ -219,17 +348,15 @@ mod tests {
         let hash = blake3::hash(b"hi there");
         let hash = Hash::from(hash);
         let peer = PeerId::from(Keypair::generate().public());
+        let peer2 = PeerId::from(Keypair::generate().public());
         let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
         let token = RequestToken::new(vec![1, 2, 3, 4, 5, 6]).unwrap();
         let derp_region = Some(0);
-        let ticket = Ticket {
-            hash,
-            peer,
-            addrs: vec![addr],
-            token: Some(token),
-            recursive: true,
-            derp_region,
-        };
+        let ticket = Ticket::new(hash, peer, vec![addr], Some(token), true, derp_region)
+            .unwrap()
+            .with_node(NodeAddr::new(peer2, vec![addr], None))
+            .unwrap()
+            .with_expires(Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
         let base32 = ticket.to_string();
         println!("Ticket: {base32}");
         println!("{} bytes", base32.len());
@@ -237,4 +364,48 @@ mod tests {
         let ticket2: Ticket = base32.parse().unwrap();
         assert_eq!(ticket2, ticket);
     }
+
+    #[test]
+    fn test_ticket_v1_compat() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let v1 = TicketV1 {
+            hash,
+            peer,
+            token: None,
+            addrs: vec![addr],
+            recursive: false,
+            derp_region: Some(1),
+        };
+        let mut base32 = data_encoding::BASE32_NOPAD.encode(&postcard::to_stdvec(&v1).unwrap());
+        base32.make_ascii_lowercase();
+
+        let ticket: Ticket = base32.parse().unwrap();
+        assert_eq!(ticket.hash(), hash);
+        assert_eq!(ticket.nodes(), &[NodeAddr::new(peer, vec![addr], Some(1))]);
+        assert!(!ticket.recursive());
+        assert_eq!(ticket.expires(), None);
+        // re-encoding upgrades to the current format
+        assert_eq!(ticket.to_bytes()[0], TICKET_VERSION);
+        assert_eq!(Ticket::from_bytes(&ticket.to_bytes()).unwrap(), ticket);
+    }
+
+    #[test]
+    fn test_ticket_expiry() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let ticket = Ticket::new(hash, peer, vec![addr], None, true, None).unwrap();
+        assert!(!ticket.is_expired());
+        let past = ticket
+            .clone()
+            .with_expires(Some(SystemTime::now() - Duration::from_secs(10)));
+        assert!(past.is_expired());
+        let future = ticket.with_expires(Some(SystemTime::now() + Duration::from_secs(3600)));
+        assert!(!future.is_expired());
+
+        assert!(Ticket::with_nodes(hash, vec![], None, true).is_err());
+        assert!(Ticket::new(hash, peer, vec![], None, true, None).is_err());
+    }
 }
@@ -0,0 +1,351 @@
+//! The download queue of a node.
+//!
+//! Every download that is requested from a node with a [`ShareRequest`] is added to a queue.
+//! If the node has a path for the queue, see [`Builder::downloads_path`], the queue is
+//! persisted there, and downloads that did not finish are resumed when the node is started
+//! again. The data that was already transferred is kept in partial entries of the store, so
+//! a resumed download only requests the ranges that are still missing.
+//!
+//! Downloads of the same data are deduplicated: while a transfer is running, other downloads
+//! of the same hash wait for it instead of starting their own. Failed transfers are retried
+//! with exponential backoff.
+//!
+//! [`ShareRequest`]: crate::rpc_protocol::ShareRequest
+//! [`Builder::downloads_path`]: crate::node::Builder::downloads_path
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use futures::future::{BoxFuture, Shared};
+use iroh_bytes::{get::Stats, protocol::RequestToken, Hash};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    task::AbortHandle,
+};
+
+use crate::dial::NodeAddr;
+
+/// Maximum number of transfers that run at the same time.
+const MAX_CONCURRENT_TRANSFERS: usize = 4;
+
+/// A download in the queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Download {
+    /// The hash of the blob or collection.
+    pub hash: Hash,
+    /// Whether the children of a collection are downloaded as well.
+    pub recursive: bool,
+    /// The providers to download from, in order of preference.
+    pub providers: Vec<NodeAddr>,
+    /// The path the data is exported to once it is downloaded.
+    pub out: Option<String>,
+    /// Whether the data is exported in place, see
+    /// [`ShareRequest::in_place`](crate::rpc_protocol::ShareRequest::in_place).
+    pub in_place: bool,
+    /// The token sent with the requests to the providers.
+    pub token: Option<RequestToken>,
+}
+
+impl Download {
+    /// Downloads with the same key share a transfer.
+    fn key(&self) -> (Hash, bool) {
+        (self.hash, self.recursive)
+    }
+}
+
+/// The status of a download in the queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DownloadStatus {
+    /// The download waits for other transfers to finish.
+    Queued,
+    /// Data is being transferred.
+    Running,
+    /// The last attempt failed, and the download will be retried.
+    Retrying {
+        /// The number of failed attempts so far.
+        attempts: u32,
+        /// The error of the last attempt.
+        error: String,
+    },
+}
+
+/// A transfer that can be awaited by several downloads.
+pub(crate) type Transfer = Shared<BoxFuture<'static, Result<Stats, Arc<anyhow::Error>>>>;
+
+/// The download queue, see the [module docs](self).
+#[derive(Debug)]
+pub(crate) struct Downloads {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+    permits: Semaphore,
+}
+
+#[derive(derive_more::Debug, Default)]
+struct State {
+    next_id: u64,
+    entries: BTreeMap<u64, Entry>,
+    #[debug("{} transfers", transfers.len())]
+    transfers: HashMap<(Hash, bool), (Transfer, AbortHandle)>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    download: Download,
+    status: DownloadStatus,
+    task: Option<AbortHandle>,
+}
+
+impl Entry {
+    fn new(download: Download) -> Self {
+        Self {
+            download,
+            status: DownloadStatus::Queued,
+            task: None,
+        }
+    }
+}
+
+impl Downloads {
+    /// Load the queue persisted at `path`.
+    ///
+    /// If `path` is `None`, the queue is only kept in memory. A queue file that can not be
+    /// parsed is moved aside to `<path>.corrupt`, and the queue starts out empty.
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let mut state = State::default();
+        if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let data = std::fs::read(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let downloads: Vec<(u64, Download)> = match postcard::from_bytes(&data) {
+                Ok(downloads) => downloads,
+                Err(cause) => {
+                    let mut aside = path.clone().into_os_string();
+                    aside.push(".corrupt");
+                    let aside = PathBuf::from(aside);
+                    tracing::error!(
+                        "invalid download queue {}, moving it to {}: {}",
+                        path.display(),
+                        aside.display(),
+                        cause
+                    );
+                    std::fs::rename(path, &aside)
+                        .with_context(|| format!("failed to move {}", path.display()))?;
+                    Vec::new()
+                }
+            };
+            for (id, download) in downloads {
+                state.next_id = state.next_id.max(id + 1);
+                state.entries.insert(id, Entry::new(download));
+            }
+        }
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+            permits: Semaphore::new(MAX_CONCURRENT_TRANSFERS),
+        })
+    }
+
+    /// Add a download to the queue, and return its id.
+    pub fn add(&self, download: Download) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.entries.insert(id, Entry::new(download));
+        if let Err(cause) = self.persist(&state) {
+            state.entries.remove(&id);
+            return Err(cause);
+        }
+        Ok(id)
+    }
+
+    /// The downloads that are not running, e.g. because they were loaded from disk.
+    pub fn pending(&self) -> Vec<(u64, Download)> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.task.is_none())
+            .map(|(id, entry)| (*id, entry.download.clone()))
+            .collect()
+    }
+
+    /// All downloads in the queue, with their status.
+    pub fn list(&self) -> Vec<(u64, Download, DownloadStatus)> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .iter()
+            .map(|(id, entry)| (*id, entry.download.clone(), entry.status.clone()))
+            .collect()
+    }
+
+    /// Set the task that runs a download, so it can be cancelled.
+    pub fn set_task(&self, id: u64, task: AbortHandle) {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get_mut(&id) {
+            Some(entry) => entry.task = Some(task),
+            // the download is already done
+            None => task.abort(),
+        }
+    }
+
+    /// Set the status of a download.
+    pub fn set_status(&self, id: u64, status: DownloadStatus) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.get_mut(&id) {
+            entry.status = status;
+        }
+    }
+
+    /// Remove a download that is done from the queue.
+    pub fn remove(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.remove(&id).is_some() {
+            if let Err(cause) = self.persist(&state) {
+                tracing::warn!("failed to persist download queue: {:?}", cause);
+            }
+        }
+    }
+
+    /// Cancel a download and remove it from the queue.
+    ///
+    /// The transfer of the download is stopped as well, unless other downloads are waiting
+    /// for it. The data that was already transferred is kept.
+    pub fn cancel(&self, id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state
+            .entries
+            .remove(&id)
+            .with_context(|| format!("download {id} not found"))?;
+        if let Some(task) = entry.task {
+            task.abort();
+        }
+        let key = entry.download.key();
+        if !state.entries.values().any(|e| e.download.key() == key) {
+            if let Some((_, task)) = state.transfers.remove(&key) {
+                task.abort();
+            }
+        }
+        self.persist(&state)
+    }
+
+    /// Get the running transfer for the data of `download`, or start a new one with `start`.
+    pub fn transfer(
+        &self,
+        download: &Download,
+        start: impl FnOnce() -> (Transfer, AbortHandle),
+    ) -> Transfer {
+        let mut state = self.state.lock().unwrap();
+        let (transfer, _) = state.transfers.entry(download.key()).or_insert_with(start);
+        transfer.clone()
+    }
+
+    /// Wait until a new transfer may run.
+    ///
+    /// Sets the status of all downloads that share the transfer to running.
+    pub async fn start_transfer(&self, download: &Download) -> SemaphorePermit<'_> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        let key = download.key();
+        let mut state = self.state.lock().unwrap();
+        for entry in state.entries.values_mut() {
+            if entry.download.key() == key {
+                entry.status = DownloadStatus::Running;
+            }
+        }
+        permit
+    }
+
+    /// Forget a transfer that has finished, so the next download starts a new one.
+    pub fn transfer_done(&self, download: &Download) {
+        let mut state = self.state.lock().unwrap();
+        state.transfers.remove(&download.key());
+    }
+
+    fn persist(&self, state: &State) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let downloads = state
+            .entries
+            .iter()
+            .map(|(id, entry)| (*id, &entry.download))
+            .collect::<Vec<_>>();
+        let data = postcard::to_stdvec(&downloads)?;
+        // write to a temp file first, so a crash does not leave a truncated queue behind
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, data)
+            .with_context(|| format!("failed to write {}", temp.display()))?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::tls::{Keypair, PeerId};
+
+    #[test]
+    fn test_downloads_persist() -> Result<()> {
+        let dir = testdir::testdir!();
+        let path = dir.join("downloads");
+        let download = |data: &[u8]| Download {
+            hash: Hash::new(data),
+            recursive: false,
+            providers: vec![NodeAddr::new(
+                PeerId::from(Keypair::generate().public()),
+                vec!["127.0.0.1:4433".parse().unwrap()],
+                Some(1),
+            )],
+            out: Some("out".into()),
+            in_place: false,
+            token: Some(RequestToken::new(data.to_vec()).unwrap()),
+        };
+        let (a, b) = (download(b"a"), download(b"b"));
+
+        let downloads = Downloads::load(Some(path.clone()))?;
+        let id_a = downloads.add(a.clone())?;
+        let id_b = downloads.add(b.clone())?;
+        downloads.remove(id_a);
+        downloads.set_status(id_b, DownloadStatus::Running);
+        assert!(downloads.cancel(id_a).is_err());
+
+        // the status is not persisted, loaded downloads are queued again
+        let downloads = Downloads::load(Some(path))?;
+        assert_eq!(downloads.pending(), vec![(id_b, b.clone())]);
+        assert_eq!(
+            downloads.list(),
+            vec![(id_b, b.clone(), DownloadStatus::Queued)]
+        );
+        // ids are not reused
+        let id_c = downloads.add(a)?;
+        assert!(id_c > id_b);
+        downloads.cancel(id_b)?;
+        assert_eq!(downloads.list().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_downloads_corrupt() -> Result<()> {
+        let dir = testdir::testdir!();
+        let path = dir.join("downloads");
+        std::fs::write(&path, b"\xffnot a queue")?;
+
+        // the corrupt file is moved aside, and the queue starts out empty
+        let downloads = Downloads::load(Some(path.clone()))?;
+        assert!(downloads.list().is_empty());
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read(dir.join("downloads.corrupt"))?,
+            b"\xffnot a queue"
+        );
+        Ok(())
+    }
+}
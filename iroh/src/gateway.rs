@@ -0,0 +1,840 @@
+//! HTTP gateway to the blobs of a node.
+//!
+//! The gateway allows clients that do not speak the iroh protocol, like browsers or `curl`,
+//! to get data from a [`Node`](crate::node::Node). It serves
+//!
+//! - `/blob/<hash>`: the blob with the given hash
+//! - `/collection/<hash>/<name>`: the blob named `name` in the given collection. For trees of
+//!   [`Directory`] collections, `name` is the path of the file relative to the root.
+//!
+//! Single byte ranges in a `Range` header are supported. The `ETag` of a response is the
+//! hash of the blob, since the content of a blob never changes.
+//!
+//! If an upstream provider is configured, data that is not in the local store is fetched
+//! from it. Only the chunk groups overlapping the requested range that are not in the
+//! partial entry yet are fetched, and the entry is completed once all of them are there.
+//!
+//! Requests are checked by the [`RequestAuthorizationHandler`] of the node, like requests
+//! over the iroh protocol. HTTP clients do not have a peer id, so they are authorized as the
+//! node itself, which means that an allow list of peers rejects all of them. A request token
+//! can be passed in an `Authorization: Bearer <token>` header, or as a `token` query
+//! parameter for clients that can not set headers.
+use std::{
+    collections::HashMap, convert::Infallible, fmt, net::SocketAddr, ops::Range, str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use bao_tree::ChunkNum;
+use bytes::Bytes;
+use futures::FutureExt;
+use hyper::{
+    header,
+    http::{response::Builder as ResponseBuilder, HeaderMap, HeaderValue},
+    server::conn::{AddrIncoming, AddrStream},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use iroh_bytes::{
+    baomap::{range_collections::RangeSet2, MapEntry, Store},
+    protocol::{GetRequest, Request as GetOrCustomRequest, RequestToken},
+    provider::RequestAuthorizationHandler,
+    util::runtime,
+    Hash, IROH_BLOCK_SIZE,
+};
+use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
+use iroh_net::{tls::PeerId, MagicEndpoint};
+use tokio::sync::{oneshot, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::collection::{
+    tree::{Directory, EntryKind},
+    Collection,
+};
+use crate::dial::NodeAddr;
+
+/// How many bytes to read from the store at once when sending a response body.
+const READ_CHUNK_SIZE: usize = 1024 * 64;
+
+/// Configuration of the HTTP gateway of a [`Node`](crate::node::Node).
+///
+/// See the [module docs](self) for what is served.
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    addr: SocketAddr,
+    upstream: Option<NodeAddr>,
+    token: Option<RequestToken>,
+}
+
+impl GatewayConfig {
+    /// Creates a new config for a gateway listening on `addr`.
+    ///
+    /// Use port 0 to pick a free port, see [`Node::gateway_addr`](crate::node::Node::gateway_addr).
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            upstream: None,
+            token: None,
+        }
+    }
+
+    /// Fetch data that is not in the local store from this provider.
+    pub fn with_upstream(mut self, upstream: Option<NodeAddr>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// The request token to send with requests to the upstream provider.
+    pub fn with_token(mut self, token: Option<RequestToken>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// The address the gateway listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Binds the gateway and serves requests until `cancel_token` is cancelled.
+///
+/// Returns the address the gateway is listening on.
+pub(crate) fn spawn<D: Store>(
+    config: GatewayConfig,
+    db: D,
+    endpoint: MagicEndpoint,
+    auth_handler: Arc<dyn RequestAuthorizationHandler>,
+    rt: runtime::Handle,
+    cancel_token: CancellationToken,
+) -> anyhow::Result<SocketAddr> {
+    let incoming = AddrIncoming::bind(&config.addr)
+        .with_context(|| format!("failed to bind gateway to {}", config.addr))?;
+    let addr = incoming.local_addr();
+    debug!("gateway listening on {addr}");
+    let gateway = Gateway(Arc::new(GatewayInner {
+        db,
+        peer_id: endpoint.peer_id(),
+        endpoint,
+        auth_handler,
+        upstream: config.upstream,
+        token: config.token,
+        fetch: Mutex::new(FetchState::default()),
+        rt: rt.clone(),
+    }));
+    let server = hyper::Server::builder(incoming)
+        .serve(make_service_fn(move |conn: &AddrStream| {
+            let gateway = gateway.clone();
+            let remote_addr = conn.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    gateway
+                        .clone()
+                        .handle(req, remote_addr)
+                        .map(Ok::<_, Infallible>)
+                }))
+            }
+        }))
+        .with_graceful_shutdown(cancel_token.cancelled_owned());
+    rt.main().spawn(async move {
+        if let Err(cause) = server.await {
+            warn!("gateway failed: {cause}");
+        }
+    });
+    Ok(addr)
+}
+
+#[derive(Debug)]
+struct Gateway<D>(Arc<GatewayInner<D>>);
+
+impl<D> Clone for Gateway<D> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[derive(derive_more::Debug)]
+struct GatewayInner<D> {
+    db: D,
+    /// The peer id of the node, which HTTP requests are authorized as.
+    peer_id: PeerId,
+    endpoint: MagicEndpoint,
+    auth_handler: Arc<dyn RequestAuthorizationHandler>,
+    upstream: Option<NodeAddr>,
+    token: Option<RequestToken>,
+    /// Held while fetching, so concurrent requests do not write to the same partial entry.
+    fetch: Mutex<FetchState>,
+    #[debug(skip)]
+    rt: runtime::Handle,
+}
+
+/// State of fetching from the upstream provider.
+#[derive(Debug, Default)]
+struct FetchState {
+    /// Connection to the upstream provider.
+    conn: Option<quinn::Connection>,
+    /// The chunk groups that were fetched into partial entries, so they are not fetched again.
+    ///
+    /// The stores can not tell which ranges of a partial entry are valid, so for entries that
+    /// were not fetched by the gateway everything is fetched once.
+    available: HashMap<Hash, RangeSet2<ChunkNum>>,
+}
+
+/// A blob range to send as the body of a response.
+#[derive(Debug)]
+struct Content {
+    hash: Hash,
+    range: Range<u64>,
+}
+
+impl<D: Store> Gateway<D> {
+    /// Handle a single request.
+    ///
+    /// Reading from the store is not `Send`, so the request is handled on the local pool.
+    async fn handle(self, req: Request<Body>, remote_addr: SocketAddr) -> Response<Body> {
+        let (send, recv) = oneshot::channel();
+        let rt = self.0.rt.clone();
+        rt.local_pool().spawn_pinned(move || async move {
+            let response = match self.serve(&req, remote_addr).await {
+                Ok((response, Some(content))) => {
+                    let (sender, body) = Body::channel();
+                    if let Ok(response) = response.body(body) {
+                        send.send(response).ok();
+                        self.send_content(content, sender).await;
+                    }
+                    return;
+                }
+                Ok((response, None)) => response.body(Body::empty()),
+                Err(cause) => cause.into_response(),
+            };
+            if let Ok(response) = response {
+                send.send(response).ok();
+            }
+        });
+        match recv.await {
+            Ok(response) => response,
+            Err(_) => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                response
+            }
+        }
+    }
+
+    async fn serve(
+        &self,
+        req: &Request<Body>,
+        remote_addr: SocketAddr,
+    ) -> Result<(ResponseBuilder, Option<Content>), HttpError> {
+        let (method, path, headers) = (req.method(), req.uri().path(), req.headers());
+        let token = request_token(headers, req.uri().query())?;
+        if method != Method::GET && method != Method::HEAD {
+            return Err(HttpError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "only GET and HEAD are supported",
+            ));
+        }
+        let hash = if let Some(hash) = path.strip_prefix("/blob/") {
+            let hash = parse_hash(hash)?;
+            self.authorize(GetRequest::single(hash), token, remote_addr)
+                .await?;
+            hash
+        } else if let Some(rest) = path.strip_prefix("/collection/") {
+            let (root, name) = rest
+                .split_once('/')
+                .ok_or_else(|| HttpError::new(StatusCode::NOT_FOUND, "missing name"))?;
+            let root = parse_hash(root)?;
+            let name = percent_encoding::percent_decode_str(name)
+                .decode_utf8()
+                .map_err(|_| HttpError::new(StatusCode::BAD_REQUEST, "invalid name"))?;
+            self.authorize(GetRequest::all(root), token, remote_addr)
+                .await?;
+            self.resolve(root, &name).await?
+        } else {
+            return Err(HttpError::new(StatusCode::NOT_FOUND, "not found"));
+        };
+
+        let etag = HeaderValue::from_str(&format!("\"{hash}\"")).expect("hashes are ascii");
+        let response = Response::builder()
+            .header(header::ETAG, etag.clone())
+            .header(header::ACCEPT_RANGES, "bytes");
+        if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+            if value == etag || value == "*" {
+                return Ok((response.status(StatusCode::NOT_MODIFIED), None));
+            }
+        }
+        let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+        let size = match self.local_size(&hash) {
+            Some(size) => size,
+            // just get the size if not all of the data is needed
+            None if range.is_some() || method == Method::HEAD => {
+                self.fetch(&hash, RangeSet2::from(..ChunkNum(1 << IROH_BLOCK_SIZE.0)))
+                    .await?
+            }
+            None => self.fetch(&hash, RangeSet2::all()).await?,
+        };
+        let (response, range) = match range.map(|range| parse_range(range, size)) {
+            Some(Ok(Some(range))) => {
+                let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, size);
+                let response = response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, content_range);
+                (response, range)
+            }
+            Some(Err(Unsatisfiable)) => {
+                let response = response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{size}"));
+                return Ok((response, None));
+            }
+            Some(Ok(None)) | None => (response.status(StatusCode::OK), 0..size),
+        };
+        let response = response
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, range.end - range.start);
+        if method == Method::HEAD || range.is_empty() {
+            return Ok((response, None));
+        }
+        if !crate::get::is_complete(&self.0.db, &hash) {
+            let ranges = crate::get::chunk_groups(range.clone(), size);
+            self.fetch(&hash, ranges).await?;
+        }
+        Ok((response, Some(Content { hash, range })))
+    }
+
+    /// Check a request with the auth handler of the node.
+    async fn authorize(
+        &self,
+        request: GetRequest,
+        token: Option<RequestToken>,
+        remote_addr: SocketAddr,
+    ) -> Result<(), HttpError> {
+        let inner = &self.0;
+        let status = if token.is_some() {
+            StatusCode::FORBIDDEN
+        } else {
+            StatusCode::UNAUTHORIZED
+        };
+        let request = GetOrCustomRequest::Get(request.with_token(token.clone()));
+        inner
+            .auth_handler
+            .authorize(inner.peer_id, remote_addr, token, &request)
+            .await
+            .map_err(|cause| HttpError::new(status, format!("not authorized: {cause}")))
+    }
+
+    /// Send a range of a blob as the body of a response.
+    ///
+    /// If reading fails, the body is aborted, since the status has already been sent.
+    async fn send_content(&self, content: Content, mut sender: hyper::body::Sender) {
+        let db = &self.0.db;
+        let reader = match db.get_partial(&content.hash) {
+            Some(entry) => entry.data_reader().await,
+            None => match db.get(&content.hash) {
+                Some(entry) => entry.data_reader().await,
+                None => {
+                    sender.abort();
+                    return;
+                }
+            },
+        };
+        let mut reader = match reader {
+            Ok(reader) => reader,
+            Err(cause) => {
+                warn!("failed to read {}: {cause}", content.hash);
+                sender.abort();
+                return;
+            }
+        };
+        let mut offset = content.range.start;
+        while offset < content.range.end {
+            let len = (content.range.end - offset).min(READ_CHUNK_SIZE as u64) as usize;
+            let data = match reader.read_at(offset, len).await {
+                Ok(data) if data.len() == len => data,
+                Ok(_) => {
+                    warn!("unexpected end of {}", content.hash);
+                    sender.abort();
+                    return;
+                }
+                Err(cause) => {
+                    warn!("failed to read {}: {cause}", content.hash);
+                    sender.abort();
+                    return;
+                }
+            };
+            if sender.send_data(data).await.is_err() {
+                // the client went away
+                return;
+            }
+            offset += len as u64;
+        }
+    }
+
+    /// Find the hash of the blob named `name` in the collection `root`.
+    async fn resolve(&self, root: Hash, name: &str) -> Result<Hash, HttpError> {
+        let not_found = || HttpError::new(StatusCode::NOT_FOUND, format!("{name} not found"));
+        let data = self.read_blob(&root).await?;
+        if !Directory::is_directory(&data) {
+            let collection = Collection::from_bytes(&data)
+                .map_err(|_| HttpError::new(StatusCode::NOT_FOUND, "not a collection"))?;
+            let blob = collection.blobs().iter().find(|blob| blob.name == name);
+            return blob.map(|blob| blob.hash).ok_or_else(not_found);
+        }
+        let mut dir = Directory::from_bytes(&data)?;
+        let mut segments = name.split('/').peekable();
+        while let Some(segment) = segments.next() {
+            let entry = dir
+                .entries()
+                .iter()
+                .find(|entry| entry.name == segment)
+                .ok_or_else(not_found)?;
+            match (&entry.kind, segments.peek().is_none()) {
+                (EntryKind::File { hash, .. }, true) => return Ok(*hash),
+                (EntryKind::Chunked { .. }, true) => {
+                    return Err(HttpError::new(
+                        StatusCode::NOT_IMPLEMENTED,
+                        format!("{name} is a chunked file, which can not be served yet"),
+                    ));
+                }
+                (EntryKind::Directory { hash, .. }, false) => {
+                    dir = Directory::from_bytes(&self.read_blob(hash).await?)?;
+                }
+                _ => break,
+            }
+        }
+        Err(not_found())
+    }
+
+    /// Read an entire blob, fetching it if it is not complete.
+    async fn read_blob(&self, hash: &Hash) -> Result<Bytes, HttpError> {
+        if !crate::get::is_complete(&self.0.db, hash) {
+            self.fetch(hash, RangeSet2::all()).await?;
+        }
+        let entry = self.0.db.get(hash).context("entry is gone")?;
+        Ok(entry.data_reader().await?.read_to_end().await?)
+    }
+
+    /// The size of a blob, if we have it and do not need to ask the upstream for it.
+    fn local_size(&self, hash: &Hash) -> Option<u64> {
+        let db = &self.0.db;
+        match db.get_partial(hash) {
+            // without an upstream, partial entries are treated as missing, since we can
+            // not tell which ranges are valid
+            Some(entry) if self.0.upstream.is_some() => Some(entry.size()),
+            Some(_) => None,
+            None => db.get(hash).map(|entry| entry.size()),
+        }
+    }
+
+    /// Fetch the chunk ranges of a blob that are not in the local store yet from the
+    /// upstream provider.
+    ///
+    /// Completes the entry once all of it is there. Returns the size of the blob.
+    async fn fetch(&self, hash: &Hash, ranges: RangeSet2<ChunkNum>) -> Result<u64, HttpError> {
+        let inner = &self.0;
+        let upstream = inner
+            .upstream
+            .as_ref()
+            .ok_or_else(|| HttpError::new(StatusCode::NOT_FOUND, format!("{hash} not found")))?;
+        let mut state = inner.fetch.lock().await;
+        let FetchState { conn, available } = &mut *state;
+        // reuse the connection, unless it was closed
+        let connect = async {
+            match conn.as_ref() {
+                Some(current) if current.close_reason().is_none() => Ok(current.clone()),
+                _ => {
+                    let new = inner
+                        .endpoint
+                        .connect(
+                            upstream.peer,
+                            &iroh_bytes::protocol::ALPN,
+                            upstream.derp_region,
+                            &upstream.addrs,
+                        )
+                        .await
+                        .context("failed to connect to upstream")?;
+                    *conn = Some(new.clone());
+                    Ok(new)
+                }
+            }
+        };
+        crate::get::fetch_blob_ranges(
+            &inner.db,
+            connect,
+            hash,
+            ranges,
+            inner.token.clone(),
+            available,
+        )
+        .await
+        .map_err(|cause| {
+            warn!("failed to fetch {hash} from upstream: {cause:#}");
+            HttpError::new(StatusCode::BAD_GATEWAY, format!("failed to fetch {hash}"))
+        })
+    }
+}
+
+/// The request token of a request, from the `Authorization` header or the `token` query
+/// parameter.
+fn request_token(
+    headers: &HeaderMap,
+    query: Option<&str>,
+) -> Result<Option<RequestToken>, HttpError> {
+    let header = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let param = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("token="));
+    header
+        .or(param)
+        .map(|token| RequestToken::from_str(token.trim()))
+        .transpose()
+        .map_err(|_| HttpError::new(StatusCode::BAD_REQUEST, "invalid request token"))
+}
+
+fn parse_hash(text: &str) -> Result<Hash, HttpError> {
+    Hash::from_str(text).map_err(|_| HttpError::new(StatusCode::BAD_REQUEST, "invalid hash"))
+}
+
+/// The requested range can not be served.
+#[derive(Debug, PartialEq, Eq)]
+struct Unsatisfiable;
+
+/// Parse the value of a `Range` header for a blob of `size` bytes.
+///
+/// Only a single byte range is supported. Other ranges are ignored, which RFC 9110 allows,
+/// so `Ok(None)` means the whole blob should be sent.
+fn parse_range(value: &str, size: u64) -> Result<Option<Range<u64>>, Unsatisfiable> {
+    let Some((start, end)) = value
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let Ok(suffix) = suffix.parse::<u64>() else {
+                return Ok(None);
+            };
+            if suffix == 0 {
+                return Err(Unsatisfiable);
+            }
+            size.saturating_sub(suffix)..size
+        }
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return Ok(None);
+            };
+            let end = if end.is_empty() {
+                size
+            } else {
+                match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.saturating_add(1).min(size),
+                    _ => return Ok(None),
+                }
+            };
+            start..end
+        }
+    };
+    if range.start >= size {
+        return Err(Unsatisfiable);
+    }
+    Ok(Some(range))
+}
+
+/// An error that is sent to the client as a response with a status code.
+#[derive(Debug)]
+struct HttpError {
+    status: StatusCode,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: StatusCode, message: impl fmt::Display) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    fn into_response(self) -> hyper::http::Result<Response<Body>> {
+        debug!("gateway error {}: {}", self.status, self.message);
+        Response::builder()
+            .status(self.status)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(self.message))
+    }
+}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(cause: anyhow::Error) -> Self {
+        warn!("gateway error: {cause:#}");
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+    }
+}
+
+impl From<std::io::Error> for HttpError {
+    fn from(cause: std::io::Error) -> Self {
+        anyhow::Error::from(cause).into()
+    }
+}
+
+#[cfg(all(test, feature = "mem-db"))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use anyhow::Result;
+    use iroh_bytes::baomap::{Map, PartialMap};
+
+    use super::*;
+    use crate::{
+        baomap::mem,
+        collection::{tree::Entry, Blob},
+        node::{Node, StaticTokenAuthHandler},
+    };
+
+    async fn get(
+        addr: SocketAddr,
+        method: Method,
+        path: &str,
+        headers: &[(header::HeaderName, &str)],
+    ) -> Result<(StatusCode, HeaderMap, Bytes)> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("http://{addr}{path}"));
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        let res = hyper::Client::new()
+            .request(req.body(Body::empty())?)
+            .await?;
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok((status, headers, body))
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..100_000u32).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some(0..10)));
+        assert_eq!(parse_range("bytes=90-", 100), Ok(Some(90..100)));
+        assert_eq!(parse_range("bytes=90-200", 100), Ok(Some(90..100)));
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some(90..100)));
+        assert_eq!(parse_range("bytes=-200", 100), Ok(Some(0..100)));
+        assert_eq!(parse_range("bytes=100-", 100), Err(Unsatisfiable));
+        assert_eq!(parse_range("bytes=-0", 100), Err(Unsatisfiable));
+        // ignored
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("bytes=9-0", 100), Ok(None));
+        assert_eq!(parse_range("items=0-1", 100), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn gateway_serves_blobs() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let db = mem::Store::new(rt.clone());
+        let data = test_data();
+        let hash = db.import_bytes(data.clone().into()).await?;
+        let small = db.import_bytes(Bytes::from_static(b"small")).await?;
+        let blobs = vec![Blob {
+            name: "dir/a b".to_string(),
+            hash: small,
+        }];
+        let collection = Collection::new(blobs, 5)?.to_bytes()?;
+        let collection = db.import_bytes(collection.into()).await?;
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+            .gateway(GatewayConfig::new((Ipv4Addr::LOCALHOST, 0).into()))
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        let addr = node.gateway_addr().context("no gateway")?;
+        let path = format!("/blob/{hash}");
+        let etag = format!("\"{hash}\"");
+
+        let (status, headers, body) = get(addr, Method::GET, &path, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_LENGTH], "100000");
+        assert_eq!(headers[header::ETAG], etag.as_str());
+        assert_eq!(&body[..], &data[..]);
+
+        let range = [(header::RANGE, "bytes=40000-40999")];
+        let (status, headers, body) = get(addr, Method::GET, &path, &range).await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes 40000-40999/100000");
+        assert_eq!(&body[..], &data[40_000..41_000]);
+
+        let range = [(header::RANGE, "bytes=200000-")];
+        let (status, headers, _) = get(addr, Method::GET, &path, &range).await?;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes */100000");
+
+        let (status, headers, body) = get(addr, Method::HEAD, &path, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_LENGTH], "100000");
+        assert!(body.is_empty());
+
+        let cached = [(header::IF_NONE_MATCH, etag.as_str())];
+        let (status, _, body) = get(addr, Method::GET, &path, &cached).await?;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+
+        let path = format!("/collection/{collection}/dir/a%20b");
+        let (status, _, body) = get(addr, Method::GET, &path, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"small");
+
+        let path = format!("/collection/{collection}/missing");
+        let (status, _, _) = get(addr, Method::GET, &path, &[]).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let path = format!("/blob/{}", Hash::new(b"missing"));
+        let (status, _, _) = get(addr, Method::GET, &path, &[]).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(addr, Method::GET, "/blob/nohash", &[]).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gateway_checks_tokens() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let db = mem::Store::new(rt.clone());
+        let hash = db.import_bytes(Bytes::from_static(b"secret")).await?;
+        let token = RequestToken::generate();
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+            .custom_auth_handler(Arc::new(StaticTokenAuthHandler::new(Some(token.clone()))))
+            .gateway(GatewayConfig::new((Ipv4Addr::LOCALHOST, 0).into()))
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        let addr = node.gateway_addr().context("no gateway")?;
+        let path = format!("/blob/{hash}");
+
+        let (status, _, body) = get(addr, Method::GET, &path, &[]).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!body.starts_with(b"secret"));
+        let wrong = format!("Bearer {}", RequestToken::generate());
+        let (status, _, _) =
+            get(addr, Method::GET, &path, &[(header::AUTHORIZATION, &wrong)]).await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) = get(
+            addr,
+            Method::GET,
+            &path,
+            &[(header::AUTHORIZATION, "Bearer !")],
+        )
+        .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let bearer = format!("Bearer {token}");
+        let (status, _, body) = get(
+            addr,
+            Method::GET,
+            &path,
+            &[(header::AUTHORIZATION, &bearer)],
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"secret");
+        let path = format!("/blob/{hash}?token={token}");
+        let (status, _, body) = get(addr, Method::GET, &path, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"secret");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gateway_fetches_from_upstream() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let db = mem::Store::new(rt.clone());
+        let data = test_data();
+        let hash = db.import_bytes(data.clone().into()).await?;
+        let sub = Directory::new(vec![Entry {
+            name: "big".to_string(),
+            mode: 0o100644,
+            mtime: 0,
+            kind: EntryKind::File {
+                hash,
+                size: data.len() as u64,
+            },
+        }])?
+        .to_bytes()?;
+        let sub_size = sub.len() as u64;
+        let sub = db.import_bytes(sub.into()).await?;
+        let root = Directory::new(vec![Entry {
+            name: "sub".to_string(),
+            mode: 0o40755,
+            mtime: 0,
+            kind: EntryKind::Directory {
+                hash: sub,
+                size: sub_size,
+            },
+        }])?
+        .to_bytes()?;
+        let root = db.import_bytes(root.into()).await?;
+        let other = db
+            .import_bytes(data.iter().rev().copied().collect())
+            .await?;
+        let provider = Node::builder(db)
+            .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let upstream = NodeAddr::new(
+            provider.peer_id(),
+            provider.local_endpoint_addresses().await?,
+            None,
+        );
+
+        let local = mem::Store::new(rt.clone());
+        let config =
+            GatewayConfig::new((Ipv4Addr::LOCALHOST, 0).into()).with_upstream(Some(upstream));
+        let node = Node::builder(local.clone())
+            .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+            .gateway(config)
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard2 = node.cancel_token().drop_guard();
+        let addr = node.gateway_addr().context("no gateway")?;
+
+        // only the requested range is fetched
+        let path = format!("/collection/{root}/sub/big");
+        let range = [(header::RANGE, "bytes=40000-40999")];
+        let (status, _, body) = get(addr, Method::GET, &path, &range).await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&body[..], &data[40_000..41_000]);
+        assert!(local.get_partial(&hash).is_some());
+
+        // getting everything completes the blob
+        let (status, _, body) = get(addr, Method::GET, &path, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], &data[..]);
+        assert!(local.get_partial(&hash).is_none());
+        assert!(local.get(&hash).is_some());
+
+        // chunk groups that were fetched before are served without the upstream
+        let path = format!("/blob/{other}");
+        let (status, _, _) = get(addr, Method::GET, &path, &range).await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        provider.shutdown();
+        let range = [(header::RANGE, "bytes=40100-40199")];
+        let (status, _, body) = get(addr, Method::GET, &path, &range).await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body.len(), 100);
+        Ok(())
+    }
+}
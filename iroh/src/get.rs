@@ -1,20 +1,23 @@
 //! Functions to get blobs from peers
 
+use std::collections::HashMap;
+use std::future::Future;
 use std::io;
+use std::ops::Range;
 
 use anyhow::Context;
 use bao_tree::io::fsm::OutboardMut;
 use bao_tree::{ByteNum, ChunkNum};
 use iroh_bytes::baomap::range_collections::{range_set::RangeSetRange, RangeSet2};
 use iroh_bytes::{
-    baomap::{MapEntry, PartialMap, PartialMapEntry, Store as BaoStore},
+    baomap::{Map, MapEntry, PartialMap, PartialMapEntry, Store as BaoStore},
     collection::CollectionParser,
     get::{
         self,
         fsm::{AtBlobHeader, AtEndBlob, ConnectedNext, EndBlobNext},
         Stats,
     },
-    protocol::{GetRequest, RangeSpecSeq},
+    protocol::{GetRequest, RangeSpecSeq, RequestToken},
     provider::ShareProgress,
     util::{
         progress::{IdGenerator, ProgressSender},
@@ -27,19 +30,24 @@ use tracing::trace;
 
 use crate::util::progress::ProgressSliceWriter2;
 
+pub mod multi;
+
 /// Get a blob or collection
+///
+/// The `token` is sent with every request, to authorize them with the provider.
 pub async fn get<D: BaoStore, C: CollectionParser>(
     db: &D,
     collection_parser: &C,
     conn: quinn::Connection,
     hash: Hash,
     recursive: bool,
+    token: Option<RequestToken>,
     sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
 ) -> anyhow::Result<Stats> {
     let res = if recursive {
-        get_collection(db, collection_parser, conn, &hash, sender).await
+        get_collection(db, collection_parser, conn, &hash, token, sender).await
     } else {
-        get_blob(db, conn, &hash, sender).await
+        get_blob(db, conn, &hash, token, sender).await
     };
     if let Err(e) = res.as_ref() {
         tracing::error!("get failed: {}", e);
@@ -55,6 +63,7 @@ pub async fn get_blob<D: BaoStore>(
     db: &D,
     conn: quinn::Connection,
     hash: &Hash,
+    token: Option<RequestToken>,
     progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
 ) -> anyhow::Result<Stats> {
     let end = if let Some(entry) = db.get_partial(hash) {
@@ -64,7 +73,8 @@ pub async fn get_blob<D: BaoStore>(
             .await
             .ok()
             .unwrap_or_else(RangeSet2::all);
-        let request = GetRequest::new(*hash, RangeSpecSeq::new([required_ranges]));
+        let request =
+            GetRequest::new(*hash, RangeSpecSeq::new([required_ranges])).with_token(token);
         // full request
         let request = get::fsm::start(conn, iroh_bytes::protocol::Request::Get(request));
         // create a new bidi stream
@@ -82,7 +92,7 @@ pub async fn get_blob<D: BaoStore>(
         // full request
         let request = get::fsm::start(
             conn,
-            iroh_bytes::protocol::Request::Get(GetRequest::single(*hash)),
+            iroh_bytes::protocol::Request::Get(GetRequest::single(*hash).with_token(token)),
         );
         // create a new bidi stream
         let connected = request.next().await?;
@@ -105,6 +115,115 @@ pub async fn get_blob<D: BaoStore>(
     anyhow::Ok(stats)
 }
 
+/// Get some chunk ranges of a blob, and store them in a partial entry.
+///
+/// Data that is already there is kept. The entry is not completed, since the outboard can
+/// not tell which ranges of a sparse entry are present, so it is up to the caller to
+/// complete it once all chunks have been fetched. Returns the size of the blob, as
+/// reported by the provider.
+pub async fn get_blob_ranges<D: BaoStore>(
+    db: &D,
+    conn: quinn::Connection,
+    hash: &Hash,
+    ranges: RangeSet2<ChunkNum>,
+    token: Option<RequestToken>,
+) -> anyhow::Result<u64> {
+    use iroh_io::AsyncSliceWriter;
+
+    let request = GetRequest::new(*hash, RangeSpecSeq::new([ranges])).with_token(token);
+    let request = get::fsm::start(conn, iroh_bytes::protocol::Request::Get(request));
+    // create a new bidi stream
+    let connected = request.next().await?;
+    // next step. we have requested a single hash, so this must be StartRoot
+    let ConnectedNext::StartRoot(start) = connected.next().await? else {
+        anyhow::bail!("expected StartRoot");
+    };
+    let (content, size) = start.next().next().await?;
+    // reuse the existing partial entry, so the data we already have is not lost
+    let entry = match db.get_partial(hash) {
+        Some(entry) => entry,
+        None => db.get_or_create_partial(*hash, size)?,
+    };
+    let mut df = entry.data_writer().await?;
+    let mut of = if needs_outboard(size) {
+        Some(entry.outboard_mut().await?)
+    } else {
+        None
+    };
+    let end = content
+        .write_all_with_outboard(of.as_mut(), &mut df)
+        .await?;
+    df.sync().await?;
+    if let Some(mut of) = of {
+        of.sync().await?;
+    }
+    // we have requested a single hash, so we must be at closing
+    let EndBlobNext::Closing(end) = end.next() else {
+        anyhow::bail!("expected Closing");
+    };
+    end.next().await?;
+    Ok(size)
+}
+
+/// Fetch the chunk ranges of a blob that are not there yet into a partial entry, and
+/// complete the entry once all chunks have been fetched.
+///
+/// `available` keeps track of the chunks fetched for every incomplete blob, since the
+/// outboard of a sparse entry can not tell which ranges are present, see
+/// [`get_blob_ranges`]. `connect` is only awaited if there is anything to fetch. Returns the
+/// size of the blob.
+pub async fn fetch_blob_ranges<D: BaoStore>(
+    db: &D,
+    connect: impl Future<Output = anyhow::Result<quinn::Connection>>,
+    hash: &Hash,
+    ranges: RangeSet2<ChunkNum>,
+    token: Option<RequestToken>,
+    available: &mut HashMap<Hash, RangeSet2<ChunkNum>>,
+) -> anyhow::Result<u64> {
+    let partial = db.get_partial(hash);
+    if partial.is_none() {
+        // the partial entry is gone, e.g. it was deleted
+        available.remove(hash);
+    }
+    let fetched = available.entry(*hash).or_insert_with(RangeSet2::empty);
+    let missing = ranges.difference(fetched);
+    let size = match partial {
+        Some(partial) if missing.is_empty() => partial.size(),
+        _ => {
+            let conn = connect.await?;
+            trace!("fetching {:?} of {}", missing, hash);
+            let size = get_blob_ranges(db, conn, hash, missing.clone(), token).await?;
+            fetched.union_with(&missing);
+            size
+        }
+    };
+    if RangeSet2::from(..ByteNum(size).chunks()).is_subset(fetched) {
+        available.remove(hash);
+        if let Some(entry) = db.get_partial(hash) {
+            db.insert_complete(entry).await?;
+        }
+    }
+    Ok(size)
+}
+
+/// True if the blob is complete in the store.
+///
+/// [`Map::get`] can also return partial entries, so check both.
+pub fn is_complete<D: BaoStore>(db: &D, hash: &Hash) -> bool {
+    db.get_partial(hash).is_none() && Map::get(db, hash).is_some()
+}
+
+/// The chunk groups overlapping the byte `range` of a blob of `size` bytes.
+///
+/// Chunk groups are the granularity at which data is verified, so this is what needs to be
+/// fetched to read the range.
+pub fn chunk_groups(range: Range<u64>, size: u64) -> RangeSet2<ChunkNum> {
+    let group = IROH_BLOCK_SIZE.bytes() as u64;
+    let start = ByteNum(range.start / group * group).full_chunks();
+    let end = ByteNum(((range.end + group - 1) / group * group).min(size)).chunks();
+    RangeSet2::from(start..end)
+}
+
 async fn get_missing_ranges_blob<D: PartialMap>(
     entry: &D::PartialEntry,
 ) -> anyhow::Result<RangeSet2<ChunkNum>> {
@@ -286,6 +405,7 @@ pub async fn get_collection<D: BaoStore, C: CollectionParser>(
     collection_parser: &C,
     conn: quinn::Connection,
     root_hash: &Hash,
+    token: Option<RequestToken>,
     sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
 ) -> anyhow::Result<Stats> {
     use tracing::info as log;
@@ -314,7 +434,8 @@ pub async fn get_collection<D: BaoStore, C: CollectionParser>(
             .chain(missing_info.iter().map(|x| x.missing_chunks()))
             .collect::<Vec<_>>();
         log!("requesting chunks {:?}", missing_iter);
-        let request = GetRequest::new(*root_hash, RangeSpecSeq::new(missing_iter));
+        let request =
+            GetRequest::new(*root_hash, RangeSpecSeq::new(missing_iter)).with_token(token);
         let request = get::fsm::start(conn, request.into());
         // create a new bidi stream
         let connected = request.next().await?;
@@ -357,7 +478,7 @@ pub async fn get_collection<D: BaoStore, C: CollectionParser>(
         // don't have the collection, so probably got nothing
         let request = get::fsm::start(
             conn,
-            iroh_bytes::protocol::Request::Get(GetRequest::all(*root_hash)),
+            iroh_bytes::protocol::Request::Get(GetRequest::all(*root_hash).with_token(token)),
         );
         // create a new bidi stream
         let connected = request.next().await?;
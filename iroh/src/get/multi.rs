@@ -0,0 +1,545 @@
+//! Get a blob or collection from multiple providers in parallel
+//!
+//! The download is split into parts, where each part is a set of chunk ranges of a
+//! single blob, identified by its offset in the [RangeSpecSeq] of the request. The
+//! parts are kept in a queue that is shared between one worker per provider. Each
+//! worker takes a batch of parts for distinct blobs from the queue and requests
+//! them from its provider in a single request, using the [fsm] state machine.
+//!
+//! If a request fails, the parts that were not yet completed are put back into the
+//! queue, the provider is not used anymore, and the remaining providers will pick
+//! up the parts.
+//!
+//! For blobs where we don't know the size yet, we first request just the first
+//! part. As soon as we know the size, the remaining parts are added to the queue,
+//! so the data of a large blob can be downloaded from several providers at once.
+//! All parts of a blob are written into the same partial entry, which is completed
+//! once all parts are done.
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::Instant,
+};
+
+use anyhow::Context;
+use bao_tree::io::fsm::OutboardMut;
+use bao_tree::{ByteNum, ChunkNum};
+use iroh_bytes::baomap::range_collections::{range_set::RangeSetRange, RangeSet2};
+use iroh_bytes::{
+    baomap::{MapEntry, PartialMapEntry, Store as BaoStore},
+    collection::CollectionParser,
+    get::{
+        fsm::{self, AtBlobHeader, AtEndBlob, ConnectedNext, EndBlobNext},
+        Stats,
+    },
+    protocol::{GetRequest, RangeSpecSeq},
+    provider::ShareProgress,
+    util::{
+        progress::{IdGenerator, ProgressSender},
+        Hash, RpcError,
+    },
+};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use super::{get_missing_ranges_collection, needs_outboard, BlobInfo};
+use crate::util::progress::ProgressSliceWriter2;
+
+/// Maximum size of a part in chunks, 4 MiB.
+const PART_CHUNKS: u64 = 4096;
+
+/// Maximum number of parts to request from a provider in a single request.
+const MAX_PARTS_PER_REQUEST: usize = 16;
+
+/// Get a blob or collection from multiple providers
+///
+/// All providers are assumed to have the complete data. If a provider fails, the
+/// data is requested from the remaining providers. The download only fails if all
+/// providers fail.
+pub async fn get<D: BaoStore, C: CollectionParser>(
+    db: &D,
+    collection_parser: &C,
+    conns: Vec<quinn::Connection>,
+    hash: Hash,
+    recursive: bool,
+    sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
+) -> anyhow::Result<Stats> {
+    anyhow::ensure!(!conns.is_empty(), "no providers");
+    let downloader = Downloader::new(db, hash, conns, sender.clone());
+    // first get the root, which is either the blob itself or the collection. `get` also
+    // returns partial entries, so look for a partial entry first
+    let info = if let Some(entry) = db.get_partial(&hash) {
+        let missing_chunks = super::get_missing_ranges_blob::<D>(&entry)
+            .await
+            .ok()
+            .unwrap_or_else(RangeSet2::all);
+        Some(BlobInfo::Partial {
+            entry,
+            missing_chunks,
+        })
+    } else if db.get(&hash).is_some() {
+        None
+    } else {
+        Some(BlobInfo::Missing)
+    };
+    if let Some(info) = info {
+        downloader.add_blob(0, hash, info);
+        downloader.run().await?;
+    }
+    if recursive {
+        anyhow::ensure!(
+            db.get_partial(&hash).is_none(),
+            "collection incomplete after download"
+        );
+        let entry = db
+            .get(&hash)
+            .context("collection not found after download")?;
+        let reader = entry.data_reader().await?;
+        let (mut collection, stats) = collection_parser.parse(0, reader).await?;
+        sender
+            .send(ShareProgress::FoundCollection {
+                hash,
+                num_blobs: stats.num_blobs,
+                total_blobs_size: stats.total_blob_size,
+            })
+            .await?;
+        let mut children = vec![];
+        while let Some(hash) = collection.next().await? {
+            children.push(hash);
+        }
+        let missing_info = get_missing_ranges_collection(db, &children).await?;
+        for (child_offset, (hash, info)) in children.into_iter().zip(missing_info).enumerate() {
+            downloader.add_blob(child_offset as u64 + 1, hash, info);
+        }
+        downloader.run().await?;
+    }
+    let stats = downloader.state.into_inner().stats;
+    anyhow::Ok(stats)
+}
+
+/// A part of a download
+#[derive(Debug)]
+struct Part {
+    /// The offset of the blob in the request, 0 is the root, children start at 1
+    offset: u64,
+    /// The chunk ranges to request
+    ranges: RangeSet2<ChunkNum>,
+}
+
+struct BlobState<D: BaoStore> {
+    hash: Hash,
+    /// The partial entry, as soon as we know the size
+    entry: Option<D::PartialEntry>,
+    /// The number of parts of this blob that are either queued or in flight
+    remaining: usize,
+}
+
+struct State<D: BaoStore> {
+    /// Parts that still need to be requested
+    queue: VecDeque<Part>,
+    /// Number of requests that are currently in flight
+    in_flight: usize,
+    /// Blobs that are being downloaded, by offset
+    blobs: BTreeMap<u64, BlobState<D>>,
+    /// Providers that have failed and will not be used anymore
+    failed: BTreeSet<usize>,
+    /// The error of the last provider that failed
+    last_error: Option<anyhow::Error>,
+    /// Accumulated stats of all requests
+    stats: Stats,
+}
+
+impl<D: BaoStore> State<D> {
+    /// Take parts for distinct blobs from the front of the queue, sorted by offset
+    fn take_batch(&mut self) -> Vec<Part> {
+        let mut batch: Vec<Part> = Vec::new();
+        while batch.len() < MAX_PARTS_PER_REQUEST {
+            match self.queue.front() {
+                Some(part) if !batch.iter().any(|x| x.offset == part.offset) => {
+                    batch.extend(self.queue.pop_front());
+                }
+                _ => break,
+            }
+        }
+        batch.sort_by_key(|part| part.offset);
+        batch
+    }
+
+    /// Queue the given ranges of a blob, split into parts
+    fn queue_ranges(&mut self, offset: u64, ranges: &RangeSet2<ChunkNum>, size: u64) {
+        let parts = split_ranges(ranges, size)
+            .map(|ranges| Part { offset, ranges })
+            .collect::<Vec<_>>();
+        if let Some(blob) = self.blobs.get_mut(&offset) {
+            blob.remaining += parts.len();
+        }
+        self.queue.extend(parts);
+    }
+}
+
+/// Downloads parts from multiple providers into the same database
+///
+/// Everything runs on a single task, so the state is in a [RefCell]. Borrows of the
+/// state must never be held across an await point.
+struct Downloader<'a, D: BaoStore, P> {
+    db: &'a D,
+    root: Hash,
+    providers: Vec<quinn::Connection>,
+    state: RefCell<State<D>>,
+    /// Notified whenever parts are added to the queue or a request is done
+    notify: Notify,
+    sender: P,
+}
+
+impl<'a, D, P> Downloader<'a, D, P>
+where
+    D: BaoStore,
+    P: ProgressSender<Msg = ShareProgress> + IdGenerator,
+{
+    fn new(db: &'a D, root: Hash, providers: Vec<quinn::Connection>, sender: P) -> Self {
+        let state = State {
+            queue: VecDeque::new(),
+            in_flight: 0,
+            blobs: BTreeMap::new(),
+            failed: BTreeSet::new(),
+            last_error: None,
+            stats: Stats::default(),
+        };
+        Self {
+            db,
+            root,
+            providers,
+            state: RefCell::new(state),
+            notify: Notify::new(),
+            sender,
+        }
+    }
+
+    /// Add a blob to be downloaded, with the given offset in the request
+    fn add_blob(&self, offset: u64, hash: Hash, info: BlobInfo<D>) {
+        let mut state = self.state.borrow_mut();
+        let (entry, ranges, size) = match info {
+            BlobInfo::Complete => return,
+            // we don't know the size, so just request the first part for now
+            BlobInfo::Missing => (None, RangeSet2::from(..ChunkNum(PART_CHUNKS)), None),
+            BlobInfo::Partial {
+                entry,
+                missing_chunks,
+            } => {
+                let size = entry.size();
+                (Some(entry), missing_chunks, Some(size))
+            }
+        };
+        state.blobs.insert(
+            offset,
+            BlobState {
+                hash,
+                entry,
+                remaining: 0,
+            },
+        );
+        match size {
+            Some(size) => state.queue_ranges(offset, &ranges, size),
+            None => {
+                state.blobs.get_mut(&offset).unwrap().remaining = 1;
+                state.queue.push_back(Part { offset, ranges });
+            }
+        }
+    }
+
+    /// Download all queued parts, and complete the entries
+    async fn run(&self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let workers = (0..self.providers.len())
+            .filter(|i| !self.state.borrow().failed.contains(i))
+            .map(|i| self.worker(i));
+        futures::future::join_all(workers).await;
+        let blobs = {
+            let mut state = self.state.borrow_mut();
+            state.stats.elapsed += start.elapsed();
+            if !state.queue.is_empty() {
+                let error = state
+                    .last_error
+                    .take()
+                    .unwrap_or_else(|| anyhow::anyhow!("no providers"));
+                return Err(error.context("all providers failed"));
+            }
+            std::mem::take(&mut state.blobs)
+        };
+        // all parts are done, so all entries are complete
+        for (_, blob) in blobs {
+            debug_assert_eq!(blob.remaining, 0);
+            if let Some(entry) = blob.entry {
+                self.db.insert_complete(entry).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Request batches of parts from a single provider until there is nothing left to do
+    async fn worker(&self, provider: usize) {
+        loop {
+            let mut batch = loop {
+                {
+                    let mut state = self.state.borrow_mut();
+                    let batch = state.take_batch();
+                    if !batch.is_empty() {
+                        state.in_flight += 1;
+                        break batch;
+                    }
+                    if state.in_flight == 0 {
+                        // nothing queued and nothing in flight, so we are done
+                        return;
+                    }
+                }
+                // wait for other requests to either queue more parts or finish
+                self.notify.notified().await;
+            };
+            let res = self.request(provider, &mut batch).await;
+            let failed = {
+                let mut state = self.state.borrow_mut();
+                state.in_flight -= 1;
+                match res {
+                    Ok(stats) => {
+                        state.stats.bytes_read += stats.bytes_read;
+                        state.stats.bytes_written += stats.bytes_written;
+                        None
+                    }
+                    Err(cause) => {
+                        warn!("provider {} failed: {:?}", provider, cause);
+                        // put back the parts that were not completed, so other
+                        // providers can pick them up
+                        state.queue.extend(batch);
+                        state.failed.insert(provider);
+                        let error = RpcError::from(anyhow::anyhow!("{:#}", cause));
+                        state.last_error = Some(cause);
+                        Some(error)
+                    }
+                }
+            };
+            self.notify.notify_waiters();
+            if let Some(error) = failed {
+                self.sender
+                    .send(ShareProgress::ProviderFailed {
+                        provider: provider as u64,
+                        error,
+                    })
+                    .await
+                    .ok();
+                return;
+            }
+        }
+    }
+
+    /// Request a batch of parts from a provider
+    ///
+    /// Parts are removed from the batch as soon as they are completed, so on error
+    /// the batch contains the parts that still need to be requested.
+    async fn request(&self, provider: usize, batch: &mut Vec<Part>) -> anyhow::Result<Stats> {
+        let ranges =
+            RangeSpecSeq::new_sparse(batch.iter().map(|part| (part.offset, part.ranges.clone())));
+        debug!("requesting {:?} from provider {}", ranges, provider);
+        let request = GetRequest::new(self.root, ranges);
+        let conn = self.providers[provider].clone();
+        let connected = fsm::start(conn, request.into()).next().await?;
+        let mut next = match connected.next().await? {
+            ConnectedNext::StartRoot(start) => {
+                anyhow::ensure!(batch[0].offset == 0, "unexpected root");
+                let header = start.next();
+                let end = self.download_part(provider, header, 0).await?;
+                self.part_done(batch.remove(0));
+                end.next()
+            }
+            ConnectedNext::StartChild(start) => EndBlobNext::MoreChildren(start),
+            ConnectedNext::Closing(_) => anyhow::bail!("provider sent no data"),
+        };
+        let end = loop {
+            let start = match next {
+                EndBlobNext::MoreChildren(start) => start,
+                EndBlobNext::Closing(end) => break end,
+            };
+            let offset = start.child_offset() + 1;
+            anyhow::ensure!(
+                batch.first().map(|part| part.offset) == Some(offset),
+                "unexpected child {}",
+                offset - 1
+            );
+            let hash = self.state.borrow().blobs[&offset].hash;
+            let header = start.next(hash);
+            let end = self.download_part(provider, header, offset).await?;
+            self.part_done(batch.remove(0));
+            next = end.next();
+        };
+        anyhow::ensure!(batch.is_empty(), "provider did not send all parts");
+        let stats = end.next().await?;
+        Ok(stats)
+    }
+
+    /// Download a single part into the partial entry of its blob
+    async fn download_part(
+        &self,
+        provider: usize,
+        header: AtBlobHeader,
+        offset: u64,
+    ) -> anyhow::Result<AtEndBlob> {
+        use iroh_io::AsyncSliceWriter;
+
+        let hash = header.hash();
+        let ranges = header.ranges().clone();
+        let (content, size) = header.next().await?;
+        let (entry, queued) = {
+            let mut state = self.state.borrow_mut();
+            let blob = state.blobs.get(&offset).context("unknown blob")?;
+            match &blob.entry {
+                Some(entry) => (entry.clone(), false),
+                None => {
+                    // first part of a blob we did not know the size of
+                    let entry = self.db.get_or_create_partial(hash, size)?;
+                    state.blobs.get_mut(&offset).unwrap().entry = Some(entry.clone());
+                    let rest = RangeSet2::from(ChunkNum(PART_CHUNKS)..);
+                    state.queue_ranges(offset, &rest, size);
+                    (entry, true)
+                }
+            }
+        };
+        if queued {
+            self.notify.notify_waiters();
+        }
+        let df = entry.data_writer().await?;
+        let mut of = if needs_outboard(size) {
+            Some(entry.outboard_mut().await?)
+        } else {
+            None
+        };
+        // report the part as an item of its own, so progress adds up
+        let (start, part_size) = byte_range(&ranges, size);
+        let id = self.sender.new_id();
+        self.sender
+            .send(ShareProgress::Found {
+                id,
+                hash,
+                size: part_size,
+            })
+            .await?;
+        self.sender
+            .send(ShareProgress::FromProvider {
+                id,
+                provider: provider as u64,
+            })
+            .await?;
+        let sender2 = self.sender.clone();
+        let on_write = move |offset: u64, _length: usize| {
+            // if try send fails it means that the receiver has been dropped.
+            // in that case we want to abort the write_all_with_outboard.
+            sender2
+                .try_send(ShareProgress::Progress {
+                    id,
+                    offset: offset.saturating_sub(start),
+                })
+                .map_err(|e| {
+                    tracing::info!("aborting download of {}", hash);
+                    e
+                })?;
+            Ok(())
+        };
+        let mut pw = ProgressSliceWriter2::new(df, on_write);
+        let end = content
+            .write_all_with_outboard(of.as_mut(), &mut pw)
+            .await?;
+        pw.sync().await?;
+        if let Some(mut of) = of {
+            of.sync().await?;
+        }
+        self.sender.send(ShareProgress::Done { id }).await?;
+        Ok(end)
+    }
+
+    fn part_done(&self, part: Part) {
+        let mut state = self.state.borrow_mut();
+        if let Some(blob) = state.blobs.get_mut(&part.offset) {
+            blob.remaining -= 1;
+        }
+    }
+}
+
+/// Split the given ranges of a blob of size `size` into parts of at most
+/// [PART_CHUNKS] chunks, skipping empty parts.
+fn split_ranges(
+    ranges: &RangeSet2<ChunkNum>,
+    size: u64,
+) -> impl Iterator<Item = RangeSet2<ChunkNum>> + '_ {
+    let chunks = ByteNum(size).chunks().0;
+    (0..chunks)
+        .step_by(PART_CHUNKS as usize)
+        .map(move |start| {
+            let end = (start + PART_CHUNKS).min(chunks);
+            ranges.intersection(&RangeSet2::from(ChunkNum(start)..ChunkNum(end)))
+        })
+        .filter(|part| !part.is_empty())
+}
+
+/// The start offset and the number of bytes of the given ranges of a blob
+fn byte_range(ranges: &RangeSet2<ChunkNum>, size: u64) -> (u64, u64) {
+    let mut start = None;
+    let mut total = 0;
+    for range in ranges.iter() {
+        let (from, to) = match range {
+            RangeSetRange::Range(range) => (range.start.to_bytes().0, range.end.to_bytes().0),
+            RangeSetRange::RangeFrom(range) => (range.start.to_bytes().0, size),
+        };
+        let (from, to) = (from.min(size), to.min(size));
+        start.get_or_insert(from);
+        total += to - from;
+    }
+    (start.unwrap_or_default(), total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_ranges() {
+        let chunk = 1024;
+        // small blob, single part
+        let parts = split_ranges(&RangeSet2::all(), 10).collect::<Vec<_>>();
+        assert_eq!(parts, vec![RangeSet2::from(ChunkNum(0)..ChunkNum(1))]);
+        // empty blob, no parts
+        assert_eq!(split_ranges(&RangeSet2::all(), 0).count(), 0);
+        // large blob, multiple parts, the last one is shorter
+        let size = (PART_CHUNKS * 2 + 10) * chunk;
+        let parts = split_ranges(&RangeSet2::all(), size).collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            vec![
+                RangeSet2::from(ChunkNum(0)..ChunkNum(PART_CHUNKS)),
+                RangeSet2::from(ChunkNum(PART_CHUNKS)..ChunkNum(PART_CHUNKS * 2)),
+                RangeSet2::from(ChunkNum(PART_CHUNKS * 2)..ChunkNum(PART_CHUNKS * 2 + 10)),
+            ]
+        );
+        // only missing ranges are requested
+        let missing = RangeSet2::from(ChunkNum(PART_CHUNKS + 1)..);
+        let parts = split_ranges(&missing, size).collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            vec![
+                RangeSet2::from(ChunkNum(PART_CHUNKS + 1)..ChunkNum(PART_CHUNKS * 2)),
+                RangeSet2::from(ChunkNum(PART_CHUNKS * 2)..ChunkNum(PART_CHUNKS * 2 + 10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let size = 10_000;
+        assert_eq!(byte_range(&RangeSet2::all(), size), (0, size));
+        assert_eq!(
+            byte_range(&RangeSet2::from(ChunkNum(1)..ChunkNum(2)), size),
+            (1024, 1024)
+        );
+        assert_eq!(
+            byte_range(&RangeSet2::from(ChunkNum(9)..), size),
+            (9 * 1024, size - 9 * 1024)
+        );
+    }
+}
@@ -4,13 +4,21 @@
 pub use iroh_bytes as bytes;
 pub use iroh_net as net;
 
+#[cfg(feature = "gossip")]
+pub mod announce;
 pub mod baomap;
 #[cfg(feature = "iroh-collection")]
 pub mod collection;
 pub mod dial;
+pub mod downloads;
+#[cfg(feature = "gateway")]
+pub mod gateway;
 pub mod get;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub mod mount;
 pub mod node;
 pub mod rpc_protocol;
+pub mod token;
 pub mod util;
 
 /// Expose metrics module
@@ -10,6 +10,8 @@ pub struct Metrics {
     pub requests_total: Counter,
     pub bytes_sent: Counter,
     pub bytes_received: Counter,
+    pub connections_rejected: Counter,
+    pub requests_rejected: Counter,
 }
 
 impl Default for Metrics {
@@ -18,6 +20,12 @@ impl Default for Metrics {
             requests_total: Counter::new("Total number of requests received"),
             bytes_sent: Counter::new("Number of bytes streamed"),
             bytes_received: Counter::new("Number of bytes received"),
+            connections_rejected: Counter::new(
+                "Number of connections rejected because a peer had too many connections",
+            ),
+            requests_rejected: Counter::new(
+                "Number of requests rejected because a connection had too many requests",
+            ),
         }
     }
 }
@@ -0,0 +1,640 @@
+//! Mount a collection as a read-only FUSE filesystem.
+//!
+//! Both flat [`Collection`]s and trees of [`Directory`] collections can be mounted. Chunked
+//! files in a tree are exposed as a single file.
+//!
+//! File data is served from the local store. Chunk ranges that are not available locally
+//! are fetched from a provider when they are read, and stored in a partial entry, so every
+//! range is only fetched once. A blob is completed once all of its chunks have been read.
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use bao_tree::ChunkNum;
+use bytes::Bytes;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use iroh_bytes::{
+    baomap::{range_collections::RangeSet2, MapEntry, Store},
+    chunked::{Chunk, Manifest},
+    protocol::RequestToken,
+    Hash, IROH_BLOCK_SIZE,
+};
+use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
+use tracing::warn;
+
+use crate::collection::{
+    tree::{Directory, EntryKind},
+    Collection,
+};
+
+/// How long the kernel may cache attributes and lookups. A mounted collection never changes.
+const TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The inode of the root directory.
+const ROOT_INO: u64 = 1;
+
+/// A provider to fetch data from that is not in the local store.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    /// Connection to the provider
+    pub conn: quinn::Connection,
+    /// Request token to send with every request, if any
+    pub token: Option<RequestToken>,
+}
+
+#[derive(Debug)]
+struct Inode {
+    parent: u64,
+    /// The permission bits
+    perm: u16,
+    mtime: SystemTime,
+    kind: InodeKind,
+}
+
+#[derive(Debug)]
+enum InodeKind {
+    Directory { children: BTreeMap<String, u64> },
+    File { hash: Hash, size: u64 },
+    Chunked { chunks: Vec<(u64, Chunk)> },
+    Symlink { target: String },
+}
+
+impl InodeKind {
+    fn file_type(&self) -> FileType {
+        match self {
+            InodeKind::Directory { .. } => FileType::Directory,
+            InodeKind::File { .. } | InodeKind::Chunked { .. } => FileType::RegularFile,
+            InodeKind::Symlink { .. } => FileType::Symlink,
+        }
+    }
+}
+
+/// The ranges of the chunk blobs to read for `len` bytes at `offset` of a chunked file.
+///
+/// `chunks` are the chunks of the file, with their offset in the file. Returns the hash and
+/// size of every blob, and the offset and length to read from it.
+fn chunk_ranges(chunks: &[(u64, Chunk)], offset: u64, len: u64) -> Vec<(Hash, u64, u64, u64)> {
+    let end = offset.saturating_add(len);
+    // the first chunk that ends after offset
+    let first = chunks.partition_point(|(start, chunk)| start + chunk.size <= offset);
+    chunks[first..]
+        .iter()
+        .take_while(|(start, _)| *start < end)
+        .map(|(start, chunk)| {
+            let from = offset.saturating_sub(*start);
+            let to = (end - start).min(chunk.size);
+            (chunk.hash, chunk.size, from, to - from)
+        })
+        .collect()
+}
+
+/// A collection, exposed as a read-only FUSE filesystem.
+///
+/// Use [`mount`] to mount it.
+#[derive(Debug)]
+pub struct CollectionFs<D> {
+    db: D,
+    remote: Option<Remote>,
+    rt: tokio::runtime::Handle,
+    /// Inode `n` is at index `n - 1`.
+    inodes: Vec<Inode>,
+    /// The chunks of incomplete blobs that have been fetched.
+    ///
+    /// Data in partial entries from before the filesystem was created is not used, since
+    /// the outboard can not tell which ranges of a sparse entry are present.
+    available: HashMap<Hash, RangeSet2<ChunkNum>>,
+    uid: u32,
+    gid: u32,
+}
+
+impl<D: Store> CollectionFs<D> {
+    /// Load the collection `root` and all of its directories.
+    ///
+    /// Collection blobs that are missing locally are fetched completely from `remote`. So is
+    /// the first chunk group of files whose size is not known yet. All other data is only
+    /// fetched when it is read.
+    ///
+    /// This must be called from within a tokio runtime, which is later used to serve reads.
+    pub async fn new(db: D, root: Hash, remote: Option<Remote>) -> Result<Self> {
+        let mtime = SystemTime::now();
+        let mut this = Self {
+            db,
+            remote,
+            rt: tokio::runtime::Handle::current(),
+            inodes: vec![Inode {
+                parent: ROOT_INO,
+                perm: 0o555,
+                mtime,
+                kind: InodeKind::Directory {
+                    children: BTreeMap::new(),
+                },
+            }],
+            available: HashMap::new(),
+            // SAFETY: these calls have no preconditions and always succeed
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        let data = this.load_blob(&root).await?;
+        if Directory::is_directory(&data) {
+            let dir = Directory::from_bytes(&data)?;
+            this.add_directory(ROOT_INO, dir).await?;
+        } else {
+            let collection = Collection::from_bytes(&data).context("invalid collection")?;
+            for blob in collection.blobs() {
+                let size = this.blob_size(&blob.hash).await?;
+                let (dirs, name) = match blob.name.rsplit_once('/') {
+                    Some((dirs, name)) => (Some(dirs), name),
+                    None => (None, blob.name.as_str()),
+                };
+                let mut parent = ROOT_INO;
+                for dir in dirs.into_iter().flat_map(|dirs| dirs.split('/')) {
+                    parent = this.get_or_add_directory(parent, dir, mtime)?;
+                }
+                let file = Inode {
+                    parent,
+                    perm: 0o444,
+                    mtime,
+                    kind: InodeKind::File {
+                        hash: blob.hash,
+                        size,
+                    },
+                };
+                this.add_inode(name, file)?;
+            }
+        }
+        Ok(this)
+    }
+
+    /// Add the entries of `dir` to the directory `parent`, recursively.
+    fn add_directory(&mut self, parent: u64, dir: Directory) -> LocalBoxFuture<'_, Result<()>> {
+        async move {
+            for entry in dir.entries() {
+                let inode = |kind| Inode {
+                    parent,
+                    // this is a read-only filesystem
+                    perm: (entry.mode & 0o555) as u16,
+                    mtime: entry.modified(),
+                    kind,
+                };
+                match &entry.kind {
+                    EntryKind::File { hash, size } => {
+                        let kind = InodeKind::File {
+                            hash: *hash,
+                            size: *size,
+                        };
+                        self.add_inode(&entry.name, inode(kind))?;
+                    }
+                    EntryKind::Directory { hash, .. } => {
+                        let data = self.load_blob(hash).await?;
+                        let sub = Directory::from_bytes(&data)?;
+                        let kind = InodeKind::Directory {
+                            children: BTreeMap::new(),
+                        };
+                        let ino = self.add_inode(&entry.name, inode(kind))?;
+                        self.add_directory(ino, sub).await?;
+                    }
+                    EntryKind::Chunked { hash, size } => {
+                        let data = self.load_blob(hash).await?;
+                        let manifest = Manifest::from_bytes(&data)?;
+                        anyhow::ensure!(manifest.size() == *size, "{} has the wrong size", hash);
+                        let mut offset = 0;
+                        let chunks = manifest
+                            .chunks()
+                            .iter()
+                            .map(|chunk| {
+                                let start = offset;
+                                offset += chunk.size;
+                                (start, *chunk)
+                            })
+                            .collect();
+                        let kind = InodeKind::Chunked { chunks };
+                        self.add_inode(&entry.name, inode(kind))?;
+                    }
+                    EntryKind::Symlink { target } => {
+                        let kind = InodeKind::Symlink {
+                            target: target.clone(),
+                        };
+                        self.add_inode(&entry.name, inode(kind))?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    /// Add an inode to its parent directory, and return its number.
+    fn add_inode(&mut self, name: &str, inode: Inode) -> Result<u64> {
+        let ino = self.inodes.len() as u64 + 1;
+        let parent = inode.parent;
+        let InodeKind::Directory { children } = &mut self.inode_mut(parent)?.kind else {
+            anyhow::bail!("parent of {name} is not a directory");
+        };
+        anyhow::ensure!(
+            children.insert(name.to_string(), ino).is_none(),
+            "duplicate name {name}"
+        );
+        self.inodes.push(inode);
+        Ok(ino)
+    }
+
+    fn get_or_add_directory(&mut self, parent: u64, name: &str, mtime: SystemTime) -> Result<u64> {
+        if let Some(ino) = self.lookup_child(parent, name) {
+            return Ok(ino);
+        }
+        let dir = Inode {
+            parent,
+            perm: 0o555,
+            mtime,
+            kind: InodeKind::Directory {
+                children: BTreeMap::new(),
+            },
+        };
+        self.add_inode(name, dir)
+    }
+
+    fn inode(&self, ino: u64) -> Option<&Inode> {
+        let index = usize::try_from(ino.checked_sub(1)?).ok()?;
+        self.inodes.get(index)
+    }
+
+    fn inode_mut(&mut self, ino: u64) -> Result<&mut Inode> {
+        let index = usize::try_from(ino - 1)?;
+        self.inodes.get_mut(index).context("no such inode")
+    }
+
+    fn lookup_child(&self, parent: u64, name: &str) -> Option<u64> {
+        match &self.inode(parent)?.kind {
+            InodeKind::Directory { children } => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    fn attr(&self, ino: u64, inode: &Inode) -> FileAttr {
+        let (size, nlink) = match &inode.kind {
+            InodeKind::Directory { .. } => (0, 2),
+            InodeKind::File { size, .. } => (*size, 1),
+            InodeKind::Chunked { chunks } => {
+                let size = chunks.last().map(|(start, chunk)| start + chunk.size);
+                (size.unwrap_or_default(), 1)
+            }
+            InodeKind::Symlink { target } => (target.len() as u64, 1),
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: inode.mtime,
+            mtime: inode.mtime,
+            ctime: inode.mtime,
+            crtime: inode.mtime,
+            kind: inode.kind.file_type(),
+            perm: inode.perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: IROH_BLOCK_SIZE.bytes() as u32,
+            flags: 0,
+        }
+    }
+
+    /// Read an entire blob, fetching it from the remote if it is not complete locally.
+    async fn load_blob(&mut self, hash: &Hash) -> Result<Bytes> {
+        if !self.is_complete(hash) {
+            self.fetch(hash, RangeSet2::all()).await?;
+        }
+        anyhow::ensure!(self.is_complete(hash), "{hash} is incomplete");
+        let entry = self.db.get(hash).context("blob is gone")?;
+        let mut reader = entry.data_reader().await?;
+        Ok(reader.read_to_end().await?)
+    }
+
+    /// The size of a blob, fetching its first chunk group if it is not known locally.
+    async fn blob_size(&mut self, hash: &Hash) -> Result<u64> {
+        if let Some(entry) = self.db.get(hash) {
+            return Ok(entry.size());
+        }
+        self.fetch(hash, RangeSet2::from(..ChunkNum(1 << IROH_BLOCK_SIZE.0)))
+            .await
+    }
+
+    /// Read `len` bytes at `offset` of a blob of the given size.
+    ///
+    /// Missing chunk groups in the requested range are fetched from the remote first.
+    async fn read_range(&mut self, hash: Hash, size: u64, offset: u64, len: u64) -> Result<Bytes> {
+        let end = offset.saturating_add(len).min(size);
+        if offset >= end {
+            return Ok(Bytes::new());
+        }
+        if !self.is_complete(&hash) {
+            let ranges = crate::get::chunk_groups(offset..end, size);
+            self.fetch(&hash, ranges).await?;
+        }
+        let len = usize::try_from(end - offset)?;
+        let data = if let Some(entry) = self.db.get_partial(&hash) {
+            entry.data_reader().await?.read_at(offset, len).await?
+        } else {
+            let entry = self.db.get(&hash).context("entry is gone")?;
+            entry.data_reader().await?.read_at(offset, len).await?
+        };
+        Ok(data)
+    }
+
+    /// Fetch the chunk ranges of a blob that are not there yet from the remote, see
+    /// [`crate::get::fetch_blob_ranges`].
+    ///
+    /// Returns the size of the blob.
+    async fn fetch(&mut self, hash: &Hash, ranges: RangeSet2<ChunkNum>) -> Result<u64> {
+        let remote = self
+            .remote
+            .as_ref()
+            .with_context(|| format!("{hash} is not available locally"))?;
+        crate::get::fetch_blob_ranges(
+            &self.db,
+            async { Ok(remote.conn.clone()) },
+            hash,
+            ranges,
+            remote.token.clone(),
+            &mut self.available,
+        )
+        .await
+    }
+
+    /// True if the blob is complete in the local store.
+    fn is_complete(&self, hash: &Hash) -> bool {
+        crate::get::is_complete(&self.db, hash)
+    }
+}
+
+impl<D: Store> Filesystem for CollectionFs<D> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(ino) = name
+            .to_str()
+            .and_then(|name| self.lookup_child(parent, name))
+        else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let inode = self.inode(ino).expect("children are valid inodes");
+        reply.entry(&TTL, &self.attr(ino, inode), 0);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.inode(ino) {
+            Some(inode) => reply.attr(&TTL, &self.attr(ino, inode)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.inode(ino).map(|inode| &inode.kind) {
+            Some(InodeKind::Symlink { target }) => reply.data(target.as_bytes()),
+            Some(_) => reply.error(libc::EINVAL),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Ok(offset) = u64::try_from(offset) else {
+            return reply.error(libc::EINVAL);
+        };
+        let len = size as u64;
+        let ranges = match self.inode(ino).map(|inode| &inode.kind) {
+            Some(InodeKind::File { hash, size }) => vec![(*hash, *size, offset, len)],
+            Some(InodeKind::Chunked { chunks }) => chunk_ranges(chunks, offset, len),
+            Some(InodeKind::Directory { .. }) => return reply.error(libc::EISDIR),
+            Some(InodeKind::Symlink { .. }) => return reply.error(libc::EINVAL),
+            None => return reply.error(libc::ENOENT),
+        };
+        let rt = self.rt.clone();
+        let mut data = Vec::new();
+        for (hash, blob_size, offset, len) in ranges {
+            match rt.block_on(self.read_range(hash, blob_size, offset, len)) {
+                Ok(bytes) => data.extend_from_slice(&bytes),
+                Err(cause) => {
+                    warn!("failed to read {} at {}: {:#}", hash, offset, cause);
+                    return reply.error(libc::EIO);
+                }
+            }
+        }
+        reply.data(&data)
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(inode) = self.inode(ino) else {
+            return reply.error(libc::ENOENT);
+        };
+        let InodeKind::Directory { children } = &inode.kind else {
+            return reply.error(libc::ENOTDIR);
+        };
+        let entries = [(ino, "."), (inode.parent, "..")]
+            .into_iter()
+            .chain(children.iter().map(|(name, ino)| (*ino, name.as_str())));
+        let skip = usize::try_from(offset).unwrap_or_default();
+        for (i, (ino, name)) in entries.enumerate().skip(skip) {
+            let kind = match self.inode(ino) {
+                Some(inode) => inode.kind.file_type(),
+                None => continue,
+            };
+            // the offset of an entry is the offset of the next one
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mount a collection at `mountpoint`.
+///
+/// The filesystem is served on a background thread, and unmounted when the returned
+/// session is dropped.
+pub fn mount<D: Store>(fs: CollectionFs<D>, mountpoint: &Path) -> Result<fuser::BackgroundSession> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("iroh".to_string()),
+        MountOption::Subtype("iroh".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    let session = fuser::spawn_mount2(fs, mountpoint, &options)
+        .with_context(|| format!("failed to mount at {}", mountpoint.display()))?;
+    Ok(session)
+}
+
+#[cfg(all(test, feature = "mem-db"))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use iroh_bytes::{
+        baomap::{Map, PartialMap},
+        util::runtime,
+    };
+    use iroh_net::tls::Keypair;
+
+    use super::*;
+    use crate::{
+        baomap::mem,
+        collection::{tree::Entry, Blob},
+        node::Node,
+    };
+
+    fn entry(name: &str, kind: EntryKind) -> Entry {
+        Entry {
+            name: name.to_string(),
+            mode: 0o100644,
+            mtime: 0,
+            kind,
+        }
+    }
+
+    #[tokio::test]
+    async fn mount_flat_collection() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let db = mem::Store::new(rt);
+        let a = db.import_bytes(Bytes::from_static(b"a")).await?;
+        let b = db.import_bytes(Bytes::from_static(b"bb")).await?;
+        let blobs = vec![
+            Blob {
+                name: "a".to_string(),
+                hash: a,
+            },
+            Blob {
+                name: "dir/sub/b".to_string(),
+                hash: b,
+            },
+        ];
+        let collection = Collection::new(blobs, 3)?.to_bytes()?;
+        let root = db.import_bytes(collection.into()).await?;
+
+        let mut fs = CollectionFs::new(db, root, None).await?;
+        let dir = fs.lookup_child(ROOT_INO, "dir").context("missing dir")?;
+        let sub = fs.lookup_child(dir, "sub").context("missing sub")?;
+        let ino = fs.lookup_child(sub, "b").context("missing file")?;
+        let inode = fs.inode(ino).unwrap();
+        assert_eq!(inode.parent, sub);
+        assert_eq!(fs.attr(ino, inode).size, 2);
+        assert!(fs.lookup_child(ROOT_INO, "b").is_none());
+        assert_eq!(&fs.read_range(b, 2, 1, 10).await?[..], b"b");
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_ranges_span_chunks() {
+        let hash = |data: &[u8]| Hash::from(bao_tree::blake3::hash(data));
+        let (a, b, c) = (hash(b"a"), hash(b"b"), hash(b"c"));
+        let chunks = vec![
+            (0, Chunk { hash: a, size: 10 }),
+            (10, Chunk { hash: b, size: 20 }),
+            (30, Chunk { hash: c, size: 5 }),
+        ];
+        assert_eq!(chunk_ranges(&chunks, 0, 5), vec![(a, 10, 0, 5)]);
+        assert_eq!(
+            chunk_ranges(&chunks, 5, 30),
+            vec![(a, 10, 5, 5), (b, 20, 0, 20), (c, 5, 0, 5)]
+        );
+        assert_eq!(chunk_ranges(&chunks, 10, 20), vec![(b, 20, 0, 20)]);
+        assert_eq!(chunk_ranges(&chunks, 32, 100), vec![(c, 5, 2, 3)]);
+        assert!(chunk_ranges(&chunks, 35, 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn mount_fetches_ranges() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let db = mem::Store::new(rt.clone());
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let size = data.len() as u64;
+        let big = db.import_bytes(data.clone().into()).await?;
+        let small = db.import_bytes(Bytes::from_static(b"small")).await?;
+        let sub = Directory::new(vec![entry(
+            "small",
+            EntryKind::File {
+                hash: small,
+                size: 5,
+            },
+        )])?
+        .to_bytes()?;
+        let sub_size = sub.len() as u64;
+        let sub = db.import_bytes(sub.into()).await?;
+        let root = Directory::new(vec![
+            entry("big", EntryKind::File { hash: big, size }),
+            entry(
+                "sub",
+                EntryKind::Directory {
+                    hash: sub,
+                    size: sub_size,
+                },
+            ),
+            entry(
+                "link",
+                EntryKind::Symlink {
+                    target: "sub/small".to_string(),
+                },
+            ),
+        ])?
+        .to_bytes()?;
+        let root = db.import_bytes(root.into()).await?;
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        let conn = crate::dial::dial(crate::dial::Options {
+            keypair: Keypair::generate(),
+            addrs: node.local_endpoint_addresses().await?,
+            peer_id: node.peer_id(),
+            keylog: false,
+            derp_map: None,
+            derp_region: None,
+        })
+        .await?;
+
+        let local = mem::Store::new(rt);
+        let remote = Remote { conn, token: None };
+        let mut fs = CollectionFs::new(local.clone(), root, Some(remote)).await?;
+        let sub = fs.lookup_child(ROOT_INO, "sub").context("missing sub")?;
+        assert!(fs.lookup_child(sub, "small").is_some());
+        let link = fs.lookup_child(ROOT_INO, "link").context("missing link")?;
+        assert_eq!(fs.inode(link).unwrap().kind.file_type(), FileType::Symlink);
+        // only the collection blobs are fetched when mounting
+        assert!(local.get(&big).is_none());
+
+        let read = fs.read_range(big, size, 40_000, 1000).await?;
+        assert_eq!(&read[..], &data[40_000..41_000]);
+        assert!(local.get_partial(&big).is_some());
+        assert!(!fs.is_complete(&big));
+
+        // reading everything completes the blob
+        let read = fs.read_range(big, size, 0, size).await?;
+        assert_eq!(&read[..], &data[..]);
+        assert!(local.get_partial(&big).is_none());
+        assert!(fs.is_complete(&big));
+        Ok(())
+    }
+}
@@ -4,7 +4,11 @@
 //!
 //! You can monitor what is happening in the node using [`Node::subscribe`].
 //!
+//! Besides the iroh-bytes protocol, a node can serve other protocols on the same endpoint,
+//! see [`Builder::accept`] and [`ProtocolHandler`].
+//!
 //! To shut down the node, call [`Node::shutdown`].
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Debug;
 use std::future::Future;
 use std::io;
@@ -13,32 +17,56 @@ use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
-use std::time::Duration;
-
-use crate::dial::Ticket;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "gossip")]
+use crate::announce::{AnnounceConfig, ProviderIndex};
+#[cfg(feature = "iroh-collection")]
+use crate::collection::tree::{Directory, Entry, EntryKind};
+use crate::dial::{NodeAddr, Ticket};
+use crate::downloads::{Download, DownloadStatus, Downloads, Transfer};
+#[cfg(feature = "gateway")]
+use crate::gateway::GatewayConfig;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
 use crate::rpc_protocol::{
-    AddrsRequest, AddrsResponse, IdRequest, IdResponse, ListBlobsRequest, ListBlobsResponse,
-    ListCollectionsRequest, ListCollectionsResponse, ListIncompleteBlobsRequest,
-    ListIncompleteBlobsResponse, ProvideRequest, ProviderRequest, ProviderResponse,
-    ProviderService, ShareRequest, ShutdownRequest, ValidateRequest, VersionRequest,
+    AddrsRequest, AddrsResponse, CancelDownloadRequest, DeleteBlobRequest, DeleteTagRequest,
+    ExportSliceRequest, ExportSliceResponse, FindProvidersRequest, FindProvidersResponse,
+    GcRequest, IdRequest, IdResponse, ImportSliceRequest, ImportSliceResponse, ListBlobsRequest,
+    ListBlobsResponse, ListCollectionsRequest, ListCollectionsResponse, ListDownloadsRequest,
+    ListDownloadsResponse, ListIncompleteBlobsRequest, ListIncompleteBlobsResponse,
+    ListTagsRequest, ListTagsResponse, ProvideRequest, ProviderRequest, ProviderResponse,
+    ProviderService, SetTagRequest, ShareRequest, ShutdownRequest, ValidateRequest, VersionRequest,
     VersionResponse, WatchRequest, WatchResponse,
 };
+use crate::token::{SignedToken, TokenClaims};
 use anyhow::{Context, Result};
 use bytes::Bytes;
+#[cfg(feature = "iroh-collection")]
+use futures::future::LocalBoxFuture;
 use futures::future::{BoxFuture, Shared};
 use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
-use iroh_bytes::baomap::{ExportMode, Map, MapEntry, ReadableStore, Store, ValidateProgress};
+use iroh_bytes::baomap::{
+    ExportMode, GcProgress, Map, MapEntry, ReadableStore, Store, ValidateProgress,
+};
 use iroh_bytes::collection::{CollectionParser, NoCollectionParser};
 use iroh_bytes::get::Stats;
-use iroh_bytes::protocol::GetRequest;
+use iroh_bytes::protocol::{GetRequest, RangeSpec};
 use iroh_bytes::provider::ShareProgress;
-use iroh_bytes::util::progress::{FlumeProgressSender, IdGenerator, ProgressSender};
+use iroh_bytes::util::progress::{
+    FlumeProgressSender, IdGenerator, IgnoreProgressSender, ProgressSender,
+};
 use iroh_bytes::{
     protocol::{Closed, Request, RequestToken},
-    provider::{CustomGetHandler, ProvideProgress, RequestAuthorizationHandler},
+    provider::{
+        limits::{Limiter, Limits},
+        CustomGetHandler, ProvideProgress, RequestAuthorizationHandler,
+    },
     util::runtime,
-    util::Hash,
+    util::{Hash, RpcResult},
 };
+#[cfg(feature = "metrics")]
+use iroh_metrics::inc;
 use iroh_net::{
     config::Endpoint,
     derp::DerpMap,
@@ -57,6 +85,16 @@ use tracing::{debug, trace};
 const MAX_CONNECTIONS: u32 = 1024;
 const MAX_STREAMS: u64 = 10;
 const HEALTH_POLL_WAIT: Duration = Duration::from_secs(1);
+/// How long to wait for more changes before rebuilding a watched collection.
+#[cfg(feature = "watch")]
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+/// How many files to import in parallel when providing a path.
+#[cfg(feature = "iroh-collection")]
+const IO_PARALLELISM: usize = 4;
+
+/// Size, modification time and hash of previously imported files, by path.
+#[cfg(feature = "iroh-collection")]
+type FileCache = BTreeMap<PathBuf, (u64, SystemTime, Hash)>;
 
 /// Default bind address for the node.
 /// 11204 is "iroh" in leetspeak <https://simple.wikipedia.org/wiki/Leet>
@@ -65,6 +103,11 @@ pub const DEFAULT_BIND_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::LOCALHOST, 11204);
 /// How long we wait at most for some endpoints to be discovered.
 const ENDPOINT_WAIT: Duration = Duration::from_secs(5);
 
+/// How often a download is attempted before it fails.
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 3;
+/// How long to wait before the first retry of a download, doubled for every further retry.
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 /// Builder for the [`Node`].
 ///
 /// You must supply a blob store. Various store implementations are available
@@ -88,13 +131,40 @@ where
     keylog: bool,
     custom_get_handler: Arc<dyn CustomGetHandler>,
     auth_handler: Arc<dyn RequestAuthorizationHandler>,
+    limits: Limits,
     derp_map: Option<DerpMap>,
     collection_parser: C,
     rt: Option<runtime::Handle>,
+    #[cfg(feature = "gateway")]
+    gateway: Option<GatewayConfig>,
+    protocols: BTreeMap<Vec<u8>, Arc<dyn ProtocolHandler>>,
+    #[cfg(feature = "gossip")]
+    gossip: Option<iroh_gossip::proto::Config>,
+    #[cfg(feature = "gossip")]
+    announce: Option<AnnounceConfig>,
+    downloads_path: Option<PathBuf>,
 }
 
 const PROTOCOLS: [&[u8]; 1] = [&iroh_bytes::protocol::ALPN];
 
+/// Handler for incoming connections of a protocol, see [`Builder::accept`].
+pub trait ProtocolHandler: Send + Sync + Debug + 'static {
+    /// Handle an incoming connection.
+    ///
+    /// The handshake has already been started, and the ALPN of the connection is the one the
+    /// handler was registered for. The returned future is spawned on the main runtime, and
+    /// errors are logged.
+    fn accept(&self, conn: quinn::Connecting) -> BoxFuture<'static, Result<()>>;
+}
+
+#[cfg(feature = "gossip")]
+impl ProtocolHandler for iroh_gossip::net::Gossip {
+    fn accept(&self, conn: quinn::Connecting) -> BoxFuture<'static, Result<()>> {
+        let gossip = self.clone();
+        async move { gossip.handle_connection(conn.await?).await }.boxed()
+    }
+}
+
 /// A noop authorization handler that does not do any authorization.
 ///
 /// This is the default. It does not have to be pub, since it is going to be
@@ -105,6 +175,8 @@ struct NoopRequestAuthorizationHandler;
 impl RequestAuthorizationHandler for NoopRequestAuthorizationHandler {
     fn authorize(
         &self,
+        _peer_id: PeerId,
+        _remote_addr: SocketAddr,
         token: Option<RequestToken>,
         _request: &Request,
     ) -> BoxFuture<'static, anyhow::Result<()>> {
@@ -146,8 +218,17 @@ impl<D: Map> Builder<D> {
             rpc_endpoint: Default::default(),
             custom_get_handler: Arc::new(NoopCustomGetHandler),
             auth_handler: Arc::new(NoopRequestAuthorizationHandler),
+            limits: Limits::default(),
             collection_parser: NoCollectionParser,
             rt: None,
+            #[cfg(feature = "gateway")]
+            gateway: None,
+            protocols: BTreeMap::new(),
+            #[cfg(feature = "gossip")]
+            gossip: None,
+            #[cfg(feature = "gossip")]
+            announce: None,
+            downloads_path: None,
         }
     }
 }
@@ -171,10 +252,19 @@ where
             keylog: self.keylog,
             custom_get_handler: self.custom_get_handler,
             auth_handler: self.auth_handler,
+            limits: self.limits,
             rpc_endpoint: value,
             derp_map: self.derp_map,
             collection_parser: self.collection_parser,
             rt: self.rt,
+            #[cfg(feature = "gateway")]
+            gateway: self.gateway,
+            protocols: self.protocols,
+            #[cfg(feature = "gossip")]
+            gossip: self.gossip,
+            #[cfg(feature = "gossip")]
+            announce: self.announce,
+            downloads_path: self.downloads_path,
         }
     }
 
@@ -192,9 +282,18 @@ where
             keylog: self.keylog,
             custom_get_handler: self.custom_get_handler,
             auth_handler: self.auth_handler,
+            limits: self.limits,
             rpc_endpoint: self.rpc_endpoint,
             derp_map: self.derp_map,
             rt: self.rt,
+            #[cfg(feature = "gateway")]
+            gateway: self.gateway,
+            protocols: self.protocols,
+            #[cfg(feature = "gossip")]
+            gossip: self.gossip,
+            #[cfg(feature = "gossip")]
+            announce: self.announce,
+            downloads_path: self.downloads_path,
         }
     }
 
@@ -220,6 +319,14 @@ where
         }
     }
 
+    /// Configures limits for serving requests.
+    ///
+    /// By default there are no limits.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Binds the node service to a different socket.
     ///
     /// By default it binds to `127.0.0.1:11204`.
@@ -252,6 +359,55 @@ where
         self
     }
 
+    /// Serves the blobs of the node over HTTP.
+    ///
+    /// See [`crate::gateway`] for what is served. Requests are checked by the auth handler
+    /// of the node, see [`Builder::custom_auth_handler`].
+    #[cfg(feature = "gateway")]
+    pub fn gateway(mut self, config: GatewayConfig) -> Self {
+        self.gateway = Some(config);
+        self
+    }
+
+    /// Serves another protocol on the endpoint of the node.
+    ///
+    /// Incoming connections with the given ALPN are passed to `handler`. The ALPN of the
+    /// iroh-bytes protocol can not be used, and registering an ALPN again replaces the
+    /// previous handler.
+    pub fn accept(mut self, alpn: impl AsRef<[u8]>, handler: Arc<dyn ProtocolHandler>) -> Self {
+        self.protocols.insert(alpn.as_ref().to_vec(), handler);
+        self
+    }
+
+    /// Runs the gossip protocol on the endpoint of the node.
+    ///
+    /// The [`Gossip`](iroh_gossip::net::Gossip) handle is available from [`Node::gossip`].
+    #[cfg(feature = "gossip")]
+    pub fn gossip(mut self, config: iroh_gossip::proto::Config) -> Self {
+        self.gossip = Some(config);
+        self
+    }
+
+    /// Announces the roots of the store to other nodes, and indexes their announcements.
+    ///
+    /// Enables gossip with the default config, unless it is already enabled. The index is
+    /// available from [`Node::provider_index`], see [`crate::announce`].
+    #[cfg(feature = "gossip")]
+    pub fn announce(mut self, config: AnnounceConfig) -> Self {
+        self.gossip.get_or_insert_with(Default::default);
+        self.announce = Some(config);
+        self
+    }
+
+    /// Persists the download queue of the node in the file at `path`.
+    ///
+    /// Downloads that did not finish are resumed when the node is spawned again, see
+    /// [`crate::downloads`]. By default the queue is only kept in memory.
+    pub fn downloads_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.downloads_path = Some(path.into());
+        self
+    }
+
     /// Spawns the [`Node`] in a tokio task.
     ///
     /// This will create the underlying network server and spawn a tokio task accepting
@@ -260,6 +416,35 @@ where
     pub async fn spawn(self) -> Result<Node<D>> {
         trace!("spawning node");
         let rt = self.rt.context("runtime not set")?;
+        let downloads = Downloads::load(self.downloads_path)?;
+        let protocols = self.protocols;
+        anyhow::ensure!(
+            !protocols.contains_key(iroh_bytes::protocol::ALPN.as_ref()),
+            "the iroh-bytes ALPN is reserved"
+        );
+        #[cfg(feature = "gossip")]
+        anyhow::ensure!(
+            self.gossip.is_none() || !protocols.contains_key(iroh_gossip::net::GOSSIP_ALPN),
+            "a handler for the gossip ALPN is registered, but gossip is enabled"
+        );
+        #[cfg(feature = "gossip")]
+        let gossip_alpn = self
+            .gossip
+            .as_ref()
+            .map(|_| iroh_gossip::net::GOSSIP_ALPN.to_vec());
+        #[cfg(not(feature = "gossip"))]
+        let gossip_alpn = None;
+        let alpns = PROTOCOLS
+            .iter()
+            .map(|p| p.to_vec())
+            .chain(protocols.keys().cloned())
+            .chain(gossip_alpn)
+            .collect();
+        // the gossip actor needs the endpoint, but wants to know about endpoint updates
+        #[cfg(feature = "gossip")]
+        let gossip_cell = Arc::new(std::sync::Mutex::new(None::<iroh_gossip::net::Gossip>));
+        #[cfg(feature = "gossip")]
+        let gossip_cell2 = gossip_cell.clone();
 
         let (endpoints_update_s, endpoints_update_r) = flume::bounded(1);
         let mut transport_config = quinn::TransportConfig::default();
@@ -269,12 +454,16 @@ where
 
         let endpoint = MagicEndpoint::builder()
             .keypair(self.keypair.clone())
-            .alpns(PROTOCOLS.iter().map(|p| p.to_vec()).collect())
+            .alpns(alpns)
             .keylog(self.keylog)
             .derp_map(self.derp_map)
             .transport_config(transport_config)
             .concurrent_connections(MAX_CONNECTIONS)
             .on_endpoints(Box::new(move |eps| {
+                #[cfg(feature = "gossip")]
+                if let Some(gossip) = &*gossip_cell2.lock().unwrap() {
+                    gossip.update_endpoints(eps).ok();
+                }
                 if !endpoints_update_s.is_disconnected() && !eps.is_empty() {
                     endpoints_update_s.send(()).ok();
                 }
@@ -283,9 +472,54 @@ where
             .await?;
         trace!("created quinn endpoint");
 
+        #[cfg(feature = "gossip")]
+        let (gossip, protocols) = {
+            let mut protocols = protocols;
+            let gossip = match self.gossip {
+                Some(config) => {
+                    let gossip = iroh_gossip::net::Gossip::from_endpoint(endpoint.clone(), config);
+                    gossip.update_endpoints(&endpoint.local_endpoints().await?)?;
+                    *gossip_cell.lock().unwrap() = Some(gossip.clone());
+                    protocols.insert(
+                        iroh_gossip::net::GOSSIP_ALPN.to_vec(),
+                        Arc::new(gossip.clone()),
+                    );
+                    Some(gossip)
+                }
+                None => None,
+            };
+            (gossip, protocols)
+        };
+
         let (cb_sender, cb_receiver) = mpsc::channel(8);
         let cancel_token = CancellationToken::new();
 
+        #[cfg(feature = "gossip")]
+        let providers = match (self.announce, &gossip) {
+            (Some(config), Some(gossip)) => Some(crate::announce::spawn(
+                config,
+                gossip.clone(),
+                endpoint.clone(),
+                self.db.clone(),
+                &rt,
+                cancel_token.clone(),
+            )),
+            _ => None,
+        };
+
+        #[cfg(feature = "gateway")]
+        let gateway_addr = match self.gateway {
+            Some(config) => Some(crate::gateway::spawn(
+                config,
+                self.db.clone(),
+                endpoint.clone(),
+                self.auth_handler.clone(),
+                rt.clone(),
+                cancel_token.clone(),
+            )?),
+            None => None,
+        };
+
         debug!("rpc listening on: {:?}", self.rpc_endpoint.local_addr());
         let (internal_rpc, controller) = quic_rpc::transport::flume::connection(1);
         let rt2 = rt.clone();
@@ -300,12 +534,24 @@ where
             callbacks: callbacks.clone(),
             cb_sender,
             rt,
+            gc_lock: Default::default(),
+            #[cfg(feature = "gateway")]
+            gateway_addr,
+            #[cfg(feature = "gossip")]
+            gossip,
+            #[cfg(feature = "gossip")]
+            providers,
+            downloads,
         });
         let task = {
             let handler = RpcHandler {
                 inner: inner.clone(),
                 collection_parser: self.collection_parser.clone(),
             };
+            // resume the downloads that did not finish when the node was stopped
+            for (id, download) in handler.inner.downloads.pending() {
+                handler.spawn_download(id, download, IgnoreProgressSender::default());
+            }
             rt2.main().spawn(async move {
                 Self::run(
                     endpoint,
@@ -316,7 +562,9 @@ where
                     internal_rpc,
                     self.custom_get_handler,
                     self.auth_handler,
+                    Limiter::new(self.limits),
                     self.collection_parser,
+                    protocols,
                     rt3,
                 )
                 .await
@@ -348,7 +596,9 @@ where
         internal_rpc: impl ServiceEndpoint<ProviderService>,
         custom_get_handler: Arc<dyn CustomGetHandler>,
         auth_handler: Arc<dyn RequestAuthorizationHandler>,
+        limiter: Limiter,
         collection_parser: C,
+        protocols: BTreeMap<Vec<u8>, Arc<dyn ProtocolHandler>>,
         rt: runtime::Handle,
     ) {
         let rpc = RpcServer::new(rpc);
@@ -407,7 +657,15 @@ where
                         let collection_parser = collection_parser.clone();
                         let rt2 = rt.clone();
                         let callbacks = callbacks.clone();
-                        rt.main().spawn(iroh_bytes::provider::handle_connection(connecting, db, callbacks, collection_parser, custom_get_handler, auth_handler, rt2));
+                        let limiter = limiter.clone();
+                        rt.main().spawn(iroh_bytes::provider::handle_connection(connecting, db, callbacks, collection_parser, custom_get_handler, auth_handler, limiter, rt2));
+                    } else if let Some(handler) = protocols.get(alpn.as_bytes()) {
+                        let handler = handler.accept(connecting);
+                        rt.main().spawn(async move {
+                            if let Err(err) = handler.await {
+                                tracing::warn!("failed to handle {} connection: {:?}", alpn, err);
+                            }
+                        });
                     } else {
                         tracing::error!("unknown protocol: {}", alpn);
                         continue;
@@ -465,6 +723,16 @@ impl Callbacks {
 
 impl iroh_bytes::provider::EventSender for Callbacks {
     fn send(&self, event: iroh_bytes::provider::Event) -> BoxFuture<()> {
+        #[cfg(feature = "metrics")]
+        match event {
+            iroh_bytes::provider::Event::ClientRejected { .. } => {
+                inc!(Metrics, connections_rejected)
+            }
+            iroh_bytes::provider::Event::RequestRejected { .. } => {
+                inc!(Metrics, requests_rejected)
+            }
+            _ => {}
+        }
         async move {
             let cbs = self.0.read().await;
             for cb in &*cbs {
@@ -503,6 +771,16 @@ struct NodeInner<D> {
     #[allow(dead_code)]
     callbacks: Callbacks,
     rt: runtime::Handle,
+    /// Held for reading while adding data, and for writing while running gc,
+    /// so gc does not delete data that is not yet pinned.
+    gc_lock: RwLock<()>,
+    #[cfg(feature = "gateway")]
+    gateway_addr: Option<SocketAddr>,
+    #[cfg(feature = "gossip")]
+    gossip: Option<iroh_gossip::net::Gossip>,
+    #[cfg(feature = "gossip")]
+    providers: Option<ProviderIndex>,
+    downloads: Downloads,
 }
 
 /// Events emitted by the [`Node`] informing about the current status.
@@ -539,6 +817,37 @@ impl<D: ReadableStore> Node<D> {
         self.inner.local_endpoint_addresses().await
     }
 
+    /// The address of the HTTP gateway, if it is enabled.
+    ///
+    /// See [`Builder::gateway`].
+    #[cfg(feature = "gateway")]
+    pub fn gateway_addr(&self) -> Option<SocketAddr> {
+        self.inner.gateway_addr
+    }
+
+    /// The gossip handle, if gossip is enabled.
+    ///
+    /// See [`Builder::gossip`].
+    #[cfg(feature = "gossip")]
+    pub fn gossip(&self) -> Option<&iroh_gossip::net::Gossip> {
+        self.inner.gossip.as_ref()
+    }
+
+    /// The index of content announced by other nodes, if announcements are enabled.
+    ///
+    /// See [`Builder::announce`].
+    #[cfg(feature = "gossip")]
+    pub fn provider_index(&self) -> Option<&ProviderIndex> {
+        self.inner.providers.as_ref()
+    }
+
+    /// The endpoint of the node.
+    ///
+    /// Can be used to connect to other peers with any of the protocols the node serves.
+    pub fn magic_endpoint(&self) -> &MagicEndpoint {
+        &self.inner.endpoint
+    }
+
     /// Returns the [`PeerId`] of the node.
     pub fn peer_id(&self) -> PeerId {
         self.inner.keypair.public().into()
@@ -573,6 +882,40 @@ impl<D: ReadableStore> Node<D> {
         Ticket::new(hash, self.peer_id(), addrs, None, true, region)
     }
 
+    /// Return a ticket for a hash, with a [`SignedToken`] minted by this node.
+    ///
+    /// The token only allows requesting `hash` until `expires`, and if `requester` is given
+    /// only by that peer. The ticket expires at the same time as the token. Requests are
+    /// only checked against the token if the node uses a
+    /// [`SignedTokenAuthHandler`](crate::token::SignedTokenAuthHandler).
+    pub async fn signed_ticket(
+        &self,
+        hash: Hash,
+        expires: SystemTime,
+        requester: Option<PeerId>,
+    ) -> Result<Ticket> {
+        let claims = TokenClaims::new(vec![hash], expires, requester);
+        let token = SignedToken::sign(&self.inner.keypair, claims).to_request_token()?;
+        Ok(self
+            .ticket(hash)
+            .await?
+            .with_token(Some(token))
+            .with_expires(Some(expires)))
+    }
+
+    /// Return a single token containing everything needed to get the data a tag points to.
+    ///
+    /// See [`Node::ticket`] and [`ReadableStore::tags`].
+    pub async fn ticket_for_tag(&self, name: &str) -> Result<Ticket> {
+        let (_, info) = self
+            .inner
+            .db
+            .tags()
+            .find(|(tag, _)| tag == name)
+            .with_context(|| format!("tag {name} not found"))?;
+        self.ticket(info.hash).await
+    }
+
     /// Return the DERP region that this provider is connected to
     pub async fn my_derp(&self) -> Option<u16> {
         self.inner.endpoint.my_derp().await
@@ -709,6 +1052,35 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         })
     }
 
+    fn list_tags(
+        self,
+        _msg: ListTagsRequest,
+    ) -> impl Stream<Item = ListTagsResponse> + Send + 'static {
+        futures::stream::iter(self.inner.db.tags().map(|(name, info)| ListTagsResponse {
+            name,
+            hash: info.hash,
+            created: info.created,
+        }))
+    }
+
+    async fn set_tag(self, msg: SetTagRequest) -> RpcResult<()> {
+        self.inner
+            .db
+            .set_tag(msg.name, msg.hash)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    async fn delete_tag(self, msg: DeleteTagRequest) -> RpcResult<()> {
+        self.inner
+            .db
+            .delete_tag(msg.name)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
     /// Invoke validate on the database and stream out the result
     fn validate(
         self,
@@ -725,6 +1097,82 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         tokio_stream::wrappers::ReceiverStream::new(rx)
     }
 
+    async fn delete_blob(self, msg: DeleteBlobRequest) -> RpcResult<()> {
+        self.inner
+            .db
+            .delete(&msg.hash)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    async fn export_slice(self, msg: ExportSliceRequest) -> RpcResult<ExportSliceResponse> {
+        // reading from the store is not Send
+        let res = self
+            .rt()
+            .local_pool()
+            .spawn_pinned(move || async move {
+                anyhow::ensure!(msg.path.is_absolute(), "path must be absolute");
+                let file = tokio::fs::File::create(&msg.path).await?;
+                let ranges = iroh_bytes::slice::export_slice(
+                    &self.inner.db,
+                    msg.hash,
+                    &msg.ranges.to_chunk_ranges(),
+                    tokio::io::BufWriter::new(file),
+                )
+                .await?;
+                anyhow::Ok(ExportSliceResponse {
+                    ranges: RangeSpec::new(ranges),
+                })
+            })
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(res?)
+    }
+
+    async fn import_slice(self, msg: ImportSliceRequest) -> RpcResult<ImportSliceResponse> {
+        let res = self
+            .rt()
+            .local_pool()
+            .spawn_pinned(move || async move {
+                anyhow::ensure!(msg.path.is_absolute(), "path must be absolute");
+                let file = tokio::fs::File::open(&msg.path).await?;
+                // prevent gc from deleting the partial data before it is pinned
+                let _guard = self.inner.gc_lock.read().await;
+                let slice = iroh_bytes::slice::import_slice(
+                    &self.inner.db,
+                    tokio::io::BufReader::new(file),
+                )
+                .await?;
+                self.inner.db.pin(slice.hash).await?;
+                anyhow::Ok(ImportSliceResponse {
+                    hash: slice.hash,
+                    size: slice.size,
+                    ranges: RangeSpec::new(slice.ranges),
+                    complete: slice.complete,
+                })
+            })
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(res?)
+    }
+
+    /// Run a garbage collection on the database and stream out the result
+    fn gc(self, _msg: GcRequest) -> impl Stream<Item = GcProgress> {
+        let (tx, rx) = flume::bounded(32);
+        let tx2 = tx.clone();
+        self.rt().local_pool().spawn_pinned(|| async move {
+            let _guard = self.inner.gc_lock.write().await;
+            let progress = FlumeProgressSender::new(tx);
+            let res =
+                iroh_bytes::baomap::gc(&self.inner.db, &self.collection_parser, progress).await;
+            if let Err(e) = res {
+                tx2.send_async(GcProgress::Abort(e.into())).await.ok();
+            }
+        });
+        rx.into_stream()
+    }
+
     fn provide(self, msg: ProvideRequest) -> impl Stream<Item = ProvideProgress> {
         // provide a little buffer so that we don't slow down the sender
         let (tx, rx) = flume::bounded(32);
@@ -742,17 +1190,83 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         conn: quinn::Connection,
         hash: Hash,
         recursive: bool,
+        token: Option<RequestToken>,
         sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<Stats> {
-        crate::get::get(
+        let stats = crate::get::get(
             &self.inner.db,
             &self.collection_parser,
-            conn,
+            conn.clone(),
             hash,
             recursive,
-            sender,
+            token.clone(),
+            sender.clone(),
         )
-        .await
+        .await?;
+        #[cfg(feature = "iroh-collection")]
+        let stats = if recursive {
+            self.get_subdirs(&conn, hash, &token, &sender, stats)
+                .await?
+        } else {
+            stats
+        };
+        Ok(stats)
+    }
+
+    /// Get the sub-directories and chunked files of a [`Directory`] that has already been
+    /// downloaded, recursively, and add their transfer stats to `stats`.
+    ///
+    /// Every sub-directory and chunk manifest is a collection of its own, so they are
+    /// requested one by one. Chunks that are already in the store are not transferred again.
+    /// Does nothing if `hash` is not a [`Directory`].
+    #[cfg(feature = "iroh-collection")]
+    fn get_subdirs<'a, P>(
+        &'a self,
+        conn: &'a quinn::Connection,
+        hash: Hash,
+        token: &'a Option<RequestToken>,
+        sender: &'a P,
+        mut stats: Stats,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Stats>>
+    where
+        P: ProgressSender<Msg = ShareProgress> + IdGenerator,
+    {
+        async move {
+            let data = self.read_blob(&hash).await?;
+            if !Directory::is_directory(&data) {
+                return Ok(stats);
+            }
+            for (hash, is_dir) in Directory::from_bytes(&data)?.links() {
+                if !is_dir {
+                    continue;
+                }
+                let sub = crate::get::get(
+                    &self.inner.db,
+                    &self.collection_parser,
+                    conn.clone(),
+                    hash,
+                    true,
+                    token.clone(),
+                    sender.clone(),
+                )
+                .await?;
+                stats.bytes_written += sub.bytes_written;
+                stats.bytes_read += sub.bytes_read;
+                stats.elapsed += sub.elapsed;
+                stats = self.get_subdirs(conn, hash, token, sender, stats).await?;
+            }
+            Ok(stats)
+        }
+        .boxed_local()
+    }
+
+    /// Read an entire blob from the store.
+    #[cfg(feature = "iroh-collection")]
+    async fn read_blob(&self, hash: &Hash) -> anyhow::Result<Bytes> {
+        use iroh_io::AsyncSliceReaderExt;
+        let entry = self.inner.db.get(hash).context("blob not there")?;
+        let mut reader = entry.data_reader().await?;
+        Ok(reader.read_to_end().await?)
     }
 
     async fn export(
@@ -775,12 +1289,19 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             {
                 use crate::collection::{Blob, Collection};
                 use crate::util::io::pathbuf_from_name;
-                use iroh_io::AsyncSliceReaderExt;
                 tracing::trace!("exporting collection {} to {}", hash, path.display());
+                let bytes = self
+                    .read_blob(&hash)
+                    .await
+                    .context("collection not there")?;
+                if Directory::is_directory(&bytes) {
+                    let dir = Directory::from_bytes(&bytes)?;
+                    let mut links = HashSet::new();
+                    return self
+                        .export_tree(path, dir, mode, &progress, &mut links)
+                        .await;
+                }
                 tokio::fs::create_dir_all(&path).await?;
-                let collection = db.get(&hash).context("collection not there")?;
-                let mut reader = collection.data_reader().await?;
-                let bytes: Bytes = reader.read_to_end().await?;
                 let collection = Collection::from_bytes(&bytes).context("invalid collection")?;
                 for Blob { hash, name } in collection.blobs() {
                     let path = path.join(pathbuf_from_name(name));
@@ -819,59 +1340,159 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         anyhow::Ok(())
     }
 
+    /// Export a [`Directory`] and everything below it to the directory `path`.
+    ///
+    /// Permissions and modification times are restored after the contents are written.
+    /// The symlinks created by the export are recorded in `links`, and are never followed.
+    #[cfg(feature = "iroh-collection")]
+    fn export_tree<'a, P>(
+        &'a self,
+        path: PathBuf,
+        dir: Directory,
+        mode: ExportMode,
+        progress: &'a P,
+        links: &'a mut HashSet<(u64, u64)>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<()>>
+    where
+        P: ProgressSender<Msg = ShareProgress> + IdGenerator,
+    {
+        use crate::util::fs::set_mode_and_mtime;
+        async move {
+            tokio::fs::create_dir_all(&path).await?;
+            for entry in dir.entries() {
+                let target = path.join(&entry.name);
+                // a link from an earlier entry, e.g. one whose name only differs in case,
+                // must not redirect the export to outside of the target directory
+                #[cfg(unix)]
+                if !matches!(entry.kind, EntryKind::Symlink { .. }) {
+                    if let Some(id) = crate::util::fs::symlink_id(&target) {
+                        anyhow::ensure!(
+                            !links.contains(&id),
+                            "refusing to follow symlink {} created by the export",
+                            target.display()
+                        );
+                    }
+                }
+                match &entry.kind {
+                    EntryKind::File { hash, .. } => {
+                        tracing::trace!("exporting blob {} to {}", hash, target.display());
+                        let id = progress.new_id();
+                        let progress1 = progress.clone();
+                        self.inner
+                            .db
+                            .export(*hash, target.clone(), mode, move |offset| {
+                                Ok(progress1
+                                    .try_send(ShareProgress::ExportProgress { id, offset })?)
+                            })
+                            .await?;
+                        set_mode_and_mtime(&target, entry.mode, entry.modified())?;
+                    }
+                    EntryKind::Directory { hash, .. } => {
+                        let data = self.read_blob(hash).await?;
+                        let sub = Directory::from_bytes(&data)?;
+                        self.export_tree(target.clone(), sub, mode, progress, &mut *links)
+                            .await?;
+                        set_mode_and_mtime(&target, entry.mode, entry.modified())?;
+                    }
+                    EntryKind::Chunked { hash, size } => {
+                        tracing::trace!("exporting chunked {} to {}", hash, target.display());
+                        self.export_chunked(*hash, *size, &target, progress).await?;
+                        set_mode_and_mtime(&target, entry.mode, entry.modified())?;
+                    }
+                    EntryKind::Symlink { target: link } => {
+                        #[cfg(unix)]
+                        {
+                            if tokio::fs::symlink_metadata(&target).await.is_ok() {
+                                tokio::fs::remove_file(&target).await?;
+                            }
+                            tokio::fs::symlink(link, &target).await?;
+                            links.extend(crate::util::fs::symlink_id(&target));
+                        }
+                        #[cfg(not(unix))]
+                        tracing::warn!("not creating symlink {} -> {}", target.display(), link);
+                    }
+                }
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    /// Export a chunked file to `target`, by writing its chunks one after the other.
+    #[cfg(feature = "iroh-collection")]
+    async fn export_chunked<P>(
+        &self,
+        hash: Hash,
+        size: u64,
+        target: &std::path::Path,
+        progress: &P,
+    ) -> anyhow::Result<()>
+    where
+        P: ProgressSender<Msg = ShareProgress> + IdGenerator,
+    {
+        use iroh_bytes::chunked::Manifest;
+        use tokio::io::AsyncWriteExt;
+
+        let manifest = Manifest::from_bytes(&self.read_blob(&hash).await?)?;
+        anyhow::ensure!(
+            manifest.size() == size,
+            "chunked file {hash} has the wrong size"
+        );
+        let id = progress.new_id();
+        progress
+            .send(ShareProgress::Export {
+                id,
+                hash,
+                target: target.display().to_string(),
+                size,
+            })
+            .await?;
+        let mut file = tokio::fs::File::create(target).await?;
+        let mut offset = 0;
+        for chunk in manifest.chunks() {
+            let data = self.read_blob(&chunk.hash).await?;
+            file.write_all(&data).await?;
+            offset += data.len() as u64;
+            progress.try_send(ShareProgress::ExportProgress { id, offset })?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Add the download of `msg` to the queue and start it.
     async fn share0(
         self,
         msg: ShareRequest,
         progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<()> {
-        let local = self.inner.rt.local_pool().clone();
-        let hash = msg.hash;
         tracing::info!("share: {:?}", msg);
-        let conn = self
-            .inner
-            .endpoint
-            .connect(
-                msg.peer,
-                &iroh_bytes::protocol::ALPN,
-                msg.derp_region,
-                &msg.addrs,
-            )
-            .await?;
-        progress.send(ShareProgress::Connected).await?;
-        let progress2 = progress.clone();
-        let progress3 = progress.clone();
-        let this = self.clone();
-        let download =
-            local.spawn_pinned(move || self.get(conn, msg.hash, msg.recursive, progress2));
-        let _export = local.spawn_pinned(move || async move {
-            let stats = download.await.unwrap()?;
-            progress
-                .send(ShareProgress::NetworkDone {
-                    bytes_written: stats.bytes_written,
-                    bytes_read: stats.bytes_read,
-                    elapsed: stats.elapsed,
-                })
-                .await?;
-            if let Some(out) = msg.out {
-                if let Err(cause) = this
-                    .export(out, hash, msg.recursive, msg.in_place, progress3)
-                    .await
-                {
-                    progress.send(ShareProgress::Abort(cause.into())).await?;
-                }
-            }
-            progress.send(ShareProgress::AllDone).await?;
-            anyhow::Ok(())
-        });
+        let download = Download {
+            hash: msg.hash,
+            recursive: msg.recursive,
+            providers: vec![NodeAddr::new(msg.peer, msg.addrs, msg.derp_region)],
+            out: msg.out,
+            in_place: msg.in_place,
+            token: msg.token,
+        };
+        let id = self.inner.downloads.add(download.clone())?;
+        self.spawn_download(id, download, progress);
         Ok(())
     }
 
     fn share(self, msg: ShareRequest) -> impl Stream<Item = ShareProgress> {
         async move {
             let (sender, receiver) = flume::bounded(1024);
-            let sender = FlumeProgressSender::new(sender);
-            if let Err(cause) = self.share0(msg, sender.clone()).await {
-                sender
+            // the download keeps running if the requester goes away, so forward the progress
+            // through a channel that is drained until the download is done
+            let (progress, progress_receiver) = flume::unbounded();
+            self.rt().main().spawn(async move {
+                while let Ok(msg) = progress_receiver.recv_async().await {
+                    sender.send_async(msg).await.ok();
+                }
+            });
+            let progress = FlumeProgressSender::new(progress);
+            if let Err(cause) = self.share0(msg, progress.clone()).await {
+                progress
                     .send(ShareProgress::Abort(cause.into()))
                     .await
                     .unwrap();
@@ -881,86 +1502,564 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         .flatten_stream()
     }
 
-    #[cfg(feature = "iroh-collection")]
-    async fn provide0(
+    /// Run a download from the queue, and remove it from the queue when it is done or failed.
+    fn spawn_download(
+        &self,
+        id: u64,
+        download: Download,
+        progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
+    ) {
+        let this = self.clone();
+        let task = self.rt().local_pool().spawn_pinned(move || async move {
+            let res = this.clone().download(id, download, progress.clone()).await;
+            this.inner.downloads.remove(id);
+            if let Err(cause) = res {
+                tracing::warn!("download {} failed: {:?}", id, cause);
+                progress.send(ShareProgress::Abort(cause.into())).await.ok();
+            }
+        });
+        self.inner.downloads.set_task(id, task.abort_handle());
+    }
+
+    async fn download(
         self,
-        msg: ProvideRequest,
-        progress: flume::Sender<ProvideProgress>,
+        id: u64,
+        download: Download,
+        progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<()> {
-        use crate::collection::{Blob, Collection};
-        use futures::TryStreamExt;
-        use iroh_bytes::baomap::{ImportMode, ImportProgress};
-        use std::{collections::BTreeMap, sync::Mutex};
-
-        let progress = FlumeProgressSender::new(progress);
-        let names = Arc::new(Mutex::new(BTreeMap::new()));
-        // convert import progress to provide progress
-        let import_progress = progress.clone().with_filter_map(move |x| match x {
-            ImportProgress::Found { id, path, .. } => {
-                names.lock().unwrap().insert(id, path);
-                None
-            }
-            ImportProgress::Size { id, size } => {
-                let path = names.lock().unwrap().remove(&id)?;
-                Some(ProvideProgress::Found {
-                    id,
-                    name: path.display().to_string(),
-                    size,
-                })
-            }
-            ImportProgress::OutboardProgress { id, offset } => {
-                Some(ProvideProgress::Progress { id, offset })
+        let hash = download.hash;
+        // pin before downloading, and keep gc out while a transfer runs, so a concurrent gc
+        // will not delete the partial data. The lock is not held between attempts, so a
+        // download that keeps failing does not block gc
+        self.inner.db.pin(hash).await?;
+        let mut attempts = 0;
+        let stats = loop {
+            let guard = self.inner.gc_lock.read().await;
+            let res = self.transfer(&download, progress.clone()).await;
+            drop(guard);
+            match res {
+                Ok(stats) => break stats,
+                Err(cause) if attempts + 1 >= MAX_DOWNLOAD_ATTEMPTS => {
+                    anyhow::bail!("{:#}", cause);
+                }
+                Err(cause) => {
+                    attempts += 1;
+                    let delay = DOWNLOAD_RETRY_DELAY * 2u32.pow(attempts - 1);
+                    tracing::debug!(
+                        "download {} failed, retrying in {:?}: {:#}",
+                        id,
+                        delay,
+                        cause
+                    );
+                    let error = format!("{cause:#}");
+                    let status = DownloadStatus::Retrying { attempts, error };
+                    self.inner.downloads.set_status(id, status);
+                    tokio::time::sleep(delay).await;
+                }
             }
-            ImportProgress::OutboardDone { hash, id } => Some(ProvideProgress::Done { hash, id }),
-            _ => None,
-        });
-        let root = msg.path;
-        anyhow::ensure!(root.is_absolute(), "path must be absolute");
-        anyhow::ensure!(
-            root.is_dir() || root.is_file(),
-            "path must be either a Directory or a File"
-        );
-        let data_sources = crate::util::fs::scan_path(root)?;
-        let mode = if msg.in_place {
-            ImportMode::TryReference
-        } else {
-            ImportMode::Copy
         };
-        const IO_PARALLELISM: usize = 4;
-        let result: Vec<(Blob, u64)> = futures::stream::iter(data_sources)
-            .map(|source| {
-                let import_progress = import_progress.clone();
-                let db = self.inner.db.clone();
-                async move {
-                    let name = source.name().to_string();
-                    let (hash, size) = db
-                        .import(source.path().to_owned(), mode, import_progress)
-                        .await?;
-                    io::Result::Ok((Blob { hash, name }, size))
-                }
+        progress
+            .send(ShareProgress::NetworkDone {
+                bytes_written: stats.bytes_written,
+                bytes_read: stats.bytes_read,
+                elapsed: stats.elapsed,
             })
-            .buffered(IO_PARALLELISM)
-            .try_collect::<Vec<_>>()
             .await?;
-        let total_blobs_size = result.iter().map(|(_, size)| *size).sum();
-        let blobs = result.into_iter().map(|(blob, _)| blob).collect::<Vec<_>>();
-        let collection = Collection::new(blobs, total_blobs_size)?;
-        let data = collection.to_bytes()?;
-        let hash = self.inner.db.import_bytes(data.into()).await?;
-        progress.send(ProvideProgress::AllDone { hash }).await?;
-
-        self.inner
-            .callbacks
-            .send(Event::ByteProvide(
-                iroh_bytes::provider::Event::CollectionAdded { hash },
-            ))
-            .await;
-
+        if let Some(out) = download.out {
+            if let Err(cause) = self
+                .clone()
+                .export(
+                    out,
+                    hash,
+                    download.recursive,
+                    download.in_place,
+                    progress.clone(),
+                )
+                .await
+            {
+                progress.send(ShareProgress::Abort(cause.into())).await?;
+            }
+        }
+        progress.send(ShareProgress::AllDone).await?;
         Ok(())
     }
 
-    #[cfg(not(feature = "iroh-collection"))]
-    async fn provide0(
+    /// Get the running transfer for the data of `download`, or start a new one.
+    ///
+    /// Only the download that starts the transfer gets progress updates for it.
+    fn transfer(
+        &self,
+        download: &Download,
+        progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
+    ) -> Transfer {
+        self.inner.downloads.transfer(download, || {
+            let this = self.clone();
+            let download = download.clone();
+            let task = self.rt().local_pool().spawn_pinned(move || async move {
+                let _permit = this.inner.downloads.start_transfer(&download).await;
+                let res = this.transfer0(&download, progress).await;
+                this.inner.downloads.transfer_done(&download);
+                res
+            });
+            let abort = task.abort_handle();
+            let transfer = task
+                .map(|res| match res {
+                    Ok(res) => res.map_err(Arc::new),
+                    Err(cause) => Err(Arc::new(cause.into())),
+                })
+                .boxed()
+                .shared();
+            (transfer, abort)
+        })
+    }
+
+    /// Download the data of `download`, trying the providers in order.
+    ///
+    /// Data that is already in the store is not downloaded again.
+    async fn transfer0(
+        &self,
+        download: &Download,
+        progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
+    ) -> anyhow::Result<Stats> {
+        let mut last_error = None;
+        for (i, provider) in download.providers.iter().enumerate() {
+            let res = async {
+                let conn = self
+                    .inner
+                    .endpoint
+                    .connect(
+                        provider.peer,
+                        &iroh_bytes::protocol::ALPN,
+                        provider.derp_region,
+                        &provider.addrs,
+                    )
+                    .await?;
+                progress.send(ShareProgress::Connected).await?;
+                self.clone()
+                    .get(
+                        conn,
+                        download.hash,
+                        download.recursive,
+                        download.token.clone(),
+                        progress.clone(),
+                    )
+                    .await
+            }
+            .await;
+            match res {
+                Ok(stats) => return Ok(stats),
+                Err(cause) => {
+                    if download.providers.len() > 1 {
+                        progress
+                            .send(ShareProgress::ProviderFailed {
+                                provider: i as u64,
+                                error: anyhow::anyhow!("{:#}", cause).into(),
+                            })
+                            .await?;
+                    }
+                    last_error = Some(cause);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no providers")))
+    }
+
+    fn list_downloads(
+        self,
+        _msg: ListDownloadsRequest,
+    ) -> impl Stream<Item = ListDownloadsResponse> + Send + 'static {
+        let downloads = self.inner.downloads.list();
+        futures::stream::iter(downloads.into_iter().map(|(id, download, status)| {
+            ListDownloadsResponse {
+                id,
+                hash: download.hash,
+                recursive: download.recursive,
+                providers: download.providers.iter().map(|p| p.peer).collect(),
+                out: download.out,
+                status,
+            }
+        }))
+    }
+
+    async fn cancel_download(self, msg: CancelDownloadRequest) -> RpcResult<()> {
+        self.inner.downloads.cancel(msg.id)?;
+        Ok(())
+    }
+
+    async fn find_providers(self, msg: FindProvidersRequest) -> RpcResult<FindProvidersResponse> {
+        #[cfg(feature = "gossip")]
+        if let Some(index) = &self.inner.providers {
+            return Ok(FindProvidersResponse {
+                providers: index.providers(&msg.hash),
+            });
+        }
+        #[cfg(not(feature = "gossip"))]
+        let _ = msg;
+        Err(anyhow::anyhow!("content announcements are not enabled").into())
+    }
+
+    #[cfg(feature = "iroh-collection")]
+    async fn provide0(
+        self,
+        msg: ProvideRequest,
+        progress: flume::Sender<ProvideProgress>,
+    ) -> anyhow::Result<()> {
+        let progress = FlumeProgressSender::new(progress);
+        let root = &msg.path;
+        anyhow::ensure!(root.is_absolute(), "path must be absolute");
+        anyhow::ensure!(
+            root.is_dir() || root.is_file(),
+            "path must be either a Directory or a File"
+        );
+        if msg.watch {
+            self.watch_collection(&msg, &progress).await?;
+        } else {
+            let mut cache = BTreeMap::new();
+            self.provide_collection(&msg, None, &mut cache, &progress)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Provide a collection for the path of `msg`, then watch it for changes and provide a
+    /// new collection after each change.
+    ///
+    /// This runs until the receiver of `progress` is dropped.
+    #[cfg(feature = "watch")]
+    async fn watch_collection(
+        &self,
+        msg: &ProvideRequest,
+        progress: &FlumeProgressSender<ProvideProgress>,
+    ) -> anyhow::Result<()> {
+        // start watching before the initial import, so no change is missed
+        let (_watcher, mut changes) = crate::util::fs::watch_path(&msg.path)?;
+        let mut cache = BTreeMap::new();
+        let mut current = None;
+        loop {
+            if msg.path.exists() {
+                match self
+                    .provide_collection(msg, current, &mut cache, progress)
+                    .await
+                {
+                    Ok(hash) => current = Some(hash),
+                    Err(cause) => {
+                        // keep providing the previous collection, and retry on the next change
+                        tracing::warn!("failed to rebuild watched collection: {:#}", cause);
+                        progress
+                            .send(ProvideProgress::RebuildFailed(cause.into()))
+                            .await?;
+                    }
+                }
+            }
+            if changes.recv().await.is_none() {
+                break;
+            }
+            // wait for a burst of changes to settle before rebuilding
+            tokio::time::sleep(WATCH_DEBOUNCE).await;
+            while changes.try_recv().is_ok() {}
+        }
+        Ok(())
+    }
+
+    #[cfg(all(feature = "iroh-collection", not(feature = "watch")))]
+    async fn watch_collection(
+        &self,
+        _msg: &ProvideRequest,
+        _progress: &FlumeProgressSender<ProvideProgress>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("watching paths is not supported");
+    }
+
+    /// Import the path of `msg` into a collection, pin it and announce it.
+    ///
+    /// Files whose size and modification time match an entry in `cache` are not imported
+    /// again. If the resulting collection is the same as `previous` it is not announced
+    /// again, otherwise `previous` is unpinned.
+    #[cfg(feature = "iroh-collection")]
+    async fn provide_collection(
+        &self,
+        msg: &ProvideRequest,
+        previous: Option<Hash>,
+        cache: &mut FileCache,
+        progress: &FlumeProgressSender<ProvideProgress>,
+    ) -> anyhow::Result<Hash> {
+        use iroh_bytes::baomap::{ImportMode, ImportProgress};
+        use std::sync::Mutex;
+
+        anyhow::ensure!(
+            msg.tree || !msg.chunked,
+            "chunked files are only supported in trees"
+        );
+        let mode = if msg.chunked {
+            ImportMode::Chunked
+        } else if msg.in_place {
+            ImportMode::TryReference
+        } else {
+            ImportMode::Copy
+        };
+        // prevent gc from deleting the blobs before the collection is pinned
+        let _guard = self.inner.gc_lock.read().await;
+        let names = Arc::new(Mutex::new(BTreeMap::new()));
+        // convert import progress to provide progress
+        let import_progress = progress.clone().with_filter_map(move |x| match x {
+            ImportProgress::Found { id, path, .. } => {
+                names.lock().unwrap().insert(id, path);
+                None
+            }
+            ImportProgress::Size { id, size } => {
+                let path = names.lock().unwrap().remove(&id)?;
+                Some(ProvideProgress::Found {
+                    id,
+                    name: path.display().to_string(),
+                    size,
+                })
+            }
+            ImportProgress::OutboardProgress { id, offset } => {
+                Some(ProvideProgress::Progress { id, offset })
+            }
+            ImportProgress::Chunk {
+                id, offset, size, ..
+            } => Some(ProvideProgress::Progress {
+                id,
+                offset: offset + size,
+            }),
+            ImportProgress::OutboardDone { hash, id } => Some(ProvideProgress::Done { hash, id }),
+            _ => None,
+        });
+        // only keep the files that are still there in the cache
+        let cached = std::mem::take(cache);
+        let hash = if msg.tree {
+            let (hash, _) = self
+                .import_tree(msg.path.clone(), mode, &cached, cache, import_progress)
+                .await?;
+            hash
+        } else {
+            self.import_flat(&msg.path, mode, &cached, cache, import_progress)
+                .await?
+        };
+        if previous == Some(hash) {
+            progress.send(ProvideProgress::AllDone { hash }).await?;
+            return Ok(hash);
+        }
+        self.inner.db.pin(hash).await?;
+        if let Some(tag) = &msg.tag {
+            self.inner.db.set_tag(tag.clone(), hash).await?;
+        }
+        if let Some(previous) = previous {
+            // let gc reclaim the superseded collection
+            self.inner.db.unpin(previous).await?;
+        }
+        progress.send(ProvideProgress::AllDone { hash }).await?;
+
+        self.inner
+            .callbacks
+            .send(Event::ByteProvide(
+                iroh_bytes::provider::Event::CollectionAdded { hash },
+            ))
+            .await;
+
+        Ok(hash)
+    }
+
+    /// Import all files below `root` into a flat collection.
+    #[cfg(feature = "iroh-collection")]
+    async fn import_flat(
+        &self,
+        root: &std::path::Path,
+        mode: iroh_bytes::baomap::ImportMode,
+        cached: &FileCache,
+        cache: &mut FileCache,
+        progress: impl ProgressSender<Msg = iroh_bytes::baomap::ImportProgress> + IdGenerator,
+    ) -> anyhow::Result<Hash> {
+        use crate::collection::{Blob, Collection};
+        use futures::TryStreamExt;
+
+        let data_sources = crate::util::fs::scan_path(root.to_owned())?;
+        let result: Vec<(Blob, PathBuf, (u64, SystemTime, Hash))> =
+            futures::stream::iter(data_sources)
+                .map(|source| {
+                    let progress = progress.clone();
+                    let cached = cached.get(source.path()).copied();
+                    async move {
+                        let name = source.name().to_string();
+                        let path = source.path().to_owned();
+                        let entry = self
+                            .import_file(path.clone(), mode, cached, progress)
+                            .await?;
+                        anyhow::Ok((
+                            Blob {
+                                hash: entry.2,
+                                name,
+                            },
+                            path,
+                            entry,
+                        ))
+                    }
+                })
+                .buffered(IO_PARALLELISM)
+                .try_collect::<Vec<_>>()
+                .await?;
+        let mut total_blobs_size = 0;
+        let mut blobs = Vec::with_capacity(result.len());
+        for (blob, path, entry) in result {
+            total_blobs_size += entry.0;
+            blobs.push(blob);
+            cache.insert(path, entry);
+        }
+        let collection = Collection::new(blobs, total_blobs_size)?;
+        let data = collection.to_bytes()?;
+        let hash = self.inner.db.import_bytes(data.into()).await?;
+        Ok(hash)
+    }
+
+    /// Import the file or directory at `path` as a tree of [`Directory`] collections.
+    ///
+    /// Returns the hash and size of the [`Directory`] for `path`. A single file is wrapped
+    /// in a [`Directory`] that contains just this file.
+    #[cfg(feature = "iroh-collection")]
+    fn import_tree<'a, P>(
+        &'a self,
+        path: PathBuf,
+        mode: iroh_bytes::baomap::ImportMode,
+        cached: &'a FileCache,
+        cache: &'a mut FileCache,
+        progress: P,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(Hash, u64)>>
+    where
+        P: ProgressSender<Msg = iroh_bytes::baomap::ImportProgress> + IdGenerator + 'a,
+    {
+        use futures::TryStreamExt;
+
+        async move {
+            let children = if path.is_dir() {
+                let mut children = Vec::new();
+                let mut read_dir = tokio::fs::read_dir(&path).await?;
+                while let Some(child) = read_dir.next_entry().await? {
+                    children.push(child.path());
+                }
+                children
+            } else {
+                vec![path]
+            };
+            let mut entries = Vec::with_capacity(children.len());
+            let mut files = Vec::new();
+            for child in children {
+                let name = child
+                    .file_name()
+                    .context("path must have a file name")?
+                    .to_str()
+                    .context("invalid character in path")?
+                    .to_owned();
+                let meta = tokio::fs::symlink_metadata(&child).await?;
+                let file_mode = crate::util::fs::file_mode(&meta);
+                let mtime = meta
+                    .modified()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                if meta.is_dir() {
+                    let (hash, size) = self
+                        .import_tree(child, mode, cached, cache, progress.clone())
+                        .await?;
+                    entries.push(Entry {
+                        name,
+                        mode: file_mode,
+                        mtime,
+                        kind: EntryKind::Directory { hash, size },
+                    });
+                } else if meta.file_type().is_symlink() {
+                    let target = tokio::fs::read_link(&child).await?;
+                    let target = target
+                        .to_str()
+                        .context("invalid character in symlink target")?
+                        .to_owned();
+                    entries.push(Entry {
+                        name,
+                        mode: file_mode,
+                        mtime,
+                        kind: EntryKind::Symlink { target },
+                    });
+                } else if meta.is_file() {
+                    files.push((child, name, file_mode, mtime));
+                }
+                // anything else, like sockets or devices, is skipped
+            }
+            let imported = futures::stream::iter(files)
+                .map(|(path, name, file_mode, mtime)| {
+                    let progress = progress.clone();
+                    let cached = cached.get(&path).copied();
+                    async move {
+                        let entry = self
+                            .import_file(path.clone(), mode, cached, progress)
+                            .await?;
+                        anyhow::Ok((path, name, file_mode, mtime, entry))
+                    }
+                })
+                .buffered(IO_PARALLELISM)
+                .try_collect::<Vec<_>>()
+                .await?;
+            for (path, name, file_mode, mtime, entry) in imported {
+                let (size, _, hash) = entry;
+                let kind = if mode == iroh_bytes::baomap::ImportMode::Chunked {
+                    EntryKind::Chunked { hash, size }
+                } else {
+                    EntryKind::File { hash, size }
+                };
+                entries.push(Entry {
+                    name,
+                    mode: file_mode,
+                    mtime,
+                    kind,
+                });
+                cache.insert(path, entry);
+            }
+            let data = Directory::new(entries)?.to_bytes()?;
+            let size = data.len() as u64;
+            let hash = self.inner.db.import_bytes(data.into()).await?;
+            Ok((hash, size))
+        }
+        .boxed_local()
+    }
+
+    /// Import a single file.
+    ///
+    /// If the size and modification time of the file match `cached`, and the cached blob
+    /// is still in the store, the file is not imported again.
+    ///
+    /// Returns the size, modification time and hash of the file.
+    #[cfg(feature = "iroh-collection")]
+    async fn import_file(
+        &self,
+        path: PathBuf,
+        mode: iroh_bytes::baomap::ImportMode,
+        cached: Option<(u64, SystemTime, Hash)>,
+        progress: impl ProgressSender<Msg = iroh_bytes::baomap::ImportProgress> + IdGenerator,
+    ) -> anyhow::Result<(u64, SystemTime, Hash)> {
+        use iroh_bytes::baomap::ImportProgress;
+
+        let meta = tokio::fs::metadata(&path).await?;
+        let modified = meta.modified()?;
+        match cached {
+            Some((size, time, hash))
+                if size == meta.len() && time == modified && self.inner.db.get(&hash).is_some() =>
+            {
+                let id = progress.new_id();
+                progress.send(ImportProgress::Found { id, path }).await?;
+                progress.send(ImportProgress::Size { id, size }).await?;
+                progress
+                    .send(ImportProgress::OutboardDone { id, hash })
+                    .await?;
+                Ok((size, modified, hash))
+            }
+            _ => {
+                let (hash, size) = self.inner.db.import(path, mode, progress).await?;
+                Ok((size, modified, hash))
+            }
+        }
+    }
+
+    #[cfg(not(feature = "iroh-collection"))]
+    async fn provide0(
         self,
         _msg: ProvideRequest,
         _progress: flume::Sender<ProvideProgress>,
@@ -1043,6 +2142,10 @@ fn handle_rpc_request<D: Store, E: ServiceEndpoint<ProviderService>, C: Collecti
                 chan.server_streaming(msg, handler, RpcHandler::list_collections)
                     .await
             }
+            ListTags(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::list_tags)
+                    .await
+            }
             Provide(msg) => {
                 chan.server_streaming(msg, handler, RpcHandler::provide)
                     .await
@@ -1057,6 +2160,18 @@ fn handle_rpc_request<D: Store, E: ServiceEndpoint<ProviderService>, C: Collecti
                 chan.server_streaming(msg, handler, RpcHandler::validate)
                     .await
             }
+            DeleteBlob(msg) => chan.rpc(msg, handler, RpcHandler::delete_blob).await,
+            ExportSlice(msg) => chan.rpc(msg, handler, RpcHandler::export_slice).await,
+            ImportSlice(msg) => chan.rpc(msg, handler, RpcHandler::import_slice).await,
+            Gc(msg) => chan.server_streaming(msg, handler, RpcHandler::gc).await,
+            SetTag(msg) => chan.rpc(msg, handler, RpcHandler::set_tag).await,
+            DeleteTag(msg) => chan.rpc(msg, handler, RpcHandler::delete_tag).await,
+            ListDownloads(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::list_downloads)
+                    .await
+            }
+            CancelDownload(msg) => chan.rpc(msg, handler, RpcHandler::cancel_download).await,
+            FindProviders(msg) => chan.rpc(msg, handler, RpcHandler::find_providers).await,
         }
     });
 }
@@ -1100,6 +2215,8 @@ impl StaticTokenAuthHandler {
 impl RequestAuthorizationHandler for StaticTokenAuthHandler {
     fn authorize(
         &self,
+        _peer_id: PeerId,
+        _remote_addr: SocketAddr,
         token: Option<RequestToken>,
         _request: &Request,
     ) -> BoxFuture<'static, anyhow::Result<()>> {
@@ -1134,6 +2251,65 @@ impl RequestAuthorizationHandler for StaticTokenAuthHandler {
     }
 }
 
+/// Authorize requests based on the [`PeerId`] of the requester
+///
+/// Requests from denied peers are always rejected. If an allow list is set, requests from
+/// peers not on it are rejected as well. All other requests are passed on to the wrapped
+/// handler, so this can be combined with token based authorization.
+#[derive(Debug, Clone)]
+pub struct PeerListAuthHandler {
+    allow: Option<HashSet<PeerId>>,
+    deny: HashSet<PeerId>,
+    inner: Arc<dyn RequestAuthorizationHandler>,
+}
+
+impl PeerListAuthHandler {
+    /// Creates a new handler that allows all peers, and passes requests on to `inner`.
+    pub fn new(inner: Arc<dyn RequestAuthorizationHandler>) -> Self {
+        Self {
+            allow: None,
+            deny: HashSet::new(),
+            inner,
+        }
+    }
+
+    /// Only allow requests from the given peers.
+    pub fn allow(mut self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.allow = Some(peers.into_iter().collect());
+        self
+    }
+
+    /// Reject requests from the given peers.
+    pub fn deny(mut self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.deny = peers.into_iter().collect();
+        self
+    }
+
+    /// Check that `peer_id` is allowed to make requests.
+    pub fn check(&self, peer_id: &PeerId) -> Result<()> {
+        anyhow::ensure!(!self.deny.contains(peer_id), "peer {peer_id} is denied");
+        if let Some(allow) = &self.allow {
+            anyhow::ensure!(allow.contains(peer_id), "peer {peer_id} is not allowed");
+        }
+        Ok(())
+    }
+}
+
+impl RequestAuthorizationHandler for PeerListAuthHandler {
+    fn authorize(
+        &self,
+        peer_id: PeerId,
+        remote_addr: SocketAddr,
+        token: Option<RequestToken>,
+        request: &Request,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        match self.check(&peer_id) {
+            Ok(()) => self.inner.authorize(peer_id, remote_addr, token, request),
+            Err(err) => async move { Err(err) }.boxed(),
+        }
+    }
+}
+
 #[cfg(all(test, feature = "flat-db"))]
 mod tests {
     use anyhow::bail;
@@ -1199,6 +2375,10 @@ mod tests {
                 .server_streaming(ProvideRequest {
                     path: Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md"),
                     in_place: false,
+                    tag: None,
+                    watch: false,
+                    tree: false,
+                    chunked: false,
                 })
                 .await?;
 
@@ -1224,4 +2404,768 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(all(feature = "mem-db", feature = "iroh-collection"))]
+    #[tokio::test]
+    async fn test_node_gc() -> Result<()> {
+        let rt = test_runtime();
+        let db = crate::baomap::mem::Store::new(rt.clone());
+        let node = Node::builder(db.clone())
+            .collection_parser(crate::collection::IrohCollectionParser)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        // data that is added as a collection is pinned
+        let readme = Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md");
+        let mut stream = node
+            .controller()
+            .server_streaming(ProvideRequest {
+                path: readme.clone(),
+                in_place: false,
+                tag: None,
+                watch: false,
+                tree: false,
+                chunked: false,
+            })
+            .await?;
+        let mut collection = None;
+        while let Some(item) = stream.next().await {
+            if let ProvideProgress::AllDone { hash } = item? {
+                collection = Some(hash);
+            }
+        }
+        let collection = collection.context("provide did not complete")?;
+        let child = Hash::new(std::fs::read(readme)?);
+
+        // data that is not reachable from a root is not
+        let garbage = db.import_bytes(Bytes::from_static(b"garbage")).await?;
+
+        let mut stream = node.controller().server_streaming(GcRequest).await?;
+        let mut deleted = Vec::new();
+        while let Some(item) = stream.next().await {
+            match item? {
+                GcProgress::Deleted { hash } => deleted.push(hash),
+                GcProgress::Abort(e) => bail!("gc failed: {e}"),
+                _ => {}
+            }
+        }
+        assert_eq!(deleted, vec![garbage]);
+        assert!(db.get(&garbage).is_none());
+        assert!(db.get(&collection).is_some());
+        assert!(db.get(&child).is_some());
+
+        // deleting a blob also unpins it
+        node.controller()
+            .rpc(DeleteBlobRequest { hash: collection })
+            .await??;
+        assert!(db.get(&collection).is_none());
+        assert_eq!(db.roots().count(), 0);
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "mem-db", feature = "iroh-collection"))]
+    #[tokio::test]
+    async fn test_node_tags() -> Result<()> {
+        let rt = test_runtime();
+        let db = crate::baomap::mem::Store::new(rt.clone());
+        let node = Node::builder(db.clone())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        let mut stream = node
+            .controller()
+            .server_streaming(ProvideRequest {
+                path: Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md"),
+                in_place: false,
+                tag: Some("readme".to_string()),
+                watch: false,
+                tree: false,
+                chunked: false,
+            })
+            .await?;
+        let mut collection = None;
+        while let Some(item) = stream.next().await {
+            if let ProvideProgress::AllDone { hash } = item? {
+                collection = Some(hash);
+            }
+        }
+        let collection = collection.context("provide did not complete")?;
+
+        let tags = node
+            .controller()
+            .server_streaming(ListTagsRequest)
+            .await?
+            .map(|item| item.map(|tag| (tag.name, tag.hash)))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(tags, vec![("readme".to_string(), collection)]);
+
+        let ticket = node.ticket_for_tag("readme").await?;
+        assert_eq!(ticket.hash(), collection);
+        assert!(node.ticket_for_tag("unknown").await.is_err());
+
+        node.controller()
+            .rpc(DeleteTagRequest {
+                name: "readme".to_string(),
+            })
+            .await??;
+        assert_eq!(db.tags().count(), 0);
+        assert!(node.ticket_for_tag("readme").await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "mem-db", feature = "iroh-collection"))]
+    #[tokio::test]
+    async fn test_node_tree() -> Result<()> {
+        use crate::collection::tree::TreeCollectionParser;
+
+        let rt = test_runtime();
+        let db = crate::baomap::mem::Store::new(rt.clone());
+        let provider = Node::builder(db.clone())
+            .collection_parser(TreeCollectionParser)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("sub/nested"))?;
+        std::fs::create_dir_all(src.join("empty"))?;
+        std::fs::write(src.join("a.txt"), b"a")?;
+        std::fs::write(src.join("sub/b.txt"), b"b")?;
+        std::fs::write(src.join("sub/nested/c.txt"), b"c")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::os::unix::fs::symlink("sub/b.txt", src.join("link"))?;
+            std::fs::set_permissions(src.join("a.txt"), std::fs::Permissions::from_mode(0o755))?;
+        }
+        let mut stream = provider
+            .controller()
+            .server_streaming(ProvideRequest {
+                path: src.clone(),
+                in_place: false,
+                tag: None,
+                watch: false,
+                tree: true,
+                chunked: false,
+            })
+            .await?;
+        let mut root = None;
+        while let Some(item) = stream.next().await {
+            match item? {
+                ProvideProgress::AllDone { hash } => root = Some(hash),
+                ProvideProgress::Abort(e) => bail!("provide failed: {e}"),
+                _ => {}
+            }
+        }
+        let root = root.context("provide did not complete")?;
+        // nested blobs are reachable from the root, so gc keeps them
+        provider
+            .controller()
+            .server_streaming(GcRequest)
+            .await?
+            .for_each(|_| async {})
+            .await;
+        assert!(db.get(&Hash::new(b"c")).is_some());
+
+        let db2 = crate::baomap::mem::Store::new(rt.clone());
+        let getter = Node::builder(db2)
+            .collection_parser(TreeCollectionParser)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard2 = getter.cancel_token().drop_guard();
+        let out = dir.path().join("out");
+        let mut stream = getter
+            .controller()
+            .server_streaming(ShareRequest {
+                hash: root,
+                recursive: true,
+                peer: provider.peer_id(),
+                addrs: provider.local_endpoint_addresses().await?,
+                token: None,
+                derp_region: None,
+                out: Some(out.display().to_string()),
+                in_place: false,
+            })
+            .await?;
+        while let Some(item) = stream.next().await {
+            match item? {
+                ShareProgress::AllDone => break,
+                ShareProgress::Abort(e) => bail!("share failed: {e}"),
+                _ => {}
+            }
+        }
+        assert_eq!(std::fs::read(out.join("a.txt"))?, b"a");
+        assert_eq!(std::fs::read(out.join("sub/b.txt"))?, b"b");
+        assert_eq!(std::fs::read(out.join("sub/nested/c.txt"))?, b"c");
+        assert!(out.join("empty").is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::read_link(out.join("link"))?,
+                Path::new("sub/b.txt")
+            );
+            let mode = std::fs::metadata(out.join("a.txt"))?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+        let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified());
+        assert_eq!(
+            mtime(&out.join("sub/nested/c.txt"))?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            mtime(&src.join("sub/nested/c.txt"))?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+        );
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "mem-db", feature = "iroh-collection"))]
+    #[tokio::test]
+    async fn test_node_chunked() -> Result<()> {
+        use crate::collection::tree::TreeCollectionParser;
+        use iroh_bytes::chunked::Manifest;
+        use rand::{Rng, SeedableRng};
+
+        async fn read(db: &crate::baomap::mem::Store, hash: &Hash) -> Result<Bytes> {
+            use iroh_io::AsyncSliceReaderExt;
+            let entry = db.get(hash).context("blob not there")?;
+            Ok(entry.data_reader().await?.read_to_end().await?)
+        }
+
+        /// Provide `path` as a chunked tree, and return the manifest of the file `big`
+        async fn provide(
+            node: &Node<crate::baomap::mem::Store>,
+            path: &Path,
+        ) -> Result<(Hash, Manifest)> {
+            let mut stream = node
+                .controller()
+                .server_streaming(ProvideRequest {
+                    path: path.to_owned(),
+                    in_place: false,
+                    tag: None,
+                    watch: false,
+                    tree: true,
+                    chunked: true,
+                })
+                .await?;
+            let mut root = None;
+            while let Some(item) = stream.next().await {
+                match item? {
+                    ProvideProgress::AllDone { hash } => root = Some(hash),
+                    ProvideProgress::Abort(e) => bail!("provide failed: {e}"),
+                    _ => {}
+                }
+            }
+            let root = root.context("provide did not complete")?;
+            let db = &node.inner.db;
+            let dir = Directory::from_bytes(&read(db, &root).await?)?;
+            let EntryKind::Chunked { hash, .. } = dir.entries()[0].kind else {
+                bail!("big is not chunked");
+            };
+            let manifest = Manifest::from_bytes(&read(db, &hash).await?)?;
+            Ok((root, manifest))
+        }
+
+        let rt = test_runtime();
+        let db = crate::baomap::mem::Store::new(rt.clone());
+        let provider = Node::builder(db.clone())
+            .collection_parser(TreeCollectionParser)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(&src)?;
+        let mut data = vec![0u8; 6 * 1024 * 1024];
+        rand::rngs::StdRng::seed_from_u64(0).fill(&mut data[..]);
+        std::fs::write(src.join("big"), &data)?;
+        let (_, before) = provide(&provider, &src).await?;
+        assert!(before.chunks().len() > 1);
+        assert_eq!(before.size(), data.len() as u64);
+
+        // an edit in the middle of the file only changes the chunks around it
+        data.splice(3_000_000..3_000_000, b"hello world".iter().copied());
+        std::fs::write(src.join("big"), &data)?;
+        let (root, after) = provide(&provider, &src).await?;
+        let shared = after
+            .chunks()
+            .iter()
+            .filter(|chunk| before.chunks().contains(chunk))
+            .count();
+        assert!(shared > 0 && shared + 3 >= after.chunks().len());
+
+        // chunks are reachable from the root, so gc keeps them
+        provider
+            .controller()
+            .server_streaming(GcRequest)
+            .await?
+            .for_each(|_| async {})
+            .await;
+        for chunk in after.chunks() {
+            assert!(db.get(&chunk.hash).is_some());
+        }
+
+        let db2 = crate::baomap::mem::Store::new(rt.clone());
+        let getter = Node::builder(db2)
+            .collection_parser(TreeCollectionParser)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard2 = getter.cancel_token().drop_guard();
+        let out = dir.path().join("out");
+        let mut stream = getter
+            .controller()
+            .server_streaming(ShareRequest {
+                hash: root,
+                recursive: true,
+                peer: provider.peer_id(),
+                addrs: provider.local_endpoint_addresses().await?,
+                token: None,
+                derp_region: None,
+                out: Some(out.display().to_string()),
+                in_place: false,
+            })
+            .await?;
+        while let Some(item) = stream.next().await {
+            match item? {
+                ShareProgress::AllDone => break,
+                ShareProgress::Abort(e) => bail!("share failed: {e}"),
+                _ => {}
+            }
+        }
+        assert_eq!(std::fs::read(out.join("big"))?, data);
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "mem-db", feature = "watch"))]
+    #[tokio::test]
+    async fn test_node_watch() -> Result<()> {
+        let rt = test_runtime();
+        let db = crate::baomap::mem::Store::new(rt.clone());
+        let node = Node::builder(db.clone())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path().canonicalize()?;
+        std::fs::write(dir.join("a.txt"), b"first")?;
+        std::fs::write(dir.join("b.txt"), b"unchanged")?;
+        let mut stream = node
+            .controller()
+            .server_streaming(ProvideRequest {
+                path: dir.clone(),
+                in_place: false,
+                tag: Some("watched".to_string()),
+                watch: true,
+                tree: false,
+                chunked: false,
+            })
+            .await?;
+        async fn next_collection<E: std::error::Error + Send + Sync + 'static>(
+            stream: &mut (impl Stream<Item = std::result::Result<ProvideProgress, E>> + Unpin),
+        ) -> Result<Hash> {
+            while let Some(item) = stream.next().await {
+                match item? {
+                    ProvideProgress::AllDone { hash } => return Ok(hash),
+                    ProvideProgress::RebuildFailed(cause) => bail!("rebuild failed: {cause}"),
+                    ProvideProgress::Abort(cause) => bail!("provide failed: {cause}"),
+                    _ => {}
+                }
+            }
+            bail!("stream ended")
+        }
+        let first = next_collection(&mut stream).await?;
+        assert_eq!(db.roots().collect::<Vec<_>>(), vec![first]);
+
+        std::fs::write(dir.join("a.txt"), b"second")?;
+        let second = tokio::time::timeout(Duration::from_secs(10), next_collection(&mut stream))
+            .await
+            .context("no new collection after change")??;
+        assert_ne!(first, second);
+        // the superseded collection is unpinned, and the tag is moved
+        assert_eq!(db.roots().collect::<Vec<_>>(), vec![second]);
+        let tags = db.tags().map(|(_, info)| info.hash).collect::<Vec<_>>();
+        assert_eq!(tags, vec![second]);
+
+        // a failed rebuild is reported, and watching continues
+        #[cfg(unix)]
+        {
+            std::fs::write(dir.join("invalid\\name"), b"invalid")?;
+            let res = tokio::time::timeout(Duration::from_secs(10), next_collection(&mut stream))
+                .await
+                .context("no error after change")?;
+            assert!(res.is_err());
+            assert_eq!(db.roots().collect::<Vec<_>>(), vec![second]);
+            std::fs::remove_file(dir.join("invalid\\name"))?;
+            std::fs::write(dir.join("a.txt"), b"third")?;
+            let third = tokio::time::timeout(Duration::from_secs(10), next_collection(&mut stream))
+                .await
+                .context("no new collection after error")??;
+            assert_ne!(second, third);
+            assert_eq!(db.roots().collect::<Vec<_>>(), vec![third]);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "mem-db")]
+    #[tokio::test]
+    async fn test_node_slices() -> Result<()> {
+        use bao_tree::ChunkNum;
+        use iroh_bytes::baomap::{range_collections::RangeSet2, PartialMap};
+        use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
+
+        let rt = test_runtime();
+        let db = crate::baomap::mem::Store::new(rt.clone());
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let hash = db.import_bytes(data.clone().into()).await?;
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        let db2 = crate::baomap::mem::Store::new(rt.clone());
+        let node2 = Node::builder(db2.clone())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard2 = node2.cancel_token().drop_guard();
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path().canonicalize()?;
+
+        // export a part of the blob, the range is limited to the size of the blob
+        let part = dir.join("part.slice");
+        let ranges: RangeSet2<ChunkNum> = RangeSet2::from(ChunkNum(40)..ChunkNum(60))
+            .union(&RangeSet2::from(ChunkNum(90)..ChunkNum(1000)));
+        let response = node
+            .controller()
+            .rpc(ExportSliceRequest {
+                hash,
+                ranges: RangeSpec::new(&ranges),
+                path: part.clone(),
+            })
+            .await??;
+        let expected: RangeSet2<ChunkNum> = RangeSet2::from(ChunkNum(40)..ChunkNum(60))
+            .union(&RangeSet2::from(ChunkNum(90)..ChunkNum(98)));
+        assert_eq!(response.ranges.to_chunk_ranges(), expected);
+
+        let response = node2
+            .controller()
+            .rpc(ImportSliceRequest { path: part.clone() })
+            .await??;
+        assert_eq!(response.hash, hash);
+        assert_eq!(response.size, data.len() as u64);
+        assert!(!response.complete);
+        let entry = db2.get_partial(&hash).context("no partial entry")?;
+        let read = entry
+            .data_reader()
+            .await?
+            .read_at(40 * 1024, 20 * 1024)
+            .await?;
+        assert_eq!(&read[..], &data[40 * 1024..60 * 1024]);
+
+        // a partial entry can be exported again
+        let again = dir.join("again.slice");
+        node2
+            .controller()
+            .rpc(ExportSliceRequest {
+                hash,
+                ranges: RangeSpec::new(RangeSet2::from(ChunkNum(48)..ChunkNum(60))),
+                path: again,
+            })
+            .await??;
+
+        // tampered data is rejected
+        let mut bytes = std::fs::read(&part)?;
+        let n = bytes.len();
+        bytes[n - 100] ^= 1;
+        let tampered = dir.join("tampered.slice");
+        std::fs::write(&tampered, bytes)?;
+        let res = node2
+            .controller()
+            .rpc(ImportSliceRequest { path: tampered })
+            .await?;
+        assert!(res.is_err());
+
+        // importing everything completes the blob
+        let all = dir.join("all.slice");
+        node.controller()
+            .rpc(ExportSliceRequest {
+                hash,
+                ranges: RangeSpec::all(),
+                path: all.clone(),
+            })
+            .await??;
+        let response = node2
+            .controller()
+            .rpc(ImportSliceRequest { path: all })
+            .await??;
+        assert!(response.complete);
+        assert!(db2.get_partial(&hash).is_none());
+        let entry = db2.get(&hash).context("no entry")?;
+        let read = entry.data_reader().await?.read_to_end().await?;
+        assert_eq!(&read[..], &data[..]);
+
+        Ok(())
+    }
+
+    /// Echoes the first bi stream of a connection.
+    #[derive(Debug)]
+    struct Echo;
+
+    impl ProtocolHandler for Echo {
+        fn accept(&self, conn: quinn::Connecting) -> BoxFuture<'static, Result<()>> {
+            async move {
+                let conn = conn.await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                tokio::io::copy(&mut recv, &mut send).await?;
+                send.finish().await?;
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_custom_protocol() -> Result<()> {
+        const ECHO_ALPN: &[u8] = b"/iroh-test/echo/0";
+        let rt = test_runtime();
+        let (db, _) = crate::baomap::readonly_mem::Store::new([("test", b"hello")]);
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+            .accept(ECHO_ALPN, Arc::new(Echo))
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        let endpoint = MagicEndpoint::builder()
+            .alpns(vec![ECHO_ALPN.to_vec()])
+            .bind(0)
+            .await?;
+        let addrs = node.local_endpoint_addresses().await?;
+        let conn = endpoint
+            .connect(node.peer_id(), ECHO_ALPN, None, &addrs)
+            .await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello echo").await?;
+        send.finish().await?;
+        let data = recv.read_to_end(1024).await?;
+        assert_eq!(&data[..], b"hello echo");
+
+        // the iroh-bytes ALPN can not be taken over
+        let (db, _) = crate::baomap::readonly_mem::Store::new([("test", b"hello")]);
+        let res = Node::builder(db)
+            .accept(iroh_bytes::protocol::ALPN, Arc::new(Echo))
+            .runtime(&rt)
+            .spawn()
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "gossip")]
+    #[tokio::test]
+    async fn test_node_gossip() -> Result<()> {
+        use iroh_gossip::{net::Event as GossipEvent, proto::TopicId};
+
+        let rt = test_runtime();
+        let spawn_node = || async {
+            let (db, _) = crate::baomap::readonly_mem::Store::new([("test", b"hello")]);
+            Node::builder(db)
+                .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+                .gossip(Default::default())
+                .runtime(&rt)
+                .spawn()
+                .await
+        };
+        let node1 = spawn_node().await?;
+        let _drop_guard1 = node1.cancel_token().drop_guard();
+        let node2 = spawn_node().await?;
+        let _drop_guard2 = node2.cancel_token().drop_guard();
+        let addrs = node1.local_endpoint_addresses().await?;
+        node2
+            .magic_endpoint()
+            .add_known_addrs(node1.peer_id(), None, &addrs)
+            .await?;
+
+        let topic = TopicId::from([1u8; 32]);
+        let go1 = node1.gossip().context("gossip enabled")?;
+        let go2 = node2.gossip().context("gossip enabled")?;
+        let mut events = go1.subscribe(topic).await?;
+        go1.join(topic, vec![]).await?;
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            go2.join(topic, vec![node1.peer_id()]).await?,
+        )
+        .await??;
+        go2.broadcast(topic, Bytes::from_static(b"hello gossip"))
+            .await?;
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let GossipEvent::Received(msg, _) = events.recv().await? {
+                    break anyhow::Ok(msg);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(&received[..], b"hello gossip");
+        Ok(())
+    }
+
+    async fn list_downloads<D: Store>(node: &Node<D>) -> Result<Vec<ListDownloadsResponse>> {
+        let stream = node
+            .controller()
+            .server_streaming(ListDownloadsRequest)
+            .await?;
+        let downloads = stream.collect::<Vec<_>>().await;
+        Ok(downloads.into_iter().collect::<Result<_, _>>()?)
+    }
+
+    #[cfg(feature = "mem-db")]
+    #[tokio::test]
+    async fn test_download_resume() -> Result<()> {
+        let rt = test_runtime();
+        let (db, hashes) = crate::baomap::readonly_mem::Store::new([("test", b"hello")]);
+        let hash = hashes["test"].into();
+        // the provider only serves requests with the token of the download
+        let token = RequestToken::new(vec![1, 2, 3])?;
+        let provider = Node::builder(db)
+            .custom_auth_handler(Arc::new(StaticTokenAuthHandler::new(Some(token.clone()))))
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+
+        // a queue that was left behind by a node that was stopped
+        let path = testdir::testdir!().join("downloads");
+        let downloads = Downloads::load(Some(path.clone()))?;
+        downloads.add(Download {
+            hash,
+            recursive: false,
+            providers: vec![NodeAddr::new(
+                provider.peer_id(),
+                provider.local_endpoint_addresses().await?,
+                None,
+            )],
+            out: None,
+            in_place: false,
+            token: Some(token),
+        })?;
+        drop(downloads);
+
+        let db2 = crate::baomap::mem::Store::new(rt.clone());
+        let getter = Node::builder(db2.clone())
+            .downloads_path(&path)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard2 = getter.cancel_token().drop_guard();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !list_downloads(&getter).await?.is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            anyhow::Ok(())
+        })
+        .await
+        .context("timeout")??;
+        assert!(db2.get(&hash).is_some());
+        // the finished download is removed from the persisted queue
+        assert!(Downloads::load(Some(path))?.list().is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "mem-db")]
+    #[tokio::test]
+    async fn test_download_cancel() -> Result<()> {
+        let rt = test_runtime();
+        let db = crate::baomap::mem::Store::new(rt.clone());
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        // a provider that does not exist, so the download does not finish
+        let request = || ShareRequest {
+            hash: Hash::new(b"hello"),
+            recursive: false,
+            peer: PeerId::from(Keypair::generate().public()),
+            addrs: vec![(Ipv4Addr::LOCALHOST, 1).into()],
+            token: None,
+            derp_region: None,
+            out: None,
+            in_place: false,
+        };
+        let stream1 = node.controller().server_streaming(request()).await?;
+        let stream2 = node.controller().server_streaming(request()).await?;
+        let downloads = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let downloads = list_downloads(&node).await?;
+                if downloads.len() == 2 {
+                    break anyhow::Ok(downloads);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .context("timeout")??;
+        assert!(downloads.iter().all(|d| d.hash == Hash::new(b"hello")));
+
+        let (id1, id2) = (downloads[0].id, downloads[1].id);
+        node.controller()
+            .rpc(CancelDownloadRequest { id: id1 })
+            .await??;
+        assert!(node
+            .controller()
+            .rpc(CancelDownloadRequest { id: id1 })
+            .await?
+            .is_err());
+        let downloads = list_downloads(&node).await?;
+        assert_eq!(downloads.len(), 1);
+        assert_eq!(downloads[0].id, id2);
+        node.controller()
+            .rpc(CancelDownloadRequest { id: id2 })
+            .await??;
+        assert!(list_downloads(&node).await?.is_empty());
+        // the progress streams of the cancelled downloads end
+        tokio::time::timeout(Duration::from_secs(5), async {
+            stream1.for_each(|_| async {}).await;
+            stream2.for_each(|_| async {}).await;
+        })
+        .await
+        .context("timeout")?;
+        Ok(())
+    }
 }
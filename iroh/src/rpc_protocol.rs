@@ -7,10 +7,15 @@
 //! response, while others like provide have a stream of responses.
 //!
 //! Note that this is subject to change. The RPC protocol is not yet stable.
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, time::SystemTime};
 
 use derive_more::{From, TryInto};
-use iroh_bytes::{protocol::RequestToken, provider::ShareProgress, Hash};
+use iroh_bytes::{
+    protocol::{RangeSpec, RequestToken},
+    provider::ShareProgress,
+    util::RpcResult,
+    Hash,
+};
 use iroh_net::tls::PeerId;
 
 use quic_rpc::{
@@ -19,7 +24,12 @@ use quic_rpc::{
 };
 use serde::{Deserialize, Serialize};
 
-pub use iroh_bytes::{baomap::ValidateProgress, provider::ProvideProgress};
+use crate::dial::NodeAddr;
+pub use crate::downloads::DownloadStatus;
+pub use iroh_bytes::{
+    baomap::{GcProgress, ValidateProgress},
+    provider::ProvideProgress,
+};
 
 /// A request to the node to provide the data at the given path
 ///
@@ -35,6 +45,26 @@ pub struct ProvideRequest {
     /// True if the provider can assume that the data will not change, so it
     /// can be shared in place.
     pub in_place: bool,
+    /// An optional tag to set for the resulting collection.
+    pub tag: Option<String>,
+    /// Keep watching the path after the initial import.
+    ///
+    /// Every time the data changes, the changed files are imported again and the
+    /// collection is rebuilt, followed by another [`ProvideProgress::AllDone`]. If the
+    /// collection changed, the previous one is unpinned and the tag, if any, is moved to
+    /// the new one. If rebuilding fails, a [`ProvideProgress::RebuildFailed`] is sent
+    /// instead and watching continues.
+    pub watch: bool,
+    /// Provide the path as a tree of directory collections, one per directory, which keeps
+    /// empty directories, symlinks, file modes and modification times.
+    ///
+    /// If false, a single flat collection of all files is created.
+    pub tree: bool,
+    /// Split files into content-defined chunks, see [`iroh_bytes::baomap::ImportMode::Chunked`].
+    ///
+    /// New versions of a file then share most of their blobs with the previous ones. This
+    /// requires `tree`, since only directory collections can link to chunked files.
+    pub chunked: bool,
 }
 
 impl Msg<ProviderService> for ProvideRequest {
@@ -100,6 +130,204 @@ impl ServerStreamingMsg<ProviderService> for ValidateRequest {
     type Response = ValidateProgress;
 }
 
+/// A request to the node to set a tag
+///
+/// If a tag with the same name already exists, it will be overwritten.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetTagRequest {
+    /// The name of the tag
+    pub name: String,
+    /// The hash the tag should point to
+    pub hash: Hash,
+}
+
+impl RpcMsg<ProviderService> for SetTagRequest {
+    type Response = RpcResult<()>;
+}
+
+/// A request to the node to delete a tag
+///
+/// This does not delete the data the tag points to.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteTagRequest {
+    /// The name of the tag
+    pub name: String,
+}
+
+impl RpcMsg<ProviderService> for DeleteTagRequest {
+    type Response = RpcResult<()>;
+}
+
+/// List all tags
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListTagsRequest;
+
+/// A response to a list tags request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListTagsResponse {
+    /// The name of the tag
+    pub name: String,
+    /// The hash the tag points to
+    pub hash: Hash,
+    /// The time the tag was created
+    pub created: SystemTime,
+}
+
+impl Msg<ProviderService> for ListTagsRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for ListTagsRequest {
+    type Response = ListTagsResponse;
+}
+
+/// List all downloads in the queue of the node
+///
+/// See [`crate::downloads`] for how downloads are queued.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListDownloadsRequest;
+
+/// A response to a list downloads request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListDownloadsResponse {
+    /// The id of the download, to cancel it
+    pub id: u64,
+    /// The hash of the data that is downloaded
+    pub hash: Hash,
+    /// Whether the children of a collection are downloaded as well
+    pub recursive: bool,
+    /// The peers the data is downloaded from
+    pub providers: Vec<PeerId>,
+    /// The path the data is exported to, if any
+    pub out: Option<String>,
+    /// The status of the download
+    pub status: DownloadStatus,
+}
+
+impl Msg<ProviderService> for ListDownloadsRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for ListDownloadsRequest {
+    type Response = ListDownloadsResponse;
+}
+
+/// A request to the node to cancel a download and remove it from the queue
+///
+/// The data that was already downloaded is kept.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelDownloadRequest {
+    /// The id of the download
+    pub id: u64,
+}
+
+impl RpcMsg<ProviderService> for CancelDownloadRequest {
+    type Response = RpcResult<()>;
+}
+
+/// A request to the node to look up providers of a hash in its index of announced content
+///
+/// See the `announce` module. Fails if the node does not have content announcements enabled.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FindProvidersRequest {
+    /// The hash to look up
+    pub hash: Hash,
+}
+
+/// A response to a find providers request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FindProvidersResponse {
+    /// The providers that announced the hash, most recent first
+    pub providers: Vec<NodeAddr>,
+}
+
+impl RpcMsg<ProviderService> for FindProvidersRequest {
+    type Response = RpcResult<FindProvidersResponse>;
+}
+
+/// A request to the node to delete a blob
+///
+/// This will delete the data and outboard of the blob, regardless of whether it
+/// is complete or partial, and remove it from the set of roots.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteBlobRequest {
+    /// The hash of the blob to delete
+    pub hash: Hash,
+}
+
+impl RpcMsg<ProviderService> for DeleteBlobRequest {
+    type Response = RpcResult<()>;
+}
+
+/// A request to the node to export a verifiable slice of a blob to a file
+///
+/// The blob can be partial, but must contain the requested ranges. See
+/// [`iroh_bytes::slice`] for the format of the file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportSliceRequest {
+    /// The hash of the blob
+    pub hash: Hash,
+    /// The chunk ranges to export
+    pub ranges: RangeSpec,
+    /// The file to write the slice to. Must be absolute.
+    pub path: PathBuf,
+}
+
+impl RpcMsg<ProviderService> for ExportSliceRequest {
+    type Response = RpcResult<ExportSliceResponse>;
+}
+
+/// The response to an [`ExportSliceRequest`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportSliceResponse {
+    /// The chunk ranges that were exported
+    ///
+    /// These are the requested ranges, limited to the size of the blob.
+    pub ranges: RangeSpec,
+}
+
+/// A request to the node to import a slice that was exported with an [`ExportSliceRequest`]
+///
+/// The slice is verified and merged into the entry for the blob. Like for a
+/// [`ShareRequest`], the blob is pinned, so gc does not delete the partial data.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportSliceRequest {
+    /// The file to read the slice from. Must be absolute.
+    pub path: PathBuf,
+}
+
+impl RpcMsg<ProviderService> for ImportSliceRequest {
+    type Response = RpcResult<ImportSliceResponse>;
+}
+
+/// The response to an [`ImportSliceRequest`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportSliceResponse {
+    /// The hash of the blob
+    pub hash: Hash,
+    /// The size of the blob
+    pub size: u64,
+    /// The chunk ranges that were imported
+    pub ranges: RangeSpec,
+    /// True if the blob is now complete
+    pub complete: bool,
+}
+
+/// A request to the node to run a garbage collection
+///
+/// Everything that is not reachable from a root will be deleted.
+/// Will produce a stream of [`GcProgress`] messages.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GcRequest;
+
+impl Msg<ProviderService> for GcRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for GcRequest {
+    type Response = GcProgress;
+}
+
 /// List all blobs, including collections
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ListBlobsRequest;
@@ -269,12 +497,22 @@ pub enum ProviderRequest {
     ListBlobs(ListBlobsRequest),
     ListIncompleteBlobs(ListIncompleteBlobsRequest),
     ListCollections(ListCollectionsRequest),
+    ListTags(ListTagsRequest),
     Provide(ProvideRequest),
     Share(ShareRequest),
     Id(IdRequest),
     Addrs(AddrsRequest),
     Shutdown(ShutdownRequest),
     Validate(ValidateRequest),
+    DeleteBlob(DeleteBlobRequest),
+    ExportSlice(ExportSliceRequest),
+    ImportSlice(ImportSliceRequest),
+    Gc(GcRequest),
+    SetTag(SetTagRequest),
+    DeleteTag(DeleteTagRequest),
+    ListDownloads(ListDownloadsRequest),
+    CancelDownload(CancelDownloadRequest),
+    FindProviders(FindProvidersRequest),
 }
 
 /// The response enum, listing all possible responses.
@@ -286,12 +524,19 @@ pub enum ProviderResponse {
     ListBlobs(ListBlobsResponse),
     ListIncompleteBlobs(ListIncompleteBlobsResponse),
     ListCollections(ListCollectionsResponse),
+    ListTags(ListTagsResponse),
+    ListDownloads(ListDownloadsResponse),
     Provide(ProvideProgress),
     Share(ShareProgress),
     Id(IdResponse),
     Addrs(AddrsResponse),
     Validate(ValidateProgress),
+    Gc(GcProgress),
+    ExportSlice(RpcResult<ExportSliceResponse>),
+    ImportSlice(RpcResult<ImportSliceResponse>),
+    FindProviders(RpcResult<FindProvidersResponse>),
     Shutdown(()),
+    Empty(RpcResult<()>),
 }
 
 impl Service for ProviderService {
@@ -0,0 +1,266 @@
+//! Request tokens signed by the provider.
+//!
+//! A [`SignedToken`] grants access to a set of root hashes until an expiry time, optionally
+//! only to a single requester. It is signed with the [`Keypair`] of the provider, so the
+//! provider can verify it without keeping any state, using [`SignedTokenAuthHandler`].
+//!
+//! On the wire the token is carried in a [`RequestToken`], as a format version byte followed
+//! by the postcard encoding of the token.
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Context, Result};
+use futures::{future::BoxFuture, FutureExt};
+use iroh_bytes::{
+    protocol::{Request, RequestToken},
+    provider::RequestAuthorizationHandler,
+    Hash,
+};
+use iroh_net::tls::{Keypair, PeerId, Signature};
+use serde::{Deserialize, Serialize};
+
+/// The current signed token format version.
+const TOKEN_VERSION: u8 = 1;
+
+/// The capabilities granted by a [`SignedToken`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TokenClaims {
+    /// The root hashes that may be requested with the token.
+    hashes: Vec<Hash>,
+    /// Expiry time, in seconds since the unix epoch.
+    expires: u64,
+    /// If set, only this peer may use the token.
+    requester: Option<PeerId>,
+}
+
+impl TokenClaims {
+    /// Creates new claims for the given root hashes.
+    ///
+    /// The expiry time is stored with a resolution of one second.
+    pub fn new(hashes: Vec<Hash>, expires: SystemTime, requester: Option<PeerId>) -> Self {
+        let expires = expires
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            hashes,
+            expires,
+            requester,
+        }
+    }
+
+    /// The root hashes that may be requested with the token.
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
+
+    /// The peer that may use the token, if it is bound to one.
+    pub fn requester(&self) -> Option<PeerId> {
+        self.requester
+    }
+
+    /// The time after which the token is no longer valid.
+    pub fn expires(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires)
+    }
+
+    /// Check that these claims allow `requester` to make `request` right now.
+    ///
+    /// If the claims are bound to a requester, `requester` must be known and match it.
+    pub fn check(&self, request: &Request, requester: Option<PeerId>) -> Result<()> {
+        ensure!(SystemTime::now() < self.expires(), "token has expired");
+        if let Some(expected) = self.requester {
+            let requester =
+                requester.context("token is bound to a requester, but the requester is unknown")?;
+            ensure!(
+                requester == expected,
+                "token is bound to a different requester"
+            );
+        }
+        match request {
+            Request::Get(get) => {
+                ensure!(
+                    self.hashes.contains(&get.hash),
+                    "token does not allow requesting {}",
+                    get.hash
+                );
+            }
+            Request::CustomGet(_) => {
+                anyhow::bail!("token does not allow custom get requests");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A [`TokenClaims`] signed by the provider's [`Keypair`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedToken {
+    claims: TokenClaims,
+    signature: Signature,
+}
+
+impl SignedToken {
+    /// Sign the given claims with the provider's keypair.
+    pub fn sign(keypair: &Keypair, claims: TokenClaims) -> Self {
+        let signature = keypair.sign(&claims_bytes(&claims));
+        Self { claims, signature }
+    }
+
+    /// Verify the signature of this token against the provider's [`PeerId`].
+    ///
+    /// This does not check the claims, see [`TokenClaims::check`].
+    pub fn verify(&self, issuer: PeerId) -> Result<&TokenClaims> {
+        issuer
+            .verify(&claims_bytes(&self.claims), &self.signature)
+            .context("invalid token signature")?;
+        Ok(&self.claims)
+    }
+
+    /// The claims of this token, which have not necessarily been verified.
+    pub fn claims(&self) -> &TokenClaims {
+        &self.claims
+    }
+
+    /// Encode this token as a [`RequestToken`].
+    ///
+    /// Fails if the token is too large, e.g. because it contains too many hashes.
+    pub fn to_request_token(&self) -> Result<RequestToken> {
+        let mut bytes = vec![TOKEN_VERSION];
+        bytes.extend(postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible"));
+        RequestToken::new(bytes)
+    }
+
+    /// Decode a token from a [`RequestToken`].
+    pub fn from_request_token(token: &RequestToken) -> Result<Self> {
+        match token.as_bytes().split_first() {
+            Some((&TOKEN_VERSION, rest)) => Ok(postcard::from_bytes(rest)?),
+            Some((version, _)) => anyhow::bail!("unsupported token version {version}"),
+            None => anyhow::bail!("empty token"),
+        }
+    }
+}
+
+fn claims_bytes(claims: &TokenClaims) -> Vec<u8> {
+    postcard::to_stdvec(claims).expect("postcard::to_stdvec is infallible")
+}
+
+/// Authorize requests using [`SignedToken`]s issued by a single provider.
+///
+/// Requests without a valid token are rejected.
+#[derive(Debug, Clone)]
+pub struct SignedTokenAuthHandler {
+    issuer: PeerId,
+}
+
+impl SignedTokenAuthHandler {
+    /// Creates a new handler that accepts tokens signed by `issuer`.
+    pub fn new(issuer: PeerId) -> Self {
+        Self { issuer }
+    }
+
+    /// Check that `token` allows `requester` to make `request`.
+    pub fn check(
+        &self,
+        token: Option<&RequestToken>,
+        request: &Request,
+        requester: Option<PeerId>,
+    ) -> Result<()> {
+        let token = token.context("missing request token")?;
+        let token = SignedToken::from_request_token(token)?;
+        token.verify(self.issuer)?.check(request, requester)
+    }
+}
+
+impl RequestAuthorizationHandler for SignedTokenAuthHandler {
+    fn authorize(
+        &self,
+        peer_id: PeerId,
+        _remote_addr: SocketAddr,
+        token: Option<RequestToken>,
+        request: &Request,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let res = self.check(token.as_ref(), request, Some(peer_id));
+        async move { res }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bao_tree::blake3;
+    use iroh_bytes::protocol::GetRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_signed_token() {
+        let keypair = Keypair::generate();
+        let issuer = PeerId::from(keypair.public());
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let other = Hash::from(blake3::hash(b"something else"));
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let token = SignedToken::sign(&keypair, TokenClaims::new(vec![hash], expires, None));
+        let request_token = token.to_request_token().unwrap();
+        assert_eq!(
+            SignedToken::from_request_token(&request_token).unwrap(),
+            token
+        );
+
+        let handler = SignedTokenAuthHandler::new(issuer);
+        let request = Request::from(GetRequest::all(hash));
+        handler.check(Some(&request_token), &request, None).unwrap();
+        // wrong hash
+        let request2 = Request::from(GetRequest::all(other));
+        assert!(handler
+            .check(Some(&request_token), &request2, None)
+            .is_err());
+        // no token
+        assert!(handler.check(None, &request, None).is_err());
+        // wrong issuer
+        let handler2 = SignedTokenAuthHandler::new(PeerId::from(Keypair::generate().public()));
+        assert!(handler2
+            .check(Some(&request_token), &request, None)
+            .is_err());
+        // expired
+        let expired = SignedToken::sign(
+            &keypair,
+            TokenClaims::new(
+                vec![hash],
+                SystemTime::now() - Duration::from_secs(60),
+                None,
+            ),
+        );
+        let expired = expired.to_request_token().unwrap();
+        assert!(handler.check(Some(&expired), &request, None).is_err());
+        // tampered claims
+        let mut tampered = token.clone();
+        tampered.claims.hashes.push(other);
+        let tampered = tampered.to_request_token().unwrap();
+        assert!(handler.check(Some(&tampered), &request2, None).is_err());
+    }
+
+    #[test]
+    fn test_signed_token_requester() {
+        let keypair = Keypair::generate();
+        let issuer = PeerId::from(keypair.public());
+        let requester = PeerId::from(Keypair::generate().public());
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let token = SignedToken::sign(
+            &keypair,
+            TokenClaims::new(vec![hash], expires, Some(requester)),
+        )
+        .to_request_token()
+        .unwrap();
+
+        let handler = SignedTokenAuthHandler::new(issuer);
+        let request = Request::from(GetRequest::all(hash));
+        handler
+            .check(Some(&token), &request, Some(requester))
+            .unwrap();
+        assert!(handler.check(Some(&token), &request, Some(issuer)).is_err());
+        assert!(handler.check(Some(&token), &request, None).is_err());
+    }
+}
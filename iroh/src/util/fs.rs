@@ -85,6 +85,92 @@ pub fn scan_path(root: PathBuf) -> anyhow::Result<Vec<DataSource>> {
     })
 }
 
+/// Watch a path for changes.
+///
+/// Directories are watched recursively. For a single file its parent directory is watched,
+/// so that files which are replaced by renaming are still noticed.
+///
+/// Returns the watcher, which must be kept alive, and a receiver that gets notified after
+/// changes. Bursts of changes are coalesced into a single notification.
+#[cfg(feature = "watch")]
+pub fn watch_path(
+    root: &Path,
+) -> anyhow::Result<(notify::RecommendedWatcher, tokio::sync::mpsc::Receiver<()>)> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            // reading files, e.g. when importing them, is not a change
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+            Ok(_) => {
+                // if the channel is full a notification is already pending
+                tx.try_send(()).ok();
+            }
+            Err(cause) => tracing::warn!("error watching path: {cause}"),
+        }
+    })?;
+    if root.is_dir() {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+    } else {
+        let parent = root.parent().context("path must have a parent")?;
+        watcher.watch(parent, RecursiveMode::NonRecursive)?;
+    }
+    Ok((watcher, rx))
+}
+
+/// The unix mode of a file, including the file type and the permission bits.
+///
+/// On other platforms the mode is derived from the file type and the read-only flag.
+pub fn file_mode(meta: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        meta.mode()
+    }
+    #[cfg(not(unix))]
+    {
+        if meta.is_dir() {
+            0o40755
+        } else if meta.file_type().is_symlink() {
+            0o120777
+        } else if meta.permissions().readonly() {
+            0o100444
+        } else {
+            0o100644
+        }
+    }
+}
+
+/// Set the permission bits of `mode` and the modification time of a file or directory.
+///
+/// The permission bits are only applied on unix.
+#[cfg(feature = "iroh-collection")]
+pub fn set_mode_and_mtime(
+    path: &Path,
+    mode: u32,
+    mtime: std::time::SystemTime,
+) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime))
+}
+
+/// The device and inode of `path` if it is a symlink, to recognize the link later.
+#[cfg(all(unix, feature = "iroh-collection"))]
+pub fn symlink_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::symlink_metadata(path).ok()?;
+    meta.file_type()
+        .is_symlink()
+        .then(|| (meta.dev(), meta.ino()))
+}
+
 /// This function converts a canonicalized relative path to a string, returning
 /// an error if the path is not valid unicode.
 ///
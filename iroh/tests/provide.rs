@@ -4,7 +4,7 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -15,7 +15,8 @@ use futures::{
 };
 use iroh::{
     collection::{ArrayLinkStream, Blob, Collection, IrohCollectionParser},
-    node::{Builder, Event, Node, StaticTokenAuthHandler},
+    node::{Builder, Event, Node, PeerListAuthHandler, StaticTokenAuthHandler},
+    token::SignedTokenAuthHandler,
 };
 use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
 use iroh_net::{
@@ -29,11 +30,11 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 
 use bao_tree::blake3;
 use iroh_bytes::{
-    baomap::Store,
+    baomap::{Map, MapEntry, PartialMap, Store},
     collection::{CollectionParser, CollectionStats, LinkStream},
     get::{fsm, fsm::ConnectedNext, Stats},
-    protocol::{AnyGetRequest, CustomGetRequest, GetRequest, RequestToken},
-    provider::{self, CustomGetHandler, RequestAuthorizationHandler},
+    protocol::{AnyGetRequest, Closed, CustomGetRequest, GetRequest, RequestToken},
+    provider::{self, limits::Limits, CustomGetHandler, RequestAuthorizationHandler},
     util::runtime,
     Hash,
 };
@@ -506,6 +507,72 @@ async fn test_run_ticket() {
     .expect("get ticket failed");
 }
 
+#[tokio::test]
+async fn test_run_signed_ticket() {
+    let rt = test_runtime();
+    let (db, hash) = create_test_db([("test", b"hello")]);
+    let keypair = Keypair::generate();
+    let auth_handler = SignedTokenAuthHandler::new(PeerId::from(keypair.public()));
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let node = test_node(db, addr)
+        .keypair(keypair)
+        .custom_auth_handler(Arc::new(auth_handler))
+        .runtime(&rt)
+        .spawn()
+        .await
+        .unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+
+    let expires = SystemTime::now() + Duration::from_secs(60);
+    let ticket = node.signed_ticket(hash, expires, None).await.unwrap();
+    assert!(ticket.token().is_some());
+    assert!(!ticket.is_expired());
+    let opts = ticket.as_get_options(Keypair::generate(), None);
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let request = GetRequest::all(hash)
+            .with_token(ticket.token().cloned())
+            .into();
+        run_get_request(opts, request).await
+    })
+    .await
+    .expect("timeout")
+    .expect("get signed ticket failed");
+
+    let expired = node
+        .signed_ticket(hash, SystemTime::now() - Duration::from_secs(60), None)
+        .await
+        .unwrap();
+    let opts = expired.as_get_options(Keypair::generate(), None);
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let request = GetRequest::all(hash)
+            .with_token(expired.token().cloned())
+            .into();
+        assert!(run_get_request(opts, request).await.is_err());
+    })
+    .await
+    .expect("timeout");
+
+    // a token bound to a requester can only be used by that requester
+    let requester = Keypair::generate();
+    let bound = node
+        .signed_ticket(hash, expires, Some(PeerId::from(requester.public())))
+        .await
+        .unwrap();
+    let opts = bound.as_get_options(requester, None);
+    let opts2 = bound.as_get_options(Keypair::generate(), None);
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let request: AnyGetRequest = GetRequest::all(hash)
+            .with_token(bound.token().cloned())
+            .into();
+        run_get_request(opts, request.clone())
+            .await
+            .expect("get with bound ticket failed");
+        assert!(run_get_request(opts2, request).await.is_err());
+    })
+    .await
+    .expect("timeout");
+}
+
 /// Utility to validate that the children of a collection are correct
 fn validate_children(collection: Collection, children: BTreeMap<u64, Bytes>) -> anyhow::Result<()> {
     let blobs = collection.into_inner();
@@ -766,6 +833,8 @@ struct CustomAuthHandler;
 impl RequestAuthorizationHandler for CustomAuthHandler {
     fn authorize(
         &self,
+        _peer_id: PeerId,
+        _remote_addr: SocketAddr,
         token: Option<RequestToken>,
         _request: &iroh_bytes::protocol::Request,
     ) -> BoxFuture<'static, Result<()>> {
@@ -845,3 +914,208 @@ async fn test_token_passthrough() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_peer_list_auth() -> Result<()> {
+    let rt = test_runtime();
+    let (db, hash) = create_test_db([("test", b"hello")]);
+    let allowed = Keypair::generate();
+    let allowed_id = PeerId::from(allowed.public());
+    let denied = Keypair::generate();
+    let auth_handler = PeerListAuthHandler::new(Arc::new(StaticTokenAuthHandler::new(None)))
+        .deny([PeerId::from(denied.public())]);
+    let node = test_node(db, (Ipv4Addr::LOCALHOST, 0).into())
+        .custom_auth_handler(Arc::new(auth_handler))
+        .runtime(&rt)
+        .spawn()
+        .await?;
+    let _drop_guard = node.cancel_token().drop_guard();
+
+    let (events_sender, mut events_recv) = mpsc::unbounded_channel();
+    node.subscribe(move |event| {
+        let events_sender = events_sender.clone();
+        async move {
+            if let Event::ByteProvide(provider::Event::ClientConnected { peer_id, .. }) = event {
+                events_sender.send(peer_id).ok();
+            }
+        }
+        .boxed()
+    })
+    .await?;
+
+    let addrs = node.local_endpoint_addresses().await?;
+    let peer_id = node.peer_id();
+    let options = |keypair| iroh::dial::Options {
+        keypair,
+        ..get_options(peer_id, addrs.clone())
+    };
+    tokio::time::timeout(Duration::from_secs(10), async {
+        run_get_request(options(allowed), GetRequest::all(hash).into()).await?;
+        let res = run_get_request(options(denied), GetRequest::all(hash).into()).await;
+        anyhow::ensure!(res.is_err(), "denied peer was served");
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")??;
+
+    // events carry the identity of the requester
+    assert_eq!(events_recv.recv().await, Some(allowed_id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_allow_list() -> Result<()> {
+    let rt = test_runtime();
+    let (db, hash) = create_test_db([("test", b"hello")]);
+    let allowed = Keypair::generate();
+    let auth_handler = PeerListAuthHandler::new(Arc::new(StaticTokenAuthHandler::new(None)))
+        .allow([PeerId::from(allowed.public())]);
+    let node = test_node(db, (Ipv4Addr::LOCALHOST, 0).into())
+        .custom_auth_handler(Arc::new(auth_handler))
+        .runtime(&rt)
+        .spawn()
+        .await?;
+    let _drop_guard = node.cancel_token().drop_guard();
+
+    let addrs = node.local_endpoint_addresses().await?;
+    let peer_id = node.peer_id();
+    let options = |keypair| iroh::dial::Options {
+        keypair,
+        ..get_options(peer_id, addrs.clone())
+    };
+    tokio::time::timeout(Duration::from_secs(10), async {
+        run_get_request(options(allowed), GetRequest::all(hash).into()).await?;
+        // a peer that is not on the allow list is rejected, even though it is not denied
+        let other = Keypair::generate();
+        let res = run_get_request(options(other), GetRequest::all(hash).into()).await;
+        anyhow::ensure!(res.is_err(), "peer that is not allowed was served");
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_limit() -> Result<()> {
+    let rt = test_runtime();
+    let (db, hash) = create_test_db([("test", b"hello")]);
+    let node = test_node(db, (Ipv4Addr::LOCALHOST, 0).into())
+        .limits(Limits {
+            max_connections_per_peer: Some(1),
+            ..Default::default()
+        })
+        .runtime(&rt)
+        .spawn()
+        .await?;
+    let _drop_guard = node.cancel_token().drop_guard();
+
+    let addrs = node.local_endpoint_addresses().await?;
+    let peer_id = node.peer_id();
+    let endpoint = MagicEndpoint::builder()
+        .keypair(Keypair::generate())
+        .bind(0)
+        .await?;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let conn1 = endpoint
+            .connect(peer_id, &iroh_bytes::protocol::ALPN, None, &addrs)
+            .await?;
+        let request = GetRequest::all(hash).into();
+        let response = fsm::start(conn1.clone(), request);
+        let connected = response.next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            bail!("expected start root");
+        };
+        let (_, data) = start.next().concatenate_into_vec().await?;
+        assert_eq!(Hash::from(blake3::hash(&data)), hash);
+
+        // a second connection of the same peer is rejected while the first is open
+        let conn2 = endpoint
+            .connect(peer_id, &iroh_bytes::protocol::ALPN, None, &addrs)
+            .await?;
+        match conn2.closed().await {
+            quinn::ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, Closed::RateLimited.into());
+            }
+            err => bail!("unexpected error {err:?}"),
+        }
+
+        // once the first connection is closed, the peer can connect again
+        conn1.close(0u32.into(), b"done");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let conn3 = endpoint
+            .connect(peer_id, &iroh_bytes::protocol::ALPN, None, &addrs)
+            .await?;
+        let response = fsm::start(conn3, GetRequest::all(hash).into());
+        let connected = response.next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            bail!("expected start root");
+        };
+        let (_, data) = start.next().concatenate_into_vec().await?;
+        assert_eq!(Hash::from(blake3::hash(&data)), hash);
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_multi() -> Result<()> {
+    setup_logging();
+    let rt = test_runtime();
+    let mut big = vec![0u8; 1024 * 1024 * 9 + 123];
+    rand::thread_rng().fill_bytes(&mut big);
+    let entries = [
+        ("big", big),
+        ("small", b"hello".to_vec()),
+        ("empty", vec![]),
+    ];
+    // the first provider does not have the data, so its requests will fail
+    let empty = iroh::baomap::readonly_mem::Store::default();
+    let (db1, hash) = create_test_db(entries.clone());
+    let (db2, _) = create_test_db(entries.clone());
+    let root_size = Map::get(&db1, &hash).context("missing collection")?.size();
+    let mut nodes = Vec::new();
+    for db in [empty, db1, db2] {
+        let node = test_node(db, (Ipv4Addr::LOCALHOST, 0).into())
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        nodes.push(node);
+    }
+    let mut conns = Vec::new();
+    for node in &nodes {
+        let addrs = node.local_endpoint_addresses().await?;
+        conns.push(iroh::dial::dial(get_options(node.peer_id(), addrs)).await?);
+    }
+
+    let db = iroh::baomap::mem::Store::new(rt.clone());
+    // a partial root is downloaded, not taken for the complete collection
+    db.get_or_create_partial(hash, root_size)?;
+    let (tx, rx) = flume::unbounded();
+    let sender = iroh_bytes::util::progress::FlumeProgressSender::new(tx);
+    tokio::time::timeout(
+        Duration::from_secs(30),
+        iroh::get::multi::get(&db, &IrohCollectionParser, conns, hash, true, sender),
+    )
+    .await
+    .context("timeout")??;
+
+    let events = rx.drain().collect::<Vec<_>>();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        provider::ShareProgress::ProviderFailed { provider: 0, .. }
+    )));
+    for (_, data) in entries {
+        let entry = db
+            .get(&blake3::hash(&data).into())
+            .context("missing blob")?;
+        let actual = entry.data_reader().await?.read_to_end().await?;
+        assert_eq!(actual, data);
+    }
+    for node in nodes {
+        node.shutdown();
+    }
+    Ok(())
+}